@@ -8,6 +8,7 @@ use std::sync::Arc;
 use ecs_derive::EngineComponent;
 
 use stardust_common::math::*;
+use stardust_world::{ModelTransform, ModelChange};
 
 mod fields;
 pub use fields::*;
@@ -71,6 +72,8 @@ pub struct Scene {
     world: World,
 
     settings: SceneSettings,
+    /// `models_generation` of the voxel world the last time the models were checked
+    models_generation: u64,
 }
 
 impl Scene {
@@ -83,6 +86,7 @@ impl Scene {
             world: world,
 
             settings: SceneSettings::new(),
+            models_generation: 0,
         }
     }
 
@@ -102,7 +106,10 @@ impl Scene {
     }
 
     pub fn update_dirty_models(&mut self, voxel_world: &stardust_world::World) {
-        let mut sys_update_dirty_models = DirtyModelsUpdate { voxel_world };
+        // Only look for replaced or unregistered models when something changed in the registry
+        let check_models = self.models_generation != voxel_world.models_generation();
+        self.models_generation = voxel_world.models_generation();
+        let mut sys_update_dirty_models = DirtyModelsUpdate { voxel_world, check_models };
         sys_update_dirty_models.run_now(&mut self.world);
     }
 
@@ -152,6 +159,7 @@ impl Scene {
 
 struct DirtyModelsUpdate<'w> {
     voxel_world: &'w stardust_world::World,
+    check_models: bool,
 }
 
 impl <'a, 'w> System<'a> for DirtyModelsUpdate<'w> {
@@ -159,19 +167,74 @@ impl <'a, 'w> System<'a> for DirtyModelsUpdate<'w> {
 
     fn run(&mut self, mut cmodel: Self::SystemData) {
         for model in (&mut cmodel).join() {
-            if model.dirty {
-                if let Some(next_model_ref) = &model.next_model {
-                    if let Some(model_ref) = &model.model_ref {
-                        self.voxel_world.update_model(Arc::clone(model_ref), model.prev_vox_pos, model.vox_pos, true);
+            if self.check_models {
+                if let Some(next_model) = &model.next_model {
+                    match self.voxel_world.model_change(next_model) {
+                        Some(ModelChange::Replaced(new_model)) => model.next_model = Some(new_model),
+                        Some(ModelChange::Unregistered) => model.next_model = None,
+                        None => {},
+                    }
+                }
+                if let Some(model_ref) = &model.model_ref {
+                    match self.voxel_world.model_change(model_ref) {
+                        Some(ModelChange::Replaced(new_model)) => if model.next_model.is_none() {
+                            model.next_model = Some(new_model);
+                            model.dirty = true;
+                        },
+                        Some(ModelChange::Unregistered) => {
+                            // Let go of the model so its buffers can be released
+                            model.model_ref = None;
+                            if let Some(owner) = model.owner {
+                                self.voxel_world.remove_model(owner);
+                            }
+                            if let Some(instance) = model.instance.take() {
+                                self.voxel_world.remove_instance(instance);
+                            }
+                        },
+                        None => {},
+                    }
+                }
+            }
+            if model.dynamic {
+                if model.instance.is_none() {
+                    // Switched from static to dynamic, take the voxels out of the world first
+                    if let Some(owner) = model.owner.take() {
+                        self.voxel_world.remove_model(owner);
+                    }
+                }
+                if model.next_model.is_some() {
+                    if let Some(instance) = model.instance.take() {
+                        self.voxel_world.remove_instance(instance);
                     }
-                    self.voxel_world.update_model(Arc::clone(next_model_ref), model.prev_vox_pos, model.vox_pos, false);
                     model.update_model_ref();
-                } else {
-                    if let Some(model_ref) = &model.model_ref {
-                        self.voxel_world.update_model(Arc::clone(model_ref), model.prev_vox_pos, model.vox_pos, false);
+                }
+                if let Some(model_ref) = &model.model_ref {
+                    match model.instance {
+                        Some(instance) => if model.dirty {
+                            self.voxel_world.set_instance_transform(instance, model.instance_transform);
+                        },
+                        None => model.instance = Some(self.voxel_world.add_instance(Arc::clone(model_ref), model.instance_transform)),
                     }
                 }
                 model.dirty = false;
+                continue;
+            }
+            if let Some(instance) = model.instance.take() {
+                // Switched from dynamic to static
+                self.voxel_world.remove_instance(instance);
+                model.dirty = true;
+            }
+            if model.dirty {
+                if model.next_model.is_some() {
+                    model.update_model_ref();
+                }
+                if let Some(model_ref) = &model.model_ref {
+                    let voxel_world = self.voxel_world;
+                    let owner = *model.owner.get_or_insert_with(|| voxel_world.create_owner());
+                    // Takes away the previous placement, restoring whatever was underneath
+                    self.voxel_world.place_model(owner, Arc::clone(model_ref), model.transform);
+                }
+                model.dirty = false;
             }
         }
     }
@@ -185,13 +248,12 @@ impl<'a> System<'a> for TransformPosModelVPosUpdate {
     type SystemData = (WriteStorage<'a, CompModel>, ReadStorage<'a, CompTransform>);
 
     fn run(&mut self, (mut cmodel, ctransform): Self::SystemData) {
-        const WORLD_SIZE_HALF: UVec3 = uvec3(0,0,0);
-
         // join() combines the iterators, so we only iterate the objects with both components
         for (model, transform) in (&mut cmodel, &ctransform).join() {
             let scaled_pos = transform.position * self.voxels_per_meter;
-            let vox_pos = scaled_pos.as_uvec3() + WORLD_SIZE_HALF;
-            model.update_voxel_position(vox_pos);
+            let vox_pos = scaled_pos.floor().as_ivec3();
+            model.update_transform(ModelTransform::new(vox_pos, transform.rotation(), transform.scale));
+            model.update_instance_transform(Mat4::from_scale_rotation_translation(transform.scale, transform.rotation(), scaled_pos));
         }
     }
 }
@@ -4,44 +4,66 @@ use ecs_derive::EngineComponent;
 use std::sync::Arc;
 
 use stardust_common::math::*;
-use stardust_world::GpuModel;
+use stardust_world::{GpuModel, ModelTransform, InstanceId, VoxelOwner};
 
 use crate::{Value, ValueOwned, FieldError, FieldMap};
 
 #[derive(Component, Clone, EngineComponent)]
 #[storage(DenseVecStorage)]
 pub struct CompModel {
-    pub prev_vox_pos: UVec3,
-    pub vox_pos: UVec3,
+    pub transform: ModelTransform,
+    /// Owner of the voxels the model placed in the world, created when it's first placed
+    pub owner: Option<VoxelOwner>,
     #[visible]
     pub dirty: bool,
 
     #[editable("Model")]
     pub model_ref: Option<Arc<GpuModel>>,
     pub next_model: Option<Arc<GpuModel>>,
+
+    /// Dynamic models are drawn as instances on top of the world instead of being written into it
+    #[editable("Dynamic")]
+    pub dynamic: bool,
+    pub instance: Option<InstanceId>,
+    /// Model voxels to world space, used for the instance of dynamic models
+    pub instance_transform: Mat4,
 }
 
 impl CompModel {
     pub fn new() -> Self {
         Self {
-            prev_vox_pos: uvec3(0,0,0),
-            vox_pos: uvec3(0,0,0),
+            transform: ModelTransform::default(),
+            owner: None,
             dirty: false,
 
             model_ref: None,
             next_model: None,
+
+            dynamic: false,
+            instance: None,
+            instance_transform: Mat4::IDENTITY,
         }
     }
 
-    /// Returns true if the new location is different to the current position
-    pub(crate) fn update_voxel_position(&mut self, new_vox_pos: UVec3) {
-        if self.vox_pos == new_vox_pos { return; }
+    /// Marks the model as dirty if the new transform is different to the current one
+    pub(crate) fn update_transform(&mut self, new_transform: ModelTransform) {
+        if self.transform == new_transform { return; }
 
-        self.prev_vox_pos = self.vox_pos;
-        self.vox_pos = new_vox_pos;
+        self.transform = new_transform;
         self.dirty = true;
     }
 
+    /// Marks dynamic models as dirty if the new transform is different to the current one.
+    /// Dynamic models aren't snapped to the voxel grid, so this catches movement `update_transform` doesn't.
+    pub(crate) fn update_instance_transform(&mut self, new_transform: Mat4) {
+        if self.instance_transform == new_transform { return; }
+
+        self.instance_transform = new_transform;
+        if self.dynamic {
+            self.dirty = true;
+        }
+    }
+
     pub(crate) fn update_model_ref(&mut self) {
         self.model_ref = self.next_model.clone();
         self.next_model = None;
@@ -10,10 +10,16 @@ use crate::{Value, FieldMap};
 pub struct CompTransform {
     #[editable]
     pub position: Vec3,
+    /// Stored as a quaternion, the inspector shows it as euler angles
+    #[editable]
     pub rotation_x: f32,
+    #[editable]
     pub rotation_y: f32,
+    #[editable]
     pub rotation_z: f32,
+    #[editable]
     pub rotation_w: f32,
+    #[editable]
     pub scale: Vec3,
 }
 
@@ -26,7 +32,11 @@ impl CompTransform {
             rotation_y: r[1],
             rotation_z: r[2],
             rotation_w: r[3],
-            scale: vec3(0.0, 0.0, 0.0),
+            scale: vec3(1.0, 1.0, 1.0),
         }
     }
+
+    pub fn rotation(&self) -> Quat {
+        Quat::from_xyzw(self.rotation_x, self.rotation_y, self.rotation_z, self.rotation_w)
+    }
 }
@@ -40,6 +40,9 @@ pub struct EngineInternals {
 
     pub console_pending_writes: VecDeque<String>,
     pub selected_entity: Option<Entity>,
+
+    /// Set to shut the engine down once the current frame is drawn
+    pub quit_requested: bool,
 }
 
 pub struct Engine {
@@ -55,10 +58,11 @@ impl Engine {
 
         ctx.set_window_title("Stardust engine");
         trace!("Demo created!");
-        let world = stardust_world::World::new(ctx);
-        let renderer = renderer::Renderer::new(ctx);
+        let world_config = stardust_world::WorldConfig::default();
+        let world = stardust_world::World::new(ctx, world_config);
+        let renderer = renderer::Renderer::new(ctx, &world_config);
         let mut camera = Camera::default();
-        camera.pos = vec3(1024.0, 1024.0, 1624.0);
+        camera.pos = vec3(0.0, 0.0, 600.0);
         camera.rotation = Quat::from_rotation_y(0.0);
 
         let render_size = ctx.size();
@@ -84,6 +88,7 @@ impl Engine {
 
                 console_pending_writes: VecDeque::new(),
                 selected_entity: None,
+                quit_requested: false,
             },
         };
 
@@ -183,7 +188,11 @@ impl App for Engine {
         puffin::profile_function!();
         self.frame_counter += 1;
 
-        self.world.process(ctx);
+        if let Some(report) = self.world.process(ctx) {
+            if report.pool_exhausted() {
+                self.console_write(format!("World ran out of bricks, {} voxels were dropped!", report.voxels_dropped));
+            }
+        }
 
         let size = self.render_size;
         let wsize = ctx.size();
@@ -230,6 +239,10 @@ impl App for Engine {
                 self.render_offset = (available_rect.min.x as u32, wsize.height - available_rect.max.y as u32);
             }
         });
+
+        if self.quit_requested {
+            self.shutdown(ctx);
+        }
     }
 }
 
@@ -237,6 +250,13 @@ impl Engine {
     fn console_write<S: Into<String>>(&mut self, s: S) {
         self.console_pending_writes.push_back(s.into());
     }
+
+    /// Frees the world's GPU resources while the GL context is still alive, then exits
+    fn shutdown(&mut self, ctx: &Context) -> ! {
+        debug!("Shutting down...");
+        self.internals.world.destroy(ctx);
+        std::process::exit(0);
+    }
 }
 
 fn main() {
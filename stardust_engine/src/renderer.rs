@@ -9,21 +9,34 @@ const FS: &'static str = include_str!("../shaders/fs.glsl");
 pub struct Renderer {
     mesh: mesh::Mesh,
     shader: shader::Shader,
+    /// Config the shader was compiled with, it gets compiled again when the world is recentered
+    config: WorldConfig,
+
+    /// Distance in voxels at which bricks start using their first LOD level, every next
+    /// level kicks in at double the distance. 0 always traces at full detail.
+    pub lod_distance: u32,
 }
 
 impl Renderer {
-    pub fn new(ctx: &Context) -> Self {
+    pub fn new(ctx: &Context, config: &WorldConfig) -> Self {
         let mesh = mesh::Mesh::quad(&ctx);
-        let shader = shader::Shader::new(&ctx, (VS, "../shaders/vs.glsl"), (FS, "../shaders/fs.glsl"));
+        let shader = compile_shader(ctx, config);
         debug!("Renderer created!");
         Self {
             mesh,
             shader,
+            config: *config,
+
+            lod_distance: 256,
         }
     }
 
-    pub fn render(&self, _ctx: &Context, world: &mut World, camera: &Camera, render_size: (u32, u32)) {
+    pub fn render(&mut self, ctx: &Context, world: &mut World, camera: &Camera, render_size: (u32, u32)) {
         puffin::profile_function!();
+        if *world.config() != self.config {
+            self.config = *world.config();
+            self.shader = compile_shader(ctx, &self.config);
+        }
         let aspect_ratio = (render_size.0 as f32) / (render_size.1 as f32);
         self.shader.while_bound(|uni| {
             puffin::profile_scope!("raytracing");
@@ -31,9 +44,16 @@ impl Renderer {
             let m = camera.matrix_invprojview(aspect_ratio).to_cols_array();
             uni.set_mat4("invprojview", m);
             uni.set_vec3("rayPos", camera.pos.into());
+            uni.set_u32("lodDistance", self.lod_distance);
+            uni.set_u32("instanceCount", world.instance_count());
             self.mesh.draw()?;
             world.unbind();
             Ok(())
         }).expect("Failed to render!");
     }
 }
+
+fn compile_shader(ctx: &Context, config: &WorldConfig) -> shader::Shader {
+    let fs = config.preprocess_shader(FS);
+    shader::Shader::new(&ctx, (VS, "../shaders/vs.glsl"), (&fs[..], "../shaders/fs.glsl"))
+}
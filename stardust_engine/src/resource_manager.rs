@@ -122,9 +122,17 @@ impl ResourceManager {
         }
 
         if let Ok(model) = self.fetch_model(&path) {
-            let gpu_model = stardust_world::GpuModel::from_model(ctx, path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or(String::from("UNKNOWN")), model);
-            let gpu_model = std::sync::Arc::new(gpu_model);
-            world.register_model(std::sync::Arc::clone(&gpu_model));
+            // Registered by path, so reloading the file replaces the model everywhere it's used
+            let gpu_model = stardust_world::GpuModel::from_model(ctx, path.to_string_lossy().to_string(), model);
+            world.register_model(std::sync::Arc::new(gpu_model));
+        }
+    }
+
+    /// Forgets a loaded model and unregisters it from the world, entities using it let go of it
+    pub fn unload_model(&mut self, path: &Path, world: &mut stardust_world::World) {
+        self.models.remove(path);
+        if let Some(id) = world.find_model(&path.to_string_lossy()) {
+            world.unregister_model(id);
         }
     }
 
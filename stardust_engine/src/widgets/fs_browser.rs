@@ -112,6 +112,14 @@ impl super::Widget for FsBrowser {
                                 let resource = engine.resources.fetch_resource(f.into());
                                 ctx.add_widget(Box::new(super::ResourceInspector::new(resource, filename.clone())), super::DockLoc::Floating);
                             }
+                            if engine.resources.fetch_model(f).is_ok() {
+                                resp.context_menu(|ui| {
+                                    if ui.button("Unload").clicked() {
+                                        engine.resources.unload_model(f, &mut engine.world);
+                                        ui.close_menu();
+                                    }
+                                });
+                            }
                             ui.label(filename);
                         });
                         i += 1;
@@ -1,4 +1,5 @@
 use stardust_ecs::prelude::*;
+use stardust_common::math::*;
 
 pub struct Inspector {
     current_entity: Option<Entity>,
@@ -72,14 +73,53 @@ impl super::Widget for Inspector {
     }
 }
 
-fn draw_generic_component<S: Into<String>>(ctx: &mut super::WidgetContext, ui: &mut egui::Ui, engine: &mut crate::EngineInternals, entity: Entity, name: S, fields: FieldMap) -> bool {
+/// Takes the `rotation_x/y/z/w` fields of a quaternion out of `fields`, returns them with the
+/// index the rotation should be drawn at
+fn take_rotation<'a>(fields: &mut FieldMap<'a>) -> Option<(usize, [&'a mut f32; 4])> {
+    let index = fields.get_index_of("rotation_x")?;
+    let mut take = |name: &str| match fields.shift_remove(name) {
+        Some((true, Value::PrimF32(f))) => Some(f),
+        _ => None,
+    };
+    Some((index, [take("rotation_x")?, take("rotation_y")?, take("rotation_z")?, take("rotation_w")?]))
+}
+
+/// Edits a quaternion as euler angles in degrees, applied around y, then x, then z
+fn draw_rotation(ui: &mut egui::Ui, [x, y, z, w]: [&mut f32; 4]) -> bool {
+    let (ry, rx, rz) = Quat::from_xyzw(*x, *y, *z, *w).to_euler(EulerRot::YXZ);
+    let mut degrees = [rx.to_degrees(), ry.to_degrees(), rz.to_degrees()];
+
+    let mut changed = false;
+    ui.label("rotation");
+    ui.columns(3, |columns| {
+        for (column, angle) in columns.iter_mut().zip(&mut degrees) {
+            let resp = column.add(egui::DragValue::new(angle).suffix("°"));
+            changed = changed || resp.changed() || resp.lost_focus();
+        }
+    });
+    ui.end_row();
+
+    if changed {
+        let q = Quat::from_euler(EulerRot::YXZ, degrees[1].to_radians(), degrees[0].to_radians(), degrees[2].to_radians());
+        [*x, *y, *z, *w] = q.to_array();
+    }
+    changed
+}
+
+fn draw_generic_component<S: Into<String>>(ctx: &mut super::WidgetContext, ui: &mut egui::Ui, engine: &mut crate::EngineInternals, entity: Entity, name: S, mut fields: FieldMap) -> bool {
     let name = name.into();
 
     let mut dirty = false;
+    let mut rotation = take_rotation(&mut fields);
 
     ui.label(egui::RichText::new(&name).strong());
     egui::Grid::new(format!("inspector_comp_generic_{}", &name)).num_columns(2).show(ui, |ui| {
-        for (k, (interactive, v)) in fields {
+        for (i, (k, (interactive, v))) in fields.into_iter().enumerate() {
+            if rotation.as_ref().map_or(false, |(index, _)| *index == i) {
+                let (_, quat) = rotation.take().unwrap();
+                dirty = draw_rotation(ui, quat) || dirty;
+            }
+
             let field_name = k.clone();
             ui.label(k);
             ui.add_enabled_ui(interactive, |ui| {
@@ -145,6 +185,11 @@ fn draw_generic_component<S: Into<String>>(ctx: &mut super::WidgetContext, ui: &
 
             ui.end_row();
         }
+
+        // The rotation was the last field
+        if let Some((_, quat)) = rotation.take() {
+            dirty = draw_rotation(ui, quat) || dirty;
+        }
     });
 
     dirty
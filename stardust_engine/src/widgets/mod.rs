@@ -178,6 +178,10 @@ impl WidgetManager {
                     if ui.button("Save project...").clicked() {
                         debug!("[BUTTON] Save project...");
                     }
+                    ui.separator();
+                    if ui.button("Quit").clicked() {
+                        engine.quit_requested = true;
+                    }
                 });
                 ui.menu_button("Widgets", |ui| {
                     if ui.button("Flamegraph").clicked() {
@@ -33,7 +33,7 @@ impl super::Widget for ModelSelector {
     fn draw(&mut self, ctx: &mut super::WidgetContext, ui: &mut egui::Ui, engine: &mut crate::EngineInternals) {
         egui::ScrollArea::vertical().show(ui, |ui| {
             egui::Grid::new("model_selector_grid").num_columns(2).show(ui, |ui| {
-                for (i, model) in engine.world.gpu_models.iter().enumerate() {
+                for (i, (_, model)) in engine.world.models().enumerate() {
                     let mut name = model.name.clone();
                     name = name.split("/").last().unwrap_or(&name).to_string();
                     if name.len() > 8 {
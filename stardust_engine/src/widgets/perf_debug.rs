@@ -10,10 +10,18 @@ impl super::Widget for PerfDebug {
         ui.label(&format!("ms: {}", engine.delta_s * 1000.0));
         ui.label(&format!("render resolution: {:?}", engine.render_size));
         ui.label(&format!("cam_pos: {:?}", engine.camera.pos));
-        ui.label(&format!("gpu_models: {}", engine.world.gpu_models.len()));
+        ui.label(&format!("gpu_models: {}", engine.world.model_count()));
         ui.label(&format!("models_queued: {}", engine.world.models_queued()));
         ui.label(&format!("voxels_queued: {}", engine.world.voxels_queued()));
-        // ui.label(&format!("bricks_free: {}", engine.world.bricks_free()));
-        // ui.label(&format!("layer0_free: {}", engine.world.layer0s_free()));
+        let report = *engine.world.last_report();
+        ui.label(&format!("bricks_free: {}", report.bricks_free));
+        ui.label(&format!("layer0_free: {}", report.layer0s_free));
+        ui.label(&format!("layer0_used: {}", report.layer0s_used));
+        ui.label(&format!("bricks_allocated: {}", report.bricks_allocated));
+        ui.label(&format!("bricks_freed: {}", report.bricks_freed));
+        ui.label(&format!("voxels_written: {}", report.voxels_written));
+        ui.label(&format!("voxels_dropped: {}", report.voxels_dropped));
+        ui.label(&format!("process_ms: {:.2}", report.process_micros as f64 / 1000.0));
+        ui.label(&format!("voxels_per_second: {:.0}", report.voxels_per_second()));
     }
 }
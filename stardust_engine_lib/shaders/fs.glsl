@@ -1,8 +1,12 @@
 #version 460
 
-#define BRICK_MAP_SIZE 64
 #define BRICK_SIZE 16
 #define LAYER0_SIZE 16
+#define CELL_SIZE 4
+
+// Offsets of each level in BrickLod.blocks, in 16 bit entries. Must match brick.rs
+#define LOD_LEVELS 3
+const uint LOD_OFFSETS[LOD_LEVELS + 1] = uint[](0u, 0u, 8u*8u*8u, 8u*8u*8u + 4u*4u*4u);
 
 #define pow2(x) (x*x)
 
@@ -10,8 +14,46 @@ in vec2 uv;
 
 out vec4 FragColor;
 
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
 struct Brick {
-    uint voxels[16*16*16 + 4];
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct BrickLod {
+    // Set when the brick changed since its LODs were last built
+    uint dirty;
+    // Averaged rgb565 colour per block, two per uint, 0 means the block is empty.
+    // Level 1 has 8x8x8 blocks of 2x2x2 voxels, level 2 4x4x4 blocks and level 3 2x2x2 blocks.
+    uint blocks[(8*8*8 + 4*4*4 + 2*2*2) / 2];
+};
+
+// Dynamic model instance, must match instance.rs
+struct Instance {
+    // Brick map space to model space, model space is in model voxels
+    mat4 storageToModel;
+    // Bounds in brick map space, w is unused
+    vec4 boundsMin;
+    vec4 boundsMax;
+    // xyz = size of the model's voxel grid, w = offset of the grid in instance_voxels
+    uvec4 grid;
 };
 
 struct Layer0Node {
@@ -31,28 +73,89 @@ layout(std430, binding = 2) buffer brick_map {
     uint layer0_pool_indices[];
 };
 
+layout(std430, binding = 9) readonly buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+layout(std430, binding = 11) readonly buffer brick_lod_pool {
+    BrickLod lods[];
+};
+
+layout(std430, binding = 12) readonly buffer instance_pool {
+    Instance instances[];
+};
+
+layout(std430, binding = 13) readonly buffer instance_voxel_pool {
+    // Every voxel in the bounding box of each instanced model, x first
+    uint instanceVoxels[];
+};
+
 uniform mat4 invprojview;
 uniform vec3 rayPos;
+// Distance in voxels at which LOD level 1 kicks in, every next level starts at double the distance. 0 disables LODs
+uniform uint lodDistance;
+uniform uint instanceCount;
+
+uint readVoxel(uint brick_pool_idx, uint voxel_idx) {
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    if (raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM) return raw_bricks[raw_idx - 1].voxels[voxel_idx];
+    // Every voxel of a uniform brick is palette entry 1, no need to look at the indices
+    if (raw_idx == RAW_IDX_UNIFORM) return bricks[brick_pool_idx - 1].palette[1];
+    uint palette_idx = (bricks[brick_pool_idx - 1].indices[voxel_idx / 8] >> ((voxel_idx % 8) * 4)) & 0xF;
+    return bricks[brick_pool_idx - 1].palette[palette_idx];
+}
 
-bool getVoxel(ivec3 pos, out vec3 color, uint brick_pool_idx) {
-    ivec3 local_pos = ivec3(pos);
-    int voxel_idx = local_pos.x + local_pos.y * 16 + local_pos.z * 16 * 16;
-    if (voxel_idx < 0) return false;
-    uint vi = uint(voxel_idx);
-    if (bricks[brick_pool_idx - 1].voxels[vi] == 0) return false;
-    // uint opacity_metalic = (bricks[brick_pool_idx - 1].voxels[vi] & (0xFF << 24)) >> 24;
-    // if ((opacity_metalic << 1) == 0) return false;
-    uint color_rgb565 = bricks[brick_pool_idx - 1].voxels[vi] & 0xFFFF;
+vec3 unpackColor(uint color_rgb565) {
     uint r5 = color_rgb565 & 31;
     uint g6 = (color_rgb565 & (63 << 5)) >> 5;
     uint b5 = (color_rgb565 & (31 << 11)) >> 11;
     uint r = r5 << 3;
     uint g = g6 << 2;
     uint b = b5 << 3;
-    color = vec3(float(r) / 255.0, float(g) / 255.0, float(b) / 255.0);
+    return vec3(float(r) / 255.0, float(g) / 255.0, float(b) / 255.0);
+}
+
+bool getVoxel(ivec3 pos, out vec3 color, uint brick_pool_idx) {
+    ivec3 local_pos = ivec3(pos);
+    int voxel_idx = local_pos.x + local_pos.y * 16 + local_pos.z * 16 * 16;
+    if (voxel_idx < 0) return false;
+    uint vi = uint(voxel_idx);
+    uint voxel = readVoxel(brick_pool_idx, vi);
+    if (voxel == 0) return false;
+    // uint opacity_metalic = (voxel & (0xFF << 24)) >> 24;
+    // if ((opacity_metalic << 1) == 0) return false;
+    color = unpackColor(voxel & 0xFFFF);
+    return true;
+}
+
+// Same as getVoxel, but reads the averaged colour of the block at `level` containing `pos`
+bool getLodVoxel(ivec3 pos, uint level, out vec3 color, uint brick_pool_idx) {
+    uvec3 block_pos = uvec3(pos) >> level;
+    uint size = 16 >> level;
+    uint entry = LOD_OFFSETS[level] + block_pos.x + block_pos.y * size + block_pos.z * size * size;
+    uint rgb565 = (lods[brick_pool_idx - 1].blocks[entry / 2] >> ((entry % 2) * 16)) & 0xFFFF;
+    if (rgb565 == 0) return false;
+    color = unpackColor(rgb565);
     return true;
 }
 
+uint lodLevel(float dist) {
+    if (lodDistance == 0) return 0;
+    uint level = 0;
+    float level_dist = float(lodDistance);
+    while (level < LOD_LEVELS && dist > level_dist) {
+        level++;
+        level_dist *= 2.0;
+    }
+    return level;
+}
+
+bool cellOccupied(ivec3 pos, uint brick_pool_idx) {
+    ivec3 cell_pos = pos / CELL_SIZE;
+    uint cell_idx = uint(cell_pos.x + cell_pos.y * 4 + cell_pos.z * 4 * 4);
+    return (bricks[brick_pool_idx - 1].occupancy[cell_idx / 32] & (1u << (cell_idx % 32))) != 0;
+}
+
 bool getBrick(ivec3 pos, uint layer0_pool_idx, out uint brick_pool_idx) {
     ivec3 p = pos;
     int layer0_idx = p.x + p.y * LAYER0_SIZE + p.z * LAYER0_SIZE * LAYER0_SIZE;
@@ -71,6 +174,21 @@ bool getLayer0(ivec3 pos, out uint layer0_pool_idx) {
     return true;
 }
 
+// Picks the axis the ray crosses first. Ties pick a single axis too, x before y before z,
+// as moving along two axes at once would skip a voxel.
+vec3 stepMask(vec3 v) {
+    if (v.x <= v.y && v.x <= v.z) return vec3(1.0, 0.0, 0.0);
+    if (v.y <= v.z) return vec3(0.0, 1.0, 0.0);
+    return vec3(0.0, 0.0, 1.0);
+}
+
+// How far to move along each axis to get to the voxel a skip ends up in. The exit point can
+// land right on the edge of a voxel along another axis, where rounding puts it a voxel back,
+// so this never moves backwards.
+vec3 skipMask(vec3 ro, vec3 rd, float dist, vec3 normal, vec3 gridPos) {
+    return max((floor(ro + rd * dist - normal * 0.1) - gridPos) * sign(rd), vec3(0.0));
+}
+
 // Calcs intersection and exit distances, and normal at intersection.
 // The ray must be in box/object space.
 vec2 boxIntersection(in vec3 ro, in vec3 rd, in vec3 rad)
@@ -98,16 +216,17 @@ float traceVoxels(vec3 ro, vec3 rd, float tmax, out vec3 normal, out vec3 color,
     hitsDeallocBrick = false;
 
     vec3 gridPos = floor(ro);
-    vec3 sideDist = abs(length(rd)/rd);
     vec3 toSide = ((sign(rd) * 0.5 + 0.5) - fract(ro)) / rd;
 
-    float dist;
+    float dist = 0.0;
     vec3 mask;
 
 	uint brick_pool_idx = 0;
     uint layer0_pool_idx = 0;
 
-    for(int i = 0; i < 1024;) {
+    // Every iteration counts as a single step no matter how far it skips, LODs keep the
+    // amount of iterations needed for distant geometry down
+    for(int i = 0; i < 1024; i++) {
         ivec3 layer0Pos = ivec3(floor(gridPos / float(LAYER0_SIZE) / float(BRICK_SIZE)));
         ivec3 brickPos = ivec3(floor(gridPos / float(BRICK_SIZE)));
         ivec3 voxelPos = ivec3(floor(gridPos)) % BRICK_SIZE;
@@ -116,33 +235,51 @@ float traceVoxels(vec3 ro, vec3 rd, float tmax, out vec3 normal, out vec3 color,
             if (getBrick(brickPos % LAYER0_SIZE, layer0_pool_idx, brick_pool_idx)) {
     			hitsBrick = true;
 
-                if (bricks[brick_pool_idx - 1].voxels[4099] > 0) {
+                if (bricks[brick_pool_idx - 1].meta[3] > 0) {
                     hitsDeallocBrick = true;
                 }
 
-                if (getVoxel(voxelPos, color, brick_pool_idx)) return dist;
-
-                mask = vec3(lessThanEqual(toSide.xyz, min(toSide.yzx, toSide.zxy)));
-                dist = dot(toSide * mask, vec3(1.0));
-                normal = mask * -sign(rd);
-    			++i;
+                uint lod = lodLevel(dist);
+                if (lod > 0) {
+                    if (getLodVoxel(voxelPos, lod, color, brick_pool_idx)) return dist;
+
+                    // The whole block is empty, skip straight to the next one
+                    float blockSize = float(1u << lod);
+                    ivec3 blockPos = ivec3(floor(gridPos / blockSize));
+                    vec3 toExit = ((sign(rd) * 0.5 + 0.5 + vec3(blockPos)) * blockSize - ro) / rd;
+                    normal = -sign(rd) * stepMask(toExit);
+                    dist = dot(abs(normal), toExit);
+                    mask = skipMask(ro, rd, dist, normal, gridPos);
+                } else if (cellOccupied(voxelPos, brick_pool_idx)) {
+                    if (getVoxel(voxelPos, color, brick_pool_idx)) return dist;
+
+                    mask = stepMask(toSide);
+                    dist = dot(toSide * mask, vec3(1.0));
+                    normal = mask * -sign(rd);
+                } else {
+                    // Nothing in this cell, skip straight to the next one
+                    ivec3 cellPos = ivec3(floor(gridPos / float(CELL_SIZE)));
+                    vec3 toExit = ((sign(rd) * 0.5 + 0.5 + vec3(cellPos)) * float(CELL_SIZE) - ro) / rd;
+                    normal = -sign(rd) * stepMask(toExit);
+                    dist = dot(abs(normal), toExit);
+                    mask = skipMask(ro, rd, dist, normal, gridPos);
+                }
             } else {
                 vec3 toExit = ((sign(rd) * 0.5 + 0.5 + vec3(brickPos)) * float(BRICK_SIZE) - ro) / rd;
-                normal = -sign(rd) * vec3(lessThanEqual(toExit.xyz, min(toExit.yzx, toExit.zxy)));
+                normal = -sign(rd) * stepMask(toExit);
                 dist = dot(abs(normal), toExit);
-                mask = abs(floor(ro + rd * dist - normal * 0.1) - gridPos);
-    			i += max(int(mask.x + mask.y + mask.z), 1);
+                mask = skipMask(ro, rd, dist, normal, gridPos);
             }
         } else {
             vec3 toExit = ((sign(rd) * 0.5 + 0.5 + vec3(layer0Pos)) * float(LAYER0_SIZE) * float(BRICK_SIZE) - ro) / rd;
-            normal = -sign(rd) * vec3(lessThanEqual(toExit.xyz, min(toExit.yzx, toExit.zxy)));
+            normal = -sign(rd) * stepMask(toExit);
             dist = dot(abs(normal), toExit);
-            mask = abs(floor(ro + rd * dist - normal * 0.1) - gridPos);
-            i += max(int(mask.x + mask.y + mask.z), 1);
+            mask = skipMask(ro, rd, dist, normal, gridPos);
         }
 
-        toSide += sideDist * mask;
         gridPos += mask * sign(rd);
+        // Recomputed rather than accumulated, so rounding errors don't depend on how the ray got here
+        toSide = ((sign(rd) * 0.5 + 0.5) + gridPos - ro) / rd;
 
         float d2 = pow2(gridPos.x - ro.x) + pow2(gridPos.y - ro.y);
         if (d2 > tmax2) return -1.0;
@@ -158,7 +295,71 @@ float trace(vec3 ro, vec3 rd, out vec3 normal, out vec3 color, out bool hitsBric
     hitsMap = true;
     vec3 hit_pos = ro + rd * hit.x;
     if (hit.x < 0.0) hit_pos = ro; // Inside the box already
-	return traceVoxels(hit_pos, rd, hit.y, normal, color, hitsBrick, hitsLayer, hitsDeallocBrick);
+	float dist = traceVoxels(hit_pos, rd, hit.y, normal, color, hitsBrick, hitsLayer, hitsDeallocBrick);
+    if (dist < 0.0) return dist;
+    // Distance from ro instead of from where the ray entered the world, so it can be compared to instance hits
+    return dist + max(hit.x, 0.0);
+}
+
+// Steps through the voxel grid of a dynamic instance. The ray is in model space and doesn't
+// need to be normalized, distances are in multiples of rd so they match the world space ray.
+float traceInstance(vec3 ro, vec3 rd, uvec4 grid, out vec3 normal, out vec3 color) {
+    vec3 size = vec3(grid.xyz);
+    vec2 hit = boxIntersection(ro - size * 0.5, rd, size * 0.5);
+    if (hit.y < 0.0) return -1.0;
+    float dist = max(hit.x, 0.0);
+
+    vec3 stepDir = sign(rd);
+    // Normal of the face the ray enters the grid through, zero if it starts inside
+    vec3 toEnter = ((0.5 - stepDir * 0.5) * size - ro) / rd;
+    normal = hit.x > 0.0 ? -stepDir * vec3(greaterThanEqual(toEnter.xyz, max(toEnter.yzx, toEnter.zxy))) : vec3(0.0);
+
+    ivec3 gridPos = clamp(ivec3(floor(ro + rd * dist)), ivec3(0), ivec3(grid.xyz) - 1);
+    vec3 sideDist = abs(1.0 / rd);
+    vec3 toSide = ((stepDir * 0.5 + 0.5) + vec3(gridPos) - ro) / rd;
+
+    int maxSteps = int(grid.x + grid.y + grid.z);
+    for (int i = 0; i < maxSteps; i++) {
+        uint voxel = instanceVoxels[grid.w + uint(gridPos.x) + uint(gridPos.y) * grid.x + uint(gridPos.z) * grid.x * grid.y];
+        if (voxel != 0) {
+            color = unpackColor(voxel & 0xFFFF);
+            return dist;
+        }
+
+        vec3 mask = stepMask(toSide);
+        dist = dot(toSide * mask, vec3(1.0));
+        normal = mask * -stepDir;
+        toSide += sideDist * mask;
+        gridPos += ivec3(mask * stepDir);
+        if (any(lessThan(gridPos, ivec3(0))) || any(greaterThanEqual(gridPos, ivec3(grid.xyz)))) return -1.0;
+    }
+
+    return -1.0;
+}
+
+// Finds the closest dynamic instance hit before maxDist, a negative maxDist means there is no limit.
+// Instances whose bounds start further away than the closest hit so far get skipped.
+float traceInstances(vec3 ro, vec3 rd, float maxDist, out vec3 normal, out vec3 color) {
+    float closest = maxDist;
+    float result = -1.0;
+    for (uint i = 0; i < instanceCount; i++) {
+        vec3 halfSize = (instances[i].boundsMax.xyz - instances[i].boundsMin.xyz) * 0.5;
+        vec2 bounds = boxIntersection(ro - instances[i].boundsMin.xyz - halfSize, rd, halfSize);
+        if (bounds.y < 0.0 || (closest >= 0.0 && bounds.x > closest)) continue;
+
+        mat4 storageToModel = instances[i].storageToModel;
+        vec3 instanceNormal;
+        vec3 instanceColor;
+        float dist = traceInstance((storageToModel * vec4(ro, 1.0)).xyz, mat3(storageToModel) * rd, instances[i].grid, instanceNormal, instanceColor);
+        if (dist < 0.0 || (closest >= 0.0 && dist >= closest)) continue;
+
+        closest = dist;
+        result = dist;
+        // Normals transform with the inverse transpose of the model to world matrix
+        normal = normalize(transpose(mat3(storageToModel)) * instanceNormal);
+        color = instanceColor;
+    }
+    return result;
 }
 
 void main() {
@@ -176,7 +377,16 @@ void main() {
     bool hitsLayer = false;
     bool hitsMap = false;
     bool hitsDeallocBrick = false;
-    float hitDist = trace(rayPos, rayDir, normal, color, hitsBrick, hitsLayer, hitsMap, hitsDeallocBrick);
+    // rayPos is in world space, the brick map starts at -WORLD_ORIGIN
+    float hitDist = trace(rayPos + vec3(WORLD_ORIGIN), rayDir, normal, color, hitsBrick, hitsLayer, hitsMap, hitsDeallocBrick);
+    vec3 instanceNormal;
+    vec3 instanceColor;
+    float instanceDist = traceInstances(rayPos + vec3(WORLD_ORIGIN), rayDir, hitDist > 0.0 ? hitDist : -1.0, instanceNormal, instanceColor);
+    if (instanceDist > 0.0) {
+        hitDist = instanceDist;
+        normal = instanceNormal;
+        color = instanceColor;
+    }
     if (hitDist > 0.0) {
         float lambert = max(dot(normalize(vec3(-3.0, 1.0, 2.0)), normal), 0.0) * 0.5 + 0.5;
         FragColor = vec4(color * lambert, 1.0);
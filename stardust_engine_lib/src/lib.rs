@@ -11,7 +11,7 @@ pub use foxtail::prelude::*;
 pub use stardust_common::camera::Camera;
 pub use stardust_common::math::*;
 pub use stardust_ecs::prelude::*;
-pub use stardust_world::GpuModel;
+pub use stardust_world::{GpuModel, WorldConfig, StreamingConfig};
 
 pub mod renderer;
 
@@ -21,11 +21,16 @@ pub fn run_app<A: VoxelApp + 'static>() {
 
 pub trait VoxelApp {
     fn new(ctx: &Context, engine: &mut EngineInternals) -> Self;
+    /// Sizes used to create the world, called once before `new`.
+    fn world_config() -> WorldConfig { WorldConfig::default() }
+    /// Enables streaming regions around the camera in and out of a disk cache, called once before `new`.
+    fn streaming_config() -> Option<StreamingConfig> { None }
     fn update(&mut self, input: &Input, engine: &mut EngineInternals) {}
 }
 
 pub struct EngineInternals {
     pub world: stardust_world::World,
+    pub streamer: Option<stardust_world::WorldStreamer>,
     pub renderer: renderer::Renderer,
     framebuffer: Framebuffer,
     render_size: (u32, u32),
@@ -38,6 +43,9 @@ pub struct EngineInternals {
     last_frame: Instant,
 
     pub current_scene: Scene,
+
+    /// Set to shut the engine down once the current frame is drawn
+    pub quit_requested: bool,
 }
 
 pub struct Engine<A: VoxelApp> {
@@ -52,16 +60,19 @@ impl<A: VoxelApp> Engine<A> {
         ctx.set_window_title("Stardust engine");
         trace!("Demo created!");
 
-        let world = stardust_world::World::new(ctx);
-        let renderer = renderer::Renderer::new(ctx);
+        let world_config = A::world_config();
+        let world = stardust_world::World::new(ctx, world_config);
+        let renderer = renderer::Renderer::new(ctx, &world_config);
+        let streamer = A::streaming_config().map(|config| stardust_world::WorldStreamer::new(config).expect("Failed to create world streamer!"));
         let mut camera = Camera::default();
-        camera.pos = vec3(1024.0, 1024.0, 1624.0);
+        camera.pos = vec3(0.0, 0.0, 600.0);
         camera.rotation = Quat::from_rotation_y(0.0);
 
         let render_size = ctx.size();
 
         let mut internals = EngineInternals {
             world,
+            streamer,
             renderer,
             framebuffer: Framebuffer::new(ctx),
             render_size: (render_size.width, render_size.height),
@@ -74,6 +85,7 @@ impl<A: VoxelApp> Engine<A> {
             last_frame: Instant::now(),
 
             current_scene: Scene::new(),
+            quit_requested: false,
         };
 
         let app = A::new(ctx, &mut internals);
@@ -85,6 +97,15 @@ impl<A: VoxelApp> Engine<A> {
     }
 }
 
+impl<A: VoxelApp> Engine<A> {
+    /// Frees the world's GPU resources while the GL context is still alive, then exits
+    fn shutdown(&mut self, ctx: &Context) -> ! {
+        debug!("Shutting down...");
+        self.internals.world.destroy(ctx);
+        std::process::exit(0);
+    }
+}
+
 impl<A: VoxelApp> Deref for Engine<A> {
     type Target = EngineInternals;
     fn deref(&self) -> &Self::Target {
@@ -147,6 +168,11 @@ impl<A: VoxelApp> App for Engine<A> {
         puffin::profile_function!();
         self.frame_counter += 1;
 
+        if let Some(streamer) = &mut self.internals.streamer {
+            if let Err(e) = streamer.update(ctx, &mut self.internals.world, self.internals.camera.pos) {
+                error!("Failed to stream world regions: {}", e);
+            }
+        }
         self.world.process(ctx);
 
         let size = self.render_size;
@@ -188,5 +214,9 @@ impl<A: VoxelApp> App for Engine<A> {
                 self.render_offset = (available_rect.min.x as u32, wsize.height - available_rect.max.y as u32);
             }
         });
+
+        if self.quit_requested {
+            self.shutdown(ctx);
+        }
     }
 }
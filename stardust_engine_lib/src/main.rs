@@ -42,6 +42,7 @@ impl VoxelApp for App {
         //     }
         // }
 
+        let mut batch = VoxelBatch::new();
         for x in 0..256 {
             for y in 0..256 {
                 for z in 0..256 {
@@ -50,32 +51,17 @@ impl VoxelApp for App {
                     let cz = z as i32 - 128;
                     let rr = cx*cx + cy*cy + cz*cz;
                     if rr < 128*128 {
-                        engine.world.set_voxel(Voxel::new([x as u8, y as u8, z as u8], 255, 0, false, 255), uvec3(1024 + x, 1024 + y, 1024 + z));
+                        batch.set_voxel(Voxel::new([x as u8, y as u8, z as u8], 255, 0, false, 255), ivec3(x, y, z));
                     }
                 }
             }
         }
+        engine.world.submit_batch(batch);
 
-        for x in 0..32 {
-            for y in 0..32 {
-                for z in 0..32 {
-                    let cx = x as i32 - 16;
-                    let cy = y as i32 - 16;
-                    let cz = z as i32 - 16;
-                    let rr = cx*cx + cy*cy + cz*cz;
-                    if rr < 16*16 {
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x, 1024 + y, 1024 + z));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x + 224, 1024 + y, 1024 + z));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x + 224, 1024 + y + 244, 1024 + z));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x, 1024 + y + 244, 1024 + z));
-
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x, 1024 + y, 1024 + z + 224));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x + 224, 1024 + y, 1024 + z + 224));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x + 224, 1024 + y + 244, 1024 + z + 224));
-                        engine.world.set_voxel(Voxel::new([255; 3], 255, 255, false, 255), uvec3(1024 + x, 1024 + y + 244, 1024 + z + 224));
-                    }
-                }
-            }
+        let white = Voxel::new([255; 3], 255, 255, false, 255);
+        for offset in [ivec3(0, 0, 0), ivec3(224, 0, 0), ivec3(224, 244, 0), ivec3(0, 244, 0)] {
+            engine.world.fill_sphere(white, ivec3(16, 16, 16) + offset, 16);
+            engine.world.fill_sphere(white, ivec3(16, 16, 240) + offset, 16);
         }
 
         Self {
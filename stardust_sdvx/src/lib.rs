@@ -1,3 +1,4 @@
+use std::path::Path;
 use serde::{Deserialize, Serialize};
 
 use stardust_common::voxel::Voxel;
@@ -64,6 +65,12 @@ impl Model {
         ciborium::ser::into_writer(&raw, &mut bytes)?;
         Ok(bytes)
     }
+
+    /// Writes the model to a .sdvx file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,451 @@
+#[macro_use]
+extern crate log;
+
+use std::collections::HashSet;
+use rayon::prelude::*;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+use stardust_world::{World, WorldConfig, VoxelBatch};
+
+mod noise;
+
+pub use noise::Noise;
+use noise::sub_seed;
+
+/// Size of a region along each axis, in voxels. Regions are layer0 nodes, see `WorldStreamer`
+pub const REGION_SIZE: i32 = 256;
+
+/// A material that goes from the surface, or the end of the layer above it, down to `depth`
+/// voxels below the surface
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialLayer {
+    pub depth: i32,
+    pub voxel: Voxel,
+}
+
+impl MaterialLayer {
+    pub fn new(depth: i32, voxel: Voxel) -> Self {
+        Self { depth, voxel }
+    }
+}
+
+/// Rules for a kind of terrain. Every column belongs to the biome closest to its temperature
+/// and moisture, and heights get blended between nearby biomes so there are no cliffs at the borders.
+#[derive(Debug, Clone)]
+pub struct Biome {
+    pub name: String,
+    /// Where the biome sits on the temperature map, in -1..1
+    pub temperature: f32,
+    /// Where the biome sits on the moisture map, in -1..1
+    pub moisture: f32,
+    /// Multiplies `TerrainConfig::height_amplitude`
+    pub height_scale: f32,
+    /// Added to `TerrainConfig::base_height`
+    pub height_offset: i32,
+    /// Materials near the surface, on top of `TerrainConfig::layers`
+    pub layers: Vec<MaterialLayer>,
+}
+
+/// Settings for `TerrainGenerator`. The same config always generates the same terrain
+#[derive(Debug, Clone)]
+pub struct TerrainConfig {
+    pub seed: u64,
+    /// World height the surface varies around
+    pub base_height: i32,
+    /// How far the surface goes above or below `base_height`, in voxels
+    pub height_amplitude: f32,
+    /// Frequency of the heightmap noise, in 1/voxels
+    pub height_frequency: f32,
+    /// Octaves of heightmap noise, more gives more detail
+    pub height_octaves: u32,
+    /// Nothing gets generated below this world height. Everything down to here is solid apart
+    /// from caves, so deep terrain costs a lot of voxels
+    pub bottom: i32,
+
+    /// Frequency of the cave noise, in 1/voxels
+    pub cave_frequency: f32,
+    /// Caves are carved where the cave noise is above this, higher gives fewer caves. Above 1 turns them off
+    pub cave_threshold: f32,
+    /// Caves don't get closer to the surface than this, in voxels
+    pub cave_min_depth: i32,
+
+    /// Frequency of the temperature and moisture maps biomes are picked by, in 1/voxels
+    pub biome_frequency: f32,
+    /// At least one biome is needed
+    pub biomes: Vec<Biome>,
+    /// Materials below the biome layers, by depth. The last layer goes on forever. Without any
+    /// layers here or in the biome, nothing gets generated
+    pub layers: Vec<MaterialLayer>,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        let grass = Voxel::new([86, 148, 62], 240, 0, false, 255);
+        let dirt = Voxel::new([121, 85, 58], 255, 0, false, 255);
+        let sand = Voxel::new([219, 197, 132], 255, 0, false, 255);
+        let snow = Voxel::new([236, 242, 248], 160, 0, false, 255);
+        let stone = Voxel::new([122, 122, 128], 200, 0, false, 255);
+        let deep_stone = Voxel::new([72, 70, 78], 200, 0, false, 255);
+        Self {
+            seed: 0,
+            base_height: 0,
+            height_amplitude: 48.0,
+            height_frequency: 1.0 / 512.0,
+            height_octaves: 5,
+            bottom: -64,
+
+            cave_frequency: 1.0 / 64.0,
+            cave_threshold: 0.35,
+            cave_min_depth: 12,
+
+            biome_frequency: 1.0 / 2048.0,
+            biomes: vec![
+                Biome {
+                    name: String::from("Plains"),
+                    temperature: 0.0,
+                    moisture: 0.2,
+                    height_scale: 0.5,
+                    height_offset: 0,
+                    layers: vec![MaterialLayer::new(1, grass), MaterialLayer::new(5, dirt)],
+                },
+                Biome {
+                    name: String::from("Desert"),
+                    temperature: 0.6,
+                    moisture: -0.5,
+                    height_scale: 0.3,
+                    height_offset: -4,
+                    layers: vec![MaterialLayer::new(8, sand)],
+                },
+                Biome {
+                    name: String::from("Mountains"),
+                    temperature: -0.3,
+                    moisture: -0.1,
+                    height_scale: 2.5,
+                    height_offset: 40,
+                    layers: vec![MaterialLayer::new(2, stone)],
+                },
+                Biome {
+                    name: String::from("Tundra"),
+                    temperature: -0.7,
+                    moisture: 0.4,
+                    height_scale: 0.8,
+                    height_offset: 8,
+                    layers: vec![MaterialLayer::new(2, snow), MaterialLayer::new(4, dirt)],
+                },
+            ],
+            layers: vec![MaterialLayer::new(64, stone), MaterialLayer::new(i32::MAX, deep_stone)],
+        }
+    }
+}
+
+/// Everything about a single column of terrain
+#[derive(Debug, Copy, Clone)]
+pub struct Column {
+    /// World height of the topmost voxel
+    pub height: i32,
+    /// Index into `TerrainConfig::biomes`
+    pub biome: usize,
+}
+
+/// Generates terrain from a `TerrainConfig`. Every voxel only depends on the config and its
+/// position, so regions can be generated in any order, on any thread, and come out the same.
+pub struct TerrainGenerator {
+    config: TerrainConfig,
+    height_noise: Noise,
+    cave_noise: Noise,
+    temperature_noise: Noise,
+    moisture_noise: Noise,
+}
+
+impl TerrainGenerator {
+    pub fn new(config: TerrainConfig) -> Self {
+        assert!(!config.biomes.is_empty(), "Terrain needs at least one biome!");
+        Self {
+            height_noise: Noise::new(sub_seed(config.seed, 0)),
+            cave_noise: Noise::new(sub_seed(config.seed, 1)),
+            temperature_noise: Noise::new(sub_seed(config.seed, 2)),
+            moisture_noise: Noise::new(sub_seed(config.seed, 3)),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &TerrainConfig {
+        &self.config
+    }
+
+    pub fn column(&self, x: i32, z: i32) -> Column {
+        let p = vec2(x as f32, z as f32);
+        let climate = p * self.config.biome_frequency;
+        let temperature = self.temperature_noise.fbm2(climate, 2);
+        let moisture = self.moisture_noise.fbm2(climate, 2);
+
+        // Blend the biome heights by how close the climate is to each biome, so they flow into each other
+        let mut biome = 0;
+        let mut closest = f32::INFINITY;
+        let mut weights = 0.0;
+        let mut height_scale = 0.0;
+        let mut height_offset = 0.0;
+        for (i, b) in self.config.biomes.iter().enumerate() {
+            let distance = vec2(temperature - b.temperature, moisture - b.moisture).length_squared();
+            if distance < closest {
+                closest = distance;
+                biome = i;
+            }
+            let weight = 1.0 / (distance + 1e-4).powi(2);
+            weights += weight;
+            height_scale += b.height_scale * weight;
+            height_offset += b.height_offset as f32 * weight;
+        }
+        height_scale /= weights;
+        height_offset /= weights;
+
+        let noise = self.height_noise.fbm2(p * self.config.height_frequency, self.config.height_octaves);
+        let height = self.config.base_height as f32 + height_offset + noise * self.config.height_amplitude * height_scale;
+        Column {
+            height: height.floor() as i32,
+            biome,
+        }
+    }
+
+    pub fn biome(&self, x: i32, z: i32) -> &Biome {
+        &self.config.biomes[self.column(x, z).biome]
+    }
+
+    /// The voxel at a world position, `None` for air and caves
+    pub fn voxel(&self, pos: IVec3) -> Option<Voxel> {
+        self.voxel_in_column(&self.column(pos.x, pos.z), pos)
+    }
+
+    fn voxel_in_column(&self, column: &Column, pos: IVec3) -> Option<Voxel> {
+        if pos.y > column.height || pos.y < self.config.bottom { return None; }
+        let depth = column.height - pos.y;
+        if depth >= self.config.cave_min_depth && self.cave_noise.fbm3(pos.as_vec3() * self.config.cave_frequency, 2) > self.config.cave_threshold {
+            return None;
+        }
+        self.material(column.biome, depth)
+    }
+
+    /// The material `depth` voxels below the surface of a biome, `None` if there are no layers at all
+    fn material(&self, biome: usize, depth: i32) -> Option<Voxel> {
+        let mut last = None;
+        for layer in self.config.biomes[biome].layers.iter().chain(&self.config.layers) {
+            if depth < layer.depth {
+                return Some(layer.voxel);
+            }
+            last = Some(layer.voxel);
+        }
+        // Below every layer, the last one keeps going
+        last
+    }
+
+    /// Generates all voxels of the region starting at a world position, see `region_of`.
+    /// Doesn't touch the world, so it can run on any thread.
+    pub fn generate_region(&self, region: IVec3) -> VoxelBatch {
+        puffin::profile_function!();
+        let mut batch = VoxelBatch::new();
+        let region_top = region.y + REGION_SIZE - 1;
+        if region_top < self.config.bottom { return batch; }
+        for z in region.z..region.z + REGION_SIZE {
+            for x in region.x..region.x + REGION_SIZE {
+                let column = self.column(x, z);
+                let top = column.height.min(region_top);
+                let bottom = self.config.bottom.max(region.y);
+                for y in bottom..=top {
+                    let pos = ivec3(x, y, z);
+                    if let Some(voxel) = self.voxel_in_column(&column, pos) {
+                        batch.set_voxel(voxel, pos);
+                    }
+                }
+            }
+        }
+        batch
+    }
+
+    /// Generates a region and queues it in the world
+    pub fn generate_into(&self, world: &World, region: IVec3) {
+        world.submit_batch(self.generate_region(region));
+    }
+}
+
+/// World position of the first voxel of the region containing a position
+pub fn region_of(pos: IVec3) -> IVec3 {
+    ivec3(pos.x.div_euclid(REGION_SIZE), pos.y.div_euclid(REGION_SIZE), pos.z.div_euclid(REGION_SIZE)) * REGION_SIZE
+}
+
+/// Generates terrain regions around the camera the first time they come within range, spread
+/// over all cores. Regions are only ever generated once, so this works together with
+/// `WorldStreamer`, which takes over once a generated region gets evicted. The streamer also
+/// recenters the world on the camera, so every region in range fits in the brick map.
+pub struct LazyTerrain {
+    generator: TerrainGenerator,
+    generated: HashSet<IVec3>,
+    /// Regions within this distance of the camera, in regions, get generated
+    pub radius: u32,
+    /// Maximum amount of regions generated per `update`, to spread the cost over multiple frames
+    pub max_regions_per_update: usize,
+}
+
+impl LazyTerrain {
+    pub fn new(generator: TerrainGenerator, radius: u32) -> Self {
+        Self {
+            generator,
+            generated: HashSet::new(),
+            radius,
+            max_regions_per_update: rayon::current_num_threads(),
+        }
+    }
+
+    pub fn generator(&self) -> &TerrainGenerator {
+        &self.generator
+    }
+
+    pub fn is_generated(&self, region: IVec3) -> bool {
+        self.generated.contains(&region)
+    }
+
+    /// Marks a region as generated without generating it, like when it was loaded from a save
+    pub fn mark_generated(&mut self, region: IVec3) {
+        self.generated.insert(region);
+    }
+
+    /// Makes a region get generated again the next time it's in range
+    pub fn forget(&mut self, region: IVec3) {
+        self.generated.remove(&region);
+    }
+
+    /// Generates the closest regions that are in range and weren't generated yet, and queues
+    /// them in the world. Call this once per frame, after `WorldStreamer::update` moved the world
+    /// to the camera and before `World::process`. Fails if a region in range isn't in the brick
+    /// map, which means the world wasn't recentered, see `World::recenter`.
+    /// Returns the amount of regions generated.
+    pub fn update(&mut self, world: &World, camera_pos: Vec3) -> anyhow::Result<usize> {
+        puffin::profile_function!();
+        let config: WorldConfig = *world.config();
+        if config.brick_map_size <= 2 * self.radius as usize {
+            anyhow::bail!("A brick map of size {} can't hold regions up to {} away from the camera!", config.brick_map_size, self.radius);
+        }
+        let camera_region = region_of(camera_pos.floor().as_ivec3());
+        let r = self.radius as i32;
+
+        let mut pending = Vec::new();
+        for z in -r..=r {
+            for y in -r..=r {
+                for x in -r..=r {
+                    let region = camera_region + ivec3(x, y, z) * REGION_SIZE;
+                    if self.generated.contains(&region) { continue; }
+                    if config.brick_map_idx(region).is_none() {
+                        anyhow::bail!("Region {} is outside of the world, it has to be recentered on the camera first!", region);
+                    }
+                    pending.push(region);
+                }
+            }
+        }
+        pending.sort_by_key(|region| (*region - camera_region).abs().max_element());
+        pending.truncate(self.max_regions_per_update);
+        if pending.is_empty() { return Ok(0); }
+
+        let generator = &self.generator;
+        let batches: Vec<VoxelBatch> = pending.par_iter().map(|region| generator.generate_region(*region)).collect();
+        for (region, batch) in pending.iter().zip(batches) {
+            debug!("Generated region {} ({} voxels)", region, batch.len());
+            world.submit_batch(batch);
+            self.generated.insert(*region);
+        }
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(seed: u64) -> TerrainGenerator {
+        TerrainGenerator::new(TerrainConfig { seed, ..Default::default() })
+    }
+
+    fn assert_same(a: &VoxelBatch, b: &VoxelBatch) {
+        assert_eq!(a.len(), b.len());
+        for ((va, pa), (vb, pb)) in a.voxels().iter().zip(b.voxels()) {
+            assert_eq!(pa, pb);
+            assert_eq!(va.0, vb.0);
+        }
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let regions = [ivec3(0, -256, 0), ivec3(-256, -256, 512), ivec3(256, 0, -256)];
+        let a = generator(7);
+        let first: Vec<VoxelBatch> = regions.iter().map(|region| a.generate_region(*region)).collect();
+        assert!(first.iter().any(|batch| !batch.is_empty()));
+
+        // A new generator with the same seed, going through the regions in another order
+        let b = generator(7);
+        for (region, batch) in regions.iter().zip(&first).rev() {
+            assert_same(&b.generate_region(*region), batch);
+        }
+
+        let other = generator(8).generate_region(regions[0]);
+        assert!(other.len() != first[0].len() || other.voxels().iter().zip(first[0].voxels()).any(|((va, pa), (vb, pb))| pa != pb || va.0 != vb.0));
+    }
+
+    #[test]
+    fn regions_match_single_voxels() {
+        let generator = generator(3);
+        let region = ivec3(0, -256, 0);
+        let batch = generator.generate_region(region);
+        for (voxel, pos) in batch.voxels().iter().step_by(101) {
+            assert!(pos.cmpge(region).all() && pos.cmplt(region + REGION_SIZE).all());
+            let column = generator.column(pos.x, pos.z);
+            assert!(pos.y <= column.height && pos.y >= generator.config().bottom);
+            assert_eq!(generator.voxel(*pos).unwrap().0, voxel.0);
+        }
+    }
+
+    #[test]
+    fn surface_uses_the_biome_layers() {
+        let generator = generator(11);
+        let column = generator.column(10, -20);
+        let top = generator.voxel(ivec3(10, column.height, -20)).unwrap();
+        assert_eq!(top.0, generator.config().biomes[column.biome].layers[0].voxel.0);
+        assert!(generator.voxel(ivec3(10, column.height + 1, -20)).is_none());
+        assert!(generator.voxel(ivec3(10, generator.config().bottom - 1, -20)).is_none());
+    }
+
+    #[test]
+    fn last_layer_goes_on_forever() {
+        let stone = Voxel::new([1, 2, 3], 255, 0, false, 255);
+        let mut config = TerrainConfig { layers: vec![MaterialLayer::new(4, stone)], bottom: -4096, ..Default::default() };
+        for biome in &mut config.biomes {
+            biome.layers.clear();
+        }
+        let generator = TerrainGenerator::new(config);
+        assert_eq!(generator.material(0, 0).unwrap().0, stone.0);
+        assert_eq!(generator.material(0, 1000).unwrap().0, stone.0);
+    }
+
+    #[test]
+    fn no_layers_generate_nothing() {
+        let mut config = TerrainConfig { layers: Vec::new(), ..Default::default() };
+        for biome in &mut config.biomes {
+            biome.layers.clear();
+        }
+        let generator = TerrainGenerator::new(config);
+        assert!(generator.material(0, 0).is_none());
+        assert!(generator.generate_region(ivec3(0, -256, 0)).is_empty());
+        assert!(generator.generate_region(ivec3(0, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn regions_above_the_terrain_or_below_the_bottom_are_empty() {
+        let generator = generator(0);
+        assert!(generator.generate_region(ivec3(0, 512, 0)).is_empty());
+        assert!(generator.generate_region(ivec3(0, -512, 0)).is_empty());
+    }
+
+    #[test]
+    fn region_of_rounds_down() {
+        assert_eq!(region_of(ivec3(0, 255, 256)), ivec3(0, 0, 256));
+        assert_eq!(region_of(ivec3(-1, -256, -257)), ivec3(-256, -256, -512));
+    }
+}
@@ -0,0 +1,210 @@
+use stardust_common::math::*;
+
+/// Seeded gradient (Perlin) noise. Only uses integer hashing and plain float math, so the same
+/// seed gives the same terrain on every machine and in every order regions get generated in.
+#[derive(Clone)]
+pub struct Noise {
+    perm: [u8; 512],
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut table = [0u8; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        // Fisher-Yates shuffle driven by splitmix64
+        let mut state = seed;
+        for i in (1..256).rev() {
+            let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+        let mut perm = [0u8; 512];
+        for (i, v) in perm.iter_mut().enumerate() {
+            *v = table[i & 255];
+        }
+        Self { perm }
+    }
+
+    fn hash(&self, x: i32, y: i32, z: i32) -> u8 {
+        let a = self.perm[(x & 255) as usize] as usize;
+        let b = self.perm[a + (y & 255) as usize] as usize;
+        self.perm[b + (z & 255) as usize]
+    }
+
+    /// Noise in about -1..1 at a point, 0 at every integer position
+    pub fn get3(&self, p: Vec3) -> f32 {
+        let cell = p.floor();
+        let f = p - cell;
+        let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+        let u = f.x * f.x * f.x * (f.x * (f.x * 6.0 - 15.0) + 10.0);
+        let v = f.y * f.y * f.y * (f.y * (f.y * 6.0 - 15.0) + 10.0);
+        let w = f.z * f.z * f.z * (f.z * (f.z * 6.0 - 15.0) + 10.0);
+
+        let corner = |dx: i32, dy: i32, dz: i32| {
+            grad3(self.hash(x + dx, y + dy, z + dz), f - vec3(dx as f32, dy as f32, dz as f32))
+        };
+        let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), u);
+        let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), u);
+        let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), u);
+        let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), u);
+        lerp(lerp(x00, x10, v), lerp(x01, x11, v), w)
+    }
+
+    /// Noise in about -1..1 on a plane, for heightmaps and other per column values
+    pub fn get2(&self, p: Vec2) -> f32 {
+        let cell = p.floor();
+        let f = p - cell;
+        let (x, y) = (cell.x as i32, cell.y as i32);
+        let u = f.x * f.x * f.x * (f.x * (f.x * 6.0 - 15.0) + 10.0);
+        let v = f.y * f.y * f.y * (f.y * (f.y * 6.0 - 15.0) + 10.0);
+
+        let corner = |dx: i32, dy: i32| {
+            grad2(self.hash(x + dx, y + dy, 0), f - vec2(dx as f32, dy as f32))
+        };
+        lerp(lerp(corner(0, 0), corner(1, 0), u), lerp(corner(0, 1), corner(1, 1), u), v)
+    }
+
+    /// Sums octaves of noise, each at double the frequency and half the strength of the last.
+    /// Stays in about -1..1 regardless of the amount of octaves
+    pub fn fbm2(&self, p: Vec2, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut frequency = 1.0;
+        for _ in 0..octaves.max(1) {
+            sum += self.get2(p * frequency) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum / total
+    }
+
+    /// Same as `fbm2`, in 3D
+    pub fn fbm3(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut frequency = 1.0;
+        for _ in 0..octaves.max(1) {
+            sum += self.get3(p * frequency) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum / total
+    }
+}
+
+/// Derives independent seeds from one seed, so every noise map of the terrain gets its own
+pub(crate) fn sub_seed(seed: u64, index: u64) -> u64 {
+    let mut state = seed ^ index.wrapping_mul(0xA24B_AED4_963E_E407);
+    splitmix64(&mut state)
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Dot product with one of the 12 edge directions of a cube
+fn grad3(hash: u8, p: Vec3) -> f32 {
+    match hash % 12 {
+        0 => p.x + p.y,
+        1 => -p.x + p.y,
+        2 => p.x - p.y,
+        3 => -p.x - p.y,
+        4 => p.x + p.z,
+        5 => -p.x + p.z,
+        6 => p.x - p.z,
+        7 => -p.x - p.z,
+        8 => p.y + p.z,
+        9 => -p.y + p.z,
+        10 => p.y - p.z,
+        _ => -p.y - p.z,
+    }
+}
+
+/// Dot product with one of 8 directions, scaled so the result stays in about -1..1
+fn grad2(hash: u8, p: Vec2) -> f32 {
+    match hash % 8 {
+        0 => p.x + p.y,
+        1 => -p.x + p.y,
+        2 => p.x - p.y,
+        3 => -p.x - p.y,
+        4 => p.x * std::f32::consts::SQRT_2,
+        5 => -p.x * std::f32::consts::SQRT_2,
+        6 => p.y * std::f32::consts::SQRT_2,
+        _ => -p.y * std::f32::consts::SQRT_2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> impl Iterator<Item = Vec3> {
+        (0..4096).map(|i| vec3(i as f32 * 0.137 - 200.0, i as f32 * 0.071 - 3.0, i as f32 * -0.029))
+    }
+
+    #[test]
+    fn same_seed_same_noise() {
+        let a = Noise::new(42);
+        let b = Noise::new(42);
+        for p in points() {
+            assert_eq!(a.get3(p), b.get3(p));
+            assert_eq!(a.get2(p.truncate()), b.get2(p.truncate()));
+            assert_eq!(a.fbm3(p, 3), b.fbm3(p, 3));
+            assert_eq!(a.fbm2(p.truncate(), 5), b.fbm2(p.truncate(), 5));
+        }
+    }
+
+    #[test]
+    fn different_seeds_differ() {
+        let a = Noise::new(1);
+        let b = Noise::new(2);
+        assert!(points().any(|p| a.get3(p) != b.get3(p)));
+        assert!(points().any(|p| a.get2(p.truncate()) != b.get2(p.truncate())));
+        assert_ne!(sub_seed(7, 0), sub_seed(7, 1));
+        assert_ne!(sub_seed(7, 0), sub_seed(8, 0));
+    }
+
+    #[test]
+    fn zero_on_the_lattice() {
+        let noise = Noise::new(3);
+        for p in [ivec3(0, 0, 0), ivec3(3, -2, 7), ivec3(-300, 255, 256)] {
+            assert_eq!(noise.get3(p.as_vec3()), 0.0);
+            assert_eq!(noise.get2(p.truncate().as_vec2()), 0.0);
+        }
+    }
+
+    #[test]
+    fn stays_in_range() {
+        let noise = Noise::new(5);
+        let mut max: f32 = 0.0;
+        for p in points() {
+            for v in [noise.get3(p), noise.get2(p.truncate()), noise.fbm3(p, 4), noise.fbm2(p.truncate(), 8)] {
+                max = max.max(v.abs());
+            }
+        }
+        assert!(max <= 1.5, "{}", max);
+        // And it isn't flat either
+        assert!(max > 0.3, "{}", max);
+    }
+
+    #[test]
+    fn zero_octaves_is_one_octave() {
+        let noise = Noise::new(9);
+        let p = vec2(1.3, -4.6);
+        assert_eq!(noise.fbm2(p, 0), noise.get2(p));
+        assert_eq!(noise.fbm3(p.extend(0.5), 0), noise.get3(p.extend(0.5)));
+    }
+}
@@ -0,0 +1,97 @@
+// Upload throughput benchmark, run with `cargo run --release --example upload_bench`
+//
+// Fills solid cubes through `World::submit_batch` and measures the wall time until
+// `World::flush_report` returns, so the GPU work is included. Large batches are expected to
+// reach at least `TARGET_VOXELS_PER_SECOND`, the run fails if they don't.
+
+use std::time::Instant;
+
+use foxtail::prelude::*;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+use stardust_world::*;
+
+/// Minimum throughput for batches of a million voxels and more
+const TARGET_VOXELS_PER_SECOND: f64 = 50_000_000.0;
+
+/// Edge lengths of the cubes, from a single voxel queue batch up to 16M voxels
+const CUBE_SIZES: [i32; 4] = [32, 64, 128, 256];
+
+struct UploadBench {
+    world: World,
+}
+
+impl UploadBench {
+    fn new(ctx: &Context) -> Self {
+        Self {
+            world: World::new(ctx, WorldConfig::default()),
+        }
+    }
+
+    /// Returns the amount of voxels written and the voxels per second
+    fn upload_cube(&mut self, ctx: &Context, size: i32, color: u8) -> (u32, f64) {
+        let mut batch = VoxelBatch::with_capacity((size * size * size) as usize);
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    // A few different voxels per brick, so the palettes get some use
+                    let shade = color.wrapping_add(((x ^ y ^ z) & 3) as u8);
+                    batch.set_voxel(Voxel::new([shade, color, 255 - color], 255, 0, false, 255), ivec3(x, y, z));
+                }
+            }
+        }
+
+        let start = Instant::now();
+        self.world.submit_batch(batch);
+        self.world.process(ctx);
+        let report = self.world.flush_report(ctx);
+        let seconds = start.elapsed().as_secs_f64();
+        (report.voxels_written, report.voxels_written as f64 / seconds)
+    }
+
+    fn run(&mut self, ctx: &Context) -> bool {
+        let mut passed = true;
+        // Warm up, so shader compilation and the first allocations don't count
+        self.upload_cube(ctx, 32, 0);
+
+        for (i, size) in CUBE_SIZES.into_iter().enumerate() {
+            // Overwrites the last cube with a different color, every voxel gets written
+            let (written, voxels_per_second) = self.upload_cube(ctx, size, 64 * (i as u8 + 1));
+            let large = written >= 1_000_000;
+            let ok = !large || voxels_per_second >= TARGET_VOXELS_PER_SECOND;
+            passed &= ok;
+            println!(
+                "{:>3}^3: {:>9} voxels, {:>7.1}M voxels/s{}",
+                size,
+                written,
+                voxels_per_second / 1_000_000.0,
+                if ok { "" } else { " (below target)" },
+            );
+        }
+        println!(
+            "{}: large batches {} the target of {:.0}M voxels/s",
+            if passed { "PASS" } else { "FAIL" },
+            if passed { "reached" } else { "missed" },
+            TARGET_VOXELS_PER_SECOND / 1_000_000.0,
+        );
+        passed
+    }
+}
+
+impl App for UploadBench {
+    fn event(&mut self, _input: &Input) {}
+
+    fn update(&mut self, _ctx: &Context) {}
+
+    fn render(&mut self, ctx: &Context) {
+        let passed = self.run(ctx);
+        self.world.destroy(ctx);
+        // A missed target fails the run, so scripts can check the exit code
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+}
+
+fn main() {
+    foxtail::run(|ctx| UploadBench::new(ctx))
+}
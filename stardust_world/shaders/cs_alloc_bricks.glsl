@@ -1,15 +1,38 @@
 #version 460
-layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
 
-#define BRICK_MAP_SIZE 64
 #define BRICK_SIZE 16
 #define LAYER0_SIZE 16
 
-#define BRICK_POOL_SIZE 32768
-#define LAYER0_POOL_SIZE 8192
+// Indices into the stats buffer, must match report.rs
+#define STAT_VOXELS_WRITTEN 0
+#define STAT_VOXELS_DROPPED 1
+#define STAT_BRICKS_ALLOCATED 2
+#define STAT_BRICKS_FREED 3
+#define STAT_LAYER0S_ALLOCATED 4
+#define STAT_BRICKS_CHANGED 5
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
 
 struct Brick {
-    uint voxels[16*16*16 + 4];
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
 };
 
 struct Layer0Node {
@@ -41,12 +64,16 @@ layout(std430, binding = 5) coherent buffer free_layer0_pool {
     uint free_layer0_indices[];
 };
 
+layout(std430, binding = 8) coherent buffer stats_buffer {
+    uint stats[];
+};
+
 layout(binding = 6) uniform atomic_uint brick_pool_counter;
 layout(binding = 7) uniform atomic_uint layer0_pool_counter;
 
 uint findBrickEmpty() {
     uint next_free_idx = atomicCounterDecrement(brick_pool_counter); // Returns modified
-    if (next_free_idx > BRICK_POOL_SIZE) {
+    if (next_free_idx >= BRICK_POOL_SIZE) {
         // Counter has underflowed
         atomicCounterExchange(brick_pool_counter, 0); // Undo the underflow
         return 0;
@@ -56,11 +83,6 @@ uint findBrickEmpty() {
     return value;
 }
 
-void undoBrickAlloc(uint brick_pool_idx) {
-    uint write_idx = atomicCounterIncrement(brick_pool_counter); // Returns original
-    atomicExchange(free_brick_indices[write_idx], brick_pool_idx);
-}
-
 bool getLayer0(ivec3 pos, out uint layer0_pool_idx) {
     ivec3 p = pos;
     int brick_map_idx = p.x + p.y * BRICK_MAP_SIZE + p.z * BRICK_MAP_SIZE * BRICK_MAP_SIZE;
@@ -87,20 +109,37 @@ void setVoxel(ivec3 wpos) {
         if (atomicCompSwap(layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx], 0, 1) == 0) {
             uint brick_pool_idx = findBrickEmpty();
             if (brick_pool_idx > 0) {
+                atomicAdd(stats[STAT_BRICKS_ALLOCATED], 1);
                 atomicExchange(layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx], brick_pool_idx);
-                atomicExchange(bricks[brick_pool_idx - 1].voxels[4096], layer0_pool_idx);
-                atomicExchange(bricks[brick_pool_idx - 1].voxels[4097], layer0_idx);
-                atomicExchange(bricks[brick_pool_idx - 1].voxels[4098], 1);
-                atomicExchange(bricks[brick_pool_idx - 1].voxels[4099], 0);
+                atomicExchange(bricks[brick_pool_idx - 1].meta[0], layer0_pool_idx);
+                atomicExchange(bricks[brick_pool_idx - 1].meta[1], layer0_idx);
+                atomicExchange(bricks[brick_pool_idx - 1].meta[2], 1);
+                atomicExchange(bricks[brick_pool_idx - 1].meta[3], 0);
+            } else {
+                // Pool is full, release the reservation so this doesn't point at brick 1
+                atomicExchange(layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx], 0);
             }
         }
     }
 }
 
+bool toStoragePos(uvec3 pos, out ivec3 wpos) {
+    // World positions are signed, stored in the queue as their bit pattern
+    wpos = ivec3(pos) + WORLD_ORIGIN;
+    return all(greaterThanEqual(wpos, ivec3(0))) && all(lessThan(wpos, ivec3(WORLD_SIZE)));
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
 void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
     if (atomicCounter(brick_pool_counter) > 0) {
         uvec4 voxel = voxels[gl_GlobalInvocationID.x];
-        ivec3 wpos = ivec3(voxel.xyz);
-        setVoxel(wpos);
+        ivec3 wpos;
+        // Removing a voxel never needs a new layer0 node or brick
+        if (voxel.w != 0 && toStoragePos(voxel.xyz, wpos)) {
+            setVoxel(wpos);
+        }
     }
 }
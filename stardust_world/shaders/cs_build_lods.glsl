@@ -0,0 +1,159 @@
+#version 460
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICK_SIZE 16
+#define LAYER0_SIZE 16
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
+// Offsets of each level in BrickLod.blocks, in 16 bit entries. Must match brick.rs
+#define LOD_LEVELS 3
+const uint LOD_OFFSETS[LOD_LEVELS + 1] = uint[](0u, 0u, 8u*8u*8u, 8u*8u*8u + 4u*4u*4u);
+
+struct Brick {
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct Layer0Node {
+    uint brick_idx[16*16*16];
+};
+struct BrickLod {
+    // Set when the brick changed since its LODs were last built
+    uint dirty;
+    // Averaged rgb565 colour per block, two per uint, 0 means the block is empty.
+    // Level 1 has 8x8x8 blocks of 2x2x2 voxels, level 2 4x4x4 blocks and level 3 2x2x2 blocks.
+    uint blocks[(8*8*8 + 4*4*4 + 2*2*2) / 2];
+};
+
+layout(std430, binding = 0) readonly buffer brick_pool {
+    Brick bricks[];
+};
+
+layout(std430, binding = 1) readonly buffer layer0_pool {
+    Layer0Node layer0_nodes[];
+};
+
+layout(std430, binding = 2) readonly buffer brick_map {
+    // Offset by 1, so 0 means not allocated
+    uint layer0_pool_indices[];
+};
+
+layout(std430, binding = 3) readonly buffer voxel_queue {
+    uvec4 voxels[];
+};
+
+layout(std430, binding = 9) readonly buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+layout(std430, binding = 11) buffer brick_lod_pool {
+    BrickLod lods[];
+};
+
+uint readVoxel(uint brick_pool_idx, uint voxel_idx) {
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    if (raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM) return raw_bricks[raw_idx - 1].voxels[voxel_idx];
+    // Every voxel of a uniform brick is palette entry 1, no need to look at the indices
+    if (raw_idx == RAW_IDX_UNIFORM) return bricks[brick_pool_idx - 1].palette[1];
+    uint palette_idx = (bricks[brick_pool_idx - 1].indices[voxel_idx / 8] >> ((voxel_idx % 8) * 4)) & 0xF;
+    return bricks[brick_pool_idx - 1].palette[palette_idx];
+}
+
+// Averages the colour of all voxels in a block, must match `average_block` in brick.rs
+uint averageBlock(uint brick_pool_idx, uvec3 block_pos, uint block_size) {
+    uvec3 sum = uvec3(0);
+    uint count = 0;
+    uvec3 min_pos = block_pos * block_size;
+    for (uint z = min_pos.z; z < min_pos.z + block_size; z++) {
+        for (uint y = min_pos.y; y < min_pos.y + block_size; y++) {
+            for (uint x = min_pos.x; x < min_pos.x + block_size; x++) {
+                uint voxel = readVoxel(brick_pool_idx, x + y * 16 + z * 16 * 16);
+                if (voxel == 0) continue;
+                sum += uvec3(voxel & 31, (voxel >> 5) & 63, (voxel >> 11) & 31);
+                count++;
+            }
+        }
+    }
+    if (count == 0) return 0;
+    uvec3 avg = sum / count;
+    // A non-empty block can't be stored as 0, so pure black becomes the darkest red instead
+    return max(avg.x | (avg.y << 5) | (avg.z << 11), 1u);
+}
+
+void buildLods(uint brick_pool_idx) {
+    for (uint level = 1; level <= LOD_LEVELS; level++) {
+        uint size = 16 >> level;
+        uint block_size = 1u << level;
+        for (uint i = 0; i < size * size * size; i += 2) {
+            uint a = averageBlock(brick_pool_idx, uvec3(i % size, (i / size) % size, i / (size * size)), block_size);
+            uint j = i + 1;
+            uint b = averageBlock(brick_pool_idx, uvec3(j % size, (j / size) % size, j / (size * size)), block_size);
+            lods[brick_pool_idx - 1].blocks[(LOD_OFFSETS[level] + i) / 2] = a | (b << 16);
+        }
+    }
+}
+
+bool getBrick(ivec3 pos, uint layer0_pool_idx, out uint brick_pool_idx) {
+    ivec3 p = pos;
+    int layer0_idx = p.x + p.y * LAYER0_SIZE + p.z * LAYER0_SIZE * LAYER0_SIZE;
+    if (layer0_idx < 0) return false;
+    brick_pool_idx = layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx];
+    if (brick_pool_idx == 0) return false;
+    return true;
+}
+
+bool getLayer0(ivec3 pos, out uint layer0_pool_idx) {
+    ivec3 p = pos;
+    int brick_map_idx = p.x + p.y * BRICK_MAP_SIZE + p.z * BRICK_MAP_SIZE * BRICK_MAP_SIZE;
+    if (brick_map_idx < 0) return false;
+    layer0_pool_idx = layer0_pool_indices[brick_map_idx];
+    if (layer0_pool_idx == 0) return false;
+    return true;
+}
+
+bool toStoragePos(uvec3 pos, out ivec3 wpos) {
+    // World positions are signed, stored in the queue as their bit pattern
+    wpos = ivec3(pos) + WORLD_ORIGIN;
+    return all(greaterThanEqual(wpos, ivec3(0))) && all(lessThan(wpos, ivec3(WORLD_SIZE)));
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
+void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
+    // Runs over the same voxel queue as cs_process_voxel_queue.glsl. Every brick that got
+    // written to is marked dirty, the first invocation to find it dirty rebuilds its LODs.
+    uvec4 voxel = voxels[gl_GlobalInvocationID.x];
+    ivec3 wpos;
+    if (!toStoragePos(voxel.xyz, wpos)) return;
+
+    ivec3 layer0Pos = ivec3(floor(wpos / float(LAYER0_SIZE) / float(BRICK_SIZE)));
+    ivec3 brickPos = ivec3(floor(wpos / float(BRICK_SIZE)));
+
+    uint brick_pool_idx = 0;
+    uint layer0_pool_idx = 0;
+    if (!getLayer0(layer0Pos, layer0_pool_idx)) return;
+    if (!getBrick(brickPos % LAYER0_SIZE, layer0_pool_idx, brick_pool_idx)) return;
+
+    if (atomicCompSwap(lods[brick_pool_idx - 1].dirty, 1, 0) == 1) {
+        buildLods(brick_pool_idx);
+    }
+}
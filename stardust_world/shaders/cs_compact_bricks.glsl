@@ -0,0 +1,103 @@
+#version 460
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICK_SIZE 16
+#define LAYER0_SIZE 16
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
+// Offsets of each level in BrickLod.blocks, in 16 bit entries. Must match brick.rs
+#define LOD_LEVELS 3
+const uint LOD_OFFSETS[LOD_LEVELS + 1] = uint[](0u, 0u, 8u*8u*8u, 8u*8u*8u + 4u*4u*4u);
+
+struct Brick {
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct Layer0Node {
+    uint brick_idx[16*16*16];
+};
+struct BrickLod {
+    // Set when the brick changed since its LODs were last built
+    uint dirty;
+    // Averaged rgb565 colour per block, two per uint, 0 means the block is empty.
+    // Level 1 has 8x8x8 blocks of 2x2x2 voxels, level 2 4x4x4 blocks and level 3 2x2x2 blocks.
+    uint blocks[(8*8*8 + 4*4*4 + 2*2*2) / 2];
+};
+
+layout(std430, binding = 0) buffer brick_pool {
+    Brick bricks[];
+};
+
+layout(std430, binding = 1) buffer layer0_pool {
+    Layer0Node layer0_nodes[];
+};
+
+// x = brick pool index to move, y = brick pool index to move it to, both offset by 1
+layout(std430, binding = 3) readonly buffer move_queue {
+    uvec4 moves[];
+};
+
+layout(std430, binding = 11) buffer brick_lod_pool {
+    BrickLod lods[];
+};
+
+layout(std430, binding = 14) buffer brick_change_frames {
+    uint changeFrames[];
+};
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
+void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
+    // One invocation per live brick that moves into a free slot closer to the front of the pool.
+    // The free list gets rebuilt on the CPU afterwards, see `World::compact_bricks`.
+    uvec4 move = moves[gl_GlobalInvocationID.x];
+    uint from = move.x;
+    uint to = move.y;
+    if (from == 0 || to == 0) return;
+
+    // Raw bricks are referenced by index from the brick, so they stay where they are
+    bricks[to - 1] = bricks[from - 1];
+    lods[to - 1] = lods[from - 1];
+    changeFrames[to - 1] = changeFrames[from - 1];
+
+    uint layer0_pool_idx = bricks[to - 1].meta[0];
+    uint l0_idx = bricks[to - 1].meta[1];
+    if (layer0_pool_idx > 0 && layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx] == from) {
+        layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx] = to;
+    }
+
+    // Same as freeing a brick in cs_dealloc_bricks.glsl
+    for (int i = 0; i < 16*16*16 / 8; i++) {
+        bricks[from - 1].indices[i] = 0;
+    }
+    for (int i = 0; i < PALETTE_SIZE; i++) {
+        bricks[from - 1].palette[i] = 0;
+    }
+    bricks[from - 1].raw_idx = 0;
+    bricks[from - 1].occupancy[0] = 0;
+    bricks[from - 1].occupancy[1] = 0;
+    for (int i = 0; i < 4; i++) {
+        bricks[from - 1].meta[i] = 0;
+    }
+    changeFrames[from - 1] = 0;
+}
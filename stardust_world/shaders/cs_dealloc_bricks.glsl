@@ -1,15 +1,39 @@
 #version 460
-layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
 
-#define BRICK_MAP_SIZE 64
 #define BRICK_SIZE 16
 #define LAYER0_SIZE 16
 
-#define BRICK_POOL_SIZE 32768
-#define LAYER0_POOL_SIZE 8192
+// Indices into the stats buffer, must match report.rs
+#define STAT_VOXELS_WRITTEN 0
+#define STAT_VOXELS_DROPPED 1
+#define STAT_BRICKS_ALLOCATED 2
+#define STAT_BRICKS_FREED 3
+#define STAT_LAYER0S_ALLOCATED 4
+#define STAT_BRICKS_CHANGED 5
+
+#define PALETTE_SIZE 16
+#define CELL_SIZE 4
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
 
 struct Brick {
-    uint voxels[16*16*16 + 4];
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
 };
 
 struct Layer0Node {
@@ -37,37 +61,123 @@ layout(std430, binding = 4) buffer free_brick_pool {
 
 layout(binding = 5) uniform atomic_uint brick_pool_counter;
 
-bool brickEmpty(uint brick_pool_idx) {
-    for (int i = 0; i < 16*16*16; i++) {
-        if (bricks[brick_pool_idx - 1].voxels[i] > 0) return false;
+layout(std430, binding = 10) buffer free_raw_brick_pool {
+    uint free_raw_indices[];
+};
+
+layout(binding = 4) uniform atomic_uint raw_brick_pool_counter;
+
+layout(std430, binding = 8) buffer stats_buffer {
+    uint stats[];
+};
+
+layout(std430, binding = 9) buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+bool hasRawBrick(uint brick_pool_idx) {
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    return raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM;
+}
+
+// Clears palette entries no voxel uses anymore, so they can be reused, and marks the brick
+// as uniform if every voxel uses palette entry 1
+void compactPalette(uint brick_pool_idx) {
+    uint used = 0;
+    for (int i = 0; i < 16*16*16 / 8; i++) {
+        uint word = bricks[brick_pool_idx - 1].indices[i];
+        for (uint j = 0; j < 8; j++) {
+            used |= 1u << ((word >> (j * 4)) & 0xF);
+        }
+    }
+    for (uint i = 1; i < PALETTE_SIZE; i++) {
+        if ((used & (1u << i)) == 0) bricks[brick_pool_idx - 1].palette[i] = 0;
+    }
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    // There's space in the palette again, so it doesn't need a raw brick anymore
+    if (raw_idx == RAW_IDX_OVERFLOW && (used | 1) != 0xFFFF) {
+        raw_idx = 0;
     }
-    return true;
+    if (raw_idx == 0 || raw_idx == RAW_IDX_UNIFORM) {
+        raw_idx = used == 2 ? RAW_IDX_UNIFORM : 0;
+    }
+    bricks[brick_pool_idx - 1].raw_idx = raw_idx;
+}
+
+// Rebuilds the occupancy mask from the voxel data, so cells that got cleared are skipped again.
+// Returns true if the brick is empty.
+bool updateOccupancy(uint brick_pool_idx) {
+    bool raw = hasRawBrick(brick_pool_idx);
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    uint occupancy[2] = uint[2](0, 0);
+    for (uint i = 0; i < 16*16*16; i++) {
+        bool filled;
+        if (raw) {
+            filled = raw_bricks[raw_idx - 1].voxels[i] > 0;
+        } else {
+            // Palette entries other than 0 are never empty voxels
+            filled = ((bricks[brick_pool_idx - 1].indices[i / 8] >> ((i % 8) * 4)) & 0xF) != 0;
+        }
+        if (filled) {
+            uvec3 cell_pos = uvec3(i % 16, (i / 16) % 16, i / (16 * 16)) / CELL_SIZE;
+            uint cell_idx = cell_pos.x + cell_pos.y * 4 + cell_pos.z * 4 * 4;
+            occupancy[cell_idx / 32] |= 1u << (cell_idx % 32);
+        }
+    }
+    bricks[brick_pool_idx - 1].occupancy[0] = occupancy[0];
+    bricks[brick_pool_idx - 1].occupancy[1] = occupancy[1];
+    return occupancy[0] == 0 && occupancy[1] == 0;
+}
+
+bool brickEmpty(uint brick_pool_idx) {
+    if (!hasRawBrick(brick_pool_idx)) compactPalette(brick_pool_idx);
+    return updateOccupancy(brick_pool_idx);
 }
 
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
 void main() {
     // This shader will go through all bricks in the pool and check if they are in use and empty.
     // If they are, they get deallocated.
+    if (gl_GlobalInvocationID.x >= count) return;
 
     uint brick_pool_idx = (atomicCounterIncrement(dealloc_counter) % BRICK_POOL_SIZE) + 1;
 
-    if (bricks[brick_pool_idx - 1].voxels[4098] > 0) {
-        uint layer0_pool_idx = bricks[brick_pool_idx - 1].voxels[4096];
+    if (bricks[brick_pool_idx - 1].meta[2] > 0) {
+        uint layer0_pool_idx = bricks[brick_pool_idx - 1].meta[0];
         if (layer0_pool_idx > 0) {
-            uint l0_idx = bricks[brick_pool_idx - 1].voxels[4097];
+            uint l0_idx = bricks[brick_pool_idx - 1].meta[1];
             if (layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx] == brick_pool_idx) {
                 if (brickEmpty(brick_pool_idx)) {
-                    bricks[brick_pool_idx - 1].voxels[4099] += 1;
+                    bricks[brick_pool_idx - 1].meta[3] += 1;
                 } else {
-                    bricks[brick_pool_idx - 1].voxels[4099] = 0;
+                    bricks[brick_pool_idx - 1].meta[3] = 0;
                 }
 
-                if (bricks[brick_pool_idx - 1].voxels[4099] > 1) {
+                if (bricks[brick_pool_idx - 1].meta[3] > 1) {
                     uint write_idx = atomicCounterIncrement(brick_pool_counter);
                     atomicExchange(free_brick_indices[write_idx], brick_pool_idx);
+                    atomicAdd(stats[STAT_BRICKS_FREED], 1);
+
+                    if (hasRawBrick(brick_pool_idx)) {
+                        // Raw bricks are overwritten completely when they get used, no need to clear them
+                        uint raw_write_idx = atomicCounterIncrement(raw_brick_pool_counter);
+                        atomicExchange(free_raw_indices[raw_write_idx], bricks[brick_pool_idx - 1].raw_idx);
+                    }
 
                     layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx] = 0;
-                    for (int i = 0; i < 16*16*16 + 4; i++) {
-                        bricks[brick_pool_idx - 1].voxels[i] = 0;
+                    for (int i = 0; i < 16*16*16 / 8; i++) {
+                        bricks[brick_pool_idx - 1].indices[i] = 0;
+                    }
+                    for (int i = 0; i < PALETTE_SIZE; i++) {
+                        bricks[brick_pool_idx - 1].palette[i] = 0;
+                    }
+                    bricks[brick_pool_idx - 1].raw_idx = 0;
+                    bricks[brick_pool_idx - 1].occupancy[0] = 0;
+                    bricks[brick_pool_idx - 1].occupancy[1] = 0;
+                    for (int i = 0; i < 4; i++) {
+                        bricks[brick_pool_idx - 1].meta[i] = 0;
                     }
                 }
             }
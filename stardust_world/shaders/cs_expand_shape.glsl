@@ -0,0 +1,155 @@
+#version 460
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICK_SIZE 16
+#define LAYER0_SIZE 16
+
+#define SHAPE_BOX 0
+#define SHAPE_SPHERE 1
+#define SHAPE_CYLINDER 2
+
+#define OP_FILL 0
+#define OP_CLEAR 1
+#define OP_REPLACE_COLOR 2
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
+struct Brick {
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct Layer0Node {
+    uint brick_idx[16*16*16];
+};
+
+layout(std430, binding = 0) readonly buffer brick_pool {
+    Brick bricks[];
+};
+
+layout(std430, binding = 1) readonly buffer layer0_pool {
+    Layer0Node layer0_nodes[];
+};
+
+layout(std430, binding = 2) readonly buffer brick_map {
+    // Offset by 1, so 0 means not allocated
+    uint layer0_pool_indices[];
+};
+
+layout(std430, binding = 9) readonly buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+layout(std430, binding = 3) writeonly buffer voxel_queue {
+    uvec4 voxels[];
+};
+
+// Signed values are passed as their bit pattern
+uniform uint offset;
+uniform uvec4 box_min; // xyz = bounding box min, w = shape
+uniform uvec4 box_size; // xyz = bounding box size, w = operation
+uniform uvec4 center; // xyz = center of the shape, w = radius
+uniform uvec4 voxel; // x = voxel to write (or color for OP_REPLACE_COLOR), y = color to replace
+
+uint readVoxel(uint brick_pool_idx, uint voxel_idx) {
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    if (raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM) return raw_bricks[raw_idx - 1].voxels[voxel_idx];
+    // Every voxel of a uniform brick is palette entry 1, no need to look at the indices
+    if (raw_idx == RAW_IDX_UNIFORM) return bricks[brick_pool_idx - 1].palette[1];
+    uint palette_idx = (bricks[brick_pool_idx - 1].indices[voxel_idx / 8] >> ((voxel_idx % 8) * 4)) & 0xF;
+    return bricks[brick_pool_idx - 1].palette[palette_idx];
+}
+
+bool getBrick(ivec3 pos, uint layer0_pool_idx, out uint brick_pool_idx) {
+    ivec3 p = pos;
+    int layer0_idx = p.x + p.y * LAYER0_SIZE + p.z * LAYER0_SIZE * LAYER0_SIZE;
+    if (layer0_idx < 0) return false;
+    brick_pool_idx = layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx];
+    if (brick_pool_idx == 0) return false;
+    return true;
+}
+
+bool getLayer0(ivec3 pos, out uint layer0_pool_idx) {
+    ivec3 p = pos;
+    if (any(greaterThanEqual(p, ivec3(BRICK_MAP_SIZE)))) return false;
+    int brick_map_idx = p.x + p.y * BRICK_MAP_SIZE + p.z * BRICK_MAP_SIZE * BRICK_MAP_SIZE;
+    if (brick_map_idx < 0) return false;
+    layer0_pool_idx = layer0_pool_indices[brick_map_idx];
+    if (layer0_pool_idx == 0) return false;
+    return true;
+}
+
+uint getVoxel(ivec3 spos) {
+    ivec3 layer0Pos = ivec3(floor(spos / float(LAYER0_SIZE) / float(BRICK_SIZE)));
+    ivec3 brickPos = ivec3(floor(spos / float(BRICK_SIZE)));
+    ivec3 voxelPos = ivec3(floor(spos)) % BRICK_SIZE;
+
+    uint brick_pool_idx = 0;
+    uint layer0_pool_idx = 0;
+
+    if (getLayer0(layer0Pos, layer0_pool_idx)) {
+        if (getBrick(brickPos % LAYER0_SIZE, layer0_pool_idx, brick_pool_idx)) {
+            int voxel_idx = voxelPos.x + voxelPos.y * 16 + voxelPos.z * 16 * 16;
+            return readVoxel(brick_pool_idx, uint(voxel_idx));
+        }
+    }
+    return 0;
+}
+
+// Mirrors `Shape::contains`, the position is already inside of the bounding box
+bool inShape(ivec3 wpos) {
+    // Unsigned, so the squared distances don't overflow for any radius that fits in the world
+    uvec3 d = uvec3(abs(wpos - ivec3(center.xyz)));
+    uint rr = center.w * center.w;
+    if (box_min.w == SHAPE_SPHERE) {
+        return d.x * d.x + d.y * d.y + d.z * d.z < rr;
+    } else if (box_min.w == SHAPE_CYLINDER) {
+        return d.x * d.x + d.z * d.z < rr;
+    }
+    return true;
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
+void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
+    uint index = gl_GlobalInvocationID.x + offset;
+    uvec3 local = uvec3(index % box_size.x, (index / box_size.x) % box_size.y, index / (box_size.x * box_size.y));
+    ivec3 wpos = ivec3(box_min.xyz) + ivec3(local);
+
+    // Skipped voxels get a position outside of the world, the other passes drop those
+    uvec4 result = uvec4(uvec3(-WORLD_ORIGIN - ivec3(1)), 0);
+    if (inShape(wpos)) {
+        if (box_size.w == OP_FILL) {
+            result = uvec4(uvec3(wpos), voxel.x);
+        } else if (box_size.w == OP_CLEAR) {
+            result = uvec4(uvec3(wpos), 0);
+        } else if (box_size.w == OP_REPLACE_COLOR) {
+            ivec3 spos = wpos + WORLD_ORIGIN;
+            if (all(greaterThanEqual(spos, ivec3(0))) && all(lessThan(spos, ivec3(WORLD_SIZE)))) {
+                uint current = getVoxel(spos);
+                if (current != 0 && (current & 0xFFFF) == voxel.y) {
+                    result = uvec4(uvec3(wpos), (current & ~0xFFFFu) | voxel.x);
+                }
+            }
+        }
+    }
+    voxels[gl_GlobalInvocationID.x] = result;
+}
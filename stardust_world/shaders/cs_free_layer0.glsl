@@ -0,0 +1,116 @@
+#version 460
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICK_SIZE 16
+#define LAYER0_SIZE 16
+
+// Indices into the stats buffer, must match report.rs
+#define STAT_VOXELS_WRITTEN 0
+#define STAT_VOXELS_DROPPED 1
+#define STAT_BRICKS_ALLOCATED 2
+#define STAT_BRICKS_FREED 3
+#define STAT_LAYER0S_ALLOCATED 4
+#define STAT_BRICKS_CHANGED 5
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
+struct Brick {
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct Layer0Node {
+    uint brick_idx[16*16*16];
+};
+
+layout(std430, binding = 0) buffer brick_pool {
+    Brick bricks[];
+};
+
+layout(std430, binding = 1) buffer layer0_pool {
+    Layer0Node layer0_nodes[];
+};
+
+layout(std430, binding = 2) buffer brick_map {
+    // Offset by 1, so 0 means not allocated
+    uint layer0_pool_indices[];
+};
+
+layout(std430, binding = 4) buffer free_brick_pool {
+    uint free_brick_indices[];
+};
+
+layout(binding = 5) uniform atomic_uint brick_pool_counter;
+
+layout(std430, binding = 10) buffer free_raw_brick_pool {
+    uint free_raw_indices[];
+};
+
+layout(binding = 4) uniform atomic_uint raw_brick_pool_counter;
+
+layout(std430, binding = 8) buffer stats_buffer {
+    uint stats[];
+};
+
+uniform uint layer0_pool_idx;
+
+bool hasRawBrick(uint brick_pool_idx) {
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    return raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM;
+}
+
+// Same as freeing a brick in cs_dealloc_bricks.glsl, without waiting for it to be empty
+void freeBrick(uint brick_pool_idx) {
+    uint write_idx = atomicCounterIncrement(brick_pool_counter);
+    atomicExchange(free_brick_indices[write_idx], brick_pool_idx);
+    atomicAdd(stats[STAT_BRICKS_FREED], 1);
+
+    if (hasRawBrick(brick_pool_idx)) {
+        uint raw_write_idx = atomicCounterIncrement(raw_brick_pool_counter);
+        atomicExchange(free_raw_indices[raw_write_idx], bricks[brick_pool_idx - 1].raw_idx);
+    }
+
+    for (int i = 0; i < 16*16*16 / 8; i++) {
+        bricks[brick_pool_idx - 1].indices[i] = 0;
+    }
+    for (int i = 0; i < PALETTE_SIZE; i++) {
+        bricks[brick_pool_idx - 1].palette[i] = 0;
+    }
+    bricks[brick_pool_idx - 1].raw_idx = 0;
+    bricks[brick_pool_idx - 1].occupancy[0] = 0;
+    bricks[brick_pool_idx - 1].occupancy[1] = 0;
+    for (int i = 0; i < 4; i++) {
+        bricks[brick_pool_idx - 1].meta[i] = 0;
+    }
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
+void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
+    // One invocation per brick in the layer0 node that's being evicted. The layer0 node itself
+    // gets freed on the CPU afterwards, see `World::evict_layer0`.
+    uint l0_idx = gl_GlobalInvocationID.x;
+    uint brick_pool_idx = layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx];
+    if (brick_pool_idx == 0) return;
+
+    layer0_nodes[layer0_pool_idx - 1].brick_idx[l0_idx] = 0;
+    freeBrick(brick_pool_idx);
+}
@@ -1,13 +1,9 @@
 #version 450
-layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
 
-#define BRICK_MAP_SIZE 64
 #define BRICK_SIZE 16
 #define LAYER0_SIZE 16
 
-#define BRICK_POOL_SIZE 32768
-#define LAYER0_POOL_SIZE 8192
-
 layout(std430, binding = 0) buffer voxel_queue {
     uvec4 voxels[];
 };
@@ -16,12 +12,37 @@ layout(std430, binding = 1) buffer model_voxels {
     uvec4 mvoxels[];
 };
 uniform uint offset;
-uniform uvec4 pos; // w = 0 means to remove voxels instead of place them
+// xyz = signed world position as its bit pattern, w is unused
+uniform uvec4 pos;
+// Rotation and scale of the model, the translation is in `pos`
+uniform mat4 transform;
+// Samples per axis per model voxel, 0 means the transform only turns the model by multiples
+// of 90 degrees and every voxel maps onto exactly one voxel
+uniform uint samples;
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
 
 void main() {
-    uint index = gl_GlobalInvocationID.x;
-    uvec4 voxel = mvoxels[index + offset];
+    if (gl_GlobalInvocationID.x >= count) return;
+    uint index = gl_GlobalInvocationID.x + offset;
+    uvec4 voxel;
+    if (samples == 0) {
+        voxel = mvoxels[index];
+        // Rotate the voxel center, in doubled coordinates so everything stays an integer.
+        // The rotated center is odd on every axis, so the division is exact.
+        ivec3 center = ivec3(voxel.xyz) * 2 + 1;
+        ivec3 rotated = ivec3(round(mat3(transform) * vec3(center)));
+        voxel.xyz = uvec3((rotated - 1) / 2);
+    } else {
+        uint sampleCount = samples * samples * samples;
+        voxel = mvoxels[index / sampleCount];
+        uint s = index % sampleCount;
+        vec3 sampleOffset = (vec3(s % samples, (s / samples) % samples, s / (samples * samples)) + 0.5) / float(samples);
+        vec3 samplePos = mat3(transform) * (vec3(ivec3(voxel.xyz)) + sampleOffset);
+        voxel.xyz = uvec3(ivec3(floor(samplePos)));
+    }
+    // Wrapping unsigned addition gives the same bits as the signed addition
     voxel.xyz += pos.xyz;
-    voxel.w *= pos.w;
-    voxels[index] = voxel;
+    voxels[gl_GlobalInvocationID.x] = voxel;
 }
@@ -1,15 +1,47 @@
 #version 460
-layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
 
-#define BRICK_MAP_SIZE 64
 #define BRICK_SIZE 16
 #define LAYER0_SIZE 16
 
-#define BRICK_POOL_SIZE 32768
-#define LAYER0_POOL_SIZE 8192
+// Indices into the stats buffer, must match report.rs
+#define STAT_VOXELS_WRITTEN 0
+#define STAT_VOXELS_DROPPED 1
+#define STAT_BRICKS_ALLOCATED 2
+#define STAT_BRICKS_FREED 3
+#define STAT_LAYER0S_ALLOCATED 4
+#define STAT_BRICKS_CHANGED 5
+
+#define PALETTE_SIZE 16
+#define CELL_SIZE 4
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
 
 struct Brick {
-    uint voxels[16*16*16 + 4];
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct BrickLod {
+    // Set when the brick changed since its LODs were last built
+    uint dirty;
+    // Averaged rgb565 colour per block, two per uint, 0 means the block is empty.
+    // Level 1 has 8x8x8 blocks of 2x2x2 voxels, level 2 4x4x4 blocks and level 3 2x2x2 blocks.
+    uint blocks[(8*8*8 + 4*4*4 + 2*2*2) / 2];
 };
 
 struct Layer0Node {
@@ -33,11 +65,93 @@ layout(std430, binding = 3) buffer voxel_queue {
     uvec4 voxels[];
 };
 
-void setVoxelInternal(ivec3 pos, uint voxel, uint brick_pool_idx) {
+layout(std430, binding = 8) buffer stats_buffer {
+    uint stats[];
+};
+
+layout(std430, binding = 9) buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+layout(std430, binding = 11) buffer brick_lod_pool {
+    BrickLod lods[];
+};
+
+layout(std430, binding = 14) buffer brick_change_frames {
+    // Last frame each brick was added to changedBricks
+    uint changeFrames[];
+};
+
+layout(std430, binding = 15) writeonly buffer changed_brick_list {
+    // xyz = position of the brick in the brick map, in bricks. Counted by STAT_BRICKS_CHANGED
+    uvec4 changedBricks[];
+};
+
+// Counts stats periods, see `World::snapshot_stats`. Never 0, so bricks that never changed
+// aren't mistaken for having changed during this period
+uniform uint frame;
+
+// Returns false if the voxel isn't in the palette, cs_alloc_palette.glsl should have put it there
+bool findPalette(uint brick_pool_idx, uint voxel, out uint palette_idx) {
+    palette_idx = 0;
+    if (voxel == 0) return true;
+    for (uint i = 1; i < PALETTE_SIZE; i++) {
+        if (bricks[brick_pool_idx - 1].palette[i] == voxel) {
+            palette_idx = i;
+            return true;
+        }
+    }
+    return false;
+}
+
+// Marks the cell as occupied. Cells only get cleared again by cs_dealloc_bricks.glsl,
+// so the mask may claim a cell has voxels when it doesn't, but never the other way around
+void markOccupied(ivec3 pos, uint brick_pool_idx) {
+    ivec3 cell_pos = pos / CELL_SIZE;
+    uint cell_idx = uint(cell_pos.x + cell_pos.y * 4 + cell_pos.z * 4 * 4);
+    atomicOr(bricks[brick_pool_idx - 1].occupancy[cell_idx / 32], 1u << (cell_idx % 32));
+}
+
+// Adds the brick to the list of changed bricks, once per stats period. Every brick ends up in the list
+// at most once, so it never holds more than BRICK_POOL_SIZE entries
+void markChanged(ivec3 brickPos, uint brick_pool_idx) {
+    if (atomicExchange(changeFrames[brick_pool_idx - 1], frame) == frame) return;
+    uint slot = atomicAdd(stats[STAT_BRICKS_CHANGED], 1);
+    changedBricks[slot] = uvec4(brickPos, 0);
+}
+
+// `changed` is set if the voxel is different from what was there before
+bool setVoxelInternal(ivec3 pos, uint voxel, uint brick_pool_idx, out bool changed) {
+    changed = false;
     ivec3 local_pos = ivec3(pos);
     int voxel_idx = local_pos.x + local_pos.y * 16 + local_pos.z * 16 * 16;
-    if (voxel_idx < 0) return;
-    bricks[brick_pool_idx - 1].voxels[voxel_idx] = voxel;
+    if (voxel_idx < 0) return false;
+    if (voxel != 0) markOccupied(local_pos, brick_pool_idx);
+    // cs_build_lods.glsl picks this up after all voxels are written
+    lods[brick_pool_idx - 1].dirty = 1;
+
+    uint raw_idx = bricks[brick_pool_idx - 1].raw_idx;
+    if (raw_idx > 0 && raw_idx < RAW_IDX_UNIFORM) {
+        changed = atomicExchange(raw_bricks[raw_idx - 1].voxels[voxel_idx], voxel) != voxel;
+        return true;
+    }
+
+    uint palette_idx;
+    if (!findPalette(brick_pool_idx, voxel, palette_idx)) return false;
+
+    // Other voxels share the same uint, so swap in our 4 bits without touching theirs
+    uint word_idx = uint(voxel_idx) / 8;
+    uint shift = (uint(voxel_idx) % 8) * 4;
+    uint old_word = bricks[brick_pool_idx - 1].indices[word_idx];
+    while (true) {
+        uint new_word = (old_word & ~(0xFu << shift)) | (palette_idx << shift);
+        uint prev_word = atomicCompSwap(bricks[brick_pool_idx - 1].indices[word_idx], old_word, new_word);
+        if (prev_word == old_word) break;
+        old_word = prev_word;
+    }
+    // Every voxel is in the palette only once, so the index changing means the voxel changed
+    changed = ((old_word >> shift) & 0xFu) != palette_idx;
+    return true;
 }
 
 bool getBrick(ivec3 pos, uint layer0_pool_idx, out uint brick_pool_idx) {
@@ -68,17 +182,36 @@ bool setVoxel(ivec3 wpos, uint voxel) {
 
     if (getLayer0(layer0Pos, layer0_pool_idx)) {
         if (getBrick(brickPos % LAYER0_SIZE, layer0_pool_idx, brick_pool_idx)) {
-            setVoxelInternal(voxelPos, voxel, brick_pool_idx);
-            return true;
+            bool changed;
+            bool placed = setVoxelInternal(voxelPos, voxel, brick_pool_idx, changed);
+            if (changed) markChanged(brickPos, brick_pool_idx);
+            return placed;
         }
     }
     return false;
 }
 
+bool toStoragePos(uvec3 pos, out ivec3 wpos) {
+    // World positions are signed, stored in the queue as their bit pattern
+    wpos = ivec3(pos) + WORLD_ORIGIN;
+    return all(greaterThanEqual(wpos, ivec3(0))) && all(lessThan(wpos, ivec3(WORLD_SIZE)));
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
 void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
     uvec4 voxel = voxels[gl_GlobalInvocationID.x];
-    ivec3 wpos = ivec3(voxel.xyz);
+    ivec3 wpos;
+    if (!toStoragePos(voxel.xyz, wpos)) return;
     uint raw = voxel.w;
 
     bool has_placed = setVoxel(wpos, raw);
+    if (has_placed) {
+        atomicAdd(stats[STAT_VOXELS_WRITTEN], 1);
+    } else if (raw != 0) {
+        // There was no brick to put it in or no space left in its palette, the pools ran out
+        atomicAdd(stats[STAT_VOXELS_DROPPED], 1);
+    }
 }
@@ -0,0 +1,138 @@
+#version 460
+layout(local_size_x = WORKGROUP_SIZE, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICK_SIZE 16
+#define LAYER0_SIZE 16
+
+#define PALETTE_SIZE 16
+// Values of Brick.raw_idx that aren't indices, must match brick.rs
+#define RAW_IDX_UNIFORM 0xFFFFFFFDu
+#define RAW_IDX_RESERVED 0xFFFFFFFEu
+#define RAW_IDX_OVERFLOW 0xFFFFFFFFu
+
+struct Brick {
+    // 4 bit indices into the palette, 8 voxels per uint
+    uint indices[16*16*16 / 8];
+    // Entry 0 is always the empty voxel, unused entries are 0 as well
+    uint palette[PALETTE_SIZE];
+    // Offset by 1 into the raw brick pool, for bricks with too many voxels for the palette
+    uint raw_idx;
+    // One bit per 4x4x4 cell, set if the cell might contain voxels
+    uint occupancy[2];
+    // layer0 pool index, index within the layer0 node, allocated, frames spent empty
+    uint meta[4];
+};
+
+struct RawBrick {
+    uint voxels[16*16*16];
+};
+
+struct Layer0Node {
+    uint brick_idx[16*16*16];
+};
+
+layout(std430, binding = 0) coherent buffer brick_pool {
+    Brick bricks[];
+};
+
+layout(std430, binding = 1) coherent buffer layer0_pool {
+    Layer0Node layer0_nodes[];
+};
+
+layout(std430, binding = 2) coherent buffer brick_map {
+    // Offset by 1, so 0 means not allocated
+    uint layer0_pool_indices[];
+};
+
+layout(std430, binding = 3) coherent buffer voxel_queue {
+    uvec4 voxels[];
+};
+
+layout(std430, binding = 9) coherent buffer raw_brick_pool {
+    RawBrick raw_bricks[];
+};
+
+layout(std430, binding = 10) coherent buffer free_raw_brick_pool {
+    uint free_raw_indices[];
+};
+
+layout(binding = 4) uniform atomic_uint raw_brick_pool_counter;
+
+bool getBrick(ivec3 pos, uint layer0_pool_idx, out uint brick_pool_idx) {
+    ivec3 p = pos;
+    int layer0_idx = p.x + p.y * LAYER0_SIZE + p.z * LAYER0_SIZE * LAYER0_SIZE;
+    if (layer0_idx < 0) return false;
+    brick_pool_idx = layer0_nodes[layer0_pool_idx - 1].brick_idx[layer0_idx];
+    if (brick_pool_idx == 0) return false;
+    return true;
+}
+
+bool getLayer0(ivec3 pos, out uint layer0_pool_idx) {
+    ivec3 p = pos;
+    int brick_map_idx = p.x + p.y * BRICK_MAP_SIZE + p.z * BRICK_MAP_SIZE * BRICK_MAP_SIZE;
+    if (brick_map_idx < 0) return false;
+    layer0_pool_idx = layer0_pool_indices[brick_map_idx];
+    if (layer0_pool_idx == 0) return false;
+    return true;
+}
+
+bool findBrick(ivec3 wpos, out uint brick_pool_idx) {
+    ivec3 layer0Pos = ivec3(floor(wpos / float(LAYER0_SIZE) / float(BRICK_SIZE)));
+    ivec3 brickPos = ivec3(floor(wpos / float(BRICK_SIZE)));
+
+    uint layer0_pool_idx = 0;
+    brick_pool_idx = 0;
+
+    if (getLayer0(layer0Pos, layer0_pool_idx)) {
+        return getBrick(brickPos % LAYER0_SIZE, layer0_pool_idx, brick_pool_idx);
+    }
+    return false;
+}
+
+bool toStoragePos(uvec3 pos, out ivec3 wpos) {
+    // World positions are signed, stored in the queue as their bit pattern
+    wpos = ivec3(pos) + WORLD_ORIGIN;
+    return all(greaterThanEqual(wpos, ivec3(0))) && all(lessThan(wpos, ivec3(WORLD_SIZE)));
+}
+
+uint findRawEmpty() {
+    uint next_free_idx = atomicCounterDecrement(raw_brick_pool_counter); // Returns modified
+    if (next_free_idx >= RAW_BRICK_POOL_SIZE) {
+        // Counter has underflowed
+        atomicCounterExchange(raw_brick_pool_counter, 0); // Undo the underflow
+        return 0;
+    }
+    uint value = free_raw_indices[next_free_idx];
+    atomicExchange(free_raw_indices[next_free_idx], 0);
+    return value;
+}
+
+// Amount of invocations that have work, the last workgroup can run past it
+uniform uint count;
+
+void main() {
+    if (gl_GlobalInvocationID.x >= count) return;
+    if (atomicCounter(raw_brick_pool_counter) == 0) return;
+
+    uvec4 voxel = voxels[gl_GlobalInvocationID.x];
+    ivec3 wpos;
+    if (voxel.w == 0 || !toStoragePos(voxel.xyz, wpos)) return;
+
+    uint brick_pool_idx;
+    if (!findBrick(wpos, brick_pool_idx)) return;
+    // Only one of the voxels in an overflowing brick gets to promote it
+    if (atomicCompSwap(bricks[brick_pool_idx - 1].raw_idx, RAW_IDX_OVERFLOW, RAW_IDX_RESERVED) != RAW_IDX_OVERFLOW) return;
+
+    uint raw_idx = findRawEmpty();
+    if (raw_idx == 0) {
+        // Pool is full, the voxels that don't fit get dropped and the next batch tries again
+        atomicExchange(bricks[brick_pool_idx - 1].raw_idx, RAW_IDX_OVERFLOW);
+        return;
+    }
+
+    for (uint i = 0; i < 16*16*16; i++) {
+        uint palette_idx = (bricks[brick_pool_idx - 1].indices[i / 8] >> ((i % 8) * 4)) & 0xF;
+        raw_bricks[raw_idx - 1].voxels[i] = bricks[brick_pool_idx - 1].palette[palette_idx];
+    }
+    atomicExchange(bricks[brick_pool_idx - 1].raw_idx, raw_idx);
+}
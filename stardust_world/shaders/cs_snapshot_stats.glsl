@@ -0,0 +1,20 @@
+#version 460
+layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+
+// Indices into the stats buffer, must match report.rs
+#define STAT_BRICKS_FREE 6
+#define STAT_LAYER0S_FREE 7
+
+layout(std430, binding = 8) buffer stats_buffer {
+    uint stats[];
+};
+
+layout(binding = 6) uniform atomic_uint brick_pool_counter;
+layout(binding = 7) uniform atomic_uint layer0_pool_counter;
+
+// Runs once at the end of a stats period, so the free counters come back along with the stats
+// instead of being read on their own, see `World::snapshot_stats`
+void main() {
+    stats[STAT_BRICKS_FREE] = atomicCounter(brick_pool_counter);
+    stats[STAT_LAYER0S_FREE] = atomicCounter(layer0_pool_counter);
+}
@@ -0,0 +1,134 @@
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+/// Voxel writes collected without touching the world, so every thread can fill its own batch
+/// and hand it over with a single `World::submit_batch` call. Writes keep their order, the last
+/// write to a position wins once the batch is processed, same as with `World::set_voxel`.
+#[derive(Debug, Clone, Default)]
+pub struct VoxelBatch {
+    pub(crate) voxels: Vec<(Voxel, IVec3)>,
+}
+
+impl VoxelBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            voxels: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Positions are in world space, see `WorldConfig::origin`
+    pub fn set_voxel(&mut self, voxel: Voxel, world_pos: IVec3) {
+        self.voxels.push((voxel, world_pos));
+    }
+
+    /// Moves the writes of another batch to the end of this one, leaving the other one empty
+    pub fn append(&mut self, other: &mut VoxelBatch) {
+        self.voxels.append(&mut other.voxels);
+    }
+
+    pub fn len(&self) -> usize {
+        self.voxels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voxels.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.voxels.clear();
+    }
+
+    pub fn voxels(&self) -> &[(Voxel, IVec3)] {
+        &self.voxels
+    }
+}
+
+impl Extend<(Voxel, IVec3)> for VoxelBatch {
+    fn extend<I: IntoIterator<Item = (Voxel, IVec3)>>(&mut self, iter: I) {
+        self.voxels.extend(iter);
+    }
+}
+
+impl FromIterator<(Voxel, IVec3)> for VoxelBatch {
+    fn from_iter<I: IntoIterator<Item = (Voxel, IVec3)>>(iter: I) -> Self {
+        Self {
+            voxels: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl From<Vec<(Voxel, IVec3)>> for VoxelBatch {
+    fn from(voxels: Vec<(Voxel, IVec3)>) -> Self {
+        Self { voxels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::{Command, CommandQueue, last_writes};
+
+    use super::*;
+
+    fn colored(i: u8, x: i32) -> (Voxel, IVec3) {
+        (Voxel::new([i * 8, 0, 0], 255, 0, false, 255), ivec3(x, 0, 0))
+    }
+
+    fn reds(writes: &[(Voxel, IVec3)]) -> Vec<(u8, i32)> {
+        writes.iter().map(|(voxel, pos)| (voxel.rgb()[0] / 8, pos.x)).collect()
+    }
+
+    #[test]
+    fn writes_keep_their_order() {
+        let mut batch = VoxelBatch::new();
+        batch.set_voxel(colored(1, 0).0, ivec3(0, 0, 0));
+        batch.extend([colored(2, 1), colored(3, 0)]);
+        batch.set_voxel(colored(4, 2).0, ivec3(2, 0, 0));
+        assert_eq!(reds(batch.voxels()), vec![(1, 0), (2, 1), (3, 0), (4, 2)]);
+        assert_eq!(batch.len(), 4);
+
+        let collected: VoxelBatch = [colored(5, 3), colored(6, 4)].into_iter().collect();
+        batch.extend(collected.voxels().iter().copied());
+        assert_eq!(reds(&batch.voxels()[4..]), vec![(5, 3), (6, 4)]);
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn later_batches_win_when_merged() {
+        // Every thread writes the same positions with its own color
+        let batches: Vec<VoxelBatch> = (1..=4u8).map(|i| {
+            std::thread::spawn(move || (0..8).map(|x| colored(i, x)).collect::<VoxelBatch>())
+        }).collect::<Vec<_>>().into_iter().map(|thread| thread.join().unwrap()).collect();
+
+        let mut merged = VoxelBatch::new();
+        for mut batch in batches {
+            merged.append(&mut batch);
+            assert!(batch.is_empty());
+        }
+        assert_eq!(merged.len(), 32);
+        assert_eq!(reds(&last_writes(merged.voxels())), (0..8).map(|x| (4, x)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn empty_batches_queue_nothing() {
+        let mut queue = CommandQueue::default();
+        queue.push_batch(VoxelBatch::new());
+        let (commands, counts) = queue.take();
+        assert!(commands.is_empty());
+        assert_eq!(counts.0, 0);
+
+        // Not even a new command after a transaction boundary
+        queue.push_batch([colored(1, 0)].into_iter().collect());
+        queue.seal();
+        queue.push_batch(VoxelBatch::with_capacity(16));
+        let (commands, counts) = queue.take();
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(&commands[0], Command::Voxels(voxels) if voxels.len() == 1));
+        assert_eq!(counts.0, 1);
+    }
+}
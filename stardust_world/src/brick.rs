@@ -3,23 +3,40 @@ use stardust_common::voxel::Voxel;
 
 const BRICK_SIZE: usize = 16*16*16 + 4;
 
+// Metadata stored behind the voxel data. `GpuBrick::meta` holds the same four values, in the same order
+pub(crate) const META_LAYER0_POOL_IDX: usize = 4096;
+pub(crate) const META_LAYER0_IDX: usize = 4097;
+pub(crate) const META_ALLOCATED: usize = 4098;
+pub(crate) const META_EMPTY_FRAMES: usize = 4099;
+
+/// Uncompressed brick, as used by `CpuWorld`. See `GpuBrick` for the layout the GPU uses.
 #[repr(C)]
 #[derive(Copy, Clone)]
-pub struct Brick([Voxel; BRICK_SIZE]);
+pub struct Brick {
+    data: [Voxel; BRICK_SIZE],
+    /// Maintained the same way as `GpuBrick::occupancy`, see `occupancy`
+    occupancy: u64,
+}
 
 impl Brick {
     pub fn empty() -> Self {
-        Self([Voxel::empty(); BRICK_SIZE])
+        Self {
+            data: [Voxel::empty(); BRICK_SIZE],
+            occupancy: 0,
+        }
     }
 
     pub fn full() -> Self {
         let mut data = [Voxel::new([255;3],255,0,false,255); BRICK_SIZE];
         for i in 0..4 { data[4096 + i] = Voxel(0); }
-        Self(data)
+        Self {
+            data,
+            occupancy: u64::MAX,
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        (self.0.iter().map(|v| v.opacity() as usize).sum::<usize>()) == 0
+        (self.data.iter().map(|v| v.opacity() as usize).sum::<usize>()) == 0
     }
 
     pub fn func<F: Fn(usize, usize, usize) -> Voxel>(f: F) -> Self {
@@ -27,20 +44,465 @@ impl Brick {
         for x in 0..16 {
             for y in 0..16 {
                 for z in 0..16 {
-                    brick.0[x+y*16+z*16*16] = f(x,y,z);
+                    brick.data[x+y*16+z*16*16] = f(x,y,z);
                 }
             }
         }
+        brick.update_occupancy();
         brick
     }
 
+    /// Like `markOccupied` in cs_process_voxel_queue.glsl, this only ever sets bits in the occupancy mask
     pub fn set_voxel(&mut self, voxel: Voxel, pos: UVec3) {
         let i = pos.x as usize + pos.y as usize * 16 + pos.z as usize * 16 * 16;
-        self.0[i] = voxel;
+        self.data[i] = voxel;
+        if voxel.0 != 0 {
+            self.occupancy |= 1 << cell_index(pos);
+        }
     }
 
     pub fn get_voxel(&self, pos: UVec3) -> &Voxel {
         let i = pos.x as usize + pos.y as usize * 16 + pos.z as usize * 16 * 16;
-        &self.0[i]
+        &self.data[i]
+    }
+
+    /// The voxel data without the metadata, in x, y, z order
+    pub fn voxels(&self) -> &[Voxel] {
+        &self.data[..4096]
+    }
+
+    /// Matches `brickEmpty` in cs_dealloc_bricks.glsl, which checks the raw value instead of the opacity
+    pub(crate) fn has_voxels(&self) -> bool {
+        self.data[..4096].iter().any(|v| v.0 > 0)
+    }
+
+    /// One bit per 4x4x4 cell, set if the cell might contain voxels. Cells are numbered the same
+    /// way voxels are, x first. `set_voxel` sets bits and only `update_occupancy` clears them,
+    /// same as the mask on the GPU, so cells that got emptied stay set until the next deallocation pass.
+    pub fn occupancy(&self) -> u64 {
+        self.occupancy
+    }
+
+    /// Rebuilds the occupancy mask from the voxel data and returns true if the brick is empty.
+    /// Mirrors `updateOccupancy` in cs_dealloc_bricks.glsl.
+    pub fn update_occupancy(&mut self) -> bool {
+        let mut occupancy = 0;
+        for (i, voxel) in self.voxels().iter().enumerate() {
+            if voxel.0 == 0 { continue; }
+            occupancy |= 1 << cell_index(UVec3::new(i as u32 % 16, (i as u32 / 16) % 16, i as u32 / 256));
+        }
+        self.occupancy = occupancy;
+        occupancy == 0
+    }
+
+    /// Checks the bit of the cell containing `pos` in `occupancy`
+    pub fn cell_occupied(&self, pos: UVec3) -> bool {
+        self.occupancy & (1 << cell_index(pos)) != 0
+    }
+
+    /// Downsamples the brick to `16 >> level` blocks along each axis, in x, y, z order. Each block
+    /// holds the averaged rgb565 colour of the voxels in it, or 0 if it's empty. Mirrors cs_build_lods.glsl
+    pub fn lod(&self, level: u32) -> Vec<u16> {
+        let size = 16 >> level;
+        (0..size * size * size)
+            .map(|i| self.average_block(UVec3::new(i % size, (i / size) % size, i / (size * size)), 1 << level))
+            .collect()
+    }
+
+    fn average_block(&self, block_pos: UVec3, block_size: u32) -> u16 {
+        let min = block_pos * block_size;
+        let mut sum = UVec3::ZERO;
+        let mut count = 0;
+        for i in 0..block_size * block_size * block_size {
+            let voxel = self.get_voxel(min + UVec3::new(i % block_size, (i / block_size) % block_size, i / (block_size * block_size))).0;
+            if voxel == 0 { continue; }
+            sum += UVec3::new(voxel & 31, (voxel >> 5) & 63, (voxel >> 11) & 31);
+            count += 1;
+        }
+        if count == 0 { return 0; }
+        let avg = sum / count;
+        // A non-empty block can't be stored as 0, so pure black becomes the darkest red instead
+        (avg.x | (avg.y << 5) | (avg.z << 11)).max(1) as u16
+    }
+
+    pub(crate) fn meta(&self, idx: usize) -> u32 {
+        self.data[idx].0
+    }
+
+    pub(crate) fn set_meta(&mut self, idx: usize, value: u32) {
+        self.data[idx] = Voxel(value);
+    }
+}
+
+/// Size of the cells tracked by the occupancy mask, in voxels
+pub const CELL_SIZE: u32 = 4;
+
+/// Index of the cell containing a position within a brick, which is the bit it has in the occupancy mask
+pub fn cell_index(pos: UVec3) -> u32 {
+    let cell_pos = pos / CELL_SIZE;
+    cell_pos.x + cell_pos.y * 4 + cell_pos.z * 4 * 4
+}
+
+/// Amount of downsampled levels kept per brick. Level n averages blocks of 2^n voxels along each axis
+pub const LOD_LEVELS: u32 = 3;
+
+// Offsets of each level in `GpuBrickLod::blocks`, in 16 bit entries
+const LOD_OFFSETS: [usize; LOD_LEVELS as usize + 1] = [0, 0, 8*8*8, 8*8*8 + 4*4*4];
+const LOD_WORDS: usize = (8*8*8 + 4*4*4 + 2*2*2) / 2;
+
+pub(crate) const PALETTE_SIZE: usize = 16;
+const GPU_INDEX_WORDS: usize = 4096 / 8;
+
+/// `GpuBrick::raw_idx` when every voxel in the brick is palette entry 1. The indices are still
+/// kept up to date, this only lets readers skip them.
+pub(crate) const RAW_IDX_UNIFORM: u32 = u32::MAX - 2;
+/// `GpuBrick::raw_idx` when the palette is full and the brick is waiting for a raw brick
+pub(crate) const RAW_IDX_OVERFLOW: u32 = u32::MAX;
+/// `GpuBrick::raw_idx` while a raw brick is being allocated for it
+pub(crate) const RAW_IDX_RESERVED: u32 = u32::MAX - 1;
+
+/// Brick as stored in the GPU brick pool. Voxels are 4 bit indices into a 16 entry palette,
+/// of which entry 0 is always the empty voxel. Bricks with more different voxels than that
+/// get promoted to a `GpuRawBrick`, which `raw_idx` points at (offset by 1). Bricks made of a
+/// single voxel get `RAW_IDX_UNIFORM` instead, see cs_dealloc_bricks.glsl.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GpuBrick {
+    pub(crate) indices: [u32; GPU_INDEX_WORDS],
+    pub(crate) palette: [u32; PALETTE_SIZE],
+    pub(crate) raw_idx: u32,
+    pub(crate) occupancy: [u32; 2],
+    pub(crate) meta: [u32; 4],
+}
+
+/// Uncompressed voxel data for bricks that don't fit in a `GpuBrick` palette
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GpuRawBrick(pub(crate) [u32; 4096]);
+
+impl GpuBrick {
+    pub fn empty() -> Self {
+        Self {
+            indices: [0; GPU_INDEX_WORDS],
+            palette: [0; PALETTE_SIZE],
+            raw_idx: 0,
+            occupancy: [0; 2],
+            meta: [0; 4],
+        }
+    }
+
+    /// Encodes a brick, including its metadata. Returns the raw voxel data as well if the
+    /// brick doesn't fit in the palette, in which case `raw_idx` still has to be set.
+    pub fn from_brick(brick: &Brick) -> (Self, Option<GpuRawBrick>) {
+        let mut gpu = Self::empty();
+        for i in 0..4 {
+            gpu.meta[i] = brick.meta(META_LAYER0_POOL_IDX + i);
+        }
+        let occupancy = brick.occupancy();
+        gpu.occupancy = [occupancy as u32, (occupancy >> 32) as u32];
+
+        let mut palette_len = 1;
+        for (i, voxel) in brick.voxels().iter().enumerate() {
+            let idx = match gpu.palette[..palette_len].iter().position(|v| *v == voxel.0) {
+                Some(idx) => idx,
+                None if palette_len < PALETTE_SIZE => {
+                    gpu.palette[palette_len] = voxel.0;
+                    palette_len += 1;
+                    palette_len - 1
+                },
+                None => {
+                    let mut raw = GpuRawBrick([0; 4096]);
+                    for (i, voxel) in brick.voxels().iter().enumerate() {
+                        raw.0[i] = voxel.0;
+                    }
+                    gpu.indices = [0; GPU_INDEX_WORDS];
+                    gpu.palette = [0; PALETTE_SIZE];
+                    return (gpu, Some(raw));
+                },
+            };
+            gpu.indices[i / 8] |= (idx as u32) << ((i % 8) * 4);
+        }
+        // Same check as `compactPalette` in cs_dealloc_bricks.glsl
+        if palette_len == 2 && gpu.indices.iter().all(|word| *word == 0x11111111) {
+            gpu.raw_idx = RAW_IDX_UNIFORM;
+        }
+        (gpu, None)
+    }
+
+    pub(crate) fn has_raw_brick(&self) -> bool {
+        !matches!(self.raw_idx, 0 | RAW_IDX_UNIFORM | RAW_IDX_RESERVED | RAW_IDX_OVERFLOW)
+    }
+
+    pub(crate) fn is_uniform(&self) -> bool {
+        self.raw_idx == RAW_IDX_UNIFORM
+    }
+
+    /// Decodes a brick, including its metadata. `raw` must be the brick `raw_idx` points at, if any.
+    pub fn to_brick(&self, raw: Option<&GpuRawBrick>) -> Brick {
+        let mut brick = Brick::empty();
+        for i in 0..4 {
+            brick.set_meta(META_LAYER0_POOL_IDX + i, self.meta[i]);
+        }
+        match raw {
+            Some(raw) => for i in 0..4096 {
+                brick.data[i] = Voxel(raw.0[i]);
+            },
+            None if self.is_uniform() => for i in 0..4096 {
+                brick.data[i] = Voxel(self.palette[1]);
+            },
+            None => for i in 0..4096 {
+                let idx = (self.indices[i / 8] >> ((i % 8) * 4)) & 0xF;
+                brick.data[i] = Voxel(self.palette[idx as usize]);
+            },
+        }
+        brick.occupancy = self.occupancy[0] as u64 | (self.occupancy[1] as u64) << 32;
+        brick
+    }
+}
+
+/// Downsampled levels of a brick, stored in a separate pool under the same index as the brick.
+/// See `Brick::lod` for what each block holds.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GpuBrickLod {
+    /// Set when the brick changed since its LODs were last built
+    pub(crate) dirty: u32,
+    /// All levels after each other, two 16 bit blocks per u32
+    pub(crate) blocks: [u32; LOD_WORDS],
+}
+
+impl GpuBrickLod {
+    pub fn empty() -> Self {
+        Self {
+            dirty: 0,
+            blocks: [0; LOD_WORDS],
+        }
+    }
+
+    pub fn from_brick(brick: &Brick) -> Self {
+        let mut gpu = Self::empty();
+        for level in 1..=LOD_LEVELS {
+            for (i, block) in brick.lod(level).into_iter().enumerate() {
+                let entry = LOD_OFFSETS[level as usize] + i;
+                gpu.blocks[entry / 2] |= (block as u32) << ((entry % 2) * 16);
+            }
+        }
+        gpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Brick whose voxels cycle through `colors` different non-empty voxels, with every 7th voxel empty if `holes` is set
+    fn colored(colors: u32, holes: bool) -> Brick {
+        let mut brick = Brick::func(|x, y, z| {
+            let i = (x + y * 16 + z * 16 * 16) as u32;
+            if holes && i % 7 == 0 { return Voxel::empty(); }
+            Voxel(i % colors + 1)
+        });
+        brick.set_meta(META_LAYER0_POOL_IDX, 3);
+        brick.set_meta(META_LAYER0_IDX, 1234);
+        brick.set_meta(META_ALLOCATED, 1);
+        brick
+    }
+
+    /// Encodes and decodes a brick, checking it comes out the same
+    fn round_trip(brick: &Brick) -> (GpuBrick, Option<GpuRawBrick>) {
+        let (gpu, raw) = GpuBrick::from_brick(brick);
+        let decoded = gpu.to_brick(raw.as_ref());
+        assert!(brick.data.iter().zip(decoded.data.iter()).all(|(a, b)| a.0 == b.0));
+        (gpu, raw)
+    }
+
+    fn palette_len(gpu: &GpuBrick) -> usize {
+        1 + gpu.palette[1..].iter().filter(|v| **v != 0).count()
+    }
+
+    #[test]
+    fn uniform_brick() {
+        let (gpu, raw) = round_trip(&colored(1, false));
+        assert!(raw.is_none());
+        assert!(gpu.is_uniform());
+        assert!(!gpu.has_raw_brick());
+        assert_eq!(palette_len(&gpu), 2);
+        assert_eq!(gpu.occupancy, [u32::MAX; 2]);
+        assert_eq!(gpu.meta, [3, 1234, 1, 0]);
+
+        // Readers skip the indices, but they still have to be right for writers
+        let mut indexed = gpu;
+        indexed.raw_idx = 0;
+        assert_eq!(indexed.to_brick(None).voxels()[4095].0, gpu.palette[1]);
+
+        // A single voxel with holes around it isn't uniform
+        let (gpu, _) = round_trip(&colored(1, true));
+        assert!(!gpu.is_uniform());
+        let (gpu, _) = round_trip(&Brick::empty());
+        assert!(!gpu.is_uniform());
+        assert_eq!(palette_len(&gpu), 1);
+    }
+
+    /// Entry 0 is kept for the empty voxel, so 15 different voxels fit in the palette
+    #[test]
+    fn palette_bricks() {
+        for (colors, holes) in [(2, false), (2, true), (4, false), (4, true), (15, true), (15, false)] {
+            let (gpu, raw) = round_trip(&colored(colors, holes));
+            assert!(raw.is_none(), "{} colors", colors);
+            assert!(!gpu.is_uniform() && !gpu.has_raw_brick());
+            assert_eq!(gpu.raw_idx, 0);
+            assert_eq!(palette_len(&gpu), colors as usize + 1);
+        }
+    }
+
+    #[test]
+    fn promotes_to_raw_brick() {
+        for (colors, holes) in [(16, false), (16, true), (4096, false)] {
+            let brick = colored(colors, holes);
+            let (mut gpu, raw) = round_trip(&brick);
+            let raw = raw.expect("Brick should not fit in the palette!");
+            assert!(raw.0.iter().zip(brick.voxels()).all(|(a, b)| *a == b.0));
+            assert_eq!(gpu.palette, [0; PALETTE_SIZE]);
+            assert_eq!(gpu.meta, [3, 1234, 1, 0]);
+
+            // Uploading points `raw_idx` at the raw brick
+            assert!(!gpu.has_raw_brick());
+            gpu.raw_idx = 1;
+            assert!(gpu.has_raw_brick());
+            assert_eq!(gpu.to_brick(Some(&raw)).voxels()[17].0, brick.voxels()[17].0);
+        }
+    }
+
+    #[test]
+    fn occupancy_is_only_cleared_by_updates() {
+        let mut brick = Brick::empty();
+        brick.set_voxel(Voxel(5), UVec3::new(5, 0, 0));
+        brick.set_voxel(Voxel(5), UVec3::new(15, 15, 15));
+        assert_eq!(brick.occupancy(), 1 << 1 | 1 << 63);
+        assert!(brick.cell_occupied(UVec3::new(4, 3, 3)));
+        assert!(!brick.cell_occupied(UVec3::new(3, 3, 3)));
+
+        // Cleared cells stay set until the mask gets rebuilt, like on the GPU
+        brick.set_voxel(Voxel::empty(), UVec3::new(5, 0, 0));
+        assert!(brick.cell_occupied(UVec3::new(5, 0, 0)));
+        let (gpu, _) = GpuBrick::from_brick(&brick);
+        assert_eq!(gpu.occupancy, [1 << 1, 1 << 31]);
+        assert_eq!(gpu.to_brick(None).occupancy(), brick.occupancy());
+
+        assert!(!brick.update_occupancy());
+        assert_eq!(brick.occupancy(), 1 << 63);
+        brick.set_voxel(Voxel::empty(), UVec3::new(15, 15, 15));
+        assert!(brick.update_occupancy());
+    }
+
+    #[test]
+    fn markers_are_not_raw_bricks() {
+        let mut gpu = GpuBrick::empty();
+        for marker in [RAW_IDX_UNIFORM, RAW_IDX_RESERVED, RAW_IDX_OVERFLOW] {
+            gpu.raw_idx = marker;
+            assert!(!gpu.has_raw_brick());
+        }
+    }
+
+    fn rgb565(r: u32, g: u32, b: u32) -> u32 {
+        r | g << 5 | b << 11
+    }
+
+    #[test]
+    fn lod_averages_non_empty_voxels() {
+        let mut brick = Brick::empty();
+        // A 2x2x2 block with 4 dark and 2 bright voxels and 2 holes, the opacity bits get ignored
+        let dark = Voxel(rgb565(4, 8, 2) | 255 << 24);
+        let bright = Voxel(rgb565(10, 20, 8) | 255 << 24);
+        for (i, voxel) in [dark, dark, dark, dark, bright, bright].into_iter().enumerate() {
+            let i = i as u32;
+            brick.set_voxel(voxel, UVec3::new(i % 2, (i / 2) % 2, i / 4));
+        }
+        let average = rgb565(6, 12, 4) as u16;
+
+        let level1 = brick.lod(1);
+        assert_eq!(level1.len(), 8 * 8 * 8);
+        assert_eq!(level1[0], average);
+        assert!(level1[1..].iter().all(|block| *block == 0));
+        // Bigger blocks holding only the same voxels come out the same
+        assert_eq!(brick.lod(2)[0], average);
+        assert_eq!(brick.lod(3)[0], average);
+        assert_eq!(brick.lod(3).len(), 2 * 2 * 2);
+
+        // Each level averages the voxels, not the blocks of the level below
+        brick.set_voxel(Voxel(rgb565(31, 0, 0)), UVec3::new(2, 0, 0));
+        assert_eq!(brick.lod(1)[1], rgb565(31, 0, 0) as u16);
+        assert_eq!(brick.lod(2)[0], rgb565((16 + 20 + 31) / 7, 72 / 7, 24 / 7) as u16);
+    }
+
+    #[test]
+    fn black_blocks_are_not_empty() {
+        let mut brick = Brick::empty();
+        brick.set_voxel(Voxel::new([0, 0, 0], 255, 0, false, 255), UVec3::new(9, 9, 9));
+        assert_ne!(brick.get_voxel(UVec3::new(9, 9, 9)).0, 0);
+        for level in 1..=LOD_LEVELS {
+            let size = 16 >> level;
+            let block = UVec3::splat(9 >> level);
+            assert_eq!(brick.lod(level)[(block.x + block.y * size + block.z * size * size) as usize], 1);
+        }
+    }
+
+    /// Parses `const uint LOD_OFFSETS[...] = uint[](...)` out of a shader
+    fn shader_lod_offsets(source: &str) -> Vec<usize> {
+        let line = source.lines().find(|line| line.starts_with("const uint LOD_OFFSETS")).expect("Shader has no LOD_OFFSETS!");
+        let list = &line[line.find("uint[](").unwrap() + 7..line.rfind(')').unwrap()];
+        list.split(',').map(|offset| {
+            offset.split('+').map(|term| term.split('*').map(|factor| factor.trim().trim_end_matches('u').parse::<usize>().unwrap()).product::<usize>()).sum()
+        }).collect()
+    }
+
+    #[test]
+    fn lod_offsets_match_the_shaders() {
+        for source in [
+            include_str!("../shaders/cs_build_lods.glsl"),
+            include_str!("../shaders/cs_compact_bricks.glsl"),
+            include_str!("../../stardust_engine/shaders/fs.glsl"),
+            include_str!("../../stardust_engine_lib/shaders/fs.glsl"),
+        ] {
+            assert_eq!(shader_lod_offsets(source), LOD_OFFSETS);
+        }
+        // Every level fits right behind the one before
+        for level in 1..LOD_LEVELS as usize {
+            assert_eq!(LOD_OFFSETS[level + 1] - LOD_OFFSETS[level], (16 >> level) * (16 >> level) * (16 >> level));
+        }
+        assert_eq!(LOD_WORDS * 2, LOD_OFFSETS[LOD_LEVELS as usize] + 2 * 2 * 2);
+    }
+
+    #[test]
+    fn lod_packing() {
+        let mut brick = Brick::empty();
+        let red = Voxel(rgb565(31, 0, 0));
+        let blue = Voxel(rgb565(0, 0, 31));
+        // Level 1 blocks 1 and 2, level 2 blocks 0 and 1, and together level 3 block 0
+        brick.set_voxel(red, UVec3::new(2, 0, 0));
+        brick.set_voxel(red, UVec3::new(4, 1, 1));
+        // Level 1 block 7 + 7 * 8 + 7 * 64, level 2 block 63 and level 3 block 7
+        brick.set_voxel(blue, UVec3::new(15, 15, 15));
+
+        let gpu = GpuBrickLod::from_brick(&brick);
+        assert_eq!(gpu.dirty, 0);
+        let entry = |entry: usize| (gpu.blocks[entry / 2] >> ((entry % 2) * 16)) & 0xFFFF;
+        let red = red.0;
+        let blue = blue.0;
+        // Two entries per u32, the lower one first
+        assert_eq!(gpu.blocks[0], red << 16);
+        assert_eq!(gpu.blocks[1], red);
+        assert_eq!(entry(LOD_OFFSETS[1] + 511), blue);
+        assert_eq!(entry(LOD_OFFSETS[2]), red);
+        assert_eq!(entry(LOD_OFFSETS[2] + 1), red);
+        assert_eq!(entry(LOD_OFFSETS[2] + 63), blue);
+        assert_eq!(entry(LOD_OFFSETS[3]), red);
+        assert_eq!(entry(LOD_OFFSETS[3] + 7), blue);
+        assert_eq!(gpu.blocks.iter().map(|word| (word & 0xFFFF != 0) as u32 + (word >> 16 != 0) as u32).sum::<u32>(), 8);
+        for level in 1..=LOD_LEVELS {
+            for (i, block) in brick.lod(level).into_iter().enumerate() {
+                assert_eq!(entry(LOD_OFFSETS[level as usize] + i), block as u32);
+            }
+        }
     }
 }
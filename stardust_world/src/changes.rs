@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Weak};
+
+use stardust_common::math::*;
+
+/// Bricks that changed since a `ChangeSubscription` was last taken from
+#[derive(Debug, Clone, Default)]
+pub struct BrickChanges {
+    /// World position of the first voxel of every brick where a voxel changed, each brick once
+    pub bricks: Vec<IVec3>,
+    /// Set when the whole world got replaced by `World::upload`, so everything has to be rebuilt
+    pub world_replaced: bool,
+}
+
+impl BrickChanges {
+    pub fn is_empty(&self) -> bool {
+        self.bricks.is_empty() && !self.world_replaced
+    }
+
+    /// World positions of the regions of `region_size` voxels along each axis that contain changed
+    /// bricks, for consumers that work in larger chunks than bricks. Each region is listed once.
+    pub fn regions(&self, region_size: u32) -> Vec<IVec3> {
+        let size = region_size.max(1) as i32;
+        let regions: HashSet<IVec3> = self.bricks.iter()
+            .map(|brick| ivec3(brick.x.div_euclid(size), brick.y.div_euclid(size), brick.z.div_euclid(size)) * size)
+            .collect();
+        regions.into_iter().collect()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct PendingChanges {
+    bricks: HashSet<IVec3>,
+    world_replaced: bool,
+}
+
+/// Collects the bricks that changed during `World::process`, returned by `World::subscribe_changes`.
+/// Changes pile up until they get taken, so consumers can update whenever suits them.
+/// Dropping every clone of the subscription unsubscribes it.
+#[derive(Clone)]
+pub struct ChangeSubscription(Arc<Mutex<PendingChanges>>);
+
+impl ChangeSubscription {
+    pub fn has_changes(&self) -> bool {
+        let pending = self.0.lock().unwrap();
+        !pending.bricks.is_empty() || pending.world_replaced
+    }
+
+    /// Takes everything that changed since the last call
+    pub fn take(&self) -> BrickChanges {
+        let mut pending = self.0.lock().unwrap();
+        BrickChanges {
+            bricks: pending.bricks.drain().collect(),
+            world_replaced: std::mem::take(&mut pending.world_replaced),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Subscriptions(Mutex<Vec<Weak<Mutex<PendingChanges>>>>);
+
+impl Subscriptions {
+    pub(crate) fn subscribe(&self) -> ChangeSubscription {
+        let pending = Arc::new(Mutex::new(PendingChanges::default()));
+        self.0.lock().unwrap().push(Arc::downgrade(&pending));
+        ChangeSubscription(pending)
+    }
+
+    /// Hands the changes to every subscription, and forgets the ones that were dropped
+    pub(crate) fn notify(&self, bricks: &[IVec3], world_replaced: bool) {
+        if bricks.is_empty() && !world_replaced { return; }
+        self.0.lock().unwrap().retain(|subscription| {
+            match subscription.upgrade() {
+                Some(pending) => {
+                    let mut pending = pending.lock().unwrap();
+                    pending.bricks.extend(bricks.iter().copied());
+                    pending.world_replaced |= world_replaced;
+                    true
+                },
+                None => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut regions: Vec<IVec3>) -> Vec<IVec3> {
+        regions.sort_by_key(|r| r.to_array());
+        regions
+    }
+
+    #[test]
+    fn negative_bricks_map_to_the_region_below() {
+        let changes = BrickChanges { bricks: vec![ivec3(-8, 0, -64), ivec3(-72, 56, 8)], world_replaced: false };
+        assert_eq!(sorted(changes.regions(64)), vec![ivec3(-128, 0, 0), ivec3(-64, 0, -64)]);
+    }
+
+    #[test]
+    fn regions_are_listed_once() {
+        let changes = BrickChanges { bricks: vec![ivec3(0, 0, 0), ivec3(8, 16, 24), ivec3(56, 56, 56), ivec3(64, 0, 0)], world_replaced: false };
+        assert_eq!(sorted(changes.regions(64)), vec![ivec3(0, 0, 0), ivec3(64, 0, 0)]);
+    }
+
+    #[test]
+    fn notify_reaches_every_subscriber() {
+        let subscriptions = Subscriptions::default();
+        let a = subscriptions.subscribe();
+        let b = subscriptions.subscribe();
+
+        subscriptions.notify(&[ivec3(8, 0, 0)], false);
+        subscriptions.notify(&[], true);
+
+        for subscription in [&a, &b] {
+            assert!(subscription.has_changes());
+            let changes = subscription.take();
+            assert_eq!(changes.bricks, vec![ivec3(8, 0, 0)]);
+            assert!(changes.world_replaced);
+            assert!(!subscription.has_changes());
+        }
+    }
+
+    #[test]
+    fn dropped_subscribers_are_pruned() {
+        let subscriptions = Subscriptions::default();
+        let kept = subscriptions.subscribe();
+        drop(subscriptions.subscribe());
+
+        subscriptions.notify(&[ivec3(0, 0, 0)], false);
+        assert_eq!(subscriptions.0.lock().unwrap().len(), 1);
+        assert_eq!(kept.take().bricks, vec![ivec3(0, 0, 0)]);
+    }
+}
@@ -0,0 +1,360 @@
+use itertools::iproduct;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+use crate::CpuWorld;
+
+/// Result of `CpuWorld::sweep_aabb`
+#[derive(Debug, Copy, Clone)]
+pub struct SweepHit {
+    /// Fraction of the motion that can be covered before touching the voxel, from 0 to 1
+    pub time: f32,
+    /// Normal of the voxel face that was hit, zero if the box started inside the voxel
+    pub normal: IVec3,
+    /// World position of the voxel that was hit
+    pub pos: IVec3,
+    pub voxel: Voxel,
+}
+
+impl CpuWorld {
+    /// True if the box from `min` to `max` overlaps any non-empty voxel. Voxel `p` fills the unit
+    /// cube from `p` to `p + 1`, and only touching a voxel doesn't count, so a box resting on the
+    /// ground doesn't collide with it. Positions are in world space.
+    pub fn overlaps_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        let (vmin, vmax) = voxel_bounds(min, max);
+        self.find_solid(vmin, vmax, |_, _| true)
+    }
+
+    /// True if the capsule around the segment from `a` to `b` overlaps any non-empty voxel.
+    /// Like `overlaps_aabb`, only touching a voxel doesn't count.
+    pub fn overlaps_capsule(&self, a: Vec3, b: Vec3, radius: f32) -> bool {
+        let (vmin, vmax) = voxel_bounds(a.min(b) - Vec3::splat(radius), a.max(b) + Vec3::splat(radius));
+        self.find_solid(vmin, vmax, |pos, _| {
+            let pos = pos.as_vec3();
+            segment_box_distance(a, b, pos, pos + Vec3::ONE) < radius
+        })
+    }
+
+    /// Moves the box from `min` to `max` along `motion` and returns the first voxel it runs into,
+    /// for moving character controllers and physics bodies without tunneling through thin walls.
+    /// A box that already touches a voxel and moves into it hits it at time 0, moving along or
+    /// away from it is fine. Voxels the box starts inside of are hit at time 0 with a zero normal.
+    /// Walks the motion brick by brick, so the cost grows with the distance and not with the
+    /// volume of the whole sweep.
+    pub fn sweep_aabb(&self, min: Vec3, max: Vec3, motion: Vec3) -> Option<SweepHit> {
+        puffin::profile_function!();
+        let center = (min + max) * 0.5 + self.config().origin.as_vec3();
+        let mut closest: Option<SweepHit> = None;
+        for (start, end) in brick_steps(center, motion) {
+            // The box overlaps a voxel right after hitting it, so earlier hits were found in earlier steps
+            if matches!(closest, Some(hit) if hit.time < start) { break; }
+            let (vmin, vmax) = voxel_bounds((min + motion * start).min(min + motion * end), (max + motion * start).max(max + motion * end));
+            let started_inside = self.find_solid(vmin, vmax, |pos, voxel| {
+                if let Some((time, normal)) = sweep_voxel(min, max, motion, pos) {
+                    let closer = match closest {
+                        Some(hit) => time < hit.time,
+                        None => true,
+                    };
+                    if closer {
+                        closest = Some(SweepHit { time, normal, pos, voxel });
+                    }
+                }
+                // Nothing can be hit before a voxel the box starts in
+                matches!(closest, Some(hit) if hit.normal == IVec3::ZERO)
+            });
+            if started_inside { break; }
+        }
+        closest
+    }
+
+    /// Calls `f` for every non-empty voxel from `min` up to (but not including) `max`, skipping
+    /// empty layer0 nodes and bricks. Stops as soon as `f` returns true, and returns whether it did.
+    fn find_solid<F: FnMut(IVec3, Voxel) -> bool>(&self, min: IVec3, max: IVec3, mut f: F) -> bool {
+        let origin = self.config().origin;
+        let min = (min + origin).max(IVec3::ZERO);
+        let max = (max + origin).min(IVec3::splat(self.config().world_size() as i32));
+        if min.cmpge(max).any() { return false; }
+
+        for layer0_pos in box_cells(min, max, 256) {
+            let layer0_pool_idx = match self.lookup_layer0(layer0_pos) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let layer0_min = min.max(layer0_pos * 256);
+            let layer0_max = max.min(layer0_pos * 256 + 256);
+            for brick_pos in box_cells(layer0_min, layer0_max, 16) {
+                let brick = match self.lookup_brick(brick_pos - layer0_pos * 16, layer0_pool_idx) {
+                    Some(brick) => brick,
+                    None => continue,
+                };
+                let brick_min = layer0_min.max(brick_pos * 16);
+                let brick_max = layer0_max.min(brick_pos * 16 + 16);
+                for pos in box_cells(brick_min, brick_max, 1) {
+                    let voxel = *brick.get_voxel((pos - brick_pos * 16).as_uvec3());
+                    if voxel.0 != 0 && f(pos - origin, voxel) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// The voxels a box overlaps, from `min` up to (but not including) `max`
+fn voxel_bounds(min: Vec3, max: Vec3) -> (IVec3, IVec3) {
+    (min.floor().as_ivec3(), max.ceil().as_ivec3())
+}
+
+/// Positions of the cells of size `size` overlapping the voxels from `min` up to (but not including) `max`
+fn box_cells(min: IVec3, max: IVec3, size: i32) -> impl Iterator<Item = IVec3> {
+    let (min, max) = (min / size, (max - IVec3::ONE) / size);
+    iproduct!(min.z..=max.z, min.y..=max.y, min.x..=max.x).map(|(z, y, x)| ivec3(x, y, z))
+}
+
+/// Splits the motion of a point in brick map space into the parts between crossing brick
+/// borders, as fractions of the motion from 0 to 1
+fn brick_steps(start: Vec3, motion: Vec3) -> Vec<(f32, f32)> {
+    let mut next = Vec3::splat(f32::INFINITY);
+    let mut delta = Vec3::splat(f32::INFINITY);
+    for axis in 0..3 {
+        if motion[axis] == 0.0 { continue; }
+        let border = if motion[axis] > 0.0 { (start[axis] / 16.0).floor() + 1.0 } else { (start[axis] / 16.0).ceil() - 1.0 };
+        next[axis] = (border * 16.0 - start[axis]) / motion[axis];
+        delta[axis] = 16.0 / motion[axis].abs();
+    }
+
+    let mut steps = Vec::new();
+    let mut time = 0.0;
+    loop {
+        let axis = if next.x <= next.y && next.x <= next.z { 0 } else if next.y <= next.z { 1 } else { 2 };
+        let end = next[axis].min(1.0);
+        // Crossing two borders at once doesn't need a step in between
+        if end > time || end >= 1.0 {
+            steps.push((time, end));
+        }
+        if end >= 1.0 { return steps; }
+        next[axis] += delta[axis];
+        time = end;
+    }
+}
+
+/// When and through which face the moving box starts overlapping the voxel at `pos`, if it does
+/// within the motion. Boxes that only touch are not overlapping.
+fn sweep_voxel(min: Vec3, max: Vec3, motion: Vec3, pos: IVec3) -> Option<(f32, IVec3)> {
+    let vmin = pos.as_vec3();
+    let vmax = vmin + Vec3::ONE;
+    let mut enter = f32::NEG_INFINITY;
+    let mut exit = f32::INFINITY;
+    let mut normal = IVec3::ZERO;
+    for axis in 0..3 {
+        let (axis_enter, axis_exit) = if motion[axis] == 0.0 {
+            if min[axis] >= vmax[axis] || max[axis] <= vmin[axis] { return None; }
+            (f32::NEG_INFINITY, f32::INFINITY)
+        } else if motion[axis] > 0.0 {
+            ((vmin[axis] - max[axis]) / motion[axis], (vmax[axis] - min[axis]) / motion[axis])
+        } else {
+            ((vmax[axis] - min[axis]) / motion[axis], (vmin[axis] - max[axis]) / motion[axis])
+        };
+        if axis_enter > enter {
+            enter = axis_enter;
+            normal = IVec3::ZERO;
+            normal[axis] = if motion[axis] > 0.0 { -1 } else { 1 };
+        }
+        exit = exit.min(axis_exit);
+    }
+
+    if enter >= exit || exit <= 0.0 || enter > 1.0 { return None; }
+    if enter < 0.0 { return Some((0.0, IVec3::ZERO)); }
+    Some((enter, normal))
+}
+
+/// Shortest distance between the segment from `a` to `b` and the box from `min` to `max`.
+/// The distance to a box is convex along a line, so a ternary search finds the closest point.
+fn segment_box_distance(a: Vec3, b: Vec3, min: Vec3, max: Vec3) -> f32 {
+    let dist = |t: f32| {
+        let p = a.lerp(b, t);
+        (p - p.clamp(min, max)).length()
+    };
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..32 {
+        let m1 = lo + (hi - lo) / 3.0;
+        let m2 = hi - (hi - lo) / 3.0;
+        if dist(m1) < dist(m2) { hi = m2; } else { lo = m1; }
+    }
+    dist((lo + hi) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WorldConfig;
+
+    fn solid() -> Voxel {
+        Voxel::new([255, 0, 0], 255, 0, false, 255)
+    }
+
+    fn world_with(voxels: impl IntoIterator<Item = IVec3>) -> CpuWorld {
+        let mut world = CpuWorld::new(WorldConfig {
+            brick_pool_size: 256,
+            layer0_pool_size: 8,
+            brick_map_size: 4,
+            origin: IVec3::splat(512),
+            voxel_queue_size: 4096,
+            dealloc_queue_size: 256,
+            ..WorldConfig::default()
+        });
+        for pos in voxels {
+            world.set_voxel(solid(), pos);
+        }
+        world.process();
+        world
+    }
+
+    fn unit_box() -> (Vec3, Vec3) {
+        (Vec3::ZERO, Vec3::ONE)
+    }
+
+    #[test]
+    fn sweep_voxel_time_of_impact() {
+        let (min, max) = unit_box();
+        assert_eq!(sweep_voxel(min, max, vec3(4.0, 0.0, 0.0), ivec3(3, 0, 0)), Some((0.5, ivec3(-1, 0, 0))));
+        assert_eq!(sweep_voxel(min + vec3(3.0, 0.0, 0.0), max + vec3(3.0, 0.0, 0.0), vec3(-4.0, 0.0, 0.0), ivec3(0, 0, 0)), Some((0.5, ivec3(1, 0, 0))));
+        assert_eq!(sweep_voxel(min, max, vec3(0.0, -8.0, 0.0), ivec3(0, -3, 0)), Some((0.25, ivec3(0, 1, 0))));
+        // Too far away
+        assert_eq!(sweep_voxel(min, max, vec3(1.0, 0.0, 0.0), ivec3(3, 0, 0)), None);
+        // The face that's reached last is the one that's hit
+        assert_eq!(sweep_voxel(min, max, vec3(2.0, 4.0, 0.0), ivec3(1, 2, 0)), Some((0.25, ivec3(0, -1, 0))));
+    }
+
+    #[test]
+    fn sweep_voxel_touching_faces() {
+        let (min, max) = unit_box();
+        // Moving into it
+        assert_eq!(sweep_voxel(min, max, vec3(1.0, 0.0, 0.0), ivec3(1, 0, 0)), Some((0.0, ivec3(-1, 0, 0))));
+        // Moving away from it
+        assert_eq!(sweep_voxel(min, max, vec3(-1.0, 0.0, 0.0), ivec3(1, 0, 0)), None);
+        // Sliding along it
+        assert_eq!(sweep_voxel(min, max, vec3(3.0, 0.0, 2.0), ivec3(0, 1, 0)), None);
+        assert_eq!(sweep_voxel(min, max, vec3(3.0, 0.0, 2.0), ivec3(1, -1, 0)), None);
+        // Only touching an edge on the way
+        assert_eq!(sweep_voxel(min, max, vec3(2.0, 0.0, 0.0), ivec3(2, 1, 0)), None);
+    }
+
+    #[test]
+    fn sweep_voxel_starting_inside() {
+        let min = vec3(0.2, 0.2, 0.2);
+        let max = vec3(0.8, 0.8, 0.8);
+        assert_eq!(sweep_voxel(min, max, vec3(3.0, 0.0, 0.0), ivec3(0, 0, 0)), Some((0.0, IVec3::ZERO)));
+        assert_eq!(sweep_voxel(min, max, Vec3::ZERO, ivec3(0, 0, 0)), Some((0.0, IVec3::ZERO)));
+        // Partly inside counts too
+        assert_eq!(sweep_voxel(min + 0.5, max + 0.5, vec3(-1.0, 0.0, 0.0), ivec3(0, 0, 0)), Some((0.0, IVec3::ZERO)));
+    }
+
+    #[test]
+    fn sweep_voxel_zero_motion_components() {
+        let (min, max) = unit_box();
+        assert_eq!(sweep_voxel(min, max, Vec3::ZERO, ivec3(1, 0, 0)), None);
+        assert_eq!(sweep_voxel(min, max, Vec3::ZERO, ivec3(5, 5, 5)), None);
+        // Out of the way on an axis it doesn't move along
+        assert_eq!(sweep_voxel(min, max, vec3(0.0, 0.0, 4.0), ivec3(0, 1, 2)), None);
+        assert_eq!(sweep_voxel(min + vec3(0.5, 0.0, 0.0), max + vec3(0.5, 0.0, 0.0), vec3(0.0, 0.0, 4.0), ivec3(1, 0, 2)), Some((0.25, ivec3(0, 0, -1))));
+    }
+
+    #[test]
+    fn segment_box_distances() {
+        let (min, max) = unit_box();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        // Through the box
+        assert_eq!(segment_box_distance(vec3(-1.0, 0.5, 0.5), vec3(2.0, 0.5, 0.5), min, max), 0.0);
+        // Starting inside
+        assert_eq!(segment_box_distance(vec3(0.5, 0.5, 0.5), vec3(4.0, 4.0, 4.0), min, max), 0.0);
+        // Parallel to a face
+        assert!(close(segment_box_distance(vec3(-3.0, 2.0, 0.5), vec3(3.0, 2.0, 0.5), min, max), 1.0));
+        // A single point
+        assert!(close(segment_box_distance(vec3(2.5, 0.5, 0.5), vec3(2.5, 0.5, 0.5), min, max), 1.5));
+        // Closest to an edge, somewhere in the middle of the segment
+        assert!(close(segment_box_distance(vec3(0.0, 3.0, 0.5), vec3(3.0, 0.0, 0.5), min, max), 0.5f32.sqrt()));
+        // Closest at an end, to a corner
+        assert!(close(segment_box_distance(vec3(2.0, 2.0, 2.0), vec3(5.0, 6.0, 7.0), min, max), 3.0f32.sqrt()));
+        // Touching
+        assert_eq!(segment_box_distance(vec3(1.0, -1.0, 0.5), vec3(1.0, 2.0, 0.5), min, max), 0.0);
+    }
+
+    #[test]
+    fn brick_steps_cover_the_motion() {
+        assert_eq!(brick_steps(vec3(8.0, 8.0, 8.0), Vec3::ZERO), vec![(0.0, 1.0)]);
+        assert_eq!(brick_steps(vec3(8.0, 8.0, 8.0), vec3(4.0, 0.0, 0.0)), vec![(0.0, 1.0)]);
+        assert_eq!(brick_steps(vec3(8.0, 8.0, 8.0), vec3(32.0, 0.0, 0.0)), vec![(0.0, 0.25), (0.25, 0.75), (0.75, 1.0)]);
+        assert_eq!(brick_steps(vec3(16.0, 8.0, 8.0), vec3(-32.0, 0.0, 0.0)), vec![(0.0, 0.5), (0.5, 1.0)]);
+        // Crossing two borders at once
+        assert_eq!(brick_steps(vec3(8.0, 8.0, 8.0), vec3(16.0, 16.0, 0.0)), vec![(0.0, 0.5), (0.5, 1.0)]);
+    }
+
+    #[test]
+    fn sweep_hits_the_ground() {
+        let world = world_with(iproduct!(-4..4, -4..4).map(|(x, z)| ivec3(x, 0, z)));
+        let hit = world.sweep_aabb(vec3(-0.4, 3.0, -0.4), vec3(0.4, 4.8, 0.4), vec3(0.0, -10.0, 0.0)).unwrap();
+        assert_eq!(hit.time, 0.2);
+        assert_eq!(hit.normal, ivec3(0, 1, 0));
+        assert_eq!(hit.pos.y, 0);
+        assert_eq!(hit.voxel.0, solid().0);
+        // Walking along the ground doesn't hit it
+        assert!(world.sweep_aabb(vec3(-3.0, 1.0, -3.0), vec3(-2.0, 3.0, -2.0), vec3(5.0, 0.0, 5.0)).is_none());
+        // Starting inside of it
+        let hit = world.sweep_aabb(vec3(0.0, 0.5, 0.0), vec3(1.0, 2.0, 1.0), vec3(3.0, 0.0, 0.0)).unwrap();
+        assert_eq!(hit.time, 0.0);
+        assert_eq!(hit.normal, IVec3::ZERO);
+    }
+
+    #[test]
+    fn sweep_doesnt_tunnel_through_thin_walls() {
+        let wall = |x: i32| iproduct!(-2..2, -2..2).map(move |(y, z)| ivec3(x, y, z));
+        let world = world_with(wall(70).chain(wall(-41)));
+        let (min, max) = (vec3(-0.5, -0.5, -0.5), vec3(0.5, 0.5, 0.5));
+        let hit = world.sweep_aabb(min, max, vec3(200.0, 0.0, 0.0)).unwrap();
+        assert_eq!(hit.time, 69.5 / 200.0);
+        assert_eq!(hit.normal, ivec3(-1, 0, 0));
+        assert_eq!(hit.pos.x, 70);
+        let hit = world.sweep_aabb(min, max, vec3(-100.0, 0.0, 0.0)).unwrap();
+        assert_eq!(hit.time, 39.5 / 100.0);
+        assert_eq!(hit.normal, ivec3(1, 0, 0));
+        // Missing the wall
+        assert!(world.sweep_aabb(min + vec3(0.0, 5.0, 0.0), max + vec3(0.0, 5.0, 0.0), vec3(200.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn sweep_finds_the_earliest_hit() {
+        // Scattered voxels, checked against testing every single one of them
+        let voxels: Vec<IVec3> = (0..300u32).map(|i| {
+            let h = i.wrapping_mul(2654435761);
+            ivec3((h % 97) as i32 - 48, ((h >> 8) % 41) as i32 - 20, ((h >> 16) % 97) as i32 - 48)
+        }).collect();
+        let world = world_with(voxels.iter().copied());
+        let mut hits = 0;
+        for i in 0..200u32 {
+            let h = i.wrapping_mul(2246822519).rotate_left(7);
+            let start = vec3((h % 80) as f32 - 40.3, ((h >> 7) % 30) as f32 - 15.6, ((h >> 14) % 80) as f32 - 40.1);
+            let size = vec3(0.6, 1.8, 0.6) + ((h >> 21) % 3) as f32;
+            let motion = vec3(((h >> 3) % 61) as f32 - 30.0, ((h >> 11) % 21) as f32 - 10.0, ((h >> 19) % 61) as f32 - 30.0) * 1.7;
+            let expected = voxels.iter().filter_map(|pos| sweep_voxel(start, start + size, motion, *pos)).map(|(time, _)| time).reduce(f32::min);
+            let hit = world.sweep_aabb(start, start + size, motion);
+            assert_eq!(hit.map(|hit| hit.time), expected, "sweep {}", i);
+            hits += hit.is_some() as u32;
+        }
+        // Both hits and misses got tested
+        assert!(hits > 20 && hits < 180, "{}", hits);
+    }
+
+    #[test]
+    fn overlaps() {
+        let world = world_with([ivec3(0, 0, 0), ivec3(10, 10, 10)]);
+        assert!(world.overlaps_aabb(vec3(0.5, 0.5, 0.5), vec3(2.0, 2.0, 2.0)));
+        // Touching isn't overlapping
+        assert!(!world.overlaps_aabb(vec3(1.0, 0.0, 0.0), vec3(2.0, 1.0, 1.0)));
+        assert!(world.overlaps_capsule(vec3(3.0, 0.5, 0.5), vec3(5.0, 0.5, 0.5), 2.5));
+        assert!(!world.overlaps_capsule(vec3(3.0, 0.5, 0.5), vec3(5.0, 0.5, 0.5), 1.5));
+        assert!(world.overlaps_capsule(vec3(9.0, 9.0, 9.0), vec3(12.0, 12.0, 12.0), 0.1));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+use crate::data::ModelCommand;
+use crate::shape::ShapeCommand;
+use crate::ownership::VoxelStack;
+use crate::VoxelBatch;
+
+/// Something queued for `World::process`
+pub(crate) enum Command {
+    /// Single voxel writes queued right after each other
+    Voxels(Vec<(Voxel, IVec3)>),
+    Shape(ShapeCommand),
+    Model(ModelCommand),
+    /// Voxels put back by `World::undo` or `World::redo`, along with the ownership stacks they had
+    Restore(Vec<(Voxel, IVec3)>, Vec<(IVec3, Option<VoxelStack>)>),
+}
+
+/// Everything queued for `World::process`, in the order it was queued. Voxels queued right
+/// after each other share a command, so they can be uploaded in as few batches as possible.
+#[derive(Default)]
+pub(crate) struct CommandQueue {
+    commands: Vec<Command>,
+    /// Voxels written before all commands, outside of any transaction, see `World::stream_in`
+    streamed: Vec<(Voxel, IVec3)>,
+    /// Set when a transaction starts or ends, so voxels queued after it start a new command
+    sealed: bool,
+    voxels: usize,
+    shapes: usize,
+    models: usize,
+}
+
+impl CommandQueue {
+    pub(crate) fn push_voxels<I: IntoIterator<Item = (Voxel, IVec3)>>(&mut self, voxels: I) {
+        if self.sealed || !matches!(self.commands.last(), Some(Command::Voxels(_))) {
+            self.commands.push(Command::Voxels(Vec::new()));
+        }
+        self.sealed = false;
+        if let Some(Command::Voxels(queued)) = self.commands.last_mut() {
+            let start = queued.len();
+            queued.extend(voxels);
+            self.voxels += queued.len() - start;
+        }
+    }
+
+    /// Queues the writes of a batch like `push_voxels`, an empty batch leaves the queue untouched
+    pub(crate) fn push_batch(&mut self, batch: VoxelBatch) {
+        if batch.is_empty() { return; }
+        self.push_voxels(batch.voxels);
+    }
+
+    pub(crate) fn push_streamed<I: IntoIterator<Item = (Voxel, IVec3)>>(&mut self, voxels: I) {
+        let start = self.streamed.len();
+        self.streamed.extend(voxels);
+        self.voxels += self.streamed.len() - start;
+    }
+
+    pub(crate) fn push_shape(&mut self, command: ShapeCommand) {
+        self.commands.push(Command::Shape(command));
+        self.sealed = false;
+        self.shapes += 1;
+    }
+
+    pub(crate) fn push_model(&mut self, command: ModelCommand) {
+        self.commands.push(Command::Model(command));
+        self.sealed = false;
+        self.models += 1;
+    }
+
+    pub(crate) fn push_restore(&mut self, voxels: Vec<(Voxel, IVec3)>, owners: Vec<(IVec3, Option<VoxelStack>)>) {
+        self.voxels += voxels.len();
+        self.commands.push(Command::Restore(voxels, owners));
+        self.sealed = false;
+    }
+
+    /// Makes sure nothing gets added to the commands that are already queued, and returns
+    /// how many there are. Used to mark where transactions start and end.
+    pub(crate) fn seal(&mut self) -> usize {
+        self.sealed = true;
+        self.commands.len()
+    }
+
+    /// Takes the streamed voxels, which have to be written before the commands from `take`
+    pub(crate) fn take_streamed(&mut self) -> Vec<(Voxel, IVec3)> {
+        std::mem::take(&mut self.streamed)
+    }
+
+    /// Takes all queued commands, along with the amount of (voxels, shapes, models) they hold
+    pub(crate) fn take(&mut self) -> (Vec<Command>, (usize, usize, usize)) {
+        let counts = (self.voxels, self.shapes, self.models);
+        let commands = std::mem::take(&mut self.commands);
+        *self = Self::default();
+        (commands, counts)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Removes all but the last write to each position, keeping the order of the remaining writes.
+/// The GPU writes the voxels of a batch in no particular order, so a batch can't hold more than one.
+pub(crate) fn last_writes(queue: &[(Voxel, IVec3)]) -> Vec<(Voxel, IVec3)> {
+    puffin::profile_function!();
+    let mut last = HashMap::with_capacity(queue.len());
+    for (i, (_, pos)) in queue.iter().enumerate() {
+        last.insert(*pos, i);
+    }
+    queue.iter().enumerate().filter(|(i, (_, pos))| last[pos] == *i).map(|(_, write)| *write).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Shape, ShapeOp, CpuWorld, WorldConfig};
+
+    use super::*;
+
+    fn voxel(i: i32) -> (Voxel, IVec3) {
+        (Voxel::new([1, 2, 3], 255, 0, false, 255), ivec3(i, 0, 0))
+    }
+
+    fn shape() -> ShapeCommand {
+        ShapeCommand { shape: Shape::Box { min: IVec3::ZERO, max: IVec3::ONE }, op: ShapeOp::Clear }
+    }
+
+    fn voxel_runs(commands: &[Command]) -> Vec<Option<Vec<i32>>> {
+        commands.iter().map(|command| match command {
+            Command::Voxels(voxels) => Some(voxels.iter().map(|(_, pos)| pos.x).collect()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn keeps_the_order() {
+        let mut queue = CommandQueue::default();
+        queue.push_voxels([voxel(0)]);
+        queue.push_voxels([voxel(1), voxel(2)]);
+        queue.push_shape(shape());
+        queue.push_voxels([voxel(3)]);
+        queue.push_shape(shape());
+
+        let (commands, counts) = queue.take();
+        assert_eq!(voxel_runs(&commands), vec![Some(vec![0, 1, 2]), None, Some(vec![3]), None]);
+        assert_eq!(counts, (4, 2, 0));
+        assert_eq!(queue.take().0.len(), 0);
+    }
+
+    #[test]
+    fn sealing_starts_a_new_command() {
+        let mut queue = CommandQueue::default();
+        queue.push_voxels([voxel(0)]);
+        assert_eq!(queue.seal(), 1);
+        queue.push_voxels([voxel(1)]);
+        queue.push_voxels([voxel(2)]);
+        assert_eq!(queue.seal(), 2);
+        assert_eq!(queue.seal(), 2);
+        queue.push_voxels(std::iter::empty());
+
+        let (commands, counts) = queue.take();
+        assert_eq!(voxel_runs(&commands), vec![Some(vec![0]), Some(vec![1, 2]), Some(vec![])]);
+        assert_eq!(counts.0, 3);
+    }
+
+    #[test]
+    fn streamed_voxels_are_kept_apart() {
+        let mut queue = CommandQueue::default();
+        queue.push_voxels([voxel(0)]);
+        queue.push_streamed([voxel(1), voxel(2)]);
+        queue.push_voxels([voxel(3)]);
+
+        let streamed: Vec<i32> = queue.take_streamed().iter().map(|(_, pos)| pos.x).collect();
+        assert_eq!(streamed, vec![1, 2]);
+        let (commands, counts) = queue.take();
+        assert_eq!(voxel_runs(&commands), vec![Some(vec![0, 3])]);
+        assert_eq!(counts.0, 4);
+        assert!(queue.take_streamed().is_empty());
+    }
+
+    fn colored(i: u8, x: i32) -> (Voxel, IVec3) {
+        (Voxel::new([i * 8, 0, 0], 255, 0, false, 255), ivec3(x, 0, 0))
+    }
+
+    fn reds(writes: &[(Voxel, IVec3)]) -> Vec<(u8, i32)> {
+        writes.iter().map(|(voxel, pos)| (voxel.rgb()[0] / 8, pos.x)).collect()
+    }
+
+    #[test]
+    fn last_writes_keep_the_order() {
+        let writes = [colored(1, 0), colored(2, 1), colored(3, 0), colored(4, 2), colored(5, 1)];
+        assert_eq!(reds(&last_writes(&writes)), vec![(3, 0), (4, 2), (5, 1)]);
+        assert!(last_writes(&[]).is_empty());
+        // Removing a voxel is a write like any other
+        assert_eq!(reds(&last_writes(&[colored(1, 0), (Voxel::empty(), ivec3(0, 0, 0))])), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn conflicting_writes_last_one_wins() {
+        let mut queue = CommandQueue::default();
+        // Within one command
+        queue.push_voxels([colored(1, 0), colored(2, 1), colored(3, 0)]);
+        queue.seal();
+        // And across commands
+        queue.push_voxels([colored(4, 1), colored(5, 2)]);
+        queue.push_shape(shape());
+        queue.push_voxels([colored(6, 2)]);
+        let (commands, _) = queue.take();
+
+        let mut world = CpuWorld::new(WorldConfig { brick_map_size: 2, origin: IVec3::ZERO, ..WorldConfig::default() });
+        let mut batches = Vec::new();
+        for command in &commands {
+            if let Command::Voxels(voxels) = command {
+                let batch = last_writes(voxels);
+                batches.push(reds(&batch));
+                world.submit_batch(VoxelBatch { voxels: batch });
+                world.process();
+            }
+        }
+        assert_eq!(batches, vec![vec![(2, 1), (3, 0)], vec![(4, 1), (5, 2)], vec![(6, 2)]]);
+        for (x, red) in [(0, 3), (1, 4), (2, 6)] {
+            assert_eq!(world.get_voxel(ivec3(x, 0, 0)).rgb()[0] / 8, red);
+        }
+    }
+}
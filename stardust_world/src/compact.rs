@@ -0,0 +1,100 @@
+/// Where live bricks go when the brick pool gets compacted, see `World::compact_bricks`
+pub(crate) struct Compaction {
+    /// Brick pool index to move from and to, both offset by 1. zw are unused, so the moves can
+    /// go through the voxel queue buffer
+    pub(crate) moves: Vec<[u32; 4]>,
+    /// The whole free list after compacting. The allocator takes from the end of the used part,
+    /// so the free bricks closest to the front get handed out first
+    pub(crate) free_list: Vec<u32>,
+}
+
+/// Free slots in front of the last live brick, relative to the amount of live bricks.
+/// 0 means every live brick is at the front of the pool already.
+/// `free` is the used part of the free list, brick pool indices offset by 1.
+pub(crate) fn fragmentation(free: &[u32], pool_size: usize) -> f32 {
+    let used = pool_size.saturating_sub(free.len());
+    if used == 0 { return 0.0; }
+    let holes = free.iter().filter(|idx| (**idx as usize) <= used).count();
+    holes as f32 / used as f32
+}
+
+/// Moves every live brick behind the first `used` slots into a free slot in front of it
+pub(crate) fn plan(free: &[u32], pool_size: usize) -> Compaction {
+    let used = pool_size.saturating_sub(free.len());
+    let mut is_free = vec![false; pool_size + 1];
+    for idx in free {
+        if let Some(slot) = is_free.get_mut(*idx as usize) {
+            *slot = true;
+        }
+    }
+
+    let holes = (1..=used).filter(|idx| is_free[*idx]);
+    let live = (used + 1..=pool_size).filter(|idx| !is_free[*idx]);
+    let moves = live.zip(holes).map(|(from, to)| [from as u32, to as u32, 0, 0]).collect();
+
+    let mut free_list: Vec<u32> = (used + 1..=pool_size).rev().map(|idx| idx as u32).collect();
+    free_list.resize(pool_size, 0);
+
+    Compaction {
+        moves,
+        free_list,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_pool() {
+        // Nothing in use, every slot is free
+        let free: Vec<u32> = (1..=8).rev().collect();
+        assert_eq!(fragmentation(&free, 8), 0.0);
+        let compaction = plan(&free, 8);
+        assert!(compaction.moves.is_empty());
+        assert_eq!(compaction.free_list, free);
+    }
+
+    #[test]
+    fn full_pool() {
+        assert_eq!(fragmentation(&[], 8), 0.0);
+        let compaction = plan(&[], 8);
+        assert!(compaction.moves.is_empty());
+        assert_eq!(compaction.free_list, vec![0; 8]);
+    }
+
+    #[test]
+    fn holes_before_and_after_used() {
+        // Live bricks at 2, 5, 9 and 10, so the first 4 slots should hold them
+        let free = [1, 3, 4, 6, 7, 8];
+        // 1, 3 and 4 are holes in the first 4 slots
+        assert_eq!(fragmentation(&free, 10), 0.75);
+
+        let compaction = plan(&free, 10);
+        // Live bricks past the 4th slot fill the holes in front of it, in order
+        assert_eq!(compaction.moves, vec![[5, 1, 0, 0], [9, 3, 0, 0], [10, 4, 0, 0]]);
+        // The slots behind the live bricks are free, the lowest gets handed out first
+        assert_eq!(compaction.free_list, vec![10, 9, 8, 7, 6, 5, 0, 0, 0, 0]);
+        assert_eq!(fragmentation(&compaction.free_list[..6], 10), 0.0);
+    }
+
+    #[test]
+    fn holes_only_after_used() {
+        // Live bricks are packed at the front already, the free list order doesn't matter
+        let free = [6, 4, 5];
+        assert_eq!(fragmentation(&free, 6), 0.0);
+        let compaction = plan(&free, 6);
+        assert!(compaction.moves.is_empty());
+        assert_eq!(compaction.free_list, vec![6, 5, 4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn holes_only_before_used() {
+        // Every free slot is in front, every live brick behind them moves
+        let free = [1, 2];
+        assert_eq!(fragmentation(&free, 4), 1.0);
+        let compaction = plan(&free, 4);
+        assert_eq!(compaction.moves, vec![[3, 1, 0, 0], [4, 2, 0, 0]]);
+        assert_eq!(compaction.free_list, vec![4, 3, 0, 0]);
+    }
+}
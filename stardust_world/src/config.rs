@@ -0,0 +1,276 @@
+use stardust_common::math::*;
+
+/// Invocations per workgroup of the compute shaders that run once per item, like a voxel in the queue
+pub(crate) const WORKGROUP_SIZE: u32 = 64;
+
+/// Amount of workgroups every GL implementation supports along x in a single dispatch
+const MAX_WORKGROUPS: usize = 65535;
+
+/// Pool indices are offset by 1 and stored as u32s, and the raw brick pool reserves the three
+/// highest values as markers, see `RAW_IDX_OVERFLOW`
+const MAX_POOL_SIZE: usize = u32::MAX as usize - 3;
+
+/// Sizes of the world and its GPU buffers. The same values get injected as `#define`s into
+/// every shader compiled through `WorldConfig::preprocess_shader`, so they can't go out of sync.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WorldConfig {
+    /// Amount of bricks (16x16x16 voxels) that can be allocated at once. Every brick also gets
+    /// a slot in the brick LOD pool, so this costs about 3.3KB of GPU memory per brick
+    pub brick_pool_size: usize,
+    /// Amount of bricks with too many different voxels to fit in a brick's palette
+    pub raw_brick_pool_size: usize,
+    /// Amount of layer0 nodes (16x16x16 bricks) that can be allocated at once
+    pub layer0_pool_size: usize,
+    /// Size of the world along each axis, in layer0 nodes
+    pub brick_map_size: usize,
+    /// Position in the brick map, in voxels, where world position (0,0,0) is stored.
+    /// World positions range from `-origin` up to (but excluding) `world_size() - origin`.
+    /// `World::recenter` can move it later on.
+    pub origin: IVec3,
+    /// Amount of voxels uploaded to the GPU per batch
+    pub voxel_queue_size: usize,
+    /// Amount of bricks checked for deallocation each frame
+    pub dealloc_queue_size: usize,
+    /// Amount of dynamic model instances that can exist at once
+    pub instance_pool_size: usize,
+    /// Amount of voxels the models of all dynamic instances can take up together. Every model
+    /// with instances takes up its whole bounding box, 4 bytes per voxel
+    pub instance_voxel_pool_size: usize,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        let brick_map_size = 64;
+        Self {
+            brick_pool_size: 32768,
+            raw_brick_pool_size: 4096,
+            layer0_pool_size: 8192,
+            brick_map_size,
+            origin: IVec3::splat((brick_map_size * 16 * 16 / 2) as i32),
+            voxel_queue_size: 32768,
+            dealloc_queue_size: 4096,
+            instance_pool_size: 1024,
+            instance_voxel_pool_size: 1 << 23,
+        }
+    }
+}
+
+impl WorldConfig {
+    /// Size of the world along each axis, in voxels
+    pub fn world_size(&self) -> usize {
+        self.brick_map_size * 16 * 16
+    }
+
+    /// Converts a world position to a position in the brick map.
+    /// Returns None if the position lies outside of the world.
+    pub fn storage_pos(&self, world_pos: IVec3) -> Option<UVec3> {
+        let pos = world_pos + self.origin;
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(self.world_size() as i32)).any() {
+            return None;
+        }
+        Some(pos.as_uvec3())
+    }
+
+    /// Index into the brick map of the layer0 node containing a world position.
+    /// Returns None if the position lies outside of the world.
+    pub fn brick_map_idx(&self, world_pos: IVec3) -> Option<usize> {
+        let layer0_pos = self.storage_pos(world_pos)? / 256;
+        let size = self.brick_map_size;
+        Some(layer0_pos.x as usize + layer0_pos.y as usize * size + layer0_pos.z as usize * size * size)
+    }
+
+    /// World position of the first voxel of the layer0 node at a brick map index
+    pub fn layer0_world_pos(&self, brick_map_idx: usize) -> IVec3 {
+        let size = self.brick_map_size;
+        let layer0_pos = uvec3((brick_map_idx % size) as u32, ((brick_map_idx / size) % size) as u32, (brick_map_idx / (size * size)) as u32);
+        (layer0_pos * 256).as_ivec3() - self.origin
+    }
+
+    /// Position in the brick map of the layer0 node containing a world position. Unlike
+    /// `brick_map_idx` this works for positions outside of the world too.
+    pub fn layer0_pos(&self, world_pos: IVec3) -> IVec3 {
+        let pos = world_pos + self.origin;
+        ivec3(pos.x.div_euclid(256), pos.y.div_euclid(256), pos.z.div_euclid(256))
+    }
+
+    /// Moves the layer0 nodes of a brick map along with a new origin, so they stay at the same
+    /// world position. The origin can only move by whole layer0 nodes, and nodes that would
+    /// end up outside of the map have to be freed first.
+    pub(crate) fn recenter_brick_map(&self, map: &[u32], origin: IVec3) -> anyhow::Result<Vec<u32>> {
+        let delta = origin - self.origin;
+        if delta.x % 256 != 0 || delta.y % 256 != 0 || delta.z % 256 != 0 {
+            anyhow::bail!("The origin can only move by multiples of 256, not {}!", delta);
+        }
+        let shift = delta / 256;
+        let size = self.brick_map_size as i32;
+        let mut recentered = vec![0; map.len()];
+        for (i, layer0_pool_idx) in map.iter().enumerate().filter(|(_, idx)| **idx > 0) {
+            let i = i as i32;
+            let pos = ivec3(i % size, (i / size) % size, i / (size * size)) + shift;
+            if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(size)).any() {
+                anyhow::bail!("The layer0 node at {} would end up outside of the world!", pos - shift);
+            }
+            recentered[(pos.x + pos.y * size + pos.z * size * size) as usize] = *layer0_pool_idx;
+        }
+        Ok(recentered)
+    }
+
+    /// Checks that the sizes are usable, so a bad config fails here instead of as an overflow
+    /// somewhere in a shader. `World::new` panics if this fails.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.brick_map_size == 0 {
+            anyhow::bail!("brick_map_size must be at least 1!");
+        }
+        // The shaders compute brick map indices and world positions as signed ints
+        let brick_map_len = self.brick_map_size.checked_pow(3).filter(|len| *len <= i32::MAX as usize);
+        let world_size = self.brick_map_size.checked_mul(256).filter(|size| *size <= i32::MAX as usize);
+        if brick_map_len.is_none() || world_size.is_none() {
+            anyhow::bail!("brick_map_size of {} is too large, the brick map would have more entries than fit in an i32!", self.brick_map_size);
+        }
+
+        for (name, size) in [("brick_pool_size", self.brick_pool_size), ("raw_brick_pool_size", self.raw_brick_pool_size), ("layer0_pool_size", self.layer0_pool_size)] {
+            if size == 0 || size > MAX_POOL_SIZE {
+                anyhow::bail!("{} of {} is out of range, it must be from 1 up to {}!", name, size, MAX_POOL_SIZE);
+            }
+        }
+        if self.instance_pool_size == 0 || self.instance_voxel_pool_size == 0 {
+            anyhow::bail!("instance_pool_size and instance_voxel_pool_size must be at least 1!");
+        }
+        if self.instance_voxel_pool_size > u32::MAX as usize {
+            anyhow::bail!("instance_voxel_pool_size of {} doesn't fit in a u32!", self.instance_voxel_pool_size);
+        }
+
+        let max_items = MAX_WORKGROUPS * WORKGROUP_SIZE as usize;
+        if self.voxel_queue_size == 0 || self.voxel_queue_size > max_items {
+            anyhow::bail!("voxel_queue_size of {} is out of range, it must be from 1 up to {}!", self.voxel_queue_size, max_items);
+        }
+        if self.dealloc_queue_size > max_items {
+            anyhow::bail!("dealloc_queue_size of {} is too large, it can be at most {}!", self.dealloc_queue_size, max_items);
+        }
+        Ok(())
+    }
+
+    pub fn shader_defines(&self) -> String {
+        format!(
+            "#define BRICK_MAP_SIZE {}\n#define WORLD_SIZE {}\n#define WORLD_ORIGIN ivec3({}, {}, {})\n#define BRICK_POOL_SIZE {}\n#define RAW_BRICK_POOL_SIZE {}\n#define LAYER0_POOL_SIZE {}\n#define VOXEL_QUEUE_SIZE {}\n#define DEALLOC_QUEUE_SIZE {}\n#define WORKGROUP_SIZE {}\n",
+            self.brick_map_size,
+            self.world_size(),
+            self.origin.x,
+            self.origin.y,
+            self.origin.z,
+            self.brick_pool_size,
+            self.raw_brick_pool_size,
+            self.layer0_pool_size,
+            self.voxel_queue_size,
+            self.dealloc_queue_size,
+            WORKGROUP_SIZE,
+        )
+    }
+
+    /// Inserts the defines for this config right after the `#version` directive of a shader.
+    pub fn preprocess_shader(&self, source: &str) -> String {
+        let defines = self.shader_defines();
+        match source.find("#version") {
+            Some(start) => {
+                let end = source[start..].find('\n').map(|i| start + i + 1).unwrap_or(source.len());
+                let mut processed = String::with_capacity(source.len() + defines.len());
+                processed.push_str(&source[..end]);
+                if !processed.ends_with('\n') { processed.push('\n'); }
+                processed.push_str(&defines);
+                processed.push_str(&source[end..]);
+                processed
+            },
+            None => defines + source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        WorldConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_catches_bad_sizes() {
+        let config = WorldConfig::default();
+        assert!(WorldConfig { voxel_queue_size: 0, ..config }.validate().is_err());
+        assert!(WorldConfig { voxel_queue_size: 1 << 23, ..config }.validate().is_err());
+        assert!(WorldConfig { brick_map_size: 0, ..config }.validate().is_err());
+        // 2^11 cubed doesn't fit in an i32, 2^22 cubed doesn't even fit in a u64
+        assert!(WorldConfig { brick_map_size: 1 << 11, ..config }.validate().is_err());
+        assert!(WorldConfig { brick_map_size: 1 << 22, ..config }.validate().is_err());
+        assert!(WorldConfig { brick_map_size: 1 << 10, ..config }.validate().is_ok());
+        assert!(WorldConfig { brick_pool_size: 0, ..config }.validate().is_err());
+        assert!(WorldConfig { layer0_pool_size: u32::MAX as usize + 1, ..config }.validate().is_err());
+        assert!(WorldConfig { raw_brick_pool_size: u32::MAX as usize, ..config }.validate().is_err());
+        assert!(WorldConfig { instance_voxel_pool_size: 1 << 33, ..config }.validate().is_err());
+        assert!(WorldConfig { dealloc_queue_size: 0, ..config }.validate().is_ok());
+    }
+
+    #[test]
+    fn defines_go_after_the_version() {
+        let config = WorldConfig::default();
+        let defines = config.shader_defines();
+        let source = "#version 460\nvoid main() {}\n";
+        assert_eq!(config.preprocess_shader(source), format!("#version 460\n{}void main() {{}}\n", defines));
+
+        // Comments in front of the version directive stay in front of it
+        let source = "// Some shader\n#version 460 core\nvoid main() {}";
+        assert_eq!(config.preprocess_shader(source), format!("// Some shader\n#version 460 core\n{}void main() {{}}", defines));
+    }
+
+    #[test]
+    fn defines_without_version() {
+        let config = WorldConfig::default();
+        let defines = config.shader_defines();
+        assert_eq!(config.preprocess_shader("void main() {}"), format!("{}void main() {{}}", defines));
+        // A version directive on the last line still gets its own line
+        assert_eq!(config.preprocess_shader("#version 460"), format!("#version 460\n{}", defines));
+    }
+
+    #[test]
+    fn defines_match_config() {
+        let config = WorldConfig { brick_map_size: 4, origin: ivec3(-1, 2, 512), ..WorldConfig::default() };
+        let defines = config.shader_defines();
+        assert!(defines.contains("#define BRICK_MAP_SIZE 4\n"));
+        assert!(defines.contains("#define WORLD_SIZE 1024\n"));
+        assert!(defines.contains("#define WORLD_ORIGIN ivec3(-1, 2, 512)\n"));
+        assert!(defines.lines().all(|line| line.starts_with("#define ")));
+    }
+
+    #[test]
+    fn storage_positions() {
+        let config = WorldConfig { brick_map_size: 4, origin: IVec3::splat(512), ..WorldConfig::default() };
+        assert_eq!(config.storage_pos(ivec3(-512, 0, 511)), Some(uvec3(0, 512, 1023)));
+        assert_eq!(config.storage_pos(ivec3(-513, 0, 0)), None);
+        assert_eq!(config.storage_pos(ivec3(0, 512, 0)), None);
+        assert_eq!(config.brick_map_idx(ivec3(-256, 0, 300)), Some(1 + 2 * 4 + 3 * 16));
+        assert_eq!(config.layer0_world_pos(1 + 2 * 4 + 3 * 16), ivec3(-256, 0, 256));
+        assert_eq!(config.layer0_pos(ivec3(-256, 0, 300)), ivec3(1, 2, 3));
+        assert_eq!(config.layer0_pos(ivec3(-513, 512, 0)), ivec3(-1, 4, 2));
+    }
+
+    #[test]
+    fn recenter_brick_map() {
+        let config = WorldConfig { brick_map_size: 4, origin: IVec3::splat(512), ..WorldConfig::default() };
+        let mut map = vec![0; 64];
+        map[config.brick_map_idx(ivec3(0, 0, 0)).unwrap()] = 7;
+        map[config.brick_map_idx(ivec3(-256, 300, 0)).unwrap()] = 3;
+
+        let origin = config.origin + ivec3(256, -256, 0);
+        let recentered = config.recenter_brick_map(&map, origin).unwrap();
+        let moved = WorldConfig { origin, ..config };
+        assert_eq!(recentered[moved.brick_map_idx(ivec3(0, 0, 0)).unwrap()], 7);
+        assert_eq!(recentered[moved.brick_map_idx(ivec3(-256, 300, 0)).unwrap()], 3);
+        assert_eq!(recentered.iter().filter(|idx| **idx > 0).count(), 2);
+
+        assert!(config.recenter_brick_map(&map, config.origin + ivec3(16, 0, 0)).is_err());
+        // Both nodes would fall off the bottom of the map
+        assert!(config.recenter_brick_map(&map, config.origin - ivec3(0, 768, 0)).is_err());
+        assert!(config.recenter_brick_map(&[0; 64], config.origin - ivec3(0, 512 * 8, 0)).is_ok());
+    }
+}
@@ -0,0 +1,627 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use itertools::iproduct;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+use stardust_sdvx::Model;
+
+use crate::layer0::*;
+use crate::brick::*;
+use crate::{Shape, ShapeOp, WorldConfig, ProcessReport, VoxelBatch};
+use crate::report::*;
+
+/// CPU-side mirror of `World`. Uses the same layer0 -> brick -> voxel hierarchy and the
+/// same allocation and deallocation rules as the compute shaders, without needing a GPU.
+/// Pool slots that were never touched are not stored, they are implicitly zeroed.
+/// Bricks are stored uncompressed, so running out of raw bricks (see `GpuBrick`) isn't mirrored.
+pub struct CpuWorld {
+    config: WorldConfig,
+
+    brick_pool: Vec<Option<Box<Brick>>>,
+    layer0_pool: Vec<Option<Box<Layer0>>>,
+    layer0_map: Vec<u32>,
+
+    free_brick_pool: Vec<u32>,
+    free_layer0_pool: Vec<u32>,
+    brick_pool_counter: u32,
+    layer0_pool_counter: u32,
+
+    dealloc_queue_counter: u32,
+
+    stats: [u32; STAT_COUNT],
+    changed_bricks: Vec<IVec3>,
+    /// Brick pool indices already in `changed_bricks`
+    changed_brick_indices: HashSet<u32>,
+
+    voxel_queue: Arc<Mutex<Vec<(Voxel, IVec3)>>>,
+
+    voxels_queued: usize,
+}
+
+/// Copies the contents, voxels that are still queued stay behind
+impl Clone for CpuWorld {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config,
+
+            brick_pool: self.brick_pool.clone(),
+            layer0_pool: self.layer0_pool.clone(),
+            layer0_map: self.layer0_map.clone(),
+
+            free_brick_pool: self.free_brick_pool.clone(),
+            free_layer0_pool: self.free_layer0_pool.clone(),
+            brick_pool_counter: self.brick_pool_counter,
+            layer0_pool_counter: self.layer0_pool_counter,
+
+            dealloc_queue_counter: self.dealloc_queue_counter,
+
+            stats: self.stats,
+            changed_bricks: self.changed_bricks.clone(),
+            changed_brick_indices: self.changed_brick_indices.clone(),
+
+            voxel_queue: Arc::new(Mutex::new(Vec::new())),
+
+            voxels_queued: 0,
+        }
+    }
+}
+
+impl CpuWorld {
+    pub fn new(config: WorldConfig) -> Self {
+        Self {
+            config,
+
+            brick_pool: (0..config.brick_pool_size).map(|_| None).collect(),
+            layer0_pool: (0..config.layer0_pool_size).map(|_| None).collect(),
+            layer0_map: vec![0; config.brick_map_size * config.brick_map_size * config.brick_map_size],
+
+            free_brick_pool: (0..config.brick_pool_size).map(|i| i as u32 + 1).collect(),
+            free_layer0_pool: (0..config.layer0_pool_size).map(|i| i as u32 + 1).collect(),
+            brick_pool_counter: config.brick_pool_size as u32,
+            layer0_pool_counter: config.layer0_pool_size as u32,
+
+            dealloc_queue_counter: 0,
+
+            stats: [0; STAT_COUNT],
+            changed_bricks: Vec::new(),
+            changed_brick_indices: HashSet::new(),
+
+            voxel_queue: Arc::new(Mutex::new(Vec::new())),
+
+            voxels_queued: 0,
+        }
+    }
+
+    pub fn config(&self) -> &WorldConfig {
+        &self.config
+    }
+
+    pub fn voxels_queued(&self) -> usize {
+        self.voxels_queued
+    }
+
+    pub fn bricks_free(&self) -> u32 {
+        self.brick_pool_counter
+    }
+
+    pub fn layer0s_free(&self) -> u32 {
+        self.layer0_pool_counter
+    }
+
+    pub fn dealloc_queue_counter(&self) -> u32 {
+        self.dealloc_queue_counter
+    }
+
+    /// Queues a voxel to be placed in the world during the next `process` call.
+    /// Voxels are placed in the order they were queued, so the last write to a position wins, same as `World::set_voxel`.
+    pub fn set_voxel(&self, voxel: Voxel, world_pos: IVec3) {
+        let mut lock = self.voxel_queue.lock().unwrap();
+        lock.push((voxel, world_pos));
+    }
+
+    /// Queues all writes of a batch at once, see `World::submit_batch`
+    pub fn submit_batch(&self, mut batch: VoxelBatch) {
+        let mut lock = self.voxel_queue.lock().unwrap();
+        lock.append(&mut batch.voxels);
+    }
+
+    /// Queues the voxels covered by a shape command, see `World::apply_shape`.
+    /// `ShapeOp::ReplaceColor` looks at the voxels currently in the world, not at queued ones.
+    pub fn apply_shape(&self, shape: Shape, op: ShapeOp) {
+        let (min, max) = shape.bounds();
+        let mut lock = self.voxel_queue.lock().unwrap();
+        for (z, y, x) in iproduct!(min.z..max.z, min.y..max.y, min.x..max.x) {
+            let pos = ivec3(x, y, z);
+            if !shape.contains(pos) { continue; }
+            if let Some(voxel) = op.apply(self.get_voxel(pos)) {
+                lock.push((voxel, pos));
+            }
+        }
+    }
+
+    /// Reads a voxel straight from the brick pool. Does not take queued voxels into account.
+    pub fn get_voxel(&self, world_pos: IVec3) -> Voxel {
+        let (brick_map_idx, layer0_idx, voxel_pos) = match self.split_pos(world_pos) {
+            Some(split) => split,
+            None => return Voxel::empty(),
+        };
+        let layer0_pool_idx = self.layer0_map[brick_map_idx];
+        if layer0_pool_idx == 0 { return Voxel::empty(); }
+        let brick_pool_idx = self.layer0(layer0_pool_idx).brick_indices[layer0_idx];
+        if brick_pool_idx == 0 { return Voxel::empty(); }
+        *self.brick(brick_pool_idx).get_voxel(voxel_pos)
+    }
+
+    /// Returns all non-empty voxels from `min` up to (but not including) `max`, same as `World::read_box`.
+    pub fn read_box(&self, min: IVec3, max: IVec3) -> Vec<(Voxel, IVec3)> {
+        iproduct!(min.z..max.z, min.y..max.y, min.x..max.x)
+            .map(|(z, y, x)| ivec3(x, y, z))
+            .map(|pos| (self.get_voxel(pos), pos))
+            .filter(|(voxel, _)| voxel.0 != 0)
+            .collect()
+    }
+
+    /// Copies a region into a model, same as `World::copy_region`
+    pub fn copy_region(&self, min: IVec3, max: IVec3) -> Model {
+        crate::region_to_model(self.read_box(min, max), min)
+    }
+
+    /// Returns (brick map index, index within the layer0 node, position within the brick),
+    /// or None if the position lies outside of the world
+    fn split_pos(&self, world_pos: IVec3) -> Option<(usize, usize, UVec3)> {
+        let brick_map_size = self.config.brick_map_size;
+        let pos = self.config.storage_pos(world_pos)?;
+        let layer0_pos = pos / 256;
+        let brick_pos = (pos / 16) % 16;
+        let voxel_pos = pos % 16;
+
+        let brick_map_idx = layer0_pos.x as usize + layer0_pos.y as usize * brick_map_size + layer0_pos.z as usize * brick_map_size * brick_map_size;
+        let layer0_idx = brick_pos.x as usize + brick_pos.y as usize * 16 + brick_pos.z as usize * 16 * 16;
+        Some((brick_map_idx, layer0_idx, voxel_pos))
+    }
+
+    fn layer0(&self, layer0_pool_idx: u32) -> &Layer0 {
+        // Nodes are zeroed by cs_alloc_layers before use, so a missing node is never referenced
+        self.layer0_pool[layer0_pool_idx as usize - 1].as_ref().expect("Layer0 node referenced but never allocated!")
+    }
+
+    fn brick(&self, brick_pool_idx: u32) -> &Brick {
+        self.brick_pool[brick_pool_idx as usize - 1].as_ref().expect("Brick referenced but never allocated!")
+    }
+
+    fn brick_mut(&mut self, brick_pool_idx: u32) -> &mut Brick {
+        self.brick_pool[brick_pool_idx as usize - 1].get_or_insert_with(|| Box::new(Brick::empty()))
+    }
+
+    /// Mirrors `findLayer0Empty` in cs_alloc_layers.glsl
+    fn find_layer0_empty(&mut self) -> u32 {
+        if self.layer0_pool_counter == 0 { return 0; }
+        self.layer0_pool_counter -= 1;
+        self.free_layer0_pool[self.layer0_pool_counter as usize]
+    }
+
+    /// Mirrors `findBrickEmpty` in cs_alloc_bricks.glsl
+    fn find_brick_empty(&mut self) -> u32 {
+        if self.brick_pool_counter == 0 { return 0; }
+        self.brick_pool_counter -= 1;
+        let next_free_idx = self.brick_pool_counter as usize;
+        let value = self.free_brick_pool[next_free_idx];
+        self.free_brick_pool[next_free_idx] = 0;
+        value
+    }
+
+    /// Mirrors cs_alloc_layers.glsl
+    fn alloc_layer(&mut self, world_pos: IVec3) {
+        if self.layer0_pool_counter == 0 { return; }
+        let (brick_map_idx, _, _) = match self.split_pos(world_pos) {
+            Some(split) => split,
+            None => return,
+        };
+        if self.layer0_map[brick_map_idx] != 0 { return; }
+
+        let layer0_pool_idx = self.find_layer0_empty();
+        if layer0_pool_idx > 0 {
+            self.stats[STAT_LAYER0S_ALLOCATED] += 1;
+            self.layer0_map[brick_map_idx] = layer0_pool_idx;
+            self.layer0_pool[layer0_pool_idx as usize - 1] = Some(Box::new(Layer0::empty()));
+        }
+    }
+
+    /// Mirrors cs_alloc_bricks.glsl
+    fn alloc_brick(&mut self, world_pos: IVec3) {
+        if self.brick_pool_counter == 0 { return; }
+        let (brick_map_idx, layer0_idx, _) = match self.split_pos(world_pos) {
+            Some(split) => split,
+            None => return,
+        };
+        let layer0_pool_idx = self.layer0_map[brick_map_idx];
+        if layer0_pool_idx == 0 { return; }
+        if self.layer0(layer0_pool_idx).brick_indices[layer0_idx] != 0 { return; }
+
+        let brick_pool_idx = self.find_brick_empty();
+        if brick_pool_idx > 0 {
+            self.stats[STAT_BRICKS_ALLOCATED] += 1;
+            self.layer0_pool[layer0_pool_idx as usize - 1].as_mut().unwrap().brick_indices[layer0_idx] = brick_pool_idx;
+            let brick = self.brick_mut(brick_pool_idx);
+            brick.set_meta(META_LAYER0_POOL_IDX, layer0_pool_idx);
+            brick.set_meta(META_LAYER0_IDX, layer0_idx as u32);
+            brick.set_meta(META_ALLOCATED, 1);
+            brick.set_meta(META_EMPTY_FRAMES, 0);
+        }
+    }
+
+    /// Mirrors cs_process_voxel_queue.glsl
+    fn write_voxel(&mut self, voxel: Voxel, world_pos: IVec3) {
+        let (brick_map_idx, layer0_idx, voxel_pos) = match self.split_pos(world_pos) {
+            Some(split) => split,
+            None => return,
+        };
+        let layer0_pool_idx = self.layer0_map[brick_map_idx];
+        let brick_pool_idx = match layer0_pool_idx {
+            0 => 0,
+            _ => self.layer0(layer0_pool_idx).brick_indices[layer0_idx],
+        };
+        if brick_pool_idx == 0 {
+            // There was no brick to put it in, the pools ran out
+            if voxel.0 != 0 { self.stats[STAT_VOXELS_DROPPED] += 1; }
+            return;
+        }
+        let brick = self.brick_mut(brick_pool_idx);
+        let changed = brick.get_voxel(voxel_pos).0 != voxel.0;
+        brick.set_voxel(voxel, voxel_pos);
+        self.stats[STAT_VOXELS_WRITTEN] += 1;
+        if changed && self.changed_brick_indices.insert(brick_pool_idx) {
+            let storage_pos = (world_pos + self.config.origin).as_uvec3();
+            self.changed_bricks.push((storage_pos / 16 * 16).as_ivec3() - self.config.origin);
+            self.stats[STAT_BRICKS_CHANGED] += 1;
+        }
+    }
+
+    /// Places a whole brick of voxels, allocating its layer0 node and brick the same way queued voxels would.
+    /// Returns false if there was no space left in either pool.
+    pub(crate) fn insert_brick(&mut self, brick_map_idx: usize, layer0_idx: usize, voxels: &[Voxel]) -> bool {
+        let brick_pos = uvec3((layer0_idx % 16) as u32, ((layer0_idx / 16) % 16) as u32, (layer0_idx / 256) as u32);
+        let world_pos = self.config.layer0_world_pos(brick_map_idx) + (brick_pos * 16).as_ivec3();
+
+        self.alloc_layer(world_pos);
+        self.alloc_brick(world_pos);
+
+        let layer0_pool_idx = self.layer0_map[brick_map_idx];
+        if layer0_pool_idx == 0 { return false; }
+        let brick_pool_idx = self.layer0(layer0_pool_idx).brick_indices[layer0_idx];
+        if brick_pool_idx == 0 { return false; }
+
+        let brick = self.brick_mut(brick_pool_idx);
+        for (i, voxel) in voxels.iter().enumerate() {
+            brick.set_voxel(*voxel, uvec3(i as u32 % 16, (i as u32 / 16) % 16, i as u32 / 256));
+        }
+        true
+    }
+
+    /// Frees a layer0 node and all of its bricks, see `World::evict_layer0`
+    pub(crate) fn evict_layer0(&mut self, brick_map_idx: usize) {
+        let layer0_pool_idx = self.layer0_map[brick_map_idx];
+        if layer0_pool_idx == 0 { return; }
+        let layer0 = self.layer0_pool[layer0_pool_idx as usize - 1].take().expect("Layer0 node referenced but never allocated!");
+        for brick_pool_idx in layer0.brick_indices.iter().copied().filter(|idx| *idx > 0) {
+            self.free_brick_pool[self.brick_pool_counter as usize] = brick_pool_idx;
+            self.brick_pool_counter += 1;
+            self.brick_pool[brick_pool_idx as usize - 1] = None;
+        }
+        self.free_layer0_pool[self.layer0_pool_counter as usize] = layer0_pool_idx;
+        self.layer0_pool_counter += 1;
+        self.layer0_map[brick_map_idx] = 0;
+    }
+
+    /// Moves the world origin, see `World::recenter`. Leaves the world alone if it fails.
+    pub(crate) fn recenter(&mut self, origin: IVec3) -> anyhow::Result<()> {
+        self.layer0_map = self.config.recenter_brick_map(&self.layer0_map, origin)?;
+        self.config.origin = origin;
+        Ok(())
+    }
+
+    /// Mirrors cs_dealloc_bricks.glsl
+    fn dealloc_brick(&mut self) {
+        let brick_pool_idx = (self.dealloc_queue_counter % self.config.brick_pool_size as u32) + 1;
+        self.dealloc_queue_counter = self.dealloc_queue_counter.wrapping_add(1);
+
+        let (layer0_pool_idx, l0_idx) = match &self.brick_pool[brick_pool_idx as usize - 1] {
+            Some(brick) if brick.meta(META_ALLOCATED) > 0 => (brick.meta(META_LAYER0_POOL_IDX), brick.meta(META_LAYER0_IDX) as usize),
+            _ => return,
+        };
+        if layer0_pool_idx == 0 { return; }
+        if self.layer0(layer0_pool_idx).brick_indices[l0_idx] != brick_pool_idx { return; }
+
+        let brick = self.brick_mut(brick_pool_idx);
+        if brick.update_occupancy() {
+            brick.set_meta(META_EMPTY_FRAMES, brick.meta(META_EMPTY_FRAMES) + 1);
+        } else {
+            brick.set_meta(META_EMPTY_FRAMES, 0);
+        }
+
+        if brick.meta(META_EMPTY_FRAMES) > 1 {
+            let write_idx = self.brick_pool_counter as usize;
+            self.brick_pool_counter += 1;
+            self.free_brick_pool[write_idx] = brick_pool_idx;
+            self.stats[STAT_BRICKS_FREED] += 1;
+
+            self.layer0_pool[layer0_pool_idx as usize - 1].as_mut().unwrap().brick_indices[l0_idx] = 0;
+            self.brick_pool[brick_pool_idx as usize - 1] = None;
+        }
+    }
+
+    /// Processes all queued voxels the same way `World::process` does, in batches of
+    /// `WorldConfig::voxel_queue_size`, followed by a single deallocation pass.
+    pub fn process(&mut self) -> ProcessReport {
+        self.stats = [0; STAT_COUNT];
+        self.changed_bricks.clear();
+        self.changed_brick_indices.clear();
+        let queue = std::mem::take(&mut *self.voxel_queue.lock().unwrap());
+        self.voxels_queued = queue.len();
+
+        for chunk in queue.chunks(self.config.voxel_queue_size) {
+            // Removing a voxel never needs a new layer0 node or brick
+            for (_, wpos) in chunk.iter().filter(|(voxel, _)| voxel.0 != 0) {
+                self.alloc_layer(*wpos);
+            }
+            for (_, wpos) in chunk.iter().filter(|(voxel, _)| voxel.0 != 0) {
+                self.alloc_brick(*wpos);
+            }
+            for (voxel, wpos) in chunk {
+                self.write_voxel(*voxel, *wpos);
+            }
+        }
+
+        for _ in 0..self.config.dealloc_queue_size {
+            self.dealloc_brick();
+        }
+
+        self.stats[STAT_BRICKS_FREE] = self.brick_pool_counter;
+        self.stats[STAT_LAYER0S_FREE] = self.layer0_pool_counter;
+        ProcessReport::from_stats(&self.stats, self.config.brick_pool_size, self.config.layer0_pool_size)
+    }
+
+    /// Mirrors `World::changed_bricks`, the bricks where a voxel changed during the last `process`
+    pub fn changed_bricks(&self) -> &[IVec3] {
+        &self.changed_bricks
+    }
+
+    pub fn layer0_map(&self) -> &[u32] {
+        &self.layer0_map
+    }
+
+    pub fn free_brick_pool(&self) -> &[u32] {
+        &self.free_brick_pool
+    }
+
+    pub fn free_layer0_pool(&self) -> &[u32] {
+        &self.free_layer0_pool
+    }
+
+    /// Looks up a layer0 node by the index stored in the brick map, which is offset by 1
+    pub fn get_layer0(&self, layer0_pool_idx: u32) -> Option<&Layer0> {
+        if layer0_pool_idx == 0 { return None; }
+        self.layer0_pool.get(layer0_pool_idx as usize - 1)?.as_deref()
+    }
+
+    /// Looks up a brick by the index stored in a layer0 node, which is offset by 1
+    pub fn get_brick(&self, brick_pool_idx: u32) -> Option<&Brick> {
+        if brick_pool_idx == 0 { return None; }
+        self.brick_pool.get(brick_pool_idx as usize - 1)?.as_deref()
+    }
+
+    /// Iterates over all bricks that are currently in use, as (index into the pool, brick).
+    /// The index is 0-based, so it's 1 lower than what the layer0 nodes store.
+    pub fn bricks(&self) -> impl Iterator<Item = (usize, &Brick)> {
+        self.brick_pool.iter().enumerate().filter_map(|(i, b)| b.as_deref().map(|b| (i, b)))
+    }
+
+    /// Iterates over all layer0 nodes that are currently in use, as (index into the pool, node).
+    pub fn layer0s(&self) -> impl Iterator<Item = (usize, &Layer0)> {
+        self.layer0_pool.iter().enumerate().filter_map(|(i, l)| l.as_deref().map(|l| (i, l)))
+    }
+
+    /// Builds the full contents of the GPU brick pool, compressing every brick. Bricks that don't
+    /// fit in a palette point at a raw brick, handed out in order and returned separately.
+    /// This allocates the entire pool, so prefer `bricks()` if you only need the bricks that are in use.
+    pub fn brick_pool_data(&self) -> (Vec<GpuBrick>, Vec<GpuRawBrick>) {
+        let mut raw_bricks = Vec::new();
+        let bricks = self.brick_pool.iter().map(|b| match b {
+            Some(brick) => {
+                let (mut gpu_brick, raw) = GpuBrick::from_brick(brick);
+                if let Some(raw) = raw {
+                    raw_bricks.push(raw);
+                    gpu_brick.raw_idx = raw_bricks.len() as u32;
+                }
+                gpu_brick
+            },
+            None => GpuBrick::empty(),
+        }).collect();
+        (bricks, raw_bricks)
+    }
+
+    /// Builds the full contents of the GPU brick LOD pool, which uses the same indices as the brick pool.
+    pub fn brick_lod_pool_data(&self) -> Vec<GpuBrickLod> {
+        self.brick_pool.iter().map(|b| match b {
+            Some(brick) => GpuBrickLod::from_brick(brick),
+            None => GpuBrickLod::empty(),
+        }).collect()
+    }
+
+    /// Builds the full contents of the GPU layer0 pool.
+    pub fn layer0_pool_data(&self) -> Vec<Layer0> {
+        self.layer0_pool.iter().map(|l| l.as_deref().copied().unwrap_or(Layer0::empty())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid() -> Voxel {
+        Voxel::new([255, 0, 0], 255, 0, false, 255)
+    }
+
+    fn small_config(brick_pool_size: usize, layer0_pool_size: usize) -> WorldConfig {
+        WorldConfig {
+            brick_pool_size,
+            layer0_pool_size,
+            brick_map_size: 4,
+            origin: IVec3::ZERO,
+            voxel_queue_size: 64,
+            dealloc_queue_size: brick_pool_size,
+            ..WorldConfig::default()
+        }
+    }
+
+    #[test]
+    fn copy_region_save_load_round_trip() {
+        let mut world = CpuWorld::new(WorldConfig { origin: IVec3::splat(32), ..small_config(16, 4) });
+        let voxels = [
+            (Voxel::new([255, 0, 0], 255, 0, false, 255), ivec3(-4, 0, 2)),
+            (Voxel::new([0, 255, 0], 128, 0, false, 255), ivec3(0, 0, 0)),
+            (Voxel::new([0, 0, 255], 255, 64, true, 255), ivec3(5, 7, -3)),
+            // Outside of the region
+            (solid(), ivec3(6, 0, 0)),
+            (solid(), ivec3(0, -6, 0)),
+        ];
+        for (voxel, pos) in voxels {
+            world.set_voxel(voxel, pos);
+        }
+        world.process();
+
+        let min = ivec3(-4, -5, -3);
+        let model = world.copy_region(min, ivec3(6, 8, 3));
+        let path = std::env::temp_dir().join(format!("stardust_copy_region_{}.sdvx", std::process::id()));
+        model.save(&path).unwrap();
+        let loaded = Model::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut copied: Vec<(u32, IVec3)> = loaded.voxels().map(|(voxel, pos)| (voxel.0, pos.as_ivec3() + min)).collect();
+        copied.sort_by_key(|(_, pos)| (pos.x, pos.y, pos.z));
+        let mut expected: Vec<(u32, IVec3)> = voxels[..3].iter().map(|(voxel, pos)| (voxel.0, *pos)).collect();
+        expected.sort_by_key(|(_, pos)| (pos.x, pos.y, pos.z));
+        assert_eq!(copied, expected);
+    }
+
+    #[test]
+    fn alloc_takes_indices_from_the_end_of_the_free_lists() {
+        let mut world = CpuWorld::new(small_config(8, 2));
+        world.set_voxel(solid(), ivec3(1, 2, 3));
+        world.set_voxel(solid(), ivec3(17, 2, 3));
+        world.set_voxel(solid(), ivec3(300, 0, 0));
+        let report = world.process();
+        assert_eq!(report.layer0s_allocated, 2);
+        assert_eq!(report.bricks_allocated, 3);
+        assert_eq!(report.voxels_dropped, 0);
+
+        assert_eq!(world.layer0s_free(), 0);
+        assert_eq!(&world.layer0_map()[..3], &[2, 1, 0]);
+        assert_eq!(world.bricks_free(), 5);
+        assert_eq!(world.free_brick_pool(), &[1, 2, 3, 4, 5, 0, 0, 0]);
+
+        let layer0 = world.get_layer0(2).unwrap();
+        assert_eq!(&layer0.brick_indices[..3], &[8, 7, 0]);
+        assert_eq!(world.get_layer0(1).unwrap().brick_indices[2], 6);
+
+        let brick = world.get_brick(7).unwrap();
+        assert_eq!(brick.meta(META_LAYER0_POOL_IDX), 2);
+        assert_eq!(brick.meta(META_LAYER0_IDX), 1);
+        assert_eq!(brick.meta(META_ALLOCATED), 1);
+        assert_eq!(world.get_voxel(ivec3(17, 2, 3)).0, solid().0);
+    }
+
+    #[test]
+    fn dealloc_returns_empty_bricks_after_two_passes() {
+        let mut world = CpuWorld::new(small_config(8, 2));
+        world.set_voxel(solid(), ivec3(1, 2, 3));
+        world.set_voxel(solid(), ivec3(17, 2, 3));
+        world.process();
+
+        world.set_voxel(Voxel::empty(), ivec3(17, 2, 3));
+        assert_eq!(world.process().bricks_freed, 0);
+        assert_eq!(world.get_brick(7).unwrap().meta(META_EMPTY_FRAMES), 1);
+
+        assert_eq!(world.process().bricks_freed, 1);
+        assert_eq!(world.bricks_free(), 7);
+        assert_eq!(world.free_brick_pool(), &[1, 2, 3, 4, 5, 6, 7, 0]);
+        assert_eq!(&world.get_layer0(2).unwrap().brick_indices[..2], &[8, 0]);
+        assert!(world.get_brick(7).is_none());
+        // The brick that still has a voxel stays
+        assert_eq!(world.get_brick(8).unwrap().meta(META_EMPTY_FRAMES), 0);
+
+        // The freed index is the next one handed out
+        world.set_voxel(solid(), ivec3(33, 2, 3));
+        world.process();
+        assert_eq!(world.get_layer0(2).unwrap().brick_indices[2], 7);
+        assert_eq!(world.free_brick_pool()[6], 0);
+    }
+
+    #[test]
+    fn full_layer0_pool_releases_the_reservation() {
+        let mut world = CpuWorld::new(small_config(8, 1));
+        world.set_voxel(solid(), ivec3(0, 0, 0));
+        world.set_voxel(solid(), ivec3(256, 0, 0));
+        let report = world.process();
+        assert_eq!(report.layer0s_allocated, 1);
+        assert_eq!(report.voxels_dropped, 1);
+        // The counter stops at 0 instead of underflowing, and the node that didn't fit
+        // isn't left pointing at layer0 node 1
+        assert_eq!(world.layer0s_free(), 0);
+        assert_eq!(&world.layer0_map()[..2], &[1, 0]);
+        assert_eq!(world.get_voxel(ivec3(256, 0, 0)).0, 0);
+
+        world.set_voxel(solid(), ivec3(512, 0, 0));
+        assert_eq!(world.process().voxels_dropped, 1);
+        assert_eq!(world.layer0s_free(), 0);
+        assert_eq!(world.layer0_map()[2], 0);
+    }
+
+    #[test]
+    fn full_brick_pool_releases_the_reservation() {
+        let mut world = CpuWorld::new(small_config(2, 2));
+        world.set_voxel(solid(), ivec3(0, 0, 0));
+        world.set_voxel(solid(), ivec3(16, 0, 0));
+        world.set_voxel(solid(), ivec3(32, 0, 0));
+        let report = world.process();
+        assert_eq!(report.bricks_allocated, 2);
+        assert_eq!(report.voxels_dropped, 1);
+        assert_eq!(world.bricks_free(), 0);
+        assert_eq!(&world.get_layer0(2).unwrap().brick_indices[..3], &[2, 1, 0]);
+
+        // Once a brick is freed, the voxel that didn't fit can be placed
+        world.set_voxel(Voxel::empty(), ivec3(16, 0, 0));
+        world.process();
+        world.process();
+        assert_eq!(world.bricks_free(), 1);
+        world.set_voxel(solid(), ivec3(32, 0, 0));
+        let report = world.process();
+        assert_eq!(report.voxels_dropped, 0);
+        assert_eq!(&world.get_layer0(2).unwrap().brick_indices[..3], &[2, 0, 1]);
+        assert_eq!(world.get_voxel(ivec3(32, 0, 0)).0, solid().0);
+    }
+
+    #[test]
+    fn evict_layer0_frees_the_node_and_its_bricks() {
+        let mut world = CpuWorld::new(small_config(8, 2));
+        world.set_voxel(solid(), ivec3(1, 2, 3));
+        world.set_voxel(solid(), ivec3(17, 2, 3));
+        world.set_voxel(solid(), ivec3(300, 0, 0));
+        world.process();
+
+        world.evict_layer0(0);
+        assert_eq!(&world.layer0_map()[..2], &[0, 1]);
+        assert_eq!(world.layer0s_free(), 1);
+        assert_eq!(world.bricks_free(), 7);
+        assert_eq!(world.get_voxel(ivec3(1, 2, 3)).0, 0);
+        assert_eq!(world.get_voxel(ivec3(300, 0, 0)).0, solid().0);
+
+        // Evicting it again does nothing, and the slots get used again
+        world.evict_layer0(0);
+        assert_eq!(world.bricks_free(), 7);
+        world.set_voxel(solid(), ivec3(17, 2, 3));
+        assert_eq!(world.process().voxels_dropped, 0);
+        assert_eq!(world.get_voxel(ivec3(17, 2, 3)).0, solid().0);
+    }
+}
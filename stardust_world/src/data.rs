@@ -1,3 +1,4 @@
+use std::sync::{Arc, Mutex};
 use foxtail::prelude::*;
 
 use stardust_common::math::*;
@@ -5,28 +6,107 @@ use stardust_common::voxel::Voxel;
 
 use stardust_sdvx::Model;
 
+use crate::VoxelOwner;
+use crate::readback::read_buffer;
+
+/// Where and how a model gets placed in the world. The model is rotated and scaled around
+/// its own origin, then moved to `translation`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ModelTransform {
+    /// World position the model's origin ends up at
+    pub translation: IVec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl ModelTransform {
+    pub fn new(translation: IVec3, rotation: Quat, scale: Vec3) -> Self {
+        Self { translation, rotation, scale }
+    }
+
+    pub fn from_translation(translation: IVec3) -> Self {
+        Self::new(translation, Quat::IDENTITY, Vec3::ONE)
+    }
+
+    /// Rotation and scale, without the translation
+    pub fn matrix(&self) -> Mat3 {
+        Mat3::from_quat(self.rotation.normalize()) * Mat3::from_diagonal(self.scale)
+    }
+
+    /// Returns the columns of the rotation if it only turns the model by multiples of 90 degrees
+    /// and doesn't scale it. Those placements map every voxel onto exactly one voxel.
+    pub(crate) fn axis_aligned(&self) -> Option<[IVec3; 3]> {
+        const EPSILON: f32 = 1e-3;
+        let m = self.matrix();
+        let mut cols = [IVec3::ZERO; 3];
+        for (i, col) in [m.x_axis, m.y_axis, m.z_axis].into_iter().enumerate() {
+            let rounded = col.round();
+            if (col - rounded).abs().max_element() > EPSILON || rounded.abs().dot(Vec3::ONE) != 1.0 {
+                return None;
+            }
+            cols[i] = rounded.as_ivec3();
+        }
+        Some(cols)
+    }
+
+    /// Samples per axis per model voxel for placements that aren't axis aligned. Samples end up
+    /// less than `1 / sqrt(3)` apart in the world, so every world voxel that's fully covered by
+    /// the model contains at least one of them and turned models don't get holes.
+    /// The placement shader numbers the samples of all `voxels` with a u32, so huge models at a
+    /// big scale get fewer samples than that and can end up with holes.
+    pub(crate) fn samples_per_axis(&self, voxels: usize) -> u32 {
+        let max_scale = self.scale.abs().max_element();
+        let mut samples = ((max_scale * 3f32.sqrt()).ceil() as u32).max(1);
+        while samples > 1 && voxels as u64 * (samples as u64).pow(3) > u32::MAX as u64 {
+            samples -= 1;
+        }
+        samples
+    }
+}
+
+impl Default for ModelTransform {
+    fn default() -> Self {
+        Self::from_translation(IVec3::ZERO)
+    }
+}
+
+/// A queued change to the models placed in the world
+pub(crate) enum ModelCommand {
+    Place { owner: VoxelOwner, model: Arc<GpuModel>, transform: ModelTransform },
+    Remove { owner: VoxelOwner },
+}
+
 /// MUST BE CREATED IN THE MAIN THREAD
 pub struct GpuModel {
     pub(crate) vox_buf: FixedSizeBuffer<[u32; 4]>, // xyz = pos, w = voxel
     pub(crate) voxels: usize,
+    /// Every voxel in the model's bounding box, x first, for tracing dynamic instances.
+    /// Only built once the model gets instanced, see `grid_buf`
+    grid: Mutex<Option<FixedSizeBuffer<u32>>>,
+    pub(crate) size: UVec3,
 
     pub name: String,
 }
 
 impl GpuModel {
     /// MUST BE RUN FROM THE MAIN THREAD
+    /// Voxel positions are relative to the position the model gets placed at.
     pub fn from_voxels(ctx: &Context, name: String, voxels: &Vec<(Voxel, UVec3)>) -> Self {
         let mut voxel_data = Vec::new();
         for (vox, pos) in voxels {
-            voxel_data.push([pos.x + 1024, pos.y + 1024, pos.z + 1024, vox.0]);
+            voxel_data.push([pos.x, pos.y, pos.z, vox.0]);
         }
 
         let vox_buf = FixedSizeBuffer::new(ctx, voxels.len());
         vox_buf.write(0, &voxel_data);
 
+        let size = voxels.iter().fold(UVec3::ZERO, |size, (_, pos)| size.max(*pos + UVec3::ONE));
+
         Self {
             vox_buf,
             voxels: voxels.len(),
+            grid: Mutex::new(None),
+            size,
 
             name,
         }
@@ -40,4 +120,90 @@ impl GpuModel {
     pub fn voxel_count(&self) -> usize {
         self.voxels
     }
+
+    /// Size of the model's bounding box, starting at the origin
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// The buffer holding the model's voxel grid. Models that never get instanced don't need
+    /// it, so it's built from the voxel buffer the first time, which stalls until the GPU is done!
+    /// MUST BE RUN FROM THE MAIN THREAD
+    pub(crate) fn grid_buf(&self, ctx: &Context) -> foxtail::glow::NativeBuffer {
+        let mut grid_buf = self.grid.lock().unwrap();
+        if grid_buf.is_none() {
+            puffin::profile_scope!("build_model_grid");
+            let voxels = read_buffer(ctx, &self.vox_buf, 0, self.voxels);
+            let grid = model_grid(&voxels, self.size);
+            let buf = FixedSizeBuffer::new(ctx, grid.len());
+            buf.write(0, &grid);
+            *grid_buf = Some(buf);
+        }
+        grid_buf.as_ref().unwrap().buf()
+    }
+}
+
+/// Lays out the voxels of a model in its bounding box, x first
+fn model_grid(voxels: &[[u32; 4]], size: UVec3) -> Vec<u32> {
+    let mut grid = vec![0u32; size.x as usize * size.y as usize * size.z as usize];
+    for [x, y, z, voxel] in voxels {
+        grid[*x as usize + *y as usize * size.x as usize + *z as usize * size.x as usize * size.y as usize] = *voxel;
+    }
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_aligned_rotations() {
+        assert_eq!(ModelTransform::default().axis_aligned(), Some([IVec3::X, IVec3::Y, IVec3::Z]));
+
+        let quarter = ModelTransform::new(IVec3::ZERO, Quat::from_rotation_y(std::f32::consts::FRAC_PI_2), Vec3::ONE);
+        assert_eq!(quarter.axis_aligned(), Some([-IVec3::Z, IVec3::Y, IVec3::X]));
+
+        let half = ModelTransform::new(IVec3::ZERO, Quat::from_rotation_z(std::f32::consts::PI), Vec3::ONE);
+        assert_eq!(half.axis_aligned(), Some([-IVec3::X, -IVec3::Y, IVec3::Z]));
+
+        // Rotations aren't normalized by the caller
+        let unnormalized = ModelTransform::new(IVec3::ZERO, Quat::from_rotation_x(std::f32::consts::FRAC_PI_2) * 2.0, Vec3::ONE);
+        assert_eq!(unnormalized.axis_aligned(), Some([IVec3::X, IVec3::Z, -IVec3::Y]));
+
+        // Mirroring maps voxels one to one too
+        let mirrored = ModelTransform::new(IVec3::ZERO, Quat::IDENTITY, vec3(-1.0, 1.0, 1.0));
+        assert_eq!(mirrored.axis_aligned(), Some([-IVec3::X, IVec3::Y, IVec3::Z]));
+
+        let turned = ModelTransform::new(IVec3::ZERO, Quat::from_rotation_y(0.3), Vec3::ONE);
+        assert_eq!(turned.axis_aligned(), None);
+        let scaled = ModelTransform::new(IVec3::ZERO, Quat::IDENTITY, Vec3::splat(2.0));
+        assert_eq!(scaled.axis_aligned(), None);
+        let flattened = ModelTransform::new(IVec3::ZERO, Quat::IDENTITY, vec3(1.0, 0.0, 1.0));
+        assert_eq!(flattened.axis_aligned(), None);
+    }
+
+    #[test]
+    fn model_grid_layout() {
+        let voxels = [[0, 0, 0, 1], [1, 0, 0, 2], [0, 1, 0, 3], [1, 1, 2, 4]];
+        let grid = model_grid(&voxels, uvec3(2, 2, 3));
+        assert_eq!(grid, vec![1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 4]);
+        assert!(model_grid(&[], UVec3::ZERO).is_empty());
+    }
+
+    #[test]
+    fn samples_per_axis() {
+        let transform = |scale: Vec3| ModelTransform::new(IVec3::ZERO, Quat::from_rotation_y(0.3), scale);
+        assert_eq!(transform(Vec3::ONE).samples_per_axis(1000), 2);
+        assert_eq!(transform(Vec3::splat(0.25)).samples_per_axis(1000), 1);
+        assert_eq!(transform(Vec3::ZERO).samples_per_axis(1000), 1);
+        // The biggest scale counts, mirrored or not
+        assert_eq!(transform(vec3(1.0, -4.0, 2.0)).samples_per_axis(1000), 7);
+
+        // Clamped so every sample of the model can be numbered with a u32
+        let big = transform(Vec3::splat(100.0));
+        assert_eq!(big.samples_per_axis(1), 174);
+        // 2^20 * 16^3 would be exactly 2^32
+        assert_eq!(big.samples_per_axis(1 << 20), 15);
+        assert_eq!(big.samples_per_axis(u32::MAX as usize), 1);
+    }
 }
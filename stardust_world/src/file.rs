@@ -0,0 +1,247 @@
+use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use stardust_common::voxel::Voxel;
+
+use crate::{CpuWorld, WorldConfig};
+use crate::brick::Brick;
+
+const WORLD_FILE_MAGIC: [u8; 4] = *b"SDWF";
+const WORLD_FILE_VERSION: u32 = 1;
+
+const REGION_FILE_MAGIC: [u8; 4] = *b"SDWR";
+const REGION_FILE_VERSION: u32 = 1;
+
+/// World files start with the magic bytes and the version (little endian u32), followed by the
+/// deflate-compressed CBOR encoding of a RawWorld. Only layer0 nodes and bricks containing
+/// voxels are stored. Pool indices are not stored, they get reassigned when loading.
+#[derive(Serialize, Deserialize)]
+struct RawWorld {
+    brick_map_size: u32,
+    layer0s: Vec<RawLayer0>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawLayer0 {
+    brick_map_idx: u32,
+    bricks: Vec<RawBrick>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawBrick {
+    layer0_idx: u32,
+    voxels: Vec<u32>,
+}
+
+/// Region files hold the bricks of a single layer0 node, as evicted by `WorldStreamer`.
+/// They use the same layout as world files, with a RawRegion instead of a RawWorld.
+/// The position of the region is not stored, it's part of the file name.
+#[derive(Serialize, Deserialize)]
+struct RawRegion {
+    bricks: Vec<RawBrick>,
+}
+
+impl RawBrick {
+    fn from_brick(layer0_idx: usize, brick: &Brick) -> Self {
+        Self {
+            layer0_idx: layer0_idx as u32,
+            voxels: brick.voxels().iter().map(|v| v.0).collect(),
+        }
+    }
+
+    fn voxels(self) -> anyhow::Result<Vec<Voxel>> {
+        if self.voxels.len() != 4096 {
+            anyhow::bail!("Brick has {} voxels, expected 4096!", self.voxels.len());
+        }
+        Ok(self.voxels.into_iter().map(Voxel).collect())
+    }
+}
+
+fn encode<T: Serialize>(magic: [u8; 4], version: u32, value: &T) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&magic);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    let mut encoder = DeflateEncoder::new(bytes, Compression::default());
+    ciborium::ser::into_writer(value, &mut encoder)?;
+    Ok(encoder.finish()?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], magic: [u8; 4], version: u32, kind: &str) -> anyhow::Result<T> {
+    if bytes.len() < 8 || bytes[0..4] != magic {
+        anyhow::bail!("Not a {} file!", kind);
+    }
+    let file_version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    if file_version != version {
+        anyhow::bail!("Unsupported {} file version {}!", kind, file_version);
+    }
+
+    let mut cbor = Vec::new();
+    DeflateDecoder::new(&bytes[8..]).read_to_end(&mut cbor)?;
+    Ok(ciborium::de::from_reader(&cbor[..])?)
+}
+
+/// Encodes the bricks of a single layer0 node, given as (index within the layer0 node, brick)
+pub(crate) fn region_to_bytes(bricks: &[(usize, Brick)]) -> anyhow::Result<Vec<u8>> {
+    puffin::profile_function!();
+    let raw = RawRegion {
+        bricks: bricks.iter().map(|(layer0_idx, brick)| RawBrick::from_brick(*layer0_idx, brick)).collect(),
+    };
+    encode(REGION_FILE_MAGIC, REGION_FILE_VERSION, &raw)
+}
+
+/// Decodes a region file into (index within the layer0 node, voxels) per brick
+pub(crate) fn region_from_bytes(bytes: &[u8]) -> anyhow::Result<Vec<(usize, Vec<Voxel>)>> {
+    puffin::profile_function!();
+    let raw: RawRegion = decode(bytes, REGION_FILE_MAGIC, REGION_FILE_VERSION, "region")?;
+    raw.bricks.into_iter().map(|brick| {
+        if brick.layer0_idx as usize >= 16 * 16 * 16 {
+            anyhow::bail!("Brick has layer0 index {}, expected less than 4096!", brick.layer0_idx);
+        }
+        Ok((brick.layer0_idx as usize, brick.voxels()?))
+    }).collect()
+}
+
+impl CpuWorld {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        puffin::profile_function!();
+        let mut layer0s = Vec::new();
+        for (brick_map_idx, layer0_pool_idx) in self.layer0_map().iter().enumerate() {
+            let layer0 = match self.get_layer0(*layer0_pool_idx) {
+                Some(layer0) => layer0,
+                None => continue,
+            };
+
+            let mut bricks = Vec::new();
+            for (layer0_idx, brick_pool_idx) in layer0.brick_indices.iter().enumerate() {
+                let brick = match self.get_brick(*brick_pool_idx) {
+                    Some(brick) => brick,
+                    None => continue,
+                };
+                if !brick.has_voxels() { continue; }
+                bricks.push(RawBrick::from_brick(layer0_idx, brick));
+            }
+
+            if bricks.len() > 0 {
+                layer0s.push(RawLayer0 {
+                    brick_map_idx: brick_map_idx as u32,
+                    bricks,
+                });
+            }
+        }
+
+        let raw = RawWorld {
+            brick_map_size: self.config().brick_map_size as u32,
+            layer0s,
+        };
+
+        encode(WORLD_FILE_MAGIC, WORLD_FILE_VERSION, &raw)
+    }
+
+    /// Loads a world file into a new world created with the given config.
+    /// The brick map size must match the one the world was saved with.
+    pub fn from_bytes(bytes: &[u8], config: WorldConfig) -> anyhow::Result<Self> {
+        puffin::profile_function!();
+        let raw: RawWorld = decode(bytes, WORLD_FILE_MAGIC, WORLD_FILE_VERSION, "world")?;
+        if raw.brick_map_size != config.brick_map_size as u32 {
+            anyhow::bail!("World file has a brick map size of {}, expected {}!", raw.brick_map_size, config.brick_map_size);
+        }
+
+        let mut world = Self::new(config);
+        for layer0 in raw.layer0s {
+            for brick in layer0.bricks {
+                let layer0_idx = brick.layer0_idx as usize;
+                let voxels = brick.voxels()?;
+                if !world.insert_brick(layer0.brick_map_idx as usize, layer0_idx, &voxels) {
+                    anyhow::bail!("World file does not fit in the brick pool!");
+                }
+            }
+        }
+        Ok(world)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
+        let bytes = self.to_bytes()?;
+        std::fs::File::create(path)?.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P, config: WorldConfig) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(&bytes, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stardust_common::math::*;
+
+    use super::*;
+
+    fn config() -> WorldConfig {
+        WorldConfig {
+            brick_pool_size: 64,
+            layer0_pool_size: 8,
+            brick_map_size: 4,
+            origin: IVec3::splat(512),
+            ..WorldConfig::default()
+        }
+    }
+
+    fn test_world() -> CpuWorld {
+        let mut world = CpuWorld::new(config());
+        for i in 0..40 {
+            let voxel = Voxel::new([i as u8 * 6, 255 - i as u8, 7], 255, 0, false, 255);
+            world.set_voxel(voxel, ivec3(i * 13 - 260, (i * 7) % 30 - 15, -i));
+        }
+        // A brick that gets emptied again shouldn't end up in the file
+        world.set_voxel(Voxel::new([1, 2, 3], 255, 0, false, 255), ivec3(300, 300, 300));
+        world.process();
+        world.set_voxel(Voxel::empty(), ivec3(300, 300, 300));
+        world.process();
+        world
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let world = test_world();
+        let path = std::env::temp_dir().join(format!("stardust_world_round_trip_{}.sdw", std::process::id()));
+        world.save(&path).unwrap();
+        let loaded = CpuWorld::load(&path, config()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let voxels = |world: &CpuWorld| -> Vec<(u32, IVec3)> {
+            world.read_box(ivec3(-300, -16, -40), ivec3(300, 16, 1)).into_iter().map(|(voxel, pos)| (voxel.0, pos)).collect()
+        };
+        assert_eq!(voxels(&loaded).len(), 40);
+        assert_eq!(voxels(&loaded), voxels(&world));
+        assert_eq!(loaded.get_voxel(ivec3(300, 300, 300)).0, 0);
+        // Only bricks with voxels are stored
+        assert_eq!(loaded.bricks().count(), world.bricks().filter(|(_, brick)| brick.has_voxels()).count());
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let bytes = test_world().to_bytes().unwrap();
+        let other_size = WorldConfig { brick_map_size: 8, ..config() };
+        assert!(CpuWorld::from_bytes(&bytes, other_size).is_err());
+        assert!(CpuWorld::from_bytes(&bytes[..6], config()).is_err());
+
+        let region = region_to_bytes(&[]).unwrap();
+        assert!(CpuWorld::from_bytes(&region, config()).is_err());
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(WORLD_FILE_VERSION + 1).to_le_bytes());
+        assert!(CpuWorld::from_bytes(&newer, config()).is_err());
+    }
+
+    #[test]
+    fn load_fails_if_the_pool_is_too_small() {
+        let bytes = test_world().to_bytes().unwrap();
+        let small = WorldConfig { brick_pool_size: 4, ..config() };
+        assert!(CpuWorld::from_bytes(&bytes, small).is_err());
+    }
+}
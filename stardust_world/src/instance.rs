@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use stardust_common::math::*;
+
+use crate::GpuModel;
+
+/// Handle to a dynamic model instance, returned by `World::add_instance`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstanceId(u32);
+
+/// Mirrors `Instance` in fs.glsl
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GpuInstance {
+    /// Brick map space to model space, model space is in model voxels
+    pub(crate) storage_to_model: [f32; 16],
+    /// Bounds in brick map space, w is unused
+    pub(crate) bounds_min: [f32; 4],
+    pub(crate) bounds_max: [f32; 4],
+    /// xyz = size of the model's voxel grid, w = offset of the grid in the instance voxel pool
+    pub(crate) grid: [u32; 4],
+}
+
+impl GpuInstance {
+    /// `storage_from_model` takes model voxels to brick map space
+    pub(crate) fn new(storage_from_model: Mat4, size: UVec3, grid_offset: u32) -> Self {
+        let size_f = size.as_vec3();
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for i in 0..8 {
+            let corner = vec3((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32) * size_f;
+            let p = storage_from_model.transform_point3(corner);
+            min = min.min(p);
+            max = max.max(p);
+        }
+        Self {
+            storage_to_model: storage_from_model.inverse().to_cols_array(),
+            bounds_min: min.extend(0.0).to_array(),
+            bounds_max: max.extend(0.0).to_array(),
+            grid: [size.x, size.y, size.z, grid_offset],
+        }
+    }
+}
+
+pub(crate) struct Instance {
+    pub(crate) model: Arc<GpuModel>,
+    /// Model voxels to world space
+    pub(crate) transform: Mat4,
+}
+
+/// All dynamic instances, uploaded to the GPU during `World::process` whenever they changed
+#[derive(Default)]
+pub(crate) struct InstanceSet {
+    pub(crate) instances: BTreeMap<InstanceId, Instance>,
+    next_id: u32,
+    /// Set when an instance got added, moved or removed since the last upload
+    pub(crate) dirty: bool,
+    /// Set when an instance got added or removed, so the model grids have to be packed again
+    pub(crate) models_changed: bool,
+}
+
+impl InstanceSet {
+    pub(crate) fn add(&mut self, model: Arc<GpuModel>, transform: Mat4) -> InstanceId {
+        let id = InstanceId(self.next_id);
+        self.next_id += 1;
+        self.instances.insert(id, Instance { model, transform });
+        self.dirty = true;
+        self.models_changed = true;
+        id
+    }
+
+    pub(crate) fn set_transform(&mut self, id: InstanceId, transform: Mat4) -> bool {
+        match self.instances.get_mut(&id) {
+            Some(instance) => {
+                instance.transform = transform;
+                self.dirty = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: InstanceId) -> bool {
+        let removed = self.instances.remove(&id).is_some();
+        self.dirty |= removed;
+        self.models_changed |= removed;
+        removed
+    }
+}
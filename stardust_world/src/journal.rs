@@ -0,0 +1,246 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+use crate::ownership::VoxelStack;
+
+/// A named group of voxel changes that gets undone and redone as a whole
+#[derive(Debug, Clone)]
+pub(crate) struct Transaction {
+    pub(crate) name: String,
+    /// Position, voxel before the transaction and voxel after it, in the order they were first changed
+    edits: Vec<(IVec3, Voxel, Voxel)>,
+    index: HashMap<IVec3, usize>,
+    /// Ownership stacks before and after the transaction, see `Ownership::finish_recording`
+    owners: Vec<(IVec3, Option<VoxelStack>, Option<VoxelStack>)>,
+}
+
+impl Transaction {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            edits: Vec::new(),
+            index: HashMap::new(),
+            owners: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.edits.is_empty() && self.owners.is_empty()
+    }
+
+    /// Records a write. Only the first write to a position keeps its previous voxel,
+    /// writes that don't change anything are left out.
+    pub(crate) fn record(&mut self, pos: IVec3, before: Voxel, after: Voxel) {
+        match self.index.get(&pos) {
+            Some(&i) => self.edits[i].2 = after,
+            None => if before.0 != after.0 {
+                self.index.insert(pos, self.edits.len());
+                self.edits.push((pos, before, after));
+            },
+        }
+    }
+
+    pub(crate) fn before(&self) -> impl Iterator<Item = (Voxel, IVec3)> + '_ {
+        self.edits.iter().map(|(pos, before, _)| (*before, *pos))
+    }
+
+    pub(crate) fn after(&self) -> impl Iterator<Item = (Voxel, IVec3)> + '_ {
+        self.edits.iter().map(|(pos, _, after)| (*after, *pos))
+    }
+
+    pub(crate) fn set_owners(&mut self, owners: Vec<(IVec3, Option<VoxelStack>, Option<VoxelStack>)>) {
+        self.owners = owners;
+    }
+
+    pub(crate) fn owners_before(&self) -> Vec<(IVec3, Option<VoxelStack>)> {
+        self.owners.iter().map(|(pos, before, _)| (*pos, before.clone())).collect()
+    }
+
+    pub(crate) fn owners_after(&self) -> Vec<(IVec3, Option<VoxelStack>)> {
+        self.owners.iter().map(|(pos, _, after)| (*pos, after.clone())).collect()
+    }
+}
+
+/// Part of the queued commands that belongs to a single transaction, or to none
+pub(crate) struct Segment {
+    /// Id and name of the transaction
+    pub(crate) transaction: Option<(u64, String)>,
+    pub(crate) commands: Range<usize>,
+}
+
+/// Undo and redo history of a `World`. Transactions are tracked by where they start and end
+/// in the command queue, so `World::process` can apply them one after the other and record which
+/// voxels each of them changed.
+#[derive(Default)]
+pub(crate) struct Journal {
+    pub(crate) enabled: bool,
+    max_transactions: usize,
+    pub(crate) undo: VecDeque<Transaction>,
+    pub(crate) redo: Vec<Transaction>,
+
+    next_id: u64,
+    /// Transaction that was open at the end of the last `take_segments`
+    open: Option<(u64, String)>,
+    /// Transaction that is open right now, as far as queueing is concerned
+    current: Option<(u64, String)>,
+    /// Where transactions start or end in the command queue, in the order they were queued
+    marks: Vec<(Option<(u64, String)>, usize)>,
+}
+
+impl Journal {
+    pub(crate) fn enable(&mut self, max_transactions: usize) {
+        self.enabled = true;
+        self.max_transactions = max_transactions;
+        while self.undo.len() > max_transactions {
+            self.undo.pop_front();
+        }
+    }
+
+    pub(crate) fn disable(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Forgets the history and where transactions start and end in the queues, for when the
+    /// queues get emptied. A transaction that's open stays open, starting at the empty queues.
+    pub(crate) fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.marks.clear();
+        self.open = None;
+        if let Some(current) = &self.current {
+            self.marks.push((Some(current.clone()), 0));
+        }
+    }
+
+    pub(crate) fn begin(&mut self, name: String, queued: usize) {
+        if !self.enabled { return; }
+        let transaction = Some((self.next_id, name));
+        self.next_id += 1;
+        self.current = transaction.clone();
+        self.marks.push((transaction, queued));
+    }
+
+    pub(crate) fn end(&mut self, queued: usize) {
+        if !self.enabled || self.current.is_none() { return; }
+        self.current = None;
+        self.marks.push((None, queued));
+    }
+
+    /// Splits everything queued into segments, one for every stretch between transaction
+    /// boundaries. A transaction that's still open carries over into the next call.
+    pub(crate) fn take_segments(&mut self, queued: usize) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        let mut transaction = self.open.take();
+        for (next, end) in self.marks.drain(..) {
+            segments.push(Segment {
+                transaction,
+                commands: start..end,
+            });
+            start = end;
+            transaction = next;
+        }
+        segments.push(Segment {
+            transaction: transaction.clone(),
+            commands: start..queued,
+        });
+        self.open = transaction;
+        segments
+    }
+
+    /// Adds a finished transaction to the history. Anything that could be redone is lost.
+    pub(crate) fn push(&mut self, transaction: Transaction) {
+        if !self.enabled || transaction.is_empty() { return; }
+        self.redo.clear();
+        self.undo.push_back(transaction);
+        while self.undo.len() > self.max_transactions {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxels<I: Iterator<Item = (Voxel, IVec3)>>(iter: I) -> Vec<(u32, i32)> {
+        iter.map(|(voxel, pos)| (voxel.0, pos.x)).collect()
+    }
+
+    #[test]
+    fn record_keeps_the_first_before_and_the_last_after() {
+        let mut transaction = Transaction::new("stroke".to_string());
+        transaction.record(ivec3(0, 0, 0), Voxel(0), Voxel(1));
+        transaction.record(ivec3(1, 0, 0), Voxel(5), Voxel(5));
+        transaction.record(ivec3(2, 0, 0), Voxel(3), Voxel(4));
+        transaction.record(ivec3(0, 0, 0), Voxel(1), Voxel(2));
+        // Written back to what it was, it still gets restored
+        transaction.record(ivec3(2, 0, 0), Voxel(4), Voxel(3));
+        // Unchanged at first, changed later
+        transaction.record(ivec3(1, 0, 0), Voxel(5), Voxel(6));
+
+        assert_eq!(voxels(transaction.before()), vec![(0, 0), (3, 2), (5, 1)]);
+        assert_eq!(voxels(transaction.after()), vec![(2, 0), (3, 2), (6, 1)]);
+        assert!(!transaction.is_empty());
+        assert!(Transaction::new("nothing".to_string()).is_empty());
+    }
+
+    fn segments(journal: &mut Journal, queued: usize) -> Vec<(Option<u64>, Range<usize>)> {
+        journal.take_segments(queued).into_iter().map(|segment| (segment.transaction.map(|(id, _)| id), segment.commands)).collect()
+    }
+
+    #[test]
+    fn take_segments_splits_at_transactions() {
+        let mut journal = Journal::default();
+        // Disabled, everything is outside of a transaction
+        journal.begin("ignored".to_string(), 1);
+        assert_eq!(segments(&mut journal, 3), vec![(None, 0..3)]);
+
+        journal.enable(10);
+        journal.begin("a".to_string(), 1);
+        journal.end(3);
+        journal.begin("b".to_string(), 3);
+        assert_eq!(segments(&mut journal, 5), vec![(None, 0..1), (Some(0), 1..3), (None, 3..3), (Some(1), 3..5)]);
+
+        // The open transaction carries over, starting a new one ends it
+        journal.begin("c".to_string(), 2);
+        assert_eq!(segments(&mut journal, 4), vec![(Some(1), 0..2), (Some(2), 2..4)]);
+        journal.end(0);
+        assert_eq!(segments(&mut journal, 2), vec![(Some(2), 0..0), (None, 0..2)]);
+        assert_eq!(segments(&mut journal, 0), vec![(None, 0..0)]);
+    }
+
+    #[test]
+    fn clear_keeps_the_open_transaction() {
+        let mut journal = Journal::default();
+        journal.enable(10);
+        journal.begin("a".to_string(), 0);
+        journal.end(2);
+        journal.begin("b".to_string(), 2);
+        journal.clear();
+        assert_eq!(segments(&mut journal, 1), vec![(None, 0..0), (Some(1), 0..1)]);
+    }
+
+    #[test]
+    fn history_is_limited() {
+        let mut journal = Journal::default();
+        journal.enable(2);
+        for name in ["a", "b", "c"] {
+            let mut transaction = Transaction::new(name.to_string());
+            transaction.record(IVec3::ZERO, Voxel(0), Voxel(1));
+            journal.push(transaction);
+        }
+        journal.push(Transaction::new("empty".to_string()));
+        let names: Vec<&str> = journal.undo.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["b", "c"]);
+
+        journal.redo.push(journal.undo.pop_back().unwrap());
+        let mut transaction = Transaction::new("d".to_string());
+        transaction.record(IVec3::ZERO, Voxel(1), Voxel(2));
+        journal.push(transaction);
+        assert!(journal.redo.is_empty());
+    }
+}
@@ -2,115 +2,388 @@
 extern crate log;
 
 use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use foxtail::prelude::*;
+use itertools::{Itertools, iproduct};
 
 use stardust_common::math::*;
 use stardust_common::voxel::Voxel;
+use stardust_sdvx::Model;
 
 pub mod layer0;
 pub mod brick;
 mod data;
+mod cpu;
+mod readback;
+mod file;
+mod config;
+mod shape;
+mod report;
+mod trace;
+mod collision;
+mod stream;
+mod instance;
+mod journal;
+mod changes;
+mod ownership;
+mod registry;
+mod compact;
+mod staging;
+mod batch;
+mod command;
 
 use layer0::*;
 use brick::*;
 pub use data::*;
+pub use cpu::*;
+pub use readback::VoxelReadHandle;
+pub use config::WorldConfig;
+pub use shape::{Shape, ShapeOp};
+use shape::ShapeCommand;
+pub use report::ProcessReport;
+pub use trace::{RayHit, TraceHit};
+pub use collision::SweepHit;
+pub use stream::{WorldStreamer, StreamingConfig, StreamReport};
+pub use instance::InstanceId;
+pub use batch::VoxelBatch;
+use instance::*;
+use journal::*;
+use command::{Command, CommandQueue, last_writes};
+pub use changes::{BrickChanges, ChangeSubscription};
+use changes::Subscriptions;
+pub use ownership::VoxelOwner;
+use ownership::{Ownership, OwnerIds, VoxelStack};
+pub use registry::{ModelId, ModelChange};
+use registry::ModelRegistry;
+use report::*;
+use readback::*;
+use staging::StagingQueue;
+use config::WORKGROUP_SIZE;
 
-pub const BRICK_POOL_SIZE: usize = 32768;
-pub const LAYER0_POOL_SIZE: usize = 8192;
-const BRICK_MAP_SIZE: usize = 64;
-const VOXEL_QUEUE_SIZE: usize = 32768;
-const DEALLOC_QUEUE_SIZE: usize = 4096;
+fn compile_shader(ctx: &Context, config: &WorldConfig, source: &str, name: &str) -> ComputeShader {
+    let source = config.preprocess_shader(source);
+    ComputeShader::new(ctx, (&source[..], name))
+}
+
+/// All compute shaders of a world. They get the world config baked in, so they have to be
+/// compiled again when it changes, see `World::recenter`.
+struct WorldShaders {
+    cs_process_voxels: ComputeShader,
+    cs_alloc_layers: ComputeShader,
+    cs_alloc_bricks: ComputeShader,
+    cs_alloc_palette: ComputeShader,
+    cs_promote_bricks: ComputeShader,
+    cs_build_lods: ComputeShader,
+    cs_free_layer0: ComputeShader,
+    cs_dealloc_bricks: ComputeShader,
+    cs_compact_bricks: ComputeShader,
+    cs_place_model: ComputeShader,
+    cs_read_voxels: ComputeShader,
+    cs_expand_shape: ComputeShader,
+    cs_snapshot_stats: ComputeShader,
+}
+
+impl WorldShaders {
+    fn new(ctx: &Context, config: &WorldConfig) -> Self {
+        let cs_process_voxels = compile_shader(ctx, config, include_str!("../shaders/cs_process_voxel_queue.glsl"), "../shaders/cs_process_voxel_queue.glsl");
+        let cs_alloc_layers = compile_shader(ctx, config, include_str!("../shaders/cs_alloc_layers.glsl"), "../shaders/cs_alloc_layers.glsl");
+        let cs_alloc_bricks = compile_shader(ctx, config, include_str!("../shaders/cs_alloc_bricks.glsl"), "../shaders/cs_alloc_bricks.glsl");
+        let cs_alloc_palette = compile_shader(ctx, config, include_str!("../shaders/cs_alloc_palette.glsl"), "../shaders/cs_alloc_palette.glsl");
+        let cs_promote_bricks = compile_shader(ctx, config, include_str!("../shaders/cs_promote_bricks.glsl"), "../shaders/cs_promote_bricks.glsl");
+        let cs_build_lods = compile_shader(ctx, config, include_str!("../shaders/cs_build_lods.glsl"), "../shaders/cs_build_lods.glsl");
+        let cs_free_layer0 = compile_shader(ctx, config, include_str!("../shaders/cs_free_layer0.glsl"), "../shaders/cs_free_layer0.glsl");
+        let cs_dealloc_bricks = compile_shader(ctx, config, include_str!("../shaders/cs_dealloc_bricks.glsl"), "../shaders/cs_dealloc_bricks.glsl");
+        let cs_compact_bricks = compile_shader(ctx, config, include_str!("../shaders/cs_compact_bricks.glsl"), "../shaders/cs_compact_bricks.glsl");
+        let cs_place_model = compile_shader(ctx, config, include_str!("../shaders/cs_place_model.glsl"), "../shaders/cs_place_model.glsl");
+        let cs_read_voxels = compile_shader(ctx, config, include_str!("../shaders/cs_read_voxels.glsl"), "../shaders/cs_read_voxels.glsl");
+        let cs_expand_shape = compile_shader(ctx, config, include_str!("../shaders/cs_expand_shape.glsl"), "../shaders/cs_expand_shape.glsl");
+        let cs_snapshot_stats = compile_shader(ctx, config, include_str!("../shaders/cs_snapshot_stats.glsl"), "../shaders/cs_snapshot_stats.glsl");
+
+        Self {
+            cs_process_voxels,
+            cs_alloc_layers,
+            cs_alloc_bricks,
+            cs_alloc_palette,
+            cs_promote_bricks,
+            cs_build_lods,
+            cs_free_layer0,
+            cs_dealloc_bricks,
+            cs_compact_bricks,
+            cs_place_model,
+            cs_read_voxels,
+            cs_expand_shape,
+            cs_snapshot_stats,
+        }
+    }
+}
+
+/// Runs a shader once per item, in workgroups of `WORKGROUP_SIZE`.
+/// The shaders skip the invocations past `count` in the last workgroup.
+fn dispatch_items(shader: &mut ComputeShader, count: u32) {
+    if count == 0 { return; }
+    shader.set_uniforms(|uni| {
+        uni.set_u32("count", count);
+    });
+    shader.dispatch([(count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1]);
+}
+
+/// Makes the buffer writes of earlier dispatches visible to later dispatches and to reading
+/// buffers back. Unlike `ctx.fence`, the CPU doesn't wait for the GPU to finish.
+fn barrier(ctx: &Context) {
+    unsafe {
+        ctx.gl.memory_barrier(foxtail::glow::SHADER_STORAGE_BARRIER_BIT | foxtail::glow::ATOMIC_COUNTER_BARRIER_BIT | foxtail::glow::BUFFER_UPDATE_BARRIER_BIT);
+    }
+}
+
+/// Where the batch of voxels `process_internal` works on lives
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum QueueSource {
+    /// Written by the CPU into the current staging slot
+    Staging,
+    /// Generated on the GPU into `voxel_queue_gpu`, by expanding a shape or placing a model
+    Gpu,
+}
 
 pub struct World {
-    brick_pool: FixedSizeBuffer<Brick>,
+    config: WorldConfig,
+
+    brick_pool: FixedSizeBuffer<GpuBrick>,
+    raw_brick_pool: FixedSizeBuffer<GpuRawBrick>,
+    brick_lod_pool: FixedSizeBuffer<GpuBrickLod>,
     layer0_pool: FixedSizeBuffer<Layer0>,
     layer0_map: FixedSizeBuffer<u32>,
 
     free_brick_pool: FixedSizeBuffer<u32>,
     free_layer0_pool: FixedSizeBuffer<u32>,
+    free_raw_brick_pool: FixedSizeBuffer<u32>,
     brick_pool_counter: AtomicCounter,
     layer0_pool_counter: AtomicCounter,
+    raw_brick_pool_counter: AtomicCounter,
 
     dealloc_queue_counter: AtomicCounter,
 
-    voxel_queue: Arc<Mutex<Vec<(Voxel, UVec3)>>>,
+    stats_gpu: FixedSizeBuffer<u32>,
+    /// Stats of the last period, swapped with `stats_gpu` by `snapshot_stats`
+    stats_readback: FixedSizeBuffer<u32>,
+    pending_stats: Option<PendingStats>,
+    /// `process` calls since the last snapshot, and the time they took
+    unreported_processes: u32,
+    unreported_micros: u64,
+    last_report: ProcessReport,
+
+    brick_change_frames: FixedSizeBuffer<u32>,
+    changed_bricks_gpu: FixedSizeBuffer<[u32; 4]>,
+    /// Bricks that changed during the last period, swapped along with the stats
+    changed_bricks_readback: FixedSizeBuffer<[u32; 4]>,
+    /// Counts stats periods, so bricks only get listed as changed once per report
+    frame: u32,
+    changed_bricks: Vec<IVec3>,
+    /// Set by `upload`, handed to the subscriptions during the next `process`
+    world_replaced: bool,
+    subscriptions: Subscriptions,
+    pool_exhausted_callbacks: Vec<Box<dyn FnMut(&ProcessReport) + Send + Sync>>,
+    /// Fragmentation above which `process` compacts the brick pool by itself
+    compaction_threshold: Option<f32>,
+
+    /// Voxels, shapes and model changes waiting for `process`, in the order they were queued
+    queue: Arc<Mutex<CommandQueue>>,
+    /// Batches of voxels generated on the GPU
     voxel_queue_gpu: FixedSizeBuffer<[u32; 4]>,
+    /// Batches of voxels coming from the CPU, double buffered
+    voxel_staging: StagingQueue,
 
-    model_queue: Arc<Mutex<Vec<(Arc<GpuModel>, UVec3, UVec3, bool)>>>,
+    ownership: Ownership,
+    owner_ids: OwnerIds,
 
-    cs_process_voxels: ComputeShader,
-    cs_alloc_layers: ComputeShader,
-    cs_alloc_bricks: ComputeShader,
-    cs_dealloc_bricks: ComputeShader,
-    cs_place_model: ComputeShader,
+    instances: Arc<Mutex<InstanceSet>>,
+    instance_pool: FixedSizeBuffer<GpuInstance>,
+    instance_voxel_pool: FixedSizeBuffer<u32>,
+    /// Offset of each instanced model's grid in the instance voxel pool, by model address
+    instance_grids: HashMap<usize, u32>,
+    instance_count: u32,
+
+    journal: Arc<Mutex<Journal>>,
+    /// Transaction `process` is recording into, with its id
+    recording: Option<(u64, Transaction)>,
+
+    /// Copy of the world on the CPU for queries like `raycast`, see `enable_mirror`
+    mirror: Option<CpuWorld>,
+
+    read_queue: Arc<Mutex<Vec<PendingRead>>>,
+    active_reads: VecDeque<PendingRead>,
+    read_queue_gpu: FixedSizeBuffer<[u32; 4]>,
+    read_results_gpu: FixedSizeBuffer<u32>,
+    /// Voxels the batch in the voxel queue is about to overwrite, see `read_batch`
+    overwritten_gpu: FixedSizeBuffer<u32>,
+    /// Copies of the batches a model placement expands to, so the next batch can be expanded
+    /// while the last one is read back
+    placement_readbacks: [BatchReadback; 2],
+    read_fence: Option<GpuFence>,
+    reads_in_flight: usize,
 
-    pub gpu_models: Vec<Arc<GpuModel>>,
+    shaders: WorldShaders,
+
+    models: ModelRegistry,
 
     voxels_queued: usize,
     models_queued: usize,
+    shapes_queued: usize,
 }
 
 impl World {
-    pub fn new(ctx: &Context) -> Self {
-        debug!("Creating new world...");
-        let brick_pool = FixedSizeBuffer::new(ctx, BRICK_POOL_SIZE);
-        brick_pool.write(0, &(vec![Brick::empty(); BRICK_POOL_SIZE]));
+    /// Panics if the config isn't valid, see `WorldConfig::validate`
+    pub fn new(ctx: &Context, config: WorldConfig) -> Self {
+        debug!("Creating new world with {:?}...", config);
+        if let Err(e) = config.validate() {
+            panic!("Invalid world config: {}", e);
+        }
+        let brick_pool = FixedSizeBuffer::new(ctx, config.brick_pool_size);
+        brick_pool.write(0, &(vec![GpuBrick::empty(); config.brick_pool_size]));
         debug!("GPU Brick pool created!");
-        let layer0_pool = FixedSizeBuffer::new(ctx, LAYER0_POOL_SIZE);
+        // Raw bricks get overwritten completely when they're handed out, so no need to clear them
+        let raw_brick_pool = FixedSizeBuffer::new(ctx, config.raw_brick_pool_size);
+        debug!("GPU Raw brick pool created!");
+        let brick_lod_pool = FixedSizeBuffer::new(ctx, config.brick_pool_size);
+        brick_lod_pool.write(0, &(vec![GpuBrickLod::empty(); config.brick_pool_size]));
+        debug!("GPU Brick LOD pool created!");
+        let layer0_pool = FixedSizeBuffer::new(ctx, config.layer0_pool_size);
         debug!("GPU Layer0 pool created!");
-        let layer0_map = FixedSizeBuffer::new(ctx, BRICK_MAP_SIZE * BRICK_MAP_SIZE * BRICK_MAP_SIZE);
+        let layer0_map = FixedSizeBuffer::new(ctx, config.brick_map_size * config.brick_map_size * config.brick_map_size);
         debug!("GPU Brick map created!");
-        let free_brick_pool = FixedSizeBuffer::new(ctx, BRICK_POOL_SIZE);
-        free_brick_pool.write(0, &(0..BRICK_POOL_SIZE).into_iter().map(|i| i as u32 + 1).collect::<Vec<u32>>());
+        let free_brick_pool = FixedSizeBuffer::new(ctx, config.brick_pool_size);
+        free_brick_pool.write(0, &(0..config.brick_pool_size).into_iter().map(|i| i as u32 + 1).collect::<Vec<u32>>());
         debug!("GPU Free brick pool created!");
-        let free_layer0_pool = FixedSizeBuffer::new(ctx, LAYER0_POOL_SIZE);
-        free_layer0_pool.write(0, &(0..LAYER0_POOL_SIZE).into_iter().map(|i| i as u32 + 1).collect::<Vec<u32>>());
+        let free_layer0_pool = FixedSizeBuffer::new(ctx, config.layer0_pool_size);
+        free_layer0_pool.write(0, &(0..config.layer0_pool_size).into_iter().map(|i| i as u32 + 1).collect::<Vec<u32>>());
         debug!("GPU Free layer0 pool created!");
-        let voxel_queue_gpu = FixedSizeBuffer::new(ctx, VOXEL_QUEUE_SIZE);
+        let free_raw_brick_pool = FixedSizeBuffer::new(ctx, config.raw_brick_pool_size);
+        free_raw_brick_pool.write(0, &(0..config.raw_brick_pool_size).into_iter().map(|i| i as u32 + 1).collect::<Vec<u32>>());
+        debug!("GPU Free raw brick pool created!");
+        let voxel_queue_gpu = FixedSizeBuffer::new(ctx, config.voxel_queue_size);
+        let voxel_staging = StagingQueue::new(ctx, config.voxel_queue_size);
         debug!("GPU Voxel queue created!");
+        let read_queue_gpu = FixedSizeBuffer::new(ctx, config.voxel_queue_size);
+        let read_results_gpu = FixedSizeBuffer::new(ctx, config.voxel_queue_size);
+        debug!("GPU Read queue created!");
+        let overwritten_gpu = FixedSizeBuffer::new(ctx, config.voxel_queue_size);
+        let placement_readbacks = [BatchReadback::new(ctx, config.voxel_queue_size), BatchReadback::new(ctx, config.voxel_queue_size)];
+        let instance_pool = FixedSizeBuffer::new(ctx, config.instance_pool_size);
+        let instance_voxel_pool = FixedSizeBuffer::new(ctx, config.instance_voxel_pool_size);
+        debug!("GPU Instance pool created!");
 
         let dealloc_queue_counter = AtomicCounter::new(ctx);
+        let stats_gpu = FixedSizeBuffer::new(ctx, STAT_COUNT);
+        stats_gpu.write(0, &[0; STAT_COUNT]);
+        let stats_readback = FixedSizeBuffer::new(ctx, STAT_COUNT);
+        let brick_change_frames = FixedSizeBuffer::new(ctx, config.brick_pool_size);
+        brick_change_frames.write(0, &vec![0u32; config.brick_pool_size]);
+        let changed_bricks_gpu = FixedSizeBuffer::new(ctx, config.brick_pool_size);
+        let changed_bricks_readback = FixedSizeBuffer::new(ctx, config.brick_pool_size);
         let brick_pool_counter = AtomicCounter::new(ctx);
-        brick_pool_counter.reset(BRICK_POOL_SIZE as u32);
+        brick_pool_counter.reset(config.brick_pool_size as u32);
         let layer0_pool_counter = AtomicCounter::new(ctx);
-        layer0_pool_counter.reset(LAYER0_POOL_SIZE as u32);
+        layer0_pool_counter.reset(config.layer0_pool_size as u32);
+        let raw_brick_pool_counter = AtomicCounter::new(ctx);
+        raw_brick_pool_counter.reset(config.raw_brick_pool_size as u32);
 
-        let cs_process_voxels = ComputeShader::new(ctx, (include_str!("../shaders/cs_process_voxel_queue.glsl"), "../shaders/cs_process_voxel_queue.glsl"));
-        let cs_alloc_layers = ComputeShader::new(ctx, (include_str!("../shaders/cs_alloc_layers.glsl"), "../shaders/cs_alloc_layers.glsl"));
-        let cs_alloc_bricks = ComputeShader::new(ctx, (include_str!("../shaders/cs_alloc_bricks.glsl"), "../shaders/cs_alloc_bricks.glsl"));
-        let cs_dealloc_bricks = ComputeShader::new(ctx, (include_str!("../shaders/cs_dealloc_bricks.glsl"), "../shaders/cs_dealloc_bricks.glsl"));
-        let cs_place_model = ComputeShader::new(ctx, (include_str!("../shaders/cs_place_model.glsl"), "../shaders/cs_place_model.glsl"));
+        let shaders = WorldShaders::new(ctx, &config);
 
         Self {
+            config,
+
             brick_pool,
+            raw_brick_pool,
+            brick_lod_pool,
             layer0_pool,
             layer0_map,
 
             free_brick_pool,
             free_layer0_pool,
+            free_raw_brick_pool,
             brick_pool_counter,
             layer0_pool_counter,
+            raw_brick_pool_counter,
 
             dealloc_queue_counter,
 
-            voxel_queue: Arc::new(Mutex::new(Vec::new())),
+            stats_gpu,
+            stats_readback,
+            pending_stats: None,
+            unreported_processes: 0,
+            unreported_micros: 0,
+            last_report: ProcessReport::default(),
+
+            brick_change_frames,
+            changed_bricks_gpu,
+            changed_bricks_readback,
+            // Bricks that never changed have 0
+            frame: 1,
+            changed_bricks: Vec::new(),
+            world_replaced: false,
+            subscriptions: Subscriptions::default(),
+            pool_exhausted_callbacks: Vec::new(),
+            compaction_threshold: None,
+
+            queue: Arc::new(Mutex::new(CommandQueue::default())),
             voxel_queue_gpu,
+            voxel_staging,
 
-            model_queue: Arc::new(Mutex::new(Vec::new())),
+            ownership: Ownership::default(),
+            owner_ids: OwnerIds::default(),
 
-            cs_process_voxels,
-            cs_alloc_layers,
-            cs_alloc_bricks,
-            cs_dealloc_bricks,
-            cs_place_model,
+            instances: Arc::new(Mutex::new(InstanceSet::default())),
+            instance_pool,
+            instance_voxel_pool,
+            instance_grids: HashMap::new(),
+            instance_count: 0,
+
+            journal: Arc::new(Mutex::new(Journal::default())),
+            recording: None,
+
+            mirror: None,
 
-            gpu_models: Vec::new(),
+            read_queue: Arc::new(Mutex::new(Vec::new())),
+            active_reads: VecDeque::new(),
+            read_queue_gpu,
+            read_results_gpu,
+            overwritten_gpu,
+            placement_readbacks,
+            read_fence: None,
+            reads_in_flight: 0,
+
+            shaders,
+
+            models: ModelRegistry::default(),
 
             voxels_queued: 0,
             models_queued: 0,
+            shapes_queued: 0,
+        }
+    }
+
+    /// Unmaps the staging buffers and deletes them along with every fence the world still
+    /// holds. Call this before the GL context goes away, the world can't be processed afterwards.
+    /// MUST BE RUN FROM THE MAIN THREAD
+    pub fn destroy(&mut self, ctx: &Context) {
+        self.voxel_staging.destroy(ctx);
+        for readback in &mut self.placement_readbacks {
+            readback.destroy(ctx);
+        }
+        unsafe {
+            if let Some(fence) = self.read_fence.take() {
+                ctx.gl.delete_sync(fence.0);
+            }
+            if let Some(pending) = self.pending_stats.take() {
+                ctx.gl.delete_sync(pending.fence.0);
+            }
         }
     }
 
+    pub fn config(&self) -> &WorldConfig {
+        &self.config
+    }
+
     pub fn voxels_queued(&self) -> usize {
         self.voxels_queued
     }
@@ -119,64 +392,647 @@ impl World {
         self.models_queued
     }
 
+    pub fn shapes_queued(&self) -> usize {
+        self.shapes_queued
+    }
+
+    /// Returns the most recent report, which lags a frame or so behind `process`
+    pub fn last_report(&self) -> &ProcessReport {
+        &self.last_report
+    }
+
+    /// Registers a callback that gets called whenever a report comes in where voxels
+    /// were dropped because the brick or layer0 pool ran out.
+    pub fn on_pool_exhausted<F: FnMut(&ProcessReport) + Send + Sync + 'static>(&mut self, callback: F) {
+        self.pool_exhausted_callbacks.push(Box::new(callback));
+    }
+
+    /// Makes `process` compact the brick pool whenever bricks got freed and the fragmentation,
+    /// see `brick_fragmentation`, ends up above the threshold. `None` turns it off, which is the default.
+    pub fn set_compaction_threshold(&mut self, threshold: Option<f32>) {
+        self.compaction_threshold = threshold;
+    }
+
+    /// World positions of the first voxel of every brick where a voxel changed during the
+    /// `process` calls covered by the most recent report
+    pub fn changed_bricks(&self) -> &[IVec3] {
+        &self.changed_bricks
+    }
+
+    /// Subscribes to the bricks that change during `process`, for systems that derive data
+    /// from the world and want to update only what changed
+    pub fn subscribe_changes(&self) -> ChangeSubscription {
+        self.subscriptions.subscribe()
+    }
+
+    /// Stalls until the GPU is done! `last_report` has a recent value without stalling
     pub fn bricks_free(&self) -> u32 {
         self.brick_pool_counter.read()
     }
 
+    /// Stalls until the GPU is done! `last_report` has a recent value without stalling
     pub fn layer0s_free(&self) -> u32 {
         self.layer0_pool_counter.read()
     }
 
     /// Queues a voxel to be uploaded to the GPU and placed in the world.
-    /// Voxel placement order cannot be relied on!
-    /// They get uploaded by a compute shader in batches of 4096 voxels, with no ordering within each batch
-    /// Batches ARE ordered, however!
-    pub fn set_voxel(&self, voxel: Voxel, world_pos: UVec3) {
+    /// If the same position is written to multiple times before the next `process`, the last write wins.
+    /// Positions are in world space, see `WorldConfig::origin`. Voxels outside of the world are dropped.
+    pub fn set_voxel(&self, voxel: Voxel, world_pos: IVec3) {
+        puffin::profile_function!();
+        let mut lock = self.queue.lock().unwrap();
+        lock.push_voxels([(voxel, world_pos)]);
+    }
+
+    /// Queues all writes of a batch at once, after everything queued before. Takes the queue's
+    /// lock only once, so threads filling their own batches don't get in each other's way.
+    pub fn submit_batch(&self, batch: VoxelBatch) {
+        puffin::profile_function!();
+        self.queue.lock().unwrap().push_batch(batch);
+    }
+
+    /// Queues voxels that get written at the start of the next `process`, before everything
+    /// queued with `set_voxel`, `submit_batch` and the like, and outside of any transaction.
+    /// Meant for streaming stored parts of the world back in, see `WorldStreamer`, so edits
+    /// queued while the data was being read still win over it.
+    pub fn stream_in(&self, batch: VoxelBatch) {
+        puffin::profile_function!();
+        if batch.is_empty() { return; }
+        self.queue.lock().unwrap().push_streamed(batch.voxels);
+    }
+
+    /// Creates a new owner for `place_model`
+    pub fn create_owner(&self) -> VoxelOwner {
+        self.owner_ids.next()
+    }
+
+    /// Queues placing a model in the world on behalf of `owner`, taking away whatever the owner
+    /// placed before. The world keeps track of what each placed voxel covers, so the voxels
+    /// underneath come back once the model moves or gets removed, whether they're terrain or
+    /// parts of other models. Writing voxels directly on top of a model makes them part of the
+    /// terrain, they stay when the model is removed.
+    pub fn place_model(&self, owner: VoxelOwner, model: Arc<GpuModel>, transform: ModelTransform) {
+        puffin::profile_function!();
+        let mut lock = self.queue.lock().unwrap();
+        lock.push_model(ModelCommand::Place { owner, model, transform });
+    }
+
+    /// Queues taking away the model `owner` placed, restoring the voxels underneath
+    pub fn remove_model(&self, owner: VoxelOwner) {
         puffin::profile_function!();
-        let mut lock = self.voxel_queue.lock().unwrap();
-        lock.push((voxel, world_pos));
+        let mut lock = self.queue.lock().unwrap();
+        lock.push_model(ModelCommand::Remove { owner });
     }
 
-    pub fn update_model(&self, model: Arc<GpuModel>, old_pos: UVec3, new_pos: UVec3, remove_only: bool) {
+    /// Queues a shape command. Only the shape's parameters are queued, the voxels get
+    /// generated by a compute shader during `process`, in order with everything else queued.
+    /// Parts of the shape outside of the world are ignored.
+    pub fn apply_shape(&self, shape: Shape, op: ShapeOp) {
         puffin::profile_function!();
-        let mut lock = self.model_queue.lock().unwrap();
-        lock.push((model, old_pos, new_pos, remove_only));
+        let mut lock = self.queue.lock().unwrap();
+        lock.push_shape(ShapeCommand { shape, op });
+    }
+
+    /// Adds a dynamic instance of a model. Instances are traced by the renderer on top of the
+    /// world, without writing to the brick map, so moving them is cheap and leaves the voxels
+    /// underneath alone. `transform` takes model voxels to world space, in voxels.
+    /// Changes show up after the next `process`.
+    pub fn add_instance(&self, model: Arc<GpuModel>, transform: Mat4) -> InstanceId {
+        puffin::profile_function!();
+        self.instances.lock().unwrap().add(model, transform)
+    }
+
+    /// Moves a dynamic instance. Returns false if the instance doesn't exist.
+    pub fn set_instance_transform(&self, id: InstanceId, transform: Mat4) -> bool {
+        self.instances.lock().unwrap().set_transform(id, transform)
+    }
+
+    /// Removes a dynamic instance. Returns false if the instance doesn't exist.
+    pub fn remove_instance(&self, id: InstanceId) -> bool {
+        self.instances.lock().unwrap().remove(id)
+    }
+
+    /// Amount of dynamic instances uploaded during the last `process`, for the `instanceCount` uniform
+    pub fn instance_count(&self) -> u32 {
+        self.instance_count
+    }
+
+    /// Starts recording an edit journal, so transactions can be undone and redone.
+    /// Only the last `max_transactions` transactions are kept.
+    /// To know what a transaction overwrote, `process` reads back every batch of voxels it writes
+    /// before moving on, which stalls until the GPU is done with it. That's once every
+    /// `WorldConfig::voxel_queue_size` voxels, so keep big generated edits out of transactions.
+    pub fn enable_journal(&self, max_transactions: usize) {
+        self.journal.lock().unwrap().enable(max_transactions);
+    }
+
+    /// Stops recording the edit journal and throws away the history
+    pub fn disable_journal(&self) {
+        self.journal.lock().unwrap().disable();
+    }
+
+    pub fn journal_enabled(&self) -> bool {
+        self.journal.lock().unwrap().enabled
+    }
+
+    /// Starts a named transaction, like a brush stroke or placing a model. Everything queued
+    /// until `end_transaction` gets undone and redone as a whole. Transactions can span multiple
+    /// `process` calls, and starting one ends the one that's open. Does nothing if the journal is disabled.
+    /// Edits queued outside of a transaction aren't recorded.
+    pub fn begin_transaction<S: Into<String>>(&self, name: S) {
+        let mut journal = self.journal.lock().unwrap();
+        let queued = self.queue.lock().unwrap().seal();
+        journal.begin(name.into(), queued);
+    }
+
+    /// Ends the open transaction. It shows up in the history once `process` applied it.
+    pub fn end_transaction(&self) {
+        let mut journal = self.journal.lock().unwrap();
+        let queued = self.queue.lock().unwrap().seal();
+        journal.end(queued);
+    }
+
+    /// Queues restoring the voxels the last applied transaction changed, along with the models
+    /// that covered them, so removing a model afterwards still reveals the right voxels. Call this outside of
+    /// transactions, or the restored voxels get recorded into the open one.
+    /// Returns the name of the transaction, or None if there is nothing to undo.
+    pub fn undo(&self) -> Option<String> {
+        puffin::profile_function!();
+        let mut journal = self.journal.lock().unwrap();
+        let transaction = journal.undo.pop_back()?;
+        self.queue.lock().unwrap().push_restore(transaction.before().collect(), transaction.owners_before());
+        let name = transaction.name.clone();
+        journal.redo.push(transaction);
+        Some(name)
+    }
+
+    /// Queues applying the last undone transaction again. Call this outside of transactions,
+    /// or the voxels get recorded into the open one.
+    /// Returns the name of the transaction, or None if there is nothing to redo.
+    pub fn redo(&self) -> Option<String> {
+        puffin::profile_function!();
+        let mut journal = self.journal.lock().unwrap();
+        let transaction = journal.redo.pop()?;
+        self.queue.lock().unwrap().push_restore(transaction.after().collect(), transaction.owners_after());
+        let name = transaction.name.clone();
+        journal.undo.push_back(transaction);
+        Some(name)
+    }
+
+    /// Name of the transaction `undo` would undo
+    pub fn undo_name(&self) -> Option<String> {
+        self.journal.lock().unwrap().undo.back().map(|transaction| transaction.name.clone())
+    }
+
+    /// Name of the transaction `redo` would redo
+    pub fn redo_name(&self) -> Option<String> {
+        self.journal.lock().unwrap().redo.last().map(|transaction| transaction.name.clone())
+    }
+
+    /// Keeps a copy of the world on the CPU, which `process` applies everything to as well, so
+    /// queries like `raycast` and `sweep_aabb` get answered right away without reading anything
+    /// back from the GPU. Takes as much memory as the bricks in use would uncompressed, and shapes
+    /// get expanded on the CPU too. Downloads the world once to start with, so this stalls!
+    pub fn enable_mirror(&mut self, ctx: &Context) {
+        puffin::profile_function!();
+        if self.mirror.is_some() { return; }
+        self.mirror = Some(self.download(ctx));
+    }
+
+    pub fn disable_mirror(&mut self) {
+        self.mirror = None;
+    }
+
+    /// The CPU copy of the world as of the last `process`, see `enable_mirror`
+    pub fn mirror(&self) -> Option<&CpuWorld> {
+        self.mirror.as_ref()
+    }
+
+    /// Finds the first voxel along a ray, see `CpuWorld::raycast`. Only sees what was
+    /// processed already. Panics if the mirror isn't enabled, see `enable_mirror`.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        self.expect_mirror().raycast(origin, dir, max_dist)
+    }
+
+    /// See `CpuWorld::overlaps_aabb`. Only sees what was processed already.
+    /// Panics if the mirror isn't enabled, see `enable_mirror`.
+    pub fn overlaps_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.expect_mirror().overlaps_aabb(min, max)
+    }
+
+    /// See `CpuWorld::overlaps_capsule`. Only sees what was processed already.
+    /// Panics if the mirror isn't enabled, see `enable_mirror`.
+    pub fn overlaps_capsule(&self, a: Vec3, b: Vec3, radius: f32) -> bool {
+        self.expect_mirror().overlaps_capsule(a, b, radius)
+    }
+
+    /// See `CpuWorld::sweep_aabb`. Only sees what was processed already.
+    /// Panics if the mirror isn't enabled, see `enable_mirror`.
+    pub fn sweep_aabb(&self, min: Vec3, max: Vec3, motion: Vec3) -> Option<SweepHit> {
+        self.expect_mirror().sweep_aabb(min, max, motion)
+    }
+
+    fn expect_mirror(&self) -> &CpuWorld {
+        self.mirror.as_ref().expect("World queries need the CPU mirror, see `World::enable_mirror`!")
+    }
+
+    /// Fills all voxels from `min` up to (but not including) `max`
+    pub fn fill_box(&self, voxel: Voxel, min: IVec3, max: IVec3) {
+        self.apply_shape(Shape::Box { min, max }, ShapeOp::Fill(voxel));
+    }
+
+    pub fn fill_sphere(&self, voxel: Voxel, center: IVec3, radius: u32) {
+        self.apply_shape(Shape::Sphere { center, radius }, ShapeOp::Fill(voxel));
+    }
+
+    /// Fills an upright cylinder, with the center of its bottom at `base`
+    pub fn fill_cylinder(&self, voxel: Voxel, base: IVec3, radius: u32, height: u32) {
+        self.apply_shape(Shape::Cylinder { base, radius, height }, ShapeOp::Fill(voxel));
+    }
+
+    /// Removes all voxels from `min` up to (but not including) `max`
+    pub fn clear_box(&self, min: IVec3, max: IVec3) {
+        self.apply_shape(Shape::Box { min, max }, ShapeOp::Clear);
+    }
+
+    /// Recolors all voxels with color `from` to `to`, from `min` up to (but not including) `max`
+    pub fn replace_color_in_box(&self, min: IVec3, max: IVec3, from: [u8; 3], to: [u8; 3]) {
+        self.apply_shape(Shape::Box { min, max }, ShapeOp::ReplaceColor { from, to });
+    }
+
+    /// Queues a batch of voxel reads. The reads happen on the GPU during `process`, after all queued
+    /// voxels have been placed, and the results are picked up by a later `process` call once the
+    /// GPU is done with them. This never stalls, so it's the way to go for many reads per frame.
+    pub fn request_voxels(&self, positions: Vec<IVec3>) -> VoxelReadHandle {
+        puffin::profile_function!();
+        let (read, handle) = PendingRead::new(positions);
+        self.read_queue.lock().unwrap().push(read);
+        handle
+    }
+
+    /// Reads a single voxel back from the GPU. Voxels that are still queued are not taken into account.
+    /// Stalls until the GPU is done, use `request_voxels` if you need a lot of these!
+    pub fn get_voxel(&mut self, ctx: &Context, world_pos: IVec3) -> Voxel {
+        puffin::profile_function!();
+        self.read_voxels_now(ctx, &[world_pos])[0]
+    }
+
+    /// Reads back all non-empty voxels from `min` up to (but not including) `max`.
+    /// Voxels that are still queued are not taken into account. Stalls until the GPU is done!
+    pub fn read_box(&mut self, ctx: &Context, min: IVec3, max: IVec3) -> Vec<(Voxel, IVec3)> {
+        puffin::profile_function!();
+        let mut voxels = Vec::new();
+        for chunk in &iproduct!(min.z..max.z, min.y..max.y, min.x..max.x).chunks(self.config.voxel_queue_size) {
+            let positions: Vec<IVec3> = chunk.map(|(z, y, x)| ivec3(x, y, z)).collect();
+            let results = self.read_voxels_now(ctx, &positions);
+            voxels.extend(results.into_iter().zip(positions).filter(|(voxel, _)| voxel.0 != 0));
+        }
+        voxels
+    }
+
+    /// Copies all voxels from `min` up to (but not including) `max` into a model, with `min` ending
+    /// up at the model's origin. Handy for turning something built in the world into a prefab.
+    /// Voxels that are still queued are not taken into account. Stalls until the GPU is done!
+    pub fn copy_region(&mut self, ctx: &Context, min: IVec3, max: IVec3) -> Model {
+        puffin::profile_function!();
+        let voxels = self.read_box(ctx, min, max);
+        region_to_model(voxels, min)
+    }
+
+    /// Copies a region into a model like `copy_region` and writes it to a .sdvx file
+    pub fn save_region<P: AsRef<Path>>(&mut self, ctx: &Context, min: IVec3, max: IVec3, path: P) -> anyhow::Result<()> {
+        self.copy_region(ctx, min, max).save(path)
     }
 
     /// Registers a model living in GPU memory. Arc<T> so you can keep a reference to it!
-    pub fn register_model(&mut self, model: Arc<GpuModel>) {
-        self.gpu_models.push(model);
+    /// Models are keyed by their name, so registering a model under a name that's already taken
+    /// replaces the old model and keeps its id. Use `model_change` to find out what happened
+    /// to a model you're holding on to.
+    pub fn register_model(&mut self, model: Arc<GpuModel>) -> ModelId {
+        self.models.register(model)
+    }
+
+    /// Removes a model from the registry. Its GPU buffers are released once nothing
+    /// references it anymore, including models placed in the world or dynamic instances.
+    /// Returns false if the id wasn't registered.
+    pub fn unregister_model(&mut self, id: ModelId) -> bool {
+        self.models.unregister(id)
+    }
+
+    pub fn model(&self, id: ModelId) -> Option<Arc<GpuModel>> {
+        self.models.get(id).cloned()
+    }
+
+    /// Looks up a model by the name it was registered under, like the path it was loaded from
+    pub fn find_model(&self, name: &str) -> Option<ModelId> {
+        self.models.find(name)
+    }
+
+    /// All registered models, ordered by id
+    pub fn models(&self) -> impl Iterator<Item = (ModelId, &Arc<GpuModel>)> {
+        self.models.iter()
+    }
+
+    pub fn model_count(&self) -> usize {
+        self.models.len()
+    }
+
+    /// Changes whenever a model gets replaced or unregistered, so users of `model_change`
+    /// only have to check their models when it does
+    pub fn models_generation(&self) -> u64 {
+        self.models.generation()
+    }
+
+    /// Whether the model got replaced by a newer one with the same name or got unregistered.
+    /// Returns `None` if it's still the registered model, or was never registered.
+    pub fn model_change(&self, model: &Arc<GpuModel>) -> Option<ModelChange> {
+        self.models.change(model)
     }
 
     pub fn bind(&mut self) {
         self.brick_pool.bind(0);
         self.layer0_pool.bind(1);
         self.layer0_map.bind(2);
+        self.raw_brick_pool.bind(9);
+        self.brick_lod_pool.bind(11);
+        self.instance_pool.bind(12);
+        self.instance_voxel_pool.bind(13);
     }
 
     pub fn unbind(&mut self) {
         self.brick_pool.unbind();
         self.layer0_pool.unbind();
         self.layer0_map.unbind();
+        self.raw_brick_pool.unbind();
+        self.brick_lod_pool.unbind();
+        self.instance_pool.unbind();
+        self.instance_voxel_pool.unbind();
+    }
+
+    /// Replaces the contents of the world with the contents of a `CpuWorld`.
+    /// Voxels, shapes and model changes still queued on either world are thrown away, and so is
+    /// the undo history, as it describes edits to the old contents.
+    /// Fails if the world has more bricks with too many different voxels for a palette than fit in the raw brick pool.
+    pub fn upload(&mut self, cpu_world: &CpuWorld) -> anyhow::Result<()> {
+        puffin::profile_function!();
+        let (bricks, raw_bricks) = cpu_world.brick_pool_data();
+        let raw_brick_pool_size = self.config.raw_brick_pool_size;
+        if raw_bricks.len() > raw_brick_pool_size {
+            anyhow::bail!("World needs {} raw bricks, but the raw brick pool only fits {}!", raw_bricks.len(), raw_brick_pool_size);
+        }
+        self.brick_pool.write(0, &bricks);
+        if !raw_bricks.is_empty() {
+            self.raw_brick_pool.write(0, &raw_bricks);
+        }
+        // Raw bricks were handed out in order, so the free ones are the ones after them
+        let mut free_raw_bricks: Vec<u32> = ((raw_bricks.len() + 1)..=raw_brick_pool_size).map(|i| i as u32).collect();
+        free_raw_bricks.resize(raw_brick_pool_size, 0);
+        self.free_raw_brick_pool.write(0, &free_raw_bricks);
+        self.raw_brick_pool_counter.reset((raw_brick_pool_size - raw_bricks.len()) as u32);
+        self.brick_lod_pool.write(0, &cpu_world.brick_lod_pool_data());
+        self.layer0_pool.write(0, &cpu_world.layer0_pool_data());
+        self.layer0_map.write(0, &cpu_world.layer0_map().to_vec());
+
+        self.free_brick_pool.write(0, &cpu_world.free_brick_pool().to_vec());
+        self.free_layer0_pool.write(0, &cpu_world.free_layer0_pool().to_vec());
+        self.brick_pool_counter.reset(cpu_world.bricks_free());
+        self.layer0_pool_counter.reset(cpu_world.layer0s_free());
+
+        self.dealloc_queue_counter.reset(cpu_world.dealloc_queue_counter());
+        if self.mirror.is_some() {
+            self.mirror = Some(cpu_world.clone());
+        }
+        self.ownership.clear();
+        self.clear_queues();
+        self.world_replaced = true;
+        Ok(())
+    }
+
+    /// Copies the contents of the world back from the GPU. Pool indices are reassigned, so the
+    /// resulting `CpuWorld` holds the same voxels but not necessarily the same buffer contents.
+    /// Each pool is read back in one go. Stalls until the GPU is done!
+    pub fn download(&mut self, ctx: &Context) -> CpuWorld {
+        puffin::profile_function!();
+        let mut cpu_world = CpuWorld::new(self.config);
+        let layer0_map = read_buffer(ctx, &self.layer0_map, 0, cpu_world.layer0_map().len());
+        let resident: Vec<(usize, u32)> = layer0_map.into_iter().enumerate().filter(|(_, idx)| *idx > 0).collect();
+        let (first, layer0_pool) = read_pool_slots(ctx, &self.layer0_pool, resident.iter().map(|(_, idx)| *idx));
+        let layer0s: Vec<(usize, Layer0)> = resident.iter().map(|(brick_map_idx, idx)| (*brick_map_idx, layer0_pool[*idx as usize - first])).collect();
+        for (brick_map_idx, layer0_idx, brick) in self.download_bricks(ctx, &layer0s) {
+            cpu_world.insert_brick(brick_map_idx, layer0_idx, brick.voxels());
+        }
+        cpu_world
+    }
+
+    /// Reads all non-empty bricks of the given layer0 nodes, as (brick map index, index within
+    /// the layer0 node, brick). The brick pool and raw brick pool are read back in one go each.
+    fn download_bricks(&self, ctx: &Context, layer0s: &[(usize, Layer0)]) -> Vec<(usize, usize, Brick)> {
+        puffin::profile_function!();
+        let brick_indices = || layer0s.iter().flat_map(|(_, layer0)| layer0.brick_indices.iter().copied()).filter(|idx| *idx > 0);
+        let (first_brick, gpu_bricks) = read_pool_slots(ctx, &self.brick_pool, brick_indices());
+        let raw_indices = brick_indices().map(|idx| &gpu_bricks[idx as usize - first_brick]).filter(|brick| brick.has_raw_brick()).map(|brick| brick.raw_idx);
+        let (first_raw, raw_bricks) = read_pool_slots(ctx, &self.raw_brick_pool, raw_indices);
+
+        let mut bricks = Vec::new();
+        for (brick_map_idx, layer0) in layer0s {
+            for (layer0_idx, brick_pool_idx) in layer0.brick_indices.iter().enumerate() {
+                if *brick_pool_idx == 0 { continue; }
+                let gpu_brick = &gpu_bricks[*brick_pool_idx as usize - first_brick];
+                let raw = match gpu_brick.has_raw_brick() {
+                    true => Some(&raw_bricks[gpu_brick.raw_idx as usize - first_raw]),
+                    false => None,
+                };
+                let brick = gpu_brick.to_brick(raw);
+                if !brick.has_voxels() { continue; }
+                bricks.push((*brick_map_idx, layer0_idx, brick));
+            }
+        }
+        bricks
+    }
+
+    /// Brick map indices of all layer0 nodes that are currently allocated.
+    /// Stalls until the GPU is done!
+    pub fn resident_layer0s(&self, ctx: &Context) -> Vec<usize> {
+        puffin::profile_function!();
+        let size = self.config.brick_map_size;
+        let layer0_map = read_buffer(ctx, &self.layer0_map, 0, size * size * size);
+        layer0_map.into_iter().enumerate().filter(|(_, idx)| *idx > 0).map(|(i, _)| i).collect()
+    }
+
+    /// Frees a layer0 node and all of its bricks, so the slots can be used for other parts of
+    /// the world. Returns the non-empty bricks it held as (index within the layer0 node, brick),
+    /// which can be placed back with `set_voxel` later on. Stalls until the GPU is done!
+    pub fn evict_layer0(&mut self, ctx: &Context, brick_map_idx: usize) -> Vec<(usize, Brick)> {
+        puffin::profile_function!();
+        let layer0_pool_idx = read_buffer(ctx, &self.layer0_map, brick_map_idx, 1)[0];
+        if layer0_pool_idx == 0 { return Vec::new(); }
+        let layer0 = read_buffer(ctx, &self.layer0_pool, layer0_pool_idx as usize - 1, 1)[0];
+        let bricks = self.download_bricks(ctx, &[(brick_map_idx, layer0)]).into_iter().map(|(_, layer0_idx, brick)| (layer0_idx, brick)).collect();
+
+        self.bind();
+        self.free_brick_pool.bind(4);
+        self.brick_pool_counter.bind(5);
+        self.raw_brick_pool_counter.bind(4);
+        self.stats_gpu.bind(8);
+        self.free_raw_brick_pool.bind(10);
+
+        self.shaders.cs_free_layer0.set_uniforms(|uni| {
+            uni.set_u32("layer0_pool_idx", layer0_pool_idx);
+        });
+        dispatch_items(&mut self.shaders.cs_free_layer0, 16 * 16 * 16);
+        barrier(ctx);
+
+        self.free_raw_brick_pool.unbind();
+        self.stats_gpu.unbind();
+        self.raw_brick_pool_counter.unbind();
+        self.brick_pool_counter.unbind();
+        self.free_brick_pool.unbind();
+        self.unbind();
+
+        // Reading the counter waits for the shader, so the layer0 node can be pushed onto the free list from here
+        let free_idx = self.layer0_pool_counter.read();
+        self.free_layer0_pool.write(free_idx as usize, &[layer0_pool_idx]);
+        self.layer0_pool_counter.reset(free_idx + 1);
+        self.layer0_map.write(brick_map_idx, &[0]);
+        if let Some(mirror) = &mut self.mirror {
+            mirror.evict_layer0(brick_map_idx);
+        }
+
+        bricks
     }
 
-    fn write_queue_to_gpu(&mut self, write_slice: Vec<[u32; 4]>) {
+    /// Moves the world origin, see `WorldConfig::origin`, so a different part of the world fits
+    /// in the brick map. Voxels keep their world position. The origin can only move by whole
+    /// layer0 nodes (256 voxels), and layer0 nodes that would end up outside of the map have to
+    /// be evicted first, see `evict_layer0`. Everything queued so far gets reported first and
+    /// every shader is compiled again, so this is slow! Renderers have to pick up the new origin too.
+    pub fn recenter(&mut self, ctx: &Context, origin: IVec3) -> anyhow::Result<()> {
         puffin::profile_function!();
-        self.voxel_queue_gpu.write(0, &write_slice);
+        if origin == self.config.origin { return Ok(()); }
+        let size = self.config.brick_map_size;
+        let layer0_map = read_buffer(ctx, &self.layer0_map, 0, size * size * size);
+        let layer0_map = self.config.recenter_brick_map(&layer0_map, origin)?;
+        if let Some(mirror) = &mut self.mirror {
+            mirror.recenter(origin)?;
+        }
+
+        // Changed bricks get listed in brick map space, so the ones so far need the old origin
+        self.flush_report(ctx);
+
+        self.layer0_map.write(0, &layer0_map);
+        self.config.origin = origin;
+        self.shaders = WorldShaders::new(ctx, &self.config);
+        // Instances get uploaded in brick map space
+        self.instances.lock().unwrap().dirty = true;
+        debug!("Moved world origin to {}", origin);
+        Ok(())
+    }
+
+    /// Free slots in the brick pool in front of the last brick in use, relative to the amount of
+    /// bricks in use. 0 means all bricks in use are packed at the front of the pool.
+    /// Stalls until the GPU is done!
+    pub fn brick_fragmentation(&self, ctx: &Context) -> f32 {
+        let free = self.read_free_bricks(ctx);
+        compact::fragmentation(&free, self.config.brick_pool_size)
     }
 
-    fn process_internal(&mut self, ctx: &Context, size: u32) {
+    /// Moves all bricks in use to the front of the brick pool, pointing their layer0 nodes at
+    /// the new slots, and rebuilds the free list so new bricks get allocated right behind them.
+    /// Voxels don't change, so nothing gets reported as changed. Returns the amount of bricks moved.
+    /// Stalls until the GPU is done!
+    pub fn compact_bricks(&mut self, ctx: &Context) -> usize {
+        puffin::profile_function!();
+        let free = self.read_free_bricks(ctx);
+        let compaction = compact::plan(&free, self.config.brick_pool_size);
+
+        self.brick_pool.bind(0);
+        self.layer0_pool.bind(1);
+        self.brick_lod_pool.bind(11);
+        self.brick_change_frames.bind(14);
+        for chunk in compaction.moves.chunks(self.config.voxel_queue_size) {
+            self.voxel_staging.write(ctx, chunk);
+            self.voxel_staging.bind(ctx, 3);
+            dispatch_items(&mut self.shaders.cs_compact_bricks, chunk.len() as u32);
+            barrier(ctx);
+            self.voxel_staging.unbind(ctx, 3);
+            self.voxel_staging.finish(ctx);
+        }
+        self.brick_change_frames.unbind();
+        self.brick_lod_pool.unbind();
+        self.layer0_pool.unbind();
+        self.brick_pool.unbind();
+
+        // Buffer writes are ordered after the dispatches, so the free list can be replaced from here.
+        // The amount of free bricks stays the same, so the counter does too
+        self.free_brick_pool.write(0, &compaction.free_list);
+
+        debug!("Compacted the brick pool, {} bricks moved", compaction.moves.len());
+        compaction.moves.len()
+    }
+
+    /// The used part of the brick free list
+    fn read_free_bricks(&self, ctx: &Context) -> Vec<u32> {
+        let count = self.brick_pool_counter.read() as usize;
+        if count == 0 { return Vec::new(); }
+        read_buffer(ctx, &self.free_brick_pool, 0, count)
+    }
+
+    /// Saves all placed voxels to a world file. Voxels that are still queued are not saved!
+    pub fn save<P: AsRef<Path>>(&mut self, ctx: &Context, path: P) -> anyhow::Result<()> {
+        puffin::profile_function!();
+        self.download(ctx).save(path)
+    }
+
+    /// Replaces the contents of the world with a world file, see `upload`.
+    pub fn load<P: AsRef<Path>>(&mut self, ctx: &Context, path: P) -> anyhow::Result<()> {
+        puffin::profile_function!();
+        let cpu_world = CpuWorld::load(path, self.config)?;
+        self.upload(&cpu_world)?;
+        ctx.fence();
+        Ok(())
+    }
+
+    /// Throws away everything that's queued, along with the undo history
+    fn clear_queues(&mut self) {
+        let mut journal = self.journal.lock().unwrap();
+        self.queue.lock().unwrap().clear();
+        journal.clear();
+        self.recording = None;
+    }
+
+    fn bind_queue(&mut self, ctx: &Context, source: QueueSource, index: u32) {
+        match source {
+            QueueSource::Staging => self.voxel_staging.bind(ctx, index),
+            QueueSource::Gpu => self.voxel_queue_gpu.bind(index),
+        }
+    }
+
+    fn unbind_queue(&mut self, ctx: &Context, source: QueueSource, index: u32) {
+        match source {
+            QueueSource::Staging => self.voxel_staging.unbind(ctx, index),
+            QueueSource::Gpu => self.voxel_queue_gpu.unbind(),
+        }
+    }
+
+    /// Runs a batch of voxels through all passes. The passes only wait on each other with memory
+    /// barriers, so the CPU can move on to the next batch while the GPU is still busy with this one.
+    fn process_internal(&mut self, ctx: &Context, size: u32, source: QueueSource) {
+        if self.recording.is_some() {
+            self.record_batch(ctx, size, source);
+        }
+
         self.bind();
-        self.voxel_queue_gpu.bind(3);
+        self.bind_queue(ctx, source, 3);
         self.free_brick_pool.bind(4);
         self.free_layer0_pool.bind(5);
         self.brick_pool_counter.bind(6);
         self.layer0_pool_counter.bind(7);
+        self.stats_gpu.bind(8);
 
-        self.cs_alloc_layers.dispatch([size, 1, 1]);
-        ctx.fence();
-        self.cs_alloc_bricks.dispatch([size, 1, 1]);
-        ctx.fence();
+        dispatch_items(&mut self.shaders.cs_alloc_layers, size);
+        barrier(ctx);
+        dispatch_items(&mut self.shaders.cs_alloc_bricks, size);
+        barrier(ctx);
 
         // Processing voxels uses different binds
         self.layer0_pool_counter.unbind();
@@ -184,15 +1040,396 @@ impl World {
         self.free_layer0_pool.unbind();
         self.free_brick_pool.unbind();
 
+        // Make sure every brick has space for its new voxels, before writing any of them
+        dispatch_items(&mut self.shaders.cs_alloc_palette, size);
+        barrier(ctx);
+        self.free_raw_brick_pool.bind(10);
+        self.raw_brick_pool_counter.bind(4);
+        dispatch_items(&mut self.shaders.cs_promote_bricks, size);
+        barrier(ctx);
+        self.raw_brick_pool_counter.unbind();
+        self.free_raw_brick_pool.unbind();
+
         // Dispatch
-        self.cs_process_voxels.dispatch([size, 1, 1]);
+        self.brick_change_frames.bind(14);
+        self.changed_bricks_gpu.bind(15);
+        let frame = self.frame;
+        self.shaders.cs_process_voxels.set_uniforms(|uni| {
+            uni.set_u32("frame", frame);
+        });
+        dispatch_items(&mut self.shaders.cs_process_voxels, size);
+        barrier(ctx);
+        self.changed_bricks_gpu.unbind();
+        self.brick_change_frames.unbind();
+        dispatch_items(&mut self.shaders.cs_build_lods, size);
+        barrier(ctx);
 
-        ctx.fence();
+        self.stats_gpu.unbind();
+        self.unbind_queue(ctx, source, 3);
+        self.unbind();
+
+        if source == QueueSource::Staging {
+            self.voxel_staging.finish(ctx);
+        }
+    }
 
-        self.voxel_queue_gpu.unbind();
+    /// Reads the voxels the batch in the voxel queue is about to overwrite into `overwritten_gpu`
+    fn read_overwritten(&mut self, ctx: &Context, size: u32, source: QueueSource) {
+        self.bind();
+        self.bind_queue(ctx, source, 3);
+        self.overwritten_gpu.bind(4);
+        dispatch_items(&mut self.shaders.cs_read_voxels, size);
+        barrier(ctx);
+        self.overwritten_gpu.unbind();
+        self.unbind_queue(ctx, source, 3);
         self.unbind();
+    }
 
-        ctx.fence();
+    /// Reads back the batch in the voxel queue, along with the voxels it's about to overwrite.
+    /// This stalls until the GPU is done!
+    fn read_batch(&mut self, ctx: &Context, size: u32, source: QueueSource) -> (Vec<[u32; 4]>, Vec<u32>) {
+        puffin::profile_function!();
+        self.read_overwritten(ctx, size, source);
+
+        let queue = match source {
+            QueueSource::Staging => self.voxel_staging.read(size as usize),
+            QueueSource::Gpu => read_buffer(ctx, &self.voxel_queue_gpu, 0, size as usize),
+        };
+        let overwritten = read_buffer(ctx, &self.overwritten_gpu, 0, size as usize);
+        (queue, overwritten)
+    }
+
+    /// Adds the voxels the batch in the voxel queue is about to overwrite to the recorded transaction
+    fn record_batch(&mut self, ctx: &Context, size: u32, source: QueueSource) {
+        let (queue, before) = self.read_batch(ctx, size, source);
+        if let Some((_, transaction)) = &mut self.recording {
+            for (entry, before) in queue.iter().zip(before) {
+                let pos = ivec3(entry[0] as i32, entry[1] as i32, entry[2] as i32);
+                if self.config.storage_pos(pos).is_none() { continue; }
+                transaction.record(pos, Voxel(before), Voxel(entry[3]));
+            }
+        }
+    }
+
+    /// Writes voxels in batches of `WorldConfig::voxel_queue_size`. There shouldn't be more than
+    /// one write to a position, as the order within a batch isn't defined.
+    fn write_voxels(&mut self, ctx: &Context, voxels: &[(Voxel, IVec3)]) {
+        for chunk in voxels.chunks(self.config.voxel_queue_size) {
+            let mut write_slice = Vec::new();
+            for (voxel, wpos) in chunk {
+                // Positions are signed, the shaders reinterpret them as ivec3
+                write_slice.push([wpos.x as u32, wpos.y as u32, wpos.z as u32, voxel.0]);
+            }
+            self.voxel_staging.write(ctx, &write_slice);
+            self.process_internal(ctx, write_slice.len() as u32, QueueSource::Staging);
+        }
+        if let Some(mirror) = &self.mirror {
+            mirror.submit_batch(VoxelBatch { voxels: voxels.to_vec() });
+        }
+    }
+
+    /// Writes voxels queued with `set_voxel` or `submit_batch`, making them part of the terrain
+    fn process_voxels(&mut self, ctx: &Context, voxels: &[(Voxel, IVec3)]) {
+        puffin::profile_function!();
+        // The GPU writes the voxels in a batch in no particular order, so only the
+        // last write to each position may end up in the queue
+        let queue = last_writes(voxels);
+        if !self.ownership.is_empty() {
+            for (_, wpos) in &queue {
+                self.ownership.flatten(*wpos);
+            }
+        }
+        self.write_voxels(ctx, &queue);
+    }
+
+    /// Puts back the voxels of a transaction along with the ownership stacks they had, instead
+    /// of making them part of the terrain like other writes
+    fn process_restore(&mut self, ctx: &Context, voxels: &[(Voxel, IVec3)], owners: &[(IVec3, Option<VoxelStack>)]) {
+        puffin::profile_function!();
+        for (pos, stack) in owners {
+            self.ownership.restore(*pos, stack.clone());
+        }
+        self.write_voxels(ctx, voxels);
+    }
+
+    /// Finishes the recorded transaction if `process` moves on to another one
+    fn switch_transaction(&mut self, transaction: Option<(u64, String)>) {
+        let current = self.recording.as_ref().map(|(id, _)| *id);
+        if current == transaction.as_ref().map(|(id, _)| *id) { return; }
+        if let Some((_, mut finished)) = self.recording.take() {
+            finished.set_owners(self.ownership.finish_recording());
+            self.journal.lock().unwrap().push(finished);
+        }
+        if transaction.is_some() {
+            self.ownership.start_recording();
+        }
+        self.recording = transaction.map(|(id, name)| (id, Transaction::new(name)));
+    }
+
+    /// Expands a shape command into the voxel queue, one chunk at a time
+    fn process_shape(&mut self, ctx: &Context, command: ShapeCommand) {
+        puffin::profile_function!();
+        // Clip to the world, nothing outside of it can be placed anyway
+        let world_min = -self.config.origin;
+        let world_max = world_min + IVec3::splat(self.config.world_size() as i32);
+        let (min, max) = command.shape.bounds();
+        let min = min.max(world_min);
+        let max = max.min(world_max);
+        if min.cmpge(max).any() { return; }
+        let size = (max - min).as_uvec3();
+
+        if let Some(mirror) = &mut self.mirror {
+            // Recoloring looks at the voxels in the mirror, so everything before has to be in there
+            mirror.process();
+            mirror.apply_shape(command.shape, command.op);
+        }
+
+        // Split into slabs along z, so indices within a slab fit in a u32
+        let slice_size = size.x as usize * size.y as usize;
+        let slab_depth = (u32::MAX as usize / slice_size).min(size.z as usize) as u32;
+        let mut z = 0;
+        while z < size.z {
+            let depth = slab_depth.min(size.z - z);
+            let count = slice_size * depth as usize;
+            let slab_min = min + ivec3(0, 0, z as i32);
+
+            let mut offset = 0;
+            while offset < count {
+                let chunk = (count - offset).min(self.config.voxel_queue_size);
+
+                self.bind();
+                self.voxel_queue_gpu.bind(3);
+                self.shaders.cs_expand_shape.set_uniforms(|uni| {
+                    uni.set_u32("offset", offset as u32);
+                    uni.set_uvec4("box_min", [slab_min.x as u32, slab_min.y as u32, slab_min.z as u32, command.shape.gpu_id()]);
+                    uni.set_uvec4("box_size", [size.x, size.y, depth, command.op.gpu_id()]);
+                    uni.set_uvec4("center", command.shape.gpu_center());
+                    uni.set_uvec4("voxel", command.op.gpu_voxel());
+                });
+                dispatch_items(&mut self.shaders.cs_expand_shape, chunk as u32);
+                barrier(ctx);
+                self.voxel_queue_gpu.unbind();
+                self.unbind();
+
+                self.process_internal(ctx, chunk as u32, QueueSource::Gpu);
+
+                offset += chunk;
+            }
+            z += depth;
+        }
+    }
+
+    /// Expands the model into the voxel queue one chunk at a time, and writes it on top of the
+    /// ownership stacks. The chunks are read back first, so the world knows what the model covers.
+    /// Reading back a chunk waits for the GPU, but by then it's busy expanding the next one.
+    fn process_model_placement(&mut self, ctx: &Context, owner: VoxelOwner, model: &GpuModel, transform: ModelTransform) {
+        puffin::profile_function!();
+        let (matrix, samples) = match transform.axis_aligned() {
+            // The rounded rotation, so the shader maps voxels exactly
+            Some([x, y, z]) => (Mat3::from_cols(x.as_vec3(), y.as_vec3(), z.as_vec3()), 0),
+            None => (transform.matrix(), transform.samples_per_axis(model.voxels)),
+        };
+        // Fits in a u32, see `samples_per_axis`
+        let count = model.voxels * (samples.max(1) as usize).pow(3);
+        let matrix = Mat4::from_mat3(matrix).to_cols_array();
+        let pos = transform.translation;
+
+        let mut pending = None;
+        let mut offset = 0;
+        let mut slot = 0;
+        while offset < count {
+            let size = (count - offset).min(self.config.voxel_queue_size);
+
+            self.voxel_queue_gpu.bind(0);
+            unsafe {
+                ctx.gl.bind_buffer_base(foxtail::glow::SHADER_STORAGE_BUFFER, 1, Some(model.vox_buf.buf()));
+            }
+
+            self.shaders.cs_place_model.set_uniforms(|uni| {
+                uni.set_u32("offset", offset as u32);
+                uni.set_uvec4("pos", [pos.x as u32, pos.y as u32, pos.z as u32, 0]);
+                uni.set_mat4("transform", matrix);
+                uni.set_u32("samples", samples);
+            });
+            dispatch_items(&mut self.shaders.cs_place_model, size as u32);
+            barrier(ctx);
+
+            unsafe {
+                ctx.gl.bind_buffer_base(foxtail::glow::SHADER_STORAGE_BUFFER, 1, None);
+            }
+            self.voxel_queue_gpu.unbind();
+
+            self.read_overwritten(ctx, size as u32, QueueSource::Gpu);
+            self.placement_readbacks[slot].copy(ctx, &self.voxel_queue_gpu, &self.overwritten_gpu, size);
+
+            // The last chunk gets written after this one read what it covers. Positions both
+            // cover are on the ownership stacks by the time this one gets placed, so the voxel
+            // this one read there doesn't matter.
+            if let Some(last) = pending.replace(slot) {
+                self.place_chunk(ctx, owner, last);
+            }
+            slot = 1 - slot;
+            offset += size;
+        }
+        if let Some(last) = pending {
+            self.place_chunk(ctx, owner, last);
+        }
+    }
+
+    /// Puts a chunk of a model placement that was read back on the ownership stacks, and writes it
+    fn place_chunk(&mut self, ctx: &Context, owner: VoxelOwner, slot: usize) {
+        let (queue, current) = self.placement_readbacks[slot].read(ctx);
+        let mut writes = Vec::with_capacity(queue.len());
+        for (entry, current) in queue.iter().zip(current) {
+            let wpos = ivec3(entry[0] as i32, entry[1] as i32, entry[2] as i32);
+            if self.config.storage_pos(wpos).is_none() { continue; }
+            self.ownership.place(owner, wpos, Voxel(entry[3]), Voxel(current));
+            writes.push((Voxel(entry[3]), wpos));
+        }
+        // Turned models can map multiple samples onto the same voxel, this way the world
+        // ends up with the same voxel as the top of the ownership stack
+        self.write_voxels(ctx, &last_writes(&writes));
+    }
+
+    fn process_model_command(&mut self, ctx: &Context, command: &ModelCommand) {
+        match command {
+            ModelCommand::Place { owner, model, transform } => {
+                let writes = self.ownership.remove(*owner);
+                self.write_voxels(ctx, &writes);
+                self.process_model_placement(ctx, *owner, model, *transform);
+            },
+            ModelCommand::Remove { owner } => {
+                let writes = self.ownership.remove(*owner);
+                self.write_voxels(ctx, &writes);
+            },
+        }
+    }
+
+    fn dispatch_reads(&mut self, ctx: &Context, positions: &[IVec3]) {
+        let write_slice: Vec<[u32; 4]> = positions.iter().map(|p| [p.x as u32, p.y as u32, p.z as u32, 0]).collect();
+        self.read_queue_gpu.write(0, &write_slice);
+
+        self.bind();
+        self.read_queue_gpu.bind(3);
+        self.read_results_gpu.bind(4);
+
+        dispatch_items(&mut self.shaders.cs_read_voxels, positions.len() as u32);
+        barrier(ctx);
+
+        self.read_results_gpu.unbind();
+        self.read_queue_gpu.unbind();
+        self.unbind();
+    }
+
+    /// Hands out the results of the batched reads currently in flight.
+    /// Returns false if `wait` is false and the GPU isn't done with them yet.
+    fn collect_reads(&mut self, ctx: &Context, wait: bool) -> bool {
+        if self.reads_in_flight == 0 { return true; }
+        if let Some(fence) = &self.read_fence {
+            unsafe {
+                if !wait && ctx.gl.get_sync_status(fence.0) != foxtail::glow::SIGNALED {
+                    return false;
+                }
+                ctx.gl.delete_sync(fence.0);
+            }
+        }
+        self.read_fence = None;
+
+        let mut results = read_buffer(ctx, &self.read_results_gpu, 0, self.reads_in_flight).into_iter();
+        self.reads_in_flight = 0;
+        while let Some(read) = self.active_reads.front_mut() {
+            let count = read.remaining();
+            read.results.extend(results.by_ref().take(count).map(Voxel));
+            if read.remaining() > 0 { break; }
+            self.active_reads.pop_front().unwrap().finish();
+        }
+        true
+    }
+
+    fn read_voxels_now(&mut self, ctx: &Context, positions: &[IVec3]) -> Vec<Voxel> {
+        // Batched reads share the result buffer, so get those out of the way first
+        self.collect_reads(ctx, true);
+
+        let mut voxels = Vec::with_capacity(positions.len());
+        for chunk in positions.chunks(self.config.voxel_queue_size) {
+            self.dispatch_reads(ctx, chunk);
+            voxels.extend(read_buffer(ctx, &self.read_results_gpu, 0, chunk.len()).into_iter().map(Voxel));
+        }
+        voxels
+    }
+
+    fn process_reads(&mut self, ctx: &Context) {
+        puffin::profile_function!();
+        if !self.collect_reads(ctx, false) { return; }
+
+        self.active_reads.extend(self.read_queue.lock().unwrap().drain(..));
+        while self.active_reads.front().map(|read| read.remaining() == 0).unwrap_or(false) {
+            self.active_reads.pop_front().unwrap().finish();
+        }
+
+        let mut positions = Vec::new();
+        for read in &self.active_reads {
+            let start = read.results.len();
+            let count = read.remaining().min(self.config.voxel_queue_size - positions.len());
+            positions.extend_from_slice(&read.positions[start..start + count]);
+            if positions.len() == self.config.voxel_queue_size { break; }
+        }
+        if positions.is_empty() { return; }
+
+        self.dispatch_reads(ctx, &positions);
+        self.reads_in_flight = positions.len();
+        self.read_fence = unsafe { ctx.gl.fence_sync(foxtail::glow::SYNC_GPU_COMMANDS_COMPLETE, 0) }.ok().map(GpuFence);
+    }
+
+    /// Uploads the dynamic instances if any of them changed. The grids of all models with
+    /// instances get packed into the instance voxel pool again when instances were added or removed.
+    fn process_instances(&mut self, ctx: &Context) {
+        puffin::profile_function!();
+        let mut set = self.instances.lock().unwrap();
+        if !set.dirty { return; }
+
+        if set.models_changed {
+            self.instance_grids.clear();
+            let mut offset = 0;
+            for instance in set.instances.values() {
+                let key = Arc::as_ptr(&instance.model) as usize;
+                if self.instance_grids.contains_key(&key) { continue; }
+                let size = instance.model.size;
+                let len = size.x as usize * size.y as usize * size.z as usize;
+                if offset + len > self.config.instance_voxel_pool_size {
+                    warn!("Instance voxel pool is full, instances of model {} won't be drawn!", instance.model.name);
+                    continue;
+                }
+                unsafe {
+                    ctx.gl.bind_buffer(foxtail::glow::COPY_READ_BUFFER, Some(instance.model.grid_buf(ctx)));
+                    ctx.gl.bind_buffer(foxtail::glow::COPY_WRITE_BUFFER, Some(self.instance_voxel_pool.buf()));
+                    ctx.gl.copy_buffer_sub_data(foxtail::glow::COPY_READ_BUFFER, foxtail::glow::COPY_WRITE_BUFFER, 0, (offset * 4) as i32, (len * 4) as i32);
+                    ctx.gl.bind_buffer(foxtail::glow::COPY_WRITE_BUFFER, None);
+                    ctx.gl.bind_buffer(foxtail::glow::COPY_READ_BUFFER, None);
+                }
+                self.instance_grids.insert(key, offset as u32);
+                offset += len;
+            }
+            set.models_changed = false;
+        }
+
+        // The renderer traces in brick map space
+        let storage_from_world = Mat4::from_translation(self.config.origin.as_vec3());
+        let mut gpu_instances = Vec::with_capacity(set.instances.len());
+        for instance in set.instances.values() {
+            let grid_offset = match self.instance_grids.get(&(Arc::as_ptr(&instance.model) as usize)) {
+                Some(offset) => *offset,
+                None => continue,
+            };
+            if gpu_instances.len() == self.config.instance_pool_size {
+                warn!("Instance pool is full, only {} instances will be drawn!", gpu_instances.len());
+                break;
+            }
+            gpu_instances.push(GpuInstance::new(storage_from_world * instance.transform, instance.model.size, grid_offset));
+        }
+        self.instance_pool.write(0, &gpu_instances);
+        self.instance_count = gpu_instances.len() as u32;
+        set.dirty = false;
     }
 
     fn process_dealloc(&mut self, ctx: &Context) {
@@ -200,100 +1437,187 @@ impl World {
         self.dealloc_queue_counter.bind(3);
         self.free_brick_pool.bind(4);
         self.brick_pool_counter.bind(5);
+        self.raw_brick_pool_counter.bind(4);
+        self.stats_gpu.bind(8);
+        self.free_raw_brick_pool.bind(10);
 
-        self.cs_dealloc_bricks.dispatch([DEALLOC_QUEUE_SIZE as u32, 1, 1]);
-        ctx.fence();
+        dispatch_items(&mut self.shaders.cs_dealloc_bricks, self.config.dealloc_queue_size as u32);
+        barrier(ctx);
 
+        self.free_raw_brick_pool.unbind();
+        self.stats_gpu.unbind();
+        self.raw_brick_pool_counter.unbind();
         self.brick_pool_counter.unbind();
         self.free_brick_pool.unbind();
         self.dealloc_queue_counter.unbind();
         self.unbind();
     }
 
-    pub fn process(&mut self, ctx: &Context) {
+    /// Places everything that was queued, hands out finished reads, uploads changed dynamic instances
+    /// and deallocates empty bricks.
+    /// Voxels, shape commands and model updates get applied in the order they were queued, so
+    /// later writes to a position overwrite earlier ones.
+    /// With the journal enabled, each transaction gets applied and recorded in that order before
+    /// moving on to the next, so transactions never interleave.
+    /// Returns a report once the GPU is done with the stats of earlier calls, which usually
+    /// takes a frame. Subscriptions get told which bricks changed at the same time.
+    pub fn process(&mut self, ctx: &Context) -> Option<ProcessReport> {
         puffin::profile_function!();
+        let start = std::time::Instant::now();
 
-        self.voxels_queued = self.voxel_queue.lock().unwrap().len();
-        self.models_queued = self.model_queue.lock().unwrap().len();
+        // Take everything that was queued, split up by the transactions it belongs to
+        let (streamed, commands, segments) = {
+            let mut journal = self.journal.lock().unwrap();
+            let mut queue = self.queue.lock().unwrap();
+            let streamed = queue.take_streamed();
+            let (commands, (voxels, shapes, models)) = queue.take();
+            self.voxels_queued = voxels;
+            self.shapes_queued = shapes;
+            self.models_queued = models;
+            let segments = journal.take_segments(commands.len());
+            (streamed, commands, segments)
+        };
 
-        // Process GPU model changes
-        {
-            puffin::profile_scope!("process_gpu_models");
-            for i in 0..self.models_queued {
-                let (model, prev, new, remove_only) = {
-                    let (ref_model, ref_prev, ref_new, ref_remove) = &self.model_queue.lock().unwrap()[i];
-                    (Arc::clone(ref_model), *ref_prev, *ref_new, *ref_remove)
-                };
-                let n = if remove_only { 1 } else { 2 };
-                for j in 0..n {
-                    let mut offset = 0;
-                    let count = model.voxels;
-                    'process: loop {
-                        let size = (count - offset).min(VOXEL_QUEUE_SIZE);
-
-                        self.voxel_queue_gpu.bind(0);
-                        unsafe {
-                            ctx.gl.bind_buffer_base(foxtail::glow::SHADER_STORAGE_BUFFER, 1, Some(model.vox_buf.buf()));
-                        }
-
-                        self.cs_place_model.set_uniforms(|uni| {
-                            uni.set_u32("offset", offset as u32);
-                            if j == 0 {
-                                uni.set_uvec4("pos", [prev.x, prev.y, prev.z, 0]);
-                            } else {
-                                uni.set_uvec4("pos", [new.x, new.y, new.z, 1]);
-                            }
-                        });
-                        self.cs_place_model.dispatch([size as u32, 1, 1]);
-                        ctx.fence();
-
-                        unsafe {
-                            ctx.gl.bind_buffer_base(foxtail::glow::SHADER_STORAGE_BUFFER, 1, None);
-                        }
-                        self.voxel_queue_gpu.unbind();
-
-                        self.process_internal(ctx, size as u32);
-
-                        offset += VOXEL_QUEUE_SIZE;
-                        if offset >= count {
-                            break 'process;
-                        }
-                    }
+        // Streamed in voxels go first and don't belong to any transaction, so everything queued
+        // in the meantime wins over them and undo doesn't take them out again
+        if !streamed.is_empty() {
+            let recording = self.recording.take();
+            self.write_voxels(ctx, &last_writes(&streamed));
+            self.recording = recording;
+        }
+
+        for segment in segments {
+            self.switch_transaction(segment.transaction);
+            for command in &commands[segment.commands] {
+                match command {
+                    Command::Voxels(voxels) => self.process_voxels(ctx, voxels),
+                    Command::Shape(command) => {
+                        self.ownership.flatten_shape(&command.shape);
+                        self.process_shape(ctx, *command);
+                    },
+                    Command::Model(command) => self.process_model_command(ctx, command),
+                    Command::Restore(voxels, owners) => self.process_restore(ctx, voxels, owners),
                 }
             }
-            self.model_queue.lock().unwrap().clear();
-        }
-
-        // Process voxel queue
-        {
-            puffin::profile_scope!("process_cpu_voxels");
-            let mut write_total: Vec<Vec<[u32; 4]>> = Vec::new();
-            {
-                let mut lock = self.voxel_queue.lock().unwrap();
-
-                for chunk in lock.chunks(VOXEL_QUEUE_SIZE) {
-                    let mut write_slice = Vec::new();
-                    for (voxel, wpos) in chunk {
-                        write_slice.push([wpos.x, wpos.y, wpos.z, voxel.0]);
-                    }
-                    write_total.push(write_slice);
-                }
+        }
+
+        self.process_reads(ctx);
+
+        self.process_instances(ctx);
+
+        self.process_dealloc(ctx);
+
+        if let Some(mirror) = &mut self.mirror {
+            mirror.process();
+        }
+
+        self.voxel_queue_gpu.clear();
+
+        let report = self.collect_stats(ctx, false);
+        self.unreported_processes += 1;
+        self.unreported_micros += start.elapsed().as_micros() as u64;
+        if self.pending_stats.is_none() {
+            self.snapshot_stats(ctx);
+        }
+        report
+    }
+
+    /// Waits for the GPU to finish everything `process` queued up so far and returns the report
+    /// of the calls that weren't reported yet. For tools and benchmarks, this stalls!
+    pub fn flush_report(&mut self, ctx: &Context) -> ProcessReport {
+        puffin::profile_function!();
+        let mut report = self.collect_stats(ctx, true);
+        if self.unreported_processes > 0 {
+            self.snapshot_stats(ctx);
+            report = self.collect_stats(ctx, true);
+        }
+        report.unwrap_or(self.last_report)
+    }
+
+    /// Ends the current stats period. The free counters get copied into the stats, then the
+    /// stats and the list of changed bricks get swapped with empty ones, so they can be read
+    /// back once the GPU gets here without waiting for it.
+    fn snapshot_stats(&mut self, ctx: &Context) {
+        self.stats_gpu.bind(8);
+        self.brick_pool_counter.bind(6);
+        self.layer0_pool_counter.bind(7);
+        self.shaders.cs_snapshot_stats.dispatch([1, 1, 1]);
+        barrier(ctx);
+        self.layer0_pool_counter.unbind();
+        self.brick_pool_counter.unbind();
+        self.stats_gpu.unbind();
+
+        // The buffers being swapped in were read back already, see `collect_stats`
+        std::mem::swap(&mut self.stats_gpu, &mut self.stats_readback);
+        std::mem::swap(&mut self.changed_bricks_gpu, &mut self.changed_bricks_readback);
+        self.stats_gpu.write(0, &[0; STAT_COUNT]);
+        // Skips 0 when wrapping around, that's what bricks that never changed have
+        self.frame = self.frame.wrapping_add(1).max(1);
+
+        let fence = unsafe { ctx.gl.fence_sync(foxtail::glow::SYNC_GPU_COMMANDS_COMPLETE, 0) }.expect("Failed to create fence!");
+        self.pending_stats = Some(PendingStats {
+            fence: GpuFence(fence),
+            processes: std::mem::take(&mut self.unreported_processes),
+            process_micros: std::mem::take(&mut self.unreported_micros),
+        });
+    }
 
-                // TODO: This doesn't actually free up the allocated memory it seems.
-                //       Might be worth looking into freeing up that memory. If users
-                //       queue up a lot of voxels, it could permanently take up a lot of memory
-                lock.clear();
+    /// Turns the stats of the last snapshot into a report, and tells the subscriptions which
+    /// bricks changed. Returns None if there is no snapshot, or if `wait` is false and the GPU
+    /// isn't done with it yet.
+    fn collect_stats(&mut self, ctx: &Context, wait: bool) -> Option<ProcessReport> {
+        let pending = self.pending_stats.as_ref()?;
+        unsafe {
+            if !wait && ctx.gl.get_sync_status(pending.fence.0) != foxtail::glow::SIGNALED {
+                return None;
             }
+            ctx.gl.delete_sync(pending.fence.0);
+        }
+        let pending = self.pending_stats.take().unwrap();
 
-            for slice in write_total {
-                let size = slice.len();
-                self.write_queue_to_gpu(slice);
-                self.process_internal(ctx, size as u32);
+        let stats = read_buffer(ctx, &self.stats_readback, 0, STAT_COUNT);
+        let mut report = ProcessReport::from_stats(&stats, self.config.brick_pool_size, self.config.layer0_pool_size);
+        report.processes = pending.processes;
+        report.process_micros = pending.process_micros;
+        if report.voxels_written as usize > self.config.voxel_queue_size {
+            debug!("Wrote {} voxels in {:.2}ms ({:.1}M voxels/s)", report.voxels_written, report.process_micros as f64 / 1000.0, report.voxels_per_second() / 1_000_000.0);
+        }
+        if report.pool_exhausted() {
+            warn!("Brick pool exhausted, {} voxels were dropped! ({} bricks free, {} layer0 nodes free)", report.voxels_dropped, report.bricks_free, report.layer0s_free);
+            for callback in &mut self.pool_exhausted_callbacks {
+                callback(&report);
             }
         }
+        // Freeing bricks is the only thing that fragments the pool, so only check after that
+        if let Some(threshold) = self.compaction_threshold {
+            if report.bricks_freed > 0 && self.brick_fragmentation(ctx) > threshold {
+                report.bricks_moved = self.compact_bricks(ctx) as u32;
+            }
+        }
+        self.last_report = report;
 
-        self.process_dealloc(ctx);
+        self.changed_bricks.clear();
+        if report.bricks_changed > 0 {
+            let changed = read_buffer(ctx, &self.changed_bricks_readback, 0, report.bricks_changed as usize);
+            let origin = self.config.origin;
+            self.changed_bricks.extend(changed.iter().map(|brick| ivec3(brick[0] as i32, brick[1] as i32, brick[2] as i32) * 16 - origin));
+        }
+        self.subscriptions.notify(&self.changed_bricks, std::mem::take(&mut self.world_replaced));
 
-        self.voxel_queue_gpu.clear();
+        Some(report)
     }
 }
+
+/// Reads the slots of a pool from the lowest up to the highest of the given indices, which are
+/// offset by 1 like everything pointing into the pools. Returns the index of the first slot that
+/// was read, also offset by 1, along with the slots. Stalls until the GPU is done!
+fn read_pool_slots<T: Copy, I: Iterator<Item = u32>>(ctx: &Context, pool: &FixedSizeBuffer<T>, indices: I) -> (usize, Vec<T>) {
+    let (min, max) = indices.fold((u32::MAX, 0), |(min, max), idx| (min.min(idx), max.max(idx)));
+    if max == 0 { return (1, Vec::new()); }
+    (min as usize, read_buffer(ctx, pool, min as usize - 1, (max - min) as usize + 1))
+}
+
+/// Turns voxels read from a region into a model, with `min` as the model's origin
+pub(crate) fn region_to_model(voxels: Vec<(Voxel, IVec3)>, min: IVec3) -> Model {
+    Model::from_voxels(voxels.into_iter().map(|(voxel, pos)| (voxel, (pos - min).as_uvec3())).collect())
+}
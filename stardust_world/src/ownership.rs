@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+use crate::Shape;
+
+/// Something that places models in the world, like an entity. Every owner has at most one
+/// model placed at a time. Created with `World::create_owner`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VoxelOwner(u64);
+
+#[derive(Default)]
+pub(crate) struct OwnerIds(AtomicU64);
+
+impl OwnerIds {
+    pub(crate) fn next(&self) -> VoxelOwner {
+        VoxelOwner(self.0.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Everything placed at a single position, the last layer is what's in the world
+#[derive(Debug, Clone)]
+pub(crate) struct VoxelStack {
+    /// The voxel that was there before any model covered it
+    base: Voxel,
+    layers: Vec<(VoxelOwner, Voxel)>,
+}
+
+impl VoxelStack {
+    fn top(&self) -> Voxel {
+        self.layers.last().map(|(_, voxel)| *voxel).unwrap_or(self.base)
+    }
+}
+
+impl PartialEq for VoxelStack {
+    fn eq(&self, other: &Self) -> bool {
+        self.base.0 == other.base.0 && self.layers.len() == other.layers.len()
+            && self.layers.iter().zip(&other.layers).all(|(a, b)| a.0 == b.0 && a.1.0 == b.1.0)
+    }
+}
+
+/// Tracks which owner placed each voxel that's covered by a model, and what was underneath.
+/// Positions no model ever covered aren't stored, they belong to the base terrain.
+#[derive(Default)]
+pub(crate) struct Ownership {
+    stacks: HashMap<IVec3, VoxelStack>,
+    owned: HashMap<VoxelOwner, HashSet<IVec3>>,
+    /// Stacks as they were before they first changed since `start_recording`
+    recorded: Option<HashMap<IVec3, Option<VoxelStack>>>,
+}
+
+impl Ownership {
+    /// Puts a voxel of the owner's model on top of the position. `current` is the voxel in the
+    /// world right now, which becomes the base if no other model covers the position yet.
+    pub(crate) fn place(&mut self, owner: VoxelOwner, pos: IVec3, voxel: Voxel, current: Voxel) {
+        self.remember(pos);
+        let stack = self.stacks.entry(pos).or_insert_with(|| VoxelStack { base: current, layers: Vec::new() });
+        stack.layers.retain(|(layer_owner, _)| *layer_owner != owner);
+        stack.layers.push((owner, voxel));
+        self.owned.entry(owner).or_default().insert(pos);
+    }
+
+    /// Takes away everything the owner placed. Returns the writes that reveal what's underneath,
+    /// positions where another model is on top are left alone.
+    pub(crate) fn remove(&mut self, owner: VoxelOwner) -> Vec<(Voxel, IVec3)> {
+        let mut writes = Vec::new();
+        for pos in self.owned.remove(&owner).unwrap_or_default() {
+            self.remember(pos);
+            let stack = match self.stacks.get_mut(&pos) {
+                Some(stack) => stack,
+                None => continue,
+            };
+            let was_top = stack.layers.last().map(|(layer_owner, _)| *layer_owner == owner).unwrap_or(false);
+            stack.layers.retain(|(layer_owner, _)| *layer_owner != owner);
+            if was_top {
+                writes.push((stack.top(), pos));
+            }
+            if stack.layers.is_empty() {
+                self.stacks.remove(&pos);
+            }
+        }
+        writes
+    }
+
+    /// A voxel was written directly, so whatever is there now becomes part of the base terrain.
+    /// Removing the models that covered it won't touch it anymore.
+    pub(crate) fn flatten(&mut self, pos: IVec3) {
+        if !self.stacks.contains_key(&pos) { return; }
+        self.remember(pos);
+        if let Some(stack) = self.stacks.remove(&pos) {
+            for (owner, _) in stack.layers {
+                if let Some(owned) = self.owned.get_mut(&owner) {
+                    owned.remove(&pos);
+                }
+            }
+        }
+    }
+
+    /// Same as `flatten`, for every position inside of a shape
+    pub(crate) fn flatten_shape(&mut self, shape: &Shape) {
+        if self.stacks.is_empty() { return; }
+        let covered: Vec<IVec3> = self.stacks.keys().copied().filter(|pos| shape.contains(*pos)).collect();
+        for pos in covered {
+            self.flatten(pos);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Forgets everything, for when the whole world gets replaced
+    pub(crate) fn clear(&mut self) {
+        self.stacks.clear();
+        self.owned.clear();
+        self.recorded = None;
+    }
+
+    /// Starts keeping track of the stacks that change, for the transaction being recorded
+    pub(crate) fn start_recording(&mut self) {
+        self.recorded = Some(HashMap::new());
+    }
+
+    /// Stops keeping track of the stacks that change. Returns every position whose stack changed
+    /// since `start_recording`, with the stack before and after, None meaning there was none.
+    pub(crate) fn finish_recording(&mut self) -> Vec<(IVec3, Option<VoxelStack>, Option<VoxelStack>)> {
+        let recorded = self.recorded.take().unwrap_or_default();
+        recorded.into_iter()
+            .map(|(pos, before)| (pos, before, self.stacks.get(&pos).cloned()))
+            .filter(|(_, before, after)| before != after)
+            .collect()
+    }
+
+    /// Puts back a stack recorded by `finish_recording`, for undoing and redoing transactions.
+    /// The voxel in the world has to be put back separately.
+    pub(crate) fn restore(&mut self, pos: IVec3, stack: Option<VoxelStack>) {
+        self.remember(pos);
+        if let Some(old) = self.stacks.remove(&pos) {
+            for (owner, _) in old.layers {
+                if let Some(owned) = self.owned.get_mut(&owner) {
+                    owned.remove(&pos);
+                }
+            }
+        }
+        if let Some(stack) = stack {
+            for (owner, _) in &stack.layers {
+                self.owned.entry(*owner).or_default().insert(pos);
+            }
+            self.stacks.insert(pos, stack);
+        }
+    }
+
+    fn remember(&mut self, pos: IVec3) {
+        if let Some(recorded) = &mut self.recorded {
+            let stacks = &self.stacks;
+            recorded.entry(pos).or_insert_with(|| stacks.get(&pos).cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(i: u32) -> Voxel {
+        Voxel(i)
+    }
+
+    fn writes(writes: Vec<(Voxel, IVec3)>) -> Vec<(u32, IVec3)> {
+        let mut writes: Vec<(u32, IVec3)> = writes.into_iter().map(|(voxel, pos)| (voxel.0, pos)).collect();
+        writes.sort_by_key(|(_, pos)| (pos.x, pos.y, pos.z));
+        writes
+    }
+
+    #[test]
+    fn overlapping_owners() {
+        let ids = OwnerIds::default();
+        let (a, b) = (ids.next(), ids.next());
+        let mut ownership = Ownership::default();
+        // a covers x 0..2 on top of terrain 1, b covers x 1..3 on top of a and empty space
+        ownership.place(a, ivec3(0, 0, 0), voxel(10), voxel(1));
+        ownership.place(a, ivec3(1, 0, 0), voxel(10), voxel(1));
+        ownership.place(b, ivec3(1, 0, 0), voxel(20), voxel(10));
+        ownership.place(b, ivec3(2, 0, 0), voxel(20), voxel(0));
+
+        // Removing the top model reveals the one underneath where they overlap
+        assert_eq!(writes(ownership.remove(b)), vec![(10, ivec3(1, 0, 0)), (0, ivec3(2, 0, 0))]);
+        assert_eq!(writes(ownership.remove(a)), vec![(1, ivec3(0, 0, 0)), (1, ivec3(1, 0, 0))]);
+        assert!(ownership.is_empty());
+        // Removing twice, or an owner that never placed anything, does nothing
+        assert!(ownership.remove(a).is_empty());
+        assert!(ownership.remove(ids.next()).is_empty());
+    }
+
+    #[test]
+    fn placing_again_replaces_the_layer() {
+        let ids = OwnerIds::default();
+        let (a, b) = (ids.next(), ids.next());
+        let mut ownership = Ownership::default();
+        let pos = ivec3(0, 0, 0);
+        ownership.place(a, pos, voxel(10), voxel(1));
+        ownership.place(b, pos, voxel(20), voxel(10));
+        // a moves back onto the same spot and ends up on top, the base stays
+        ownership.place(a, pos, voxel(11), voxel(20));
+        assert_eq!(writes(ownership.remove(a)), vec![(20, pos)]);
+        assert_eq!(writes(ownership.remove(b)), vec![(1, pos)]);
+    }
+
+    #[test]
+    fn removing_an_owner_that_is_not_on_top() {
+        let ids = OwnerIds::default();
+        let (a, b) = (ids.next(), ids.next());
+        let mut ownership = Ownership::default();
+        let pos = ivec3(5, 5, 5);
+        ownership.place(a, pos, voxel(10), voxel(1));
+        ownership.place(b, pos, voxel(20), voxel(10));
+
+        // b stays visible, nothing gets written
+        assert!(ownership.remove(a).is_empty());
+        // and once b goes, the terrain shows up instead of a
+        assert_eq!(writes(ownership.remove(b)), vec![(1, pos)]);
+        assert!(ownership.is_empty());
+    }
+
+    #[test]
+    fn flattening_under_a_model() {
+        let ids = OwnerIds::default();
+        let (a, b) = (ids.next(), ids.next());
+        let mut ownership = Ownership::default();
+        ownership.place(a, ivec3(0, 0, 0), voxel(10), voxel(1));
+        ownership.place(a, ivec3(1, 0, 0), voxel(10), voxel(1));
+        ownership.place(b, ivec3(1, 0, 0), voxel(20), voxel(10));
+
+        // A voxel written directly becomes terrain, neither model touches it anymore
+        ownership.flatten(ivec3(1, 0, 0));
+        assert!(ownership.remove(b).is_empty());
+        assert_eq!(writes(ownership.remove(a)), vec![(1, ivec3(0, 0, 0))]);
+        assert!(ownership.is_empty());
+
+        // Same for shapes, positions outside of the shape keep their stacks
+        ownership.place(a, ivec3(0, 0, 0), voxel(10), voxel(1));
+        ownership.place(a, ivec3(4, 0, 0), voxel(10), voxel(1));
+        ownership.flatten_shape(&Shape::Box { min: ivec3(0, 0, 0), max: ivec3(2, 1, 1) });
+        assert_eq!(writes(ownership.remove(a)), vec![(1, ivec3(4, 0, 0))]);
+        // Flattening where nothing is placed does nothing
+        ownership.flatten(ivec3(9, 9, 9));
+        assert!(ownership.is_empty());
+    }
+
+    #[test]
+    fn restoring_recorded_stacks() {
+        let ids = OwnerIds::default();
+        let (a, b) = (ids.next(), ids.next());
+        let pos = ivec3(1, 2, 3);
+        let mut ownership = Ownership::default();
+        ownership.place(a, pos, voxel(10), voxel(1));
+
+        ownership.start_recording();
+        ownership.place(b, pos, voxel(20), voxel(10));
+        ownership.place(b, ivec3(4, 5, 6), voxel(20), voxel(2));
+        ownership.flatten(ivec3(7, 7, 7));
+        let changes = ownership.finish_recording();
+        assert_eq!(changes.len(), 2);
+        let (_, before, after) = changes.iter().find(|(p, _, _)| *p == pos).unwrap().clone();
+        assert_eq!(before.as_ref().unwrap().layers.len(), 1);
+        assert_eq!(after.as_ref().unwrap().layers.len(), 2);
+
+        // Undo
+        for (pos, before, _) in &changes {
+            ownership.restore(*pos, before.clone());
+        }
+        assert!(!ownership.stacks.contains_key(&ivec3(4, 5, 6)));
+        assert!(ownership.remove(b).is_empty());
+        // Removing the model underneath reveals the terrain again
+        assert_eq!(ownership.remove(a).iter().map(|(v, p)| (v.0, *p)).collect::<Vec<_>>(), vec![(1, pos)]);
+
+        // Redo, on top of what's there now
+        for (pos, _, after) in &changes {
+            ownership.restore(*pos, after.clone());
+        }
+        let mut writes: Vec<(u32, IVec3)> = ownership.remove(b).iter().map(|(v, p)| (v.0, *p)).collect();
+        writes.sort_by_key(|(_, p)| p.x);
+        assert_eq!(writes, vec![(10, pos), (2, ivec3(4, 5, 6))]);
+        assert_eq!(ownership.remove(a).iter().map(|(v, p)| (v.0, *p)).collect::<Vec<_>>(), vec![(1, pos)]);
+        assert!(ownership.is_empty());
+    }
+
+    #[test]
+    fn nothing_is_recorded_unless_asked() {
+        let ids = OwnerIds::default();
+        let mut ownership = Ownership::default();
+        ownership.place(ids.next(), IVec3::ZERO, voxel(1), voxel(0));
+        assert!(ownership.finish_recording().is_empty());
+
+        // Changes that end up where they started aren't reported
+        let owner = ids.next();
+        ownership.start_recording();
+        ownership.place(owner, IVec3::ONE, voxel(1), voxel(0));
+        ownership.remove(owner);
+        assert!(ownership.finish_recording().is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use foxtail::prelude::*;
+
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+/// Reads `count` elements starting at `offset` back from a GPU buffer.
+/// This stalls until the GPU is done writing to the buffer!
+pub(crate) fn read_buffer<T: Copy>(ctx: &Context, buf: &FixedSizeBuffer<T>, offset: usize, count: usize) -> Vec<T> {
+    puffin::profile_function!();
+    let mut data: Vec<T> = Vec::with_capacity(count);
+    unsafe {
+        let bytes = std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, count * std::mem::size_of::<T>());
+        ctx.gl.bind_buffer(foxtail::glow::SHADER_STORAGE_BUFFER, Some(buf.buf()));
+        ctx.gl.get_buffer_sub_data(foxtail::glow::SHADER_STORAGE_BUFFER, (offset * std::mem::size_of::<T>()) as i32, bytes);
+        ctx.gl.bind_buffer(foxtail::glow::SHADER_STORAGE_BUFFER, None);
+        data.set_len(count);
+    }
+    data
+}
+
+/// Sync object marking the end of commands the CPU has to wait for, like a batched voxel read.
+pub(crate) struct GpuFence(pub(crate) foxtail::glow::NativeFence);
+
+// SAFETY: The fence is only ever touched from `World::process`, which runs on the main thread.
+//         It's only stored here so the world can still be shared with other threads for queueing.
+unsafe impl Send for GpuFence {}
+unsafe impl Sync for GpuFence {}
+
+/// Copy of a batch in the voxel queue and the voxels it's about to overwrite, see
+/// `World::read_batch`. Copying lets the GPU move on to the next batch, and the copy can be
+/// read back once the GPU gets there instead of stalling right away.
+pub(crate) struct BatchReadback {
+    queue: FixedSizeBuffer<[u32; 4]>,
+    overwritten: FixedSizeBuffer<u32>,
+    size: usize,
+    fence: Option<GpuFence>,
+}
+
+impl BatchReadback {
+    /// MUST BE RUN FROM THE MAIN THREAD
+    pub(crate) fn new(ctx: &Context, capacity: usize) -> Self {
+        Self {
+            queue: FixedSizeBuffer::new(ctx, capacity),
+            overwritten: FixedSizeBuffer::new(ctx, capacity),
+            size: 0,
+            fence: None,
+        }
+    }
+
+    /// Copies the first `size` entries of both buffers, once the commands before are done.
+    /// The buffers can be written again right after.
+    pub(crate) fn copy(&mut self, ctx: &Context, queue: &FixedSizeBuffer<[u32; 4]>, overwritten: &FixedSizeBuffer<u32>, size: usize) {
+        unsafe {
+            copy_buffer(ctx, queue.buf(), self.queue.buf(), size * std::mem::size_of::<[u32; 4]>());
+            copy_buffer(ctx, overwritten.buf(), self.overwritten.buf(), size * std::mem::size_of::<u32>());
+            if let Some(fence) = self.fence.take() {
+                ctx.gl.delete_sync(fence.0);
+            }
+            self.fence = ctx.gl.fence_sync(foxtail::glow::SYNC_GPU_COMMANDS_COMPLETE, 0).ok().map(GpuFence);
+        }
+        self.size = size;
+    }
+
+    /// Waits for the last copy and reads it back
+    pub(crate) fn read(&mut self, ctx: &Context) -> (Vec<[u32; 4]>, Vec<u32>) {
+        puffin::profile_function!();
+        if let Some(fence) = self.fence.take() {
+            unsafe {
+                while ctx.gl.client_wait_sync(fence.0, foxtail::glow::SYNC_FLUSH_COMMANDS_BIT, 1_000_000) == foxtail::glow::TIMEOUT_EXPIRED {}
+                ctx.gl.delete_sync(fence.0);
+            }
+        }
+        (read_buffer(ctx, &self.queue, 0, self.size), read_buffer(ctx, &self.overwritten, 0, self.size))
+    }
+
+    /// Deletes the fence of a copy that was never read back
+    pub(crate) fn destroy(&mut self, ctx: &Context) {
+        if let Some(fence) = self.fence.take() {
+            unsafe { ctx.gl.delete_sync(fence.0); }
+        }
+        self.size = 0;
+    }
+}
+
+unsafe fn copy_buffer(ctx: &Context, from: foxtail::glow::NativeBuffer, to: foxtail::glow::NativeBuffer, bytes: usize) {
+    ctx.gl.bind_buffer(foxtail::glow::COPY_READ_BUFFER, Some(from));
+    ctx.gl.bind_buffer(foxtail::glow::COPY_WRITE_BUFFER, Some(to));
+    ctx.gl.copy_buffer_sub_data(foxtail::glow::COPY_READ_BUFFER, foxtail::glow::COPY_WRITE_BUFFER, 0, 0, bytes as i32);
+    ctx.gl.bind_buffer(foxtail::glow::COPY_WRITE_BUFFER, None);
+    ctx.gl.bind_buffer(foxtail::glow::COPY_READ_BUFFER, None);
+}
+
+/// Stats of the `process` calls since the last report, on their way back from the GPU
+pub(crate) struct PendingStats {
+    pub(crate) fence: GpuFence,
+    pub(crate) processes: u32,
+    pub(crate) process_micros: u64,
+}
+
+/// Handle to a batched voxel read, returned by `World::request_voxels`.
+/// The results become available after one or more calls to `World::process`.
+#[derive(Clone)]
+pub struct VoxelReadHandle(Arc<Mutex<Option<Vec<Voxel>>>>);
+
+impl VoxelReadHandle {
+    pub fn is_ready(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Takes the results, in the same order as the requested positions.
+    /// Returns None if the read hasn't finished yet, or if the results were already taken.
+    pub fn try_take(&self) -> Option<Vec<Voxel>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+pub(crate) struct PendingRead {
+    pub(crate) positions: Vec<IVec3>,
+    pub(crate) results: Vec<Voxel>,
+    handle: VoxelReadHandle,
+}
+
+impl PendingRead {
+    pub(crate) fn new(positions: Vec<IVec3>) -> (Self, VoxelReadHandle) {
+        let handle = VoxelReadHandle(Arc::new(Mutex::new(None)));
+        let read = Self {
+            results: Vec::with_capacity(positions.len()),
+            positions,
+            handle: handle.clone(),
+        };
+        (read, handle)
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.positions.len() - self.results.len()
+    }
+
+    pub(crate) fn finish(self) {
+        *self.handle.0.lock().unwrap() = Some(self.results);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+
+use crate::GpuModel;
+
+/// Stable handle to a registered model, returned by `World::register_model`.
+/// Registering a model under a name that's already taken keeps the id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModelId(u32);
+
+/// What happened to a model that is no longer the registered one
+pub enum ModelChange<M = GpuModel> {
+    /// A model got registered under the same name, like when the file got reloaded
+    Replaced(Arc<M>),
+    Unregistered,
+}
+
+/// Anything the registry can hold, which is just `GpuModel` outside of tests
+pub(crate) trait RegistryEntry {
+    fn name(&self) -> &str;
+}
+
+impl RegistryEntry for GpuModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Every model the world knows about, keyed by name. Models that got replaced or unregistered
+/// are only remembered weakly, so their GPU buffers are released as soon as nothing uses them anymore.
+pub(crate) struct ModelRegistry<M = GpuModel> {
+    models: BTreeMap<ModelId, Arc<M>>,
+    names: HashMap<String, ModelId>,
+    next_id: u32,
+
+    /// Models that are no longer registered, with the id they were registered under
+    retired: Vec<(Weak<M>, ModelId)>,
+    /// Bumped whenever a model gets replaced or unregistered
+    generation: u64,
+}
+
+impl<M> Default for ModelRegistry<M> {
+    fn default() -> Self {
+        Self {
+            models: BTreeMap::new(),
+            names: HashMap::new(),
+            next_id: 0,
+
+            retired: Vec::new(),
+            generation: 0,
+        }
+    }
+}
+
+impl<M: RegistryEntry> ModelRegistry<M> {
+    pub(crate) fn register(&mut self, model: Arc<M>) -> ModelId {
+        if let Some(&id) = self.names.get(model.name()) {
+            if let Some(old) = self.models.insert(id, model) {
+                if !Arc::ptr_eq(&old, &self.models[&id]) {
+                    self.retire(old, id);
+                }
+            }
+            return id;
+        }
+        let id = ModelId(self.next_id);
+        self.next_id += 1;
+        self.names.insert(model.name().to_string(), id);
+        self.models.insert(id, model);
+        id
+    }
+
+    pub(crate) fn unregister(&mut self, id: ModelId) -> bool {
+        match self.models.remove(&id) {
+            Some(model) => {
+                self.names.remove(model.name());
+                self.retire(model, id);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn retire(&mut self, model: Arc<M>, id: ModelId) {
+        self.retired.retain(|(old, _)| old.strong_count() > 0);
+        self.retired.push((Arc::downgrade(&model), id));
+        self.generation += 1;
+    }
+
+    pub(crate) fn get(&self, id: ModelId) -> Option<&Arc<M>> {
+        self.models.get(&id)
+    }
+
+    pub(crate) fn find(&self, name: &str) -> Option<ModelId> {
+        self.names.get(name).copied()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ModelId, &Arc<M>)> {
+        self.models.iter().map(|(id, model)| (*id, model))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Follows a model that's no longer registered to whatever took its place.
+    /// Returns `None` for models that are still registered, or never were.
+    pub(crate) fn change(&self, model: &Arc<M>) -> Option<ModelChange<M>> {
+        let ptr = Arc::as_ptr(model);
+        let id = self.retired.iter().find(|(old, _)| old.as_ptr() == ptr).map(|(_, id)| *id)?;
+        Some(match self.models.get(&id) {
+            Some(current) if Arc::ptr_eq(current, model) => return None,
+            Some(current) => ModelChange::Replaced(Arc::clone(current)),
+            None => ModelChange::Unregistered,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestModel(String);
+
+    impl RegistryEntry for TestModel {
+        fn name(&self) -> &str {
+            &self.0
+        }
+    }
+
+    fn model(name: &str) -> Arc<TestModel> {
+        Arc::new(TestModel(name.to_string()))
+    }
+
+    #[test]
+    fn replacing_keeps_the_id() {
+        let mut registry = ModelRegistry::default();
+        let tree = model("tree.sdvx");
+        let id = registry.register(Arc::clone(&tree));
+        let rock = registry.register(model("rock.sdvx"));
+        assert_ne!(id, rock);
+        assert!(registry.change(&tree).is_none());
+        assert_eq!(registry.generation(), 0);
+
+        // Registering the same model again changes nothing
+        assert_eq!(registry.register(Arc::clone(&tree)), id);
+        assert_eq!(registry.generation(), 0);
+
+        let reloaded = model("tree.sdvx");
+        assert_eq!(registry.register(Arc::clone(&reloaded)), id);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.generation(), 1);
+        assert!(Arc::ptr_eq(registry.get(id).unwrap(), &reloaded));
+        assert_eq!(registry.find("tree.sdvx"), Some(id));
+        match registry.change(&tree) {
+            Some(ModelChange::Replaced(new)) => assert!(Arc::ptr_eq(&new, &reloaded)),
+            _ => panic!("The old model should be replaced"),
+        }
+        assert!(registry.change(&reloaded).is_none());
+
+        // Replaced twice, the oldest one follows along to the newest
+        let again = model("tree.sdvx");
+        registry.register(Arc::clone(&again));
+        match registry.change(&tree) {
+            Some(ModelChange::Replaced(new)) => assert!(Arc::ptr_eq(&new, &again)),
+            _ => panic!("The old model should be replaced"),
+        }
+    }
+
+    #[test]
+    fn unregistering() {
+        let mut registry = ModelRegistry::default();
+        let tree = model("tree.sdvx");
+        let id = registry.register(Arc::clone(&tree));
+        assert!(registry.unregister(id));
+        assert!(!registry.unregister(id));
+        assert!(registry.get(id).is_none());
+        assert_eq!(registry.find("tree.sdvx"), None);
+        assert_eq!(registry.len(), 0);
+        assert_eq!(registry.generation(), 1);
+        assert!(matches!(registry.change(&tree), Some(ModelChange::Unregistered)));
+
+        // The registry doesn't keep it alive
+        let weak = Arc::downgrade(&tree);
+        drop(tree);
+        assert!(weak.upgrade().is_none());
+
+        // The name can be used again, with a new id
+        let new_id = registry.register(model("tree.sdvx"));
+        assert_ne!(new_id, id);
+    }
+
+    #[test]
+    fn change_of_unknown_models() {
+        let mut registry = ModelRegistry::default();
+        registry.register(model("tree.sdvx"));
+        assert!(registry.change(&model("tree.sdvx")).is_none());
+        assert!(registry.change(&model("rock.sdvx")).is_none());
+    }
+
+    #[test]
+    fn iterates_in_id_order() {
+        let mut registry = ModelRegistry::default();
+        let ids: Vec<ModelId> = ["c", "a", "b"].iter().map(|name| registry.register(model(name))).collect();
+        let names: Vec<(ModelId, &str)> = registry.iter().map(|(id, model)| (id, model.name())).collect();
+        assert_eq!(names, vec![(ids[0], "c"), (ids[1], "a"), (ids[2], "b")]);
+    }
+}
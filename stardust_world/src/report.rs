@@ -0,0 +1,81 @@
+// Indices into the stats buffer, must match the STAT_ defines in the compute shaders
+pub(crate) const STAT_VOXELS_WRITTEN: usize = 0;
+pub(crate) const STAT_VOXELS_DROPPED: usize = 1;
+pub(crate) const STAT_BRICKS_ALLOCATED: usize = 2;
+pub(crate) const STAT_BRICKS_FREED: usize = 3;
+pub(crate) const STAT_LAYER0S_ALLOCATED: usize = 4;
+pub(crate) const STAT_BRICKS_CHANGED: usize = 5;
+// Copies of the free counters, taken when the stats get read back
+pub(crate) const STAT_BRICKS_FREE: usize = 6;
+pub(crate) const STAT_LAYER0S_FREE: usize = 7;
+pub(crate) const STAT_COUNT: usize = 8;
+
+/// What happened during one or more `process` calls. `World` reads its stats back once the GPU
+/// is done with them instead of waiting for it, so a report can cover a few calls.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProcessReport {
+    /// Amount of `process` calls the report covers
+    pub processes: u32,
+
+    /// Voxels placed or removed
+    pub voxels_written: u32,
+    /// Voxels that could not be placed because the brick or layer0 pool ran out
+    pub voxels_dropped: u32,
+
+    pub bricks_allocated: u32,
+    pub bricks_freed: u32,
+    pub layer0s_allocated: u32,
+    /// Bricks where at least one voxel changed, see `World::changed_bricks`
+    pub bricks_changed: u32,
+    /// Bricks moved to the front of the pool, see `World::set_compaction_threshold`
+    pub bricks_moved: u32,
+
+    /// Bricks in use after processing
+    pub bricks_used: u32,
+    pub bricks_free: u32,
+    /// Layer0 nodes in use after processing
+    pub layer0s_used: u32,
+    pub layer0s_free: u32,
+
+    /// Time spent in the `process` calls, in microseconds. This is time spent on the CPU,
+    /// the GPU keeps working on what they queued up afterwards
+    pub process_micros: u64,
+}
+
+impl ProcessReport {
+    pub(crate) fn from_stats(stats: &[u32], brick_pool_size: usize, layer0_pool_size: usize) -> Self {
+        let bricks_free = stats[STAT_BRICKS_FREE];
+        let layer0s_free = stats[STAT_LAYER0S_FREE];
+        Self {
+            processes: 1,
+
+            voxels_written: stats[STAT_VOXELS_WRITTEN],
+            voxels_dropped: stats[STAT_VOXELS_DROPPED],
+
+            bricks_allocated: stats[STAT_BRICKS_ALLOCATED],
+            bricks_freed: stats[STAT_BRICKS_FREED],
+            layer0s_allocated: stats[STAT_LAYER0S_ALLOCATED],
+            bricks_changed: stats[STAT_BRICKS_CHANGED],
+            bricks_moved: 0,
+
+            bricks_used: brick_pool_size as u32 - bricks_free,
+            bricks_free,
+            layer0s_used: layer0_pool_size as u32 - layer0s_free,
+            layer0s_free,
+
+            process_micros: 0,
+        }
+    }
+
+    /// Rate at which the `process` calls got through voxels, on the CPU side. Only meaningful
+    /// for large batches. Time `World::flush_report` to include the GPU's part of the work
+    pub fn voxels_per_second(&self) -> f64 {
+        if self.process_micros == 0 { return 0.0; }
+        self.voxels_written as f64 * 1_000_000.0 / self.process_micros as f64
+    }
+
+    /// True if voxels got dropped because one of the pools ran out
+    pub fn pool_exhausted(&self) -> bool {
+        self.voxels_dropped > 0
+    }
+}
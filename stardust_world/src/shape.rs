@@ -0,0 +1,212 @@
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+/// A region of the world that shape commands operate on. Positions are in world space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shape {
+    /// All voxels from `min` up to (but not including) `max`
+    Box { min: IVec3, max: IVec3 },
+    /// All voxels closer than `radius` to `center`
+    Sphere { center: IVec3, radius: u32 },
+    /// Upright cylinder, with the center of its bottom at `base`
+    Cylinder { base: IVec3, radius: u32, height: u32 },
+}
+
+impl Shape {
+    /// Returns the bounding box of the shape, `max` is exclusive
+    pub fn bounds(&self) -> (IVec3, IVec3) {
+        match *self {
+            Self::Box { min, max } => (min, max.max(min)),
+            Self::Sphere { center, radius } => {
+                let r = IVec3::splat(radius as i32);
+                (center - r, center + r + IVec3::ONE)
+            },
+            Self::Cylinder { base, radius, height } => {
+                let r = radius as i32;
+                (base - ivec3(r, 0, r), base + ivec3(r + 1, height as i32, r + 1))
+            },
+        }
+    }
+
+    /// Amount of voxels in the bounding box
+    pub fn bounds_volume(&self) -> usize {
+        let (min, max) = self.bounds();
+        let size = (max - min).as_uvec3();
+        size.x as usize * size.y as usize * size.z as usize
+    }
+
+    /// Mirrors `inShape` in cs_expand_shape.glsl
+    pub fn contains(&self, pos: IVec3) -> bool {
+        let (min, max) = self.bounds();
+        if pos.cmplt(min).any() || pos.cmpge(max).any() { return false; }
+        match *self {
+            Self::Box { .. } => true,
+            Self::Sphere { center, radius } => {
+                let d = pos - center;
+                let (x, y, z) = (d.x as i64, d.y as i64, d.z as i64);
+                x * x + y * y + z * z < radius as i64 * radius as i64
+            },
+            Self::Cylinder { base, radius, .. } => {
+                let d = pos - base;
+                let (x, z) = (d.x as i64, d.z as i64);
+                x * x + z * z < radius as i64 * radius as i64
+            },
+        }
+    }
+
+    pub(crate) fn gpu_id(&self) -> u32 {
+        match self {
+            Self::Box { .. } => 0,
+            Self::Sphere { .. } => 1,
+            Self::Cylinder { .. } => 2,
+        }
+    }
+
+    /// xyz = center of the shape, w = radius
+    pub(crate) fn gpu_center(&self) -> [u32; 4] {
+        let (center, radius) = match *self {
+            Self::Box { min, .. } => (min, 0),
+            Self::Sphere { center, radius } => (center, radius),
+            Self::Cylinder { base, radius, .. } => (base, radius),
+        };
+        [center.x as u32, center.y as u32, center.z as u32, radius]
+    }
+}
+
+/// What a shape command does to the voxels inside of its shape
+#[derive(Debug, Copy, Clone)]
+pub enum ShapeOp {
+    /// Overwrites every voxel with the given voxel
+    Fill(Voxel),
+    /// Removes every voxel
+    Clear,
+    /// Changes the color of voxels with color `from` to `to`, keeping their other properties.
+    /// Colors are compared after converting them to rgb565, the same way `Voxel::new` does.
+    ReplaceColor { from: [u8; 3], to: [u8; 3] },
+}
+
+impl ShapeOp {
+    pub(crate) fn gpu_id(&self) -> u32 {
+        match self {
+            Self::Fill(_) => 0,
+            Self::Clear => 1,
+            Self::ReplaceColor { .. } => 2,
+        }
+    }
+
+    /// x = voxel to write (or rgb565 color to write), y = rgb565 color to replace
+    pub(crate) fn gpu_voxel(&self) -> [u32; 4] {
+        match *self {
+            Self::Fill(voxel) => [voxel.0, 0, 0, 0],
+            Self::Clear => [0; 4],
+            Self::ReplaceColor { from, to } => [rgb565(to), rgb565(from), 0, 0],
+        }
+    }
+
+    /// Returns the voxel that should end up at a position currently holding `current`,
+    /// or None if the voxel should be left alone. Mirrors cs_expand_shape.glsl
+    pub fn apply(&self, current: Voxel) -> Option<Voxel> {
+        match *self {
+            Self::Fill(voxel) => Some(voxel),
+            Self::Clear => Some(Voxel::empty()),
+            Self::ReplaceColor { from, to } => {
+                if current.0 == 0 || current.0 & 0xFFFF != rgb565(from) { return None; }
+                Some(Voxel((current.0 & !0xFFFF) | rgb565(to)))
+            },
+        }
+    }
+}
+
+fn rgb565(rgb: [u8; 3]) -> u32 {
+    Voxel::new(rgb, 0, 0, false, 0).0 & 0xFFFF
+}
+
+/// A shape command waiting to be expanded on the GPU
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ShapeCommand {
+    pub(crate) shape: Shape,
+    pub(crate) op: ShapeOp,
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::iproduct;
+
+    use super::*;
+
+    fn count(shape: Shape) -> usize {
+        let (min, max) = shape.bounds();
+        iproduct!(min.z..max.z, min.y..max.y, min.x..max.x).filter(|(z, y, x)| shape.contains(ivec3(*x, *y, *z))).count()
+    }
+
+    #[test]
+    fn box_contains() {
+        let shape = Shape::Box { min: ivec3(-2, 0, 3), max: ivec3(2, 1, 5) };
+        assert!(shape.contains(ivec3(-2, 0, 3)));
+        assert!(shape.contains(ivec3(1, 0, 4)));
+        assert!(!shape.contains(ivec3(2, 0, 4)));
+        assert!(!shape.contains(ivec3(0, -1, 4)));
+        assert_eq!(count(shape), 8);
+        assert_eq!(shape.bounds_volume(), 8);
+
+        // Inverted boxes are empty
+        let shape = Shape::Box { min: ivec3(2, 0, 0), max: ivec3(-2, 4, 4) };
+        assert_eq!(shape.bounds_volume(), 0);
+        assert!(!shape.contains(ivec3(0, 1, 1)));
+    }
+
+    #[test]
+    fn sphere_contains() {
+        let center = ivec3(10, -4, 7);
+        let shape = Shape::Sphere { center, radius: 3 };
+        assert!(shape.contains(center));
+        assert!(shape.contains(center + ivec3(2, 2, 0)));
+        // Exactly `radius` away is outside
+        assert!(!shape.contains(center + ivec3(3, 0, 0)));
+        assert!(!shape.contains(center + ivec3(0, 0, -3)));
+        assert_eq!(count(Shape::Sphere { center, radius: 1 }), 1);
+        assert_eq!(count(Shape::Sphere { center, radius: 0 }), 0);
+        // The corners of the 3x3x3 cube around the center are sqrt(3) away
+        assert_eq!(count(Shape::Sphere { center, radius: 2 }), 27);
+        // Squared distances below 9, which leaves out offsets like (2, 2, 1) and the corners
+        assert_eq!(count(Shape::Sphere { center, radius: 3 }), 125 - 3 * 8 - 8);
+    }
+
+    #[test]
+    fn cylinder_contains() {
+        let base = ivec3(0, 5, 0);
+        let shape = Shape::Cylinder { base, radius: 2, height: 3 };
+        assert!(shape.contains(base));
+        assert!(shape.contains(base + ivec3(1, 2, -1)));
+        assert!(!shape.contains(base + ivec3(0, 3, 0)));
+        assert!(!shape.contains(base + ivec3(0, -1, 0)));
+        assert!(!shape.contains(base + ivec3(2, 0, 0)));
+        assert_eq!(count(shape), 9 * 3);
+    }
+
+    #[test]
+    fn huge_radius_does_not_overflow() {
+        let shape = Shape::Sphere { center: IVec3::ZERO, radius: 1 << 20 };
+        assert!(shape.contains(ivec3(-(1 << 19), 1 << 19, 1 << 19)));
+        assert!(!shape.contains(IVec3::splat(1 << 20)));
+    }
+
+    #[test]
+    fn apply_ops() {
+        let red = Voxel::new([255, 0, 0], 200, 3, true, 255);
+        let blue = Voxel::new([0, 0, 255], 255, 0, false, 255);
+        assert_eq!(ShapeOp::Fill(blue).apply(red).map(|v| v.0), Some(blue.0));
+        assert_eq!(ShapeOp::Fill(blue).apply(Voxel::empty()).map(|v| v.0), Some(blue.0));
+        assert_eq!(ShapeOp::Clear.apply(red).map(|v| v.0), Some(0));
+
+        let replace = ShapeOp::ReplaceColor { from: [255, 0, 0], to: [0, 255, 0] };
+        let replaced = replace.apply(red).unwrap();
+        // Only the color changes
+        assert_eq!(replaced.0 & 0xFFFF, Voxel::new([0, 255, 0], 0, 0, false, 0).0 & 0xFFFF);
+        assert_eq!(replaced.0 & !0xFFFF, red.0 & !0xFFFF);
+        assert!(replace.apply(blue).is_none());
+        assert!(replace.apply(Voxel::empty()).is_none());
+        // Colors are compared as rgb565, so close colors match too
+        assert!(replace.apply(Voxel::new([254, 1, 2], 200, 3, true, 255)).is_some());
+    }
+}
@@ -0,0 +1,120 @@
+use foxtail::prelude::*;
+use foxtail::glow;
+
+use crate::readback::GpuFence;
+
+/// Amount of staging buffers the voxel queue cycles through, so the CPU can fill the next
+/// batch while the GPU is still working on the previous one
+pub(crate) const STAGING_SLOTS: usize = 2;
+
+/// Persistently mapped buffers the voxel queue gets written to, without going through
+/// `glBufferSubData`. A slot can only be written again once the GPU is done with everything
+/// that was submitted while it was in use, which is tracked with a fence per slot.
+pub(crate) struct StagingQueue {
+    slots: Vec<StagingSlot>,
+    capacity: usize,
+    current: usize,
+}
+
+struct StagingSlot {
+    buf: glow::NativeBuffer,
+    ptr: *mut [u32; 4],
+    fence: Option<GpuFence>,
+}
+
+// SAFETY: The mapped memory is only ever touched from `World::process`, which runs on the main thread.
+//         It's only stored here so the world can still be shared with other threads for queueing.
+unsafe impl Send for StagingQueue {}
+unsafe impl Sync for StagingQueue {}
+
+impl StagingQueue {
+    /// MUST BE RUN FROM THE MAIN THREAD
+    pub(crate) fn new(ctx: &Context, capacity: usize) -> Self {
+        let size = (capacity * std::mem::size_of::<[u32; 4]>()) as i32;
+        let flags = glow::MAP_WRITE_BIT | glow::MAP_READ_BIT | glow::MAP_PERSISTENT_BIT | glow::MAP_COHERENT_BIT;
+        let slots = (0..STAGING_SLOTS).map(|_| unsafe {
+            let buf = ctx.gl.create_buffer().expect("Failed to create staging buffer!");
+            ctx.gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, Some(buf));
+            ctx.gl.buffer_storage(glow::SHADER_STORAGE_BUFFER, size, None, flags);
+            let ptr = ctx.gl.map_buffer_range(glow::SHADER_STORAGE_BUFFER, 0, size, flags) as *mut [u32; 4];
+            ctx.gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, None);
+            StagingSlot {
+                buf,
+                ptr,
+                fence: None,
+            }
+        }).collect();
+        Self {
+            slots,
+            capacity,
+            current: 0,
+        }
+    }
+
+    /// Moves on to the next slot and copies the batch into it, waiting for the GPU only if it's
+    /// still using that slot. The slot stays current until the next call.
+    pub(crate) fn write(&mut self, ctx: &Context, batch: &[[u32; 4]]) {
+        puffin::profile_function!();
+        assert!(batch.len() <= self.capacity, "Batch doesn't fit in the staging buffer!");
+        self.current = (self.current + 1) % self.slots.len();
+        let slot = &mut self.slots[self.current];
+        if let Some(fence) = slot.fence.take() {
+            unsafe {
+                while ctx.gl.client_wait_sync(fence.0, glow::SYNC_FLUSH_COMMANDS_BIT, 1_000_000) == glow::TIMEOUT_EXPIRED {}
+                ctx.gl.delete_sync(fence.0);
+            }
+        }
+        // SAFETY: The mapping is coherent and the GPU is done with the slot, so nothing else touches this memory
+        unsafe {
+            std::ptr::copy_nonoverlapping(batch.as_ptr(), slot.ptr, batch.len());
+        }
+    }
+
+    /// The batch that was last written. Doesn't have to wait, the CPU wrote it
+    pub(crate) fn read(&self, count: usize) -> Vec<[u32; 4]> {
+        let slot = &self.slots[self.current];
+        // SAFETY: Shaders only ever read from the staging buffers
+        unsafe {
+            std::slice::from_raw_parts(slot.ptr, count.min(self.capacity)).to_vec()
+        }
+    }
+
+    pub(crate) fn bind(&self, ctx: &Context, index: u32) {
+        unsafe {
+            ctx.gl.bind_buffer_base(glow::SHADER_STORAGE_BUFFER, index, Some(self.slots[self.current].buf));
+        }
+    }
+
+    pub(crate) fn unbind(&self, ctx: &Context, index: u32) {
+        unsafe {
+            ctx.gl.bind_buffer_base(glow::SHADER_STORAGE_BUFFER, index, None);
+        }
+    }
+
+    /// Unmaps and deletes the staging buffers along with their fences. The queue can't be used
+    /// afterwards. MUST BE RUN FROM THE MAIN THREAD
+    pub(crate) fn destroy(&mut self, ctx: &Context) {
+        for slot in self.slots.drain(..) {
+            unsafe {
+                if let Some(fence) = slot.fence {
+                    ctx.gl.delete_sync(fence.0);
+                }
+                ctx.gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, Some(slot.buf));
+                ctx.gl.unmap_buffer(glow::SHADER_STORAGE_BUFFER);
+                ctx.gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, None);
+                ctx.gl.delete_buffer(slot.buf);
+            }
+        }
+        self.capacity = 0;
+        self.current = 0;
+    }
+
+    /// Marks the end of the commands that use the current slot
+    pub(crate) fn finish(&mut self, ctx: &Context) {
+        let slot = &mut self.slots[self.current];
+        if let Some(fence) = slot.fence.take() {
+            unsafe { ctx.gl.delete_sync(fence.0); }
+        }
+        slot.fence = unsafe { ctx.gl.fence_sync(glow::SYNC_GPU_COMMANDS_COMPLETE, 0) }.ok().map(GpuFence);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use foxtail::prelude::*;
+
+use stardust_common::math::*;
+
+use stardust_common::voxel::Voxel;
+
+use crate::{World, WorldConfig, VoxelBatch};
+use crate::brick::Brick;
+use crate::file::{region_from_bytes, region_to_bytes};
+
+const REGION_FILE_EXTENSION: &str = "sdwr";
+
+/// Settings for `WorldStreamer`
+#[derive(Debug, Clone)]
+pub struct StreamingConfig {
+    /// Directory evicted layer0 regions get written to. Created if it doesn't exist yet.
+    pub cache_dir: PathBuf,
+    /// Regions within this distance of the camera, in layer0 nodes (256 voxels), are streamed in
+    pub resident_radius: u32,
+    /// Regions further away than this get evicted. Should be larger than `resident_radius`,
+    /// so moving back and forth over a region border doesn't keep loading and evicting regions.
+    pub evict_radius: u32,
+    /// Maximum amount of regions streamed in per `update`, to spread the cost over multiple frames
+    pub max_loads_per_update: usize,
+}
+
+impl StreamingConfig {
+    pub fn new<P: AsRef<Path>>(cache_dir: P) -> Self {
+        Self {
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            resident_radius: 4,
+            evict_radius: 6,
+            max_loads_per_update: 2,
+        }
+    }
+}
+
+/// What happened during a single `WorldStreamer::update` call
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StreamReport {
+    pub regions_loaded: u32,
+    pub regions_evicted: u32,
+    /// Regions waiting to be streamed in
+    pub regions_pending: u32,
+}
+
+/// Keeps only the layer0 regions near the camera in GPU memory. Regions that end up too far
+/// away are written to a disk cache and freed, and get streamed back in through the voxel
+/// queue once the camera comes close again. This way the amount of layer0 nodes that are in
+/// use at once stays bounded by the radius, instead of growing with everything ever visited.
+/// Regions are identified by the world position of their first voxel.
+///
+/// Edits to a region that's evicted allocate it again, without the voxels in its cache file.
+/// Those edits get merged into the file before it's streamed back in, or when the region gets
+/// evicted again. Only voxels that were placed survive this, removing voxels from a region
+/// while it's evicted doesn't stick.
+pub struct WorldStreamer {
+    config: StreamingConfig,
+
+    /// Regions that have a file in the cache
+    cached: HashSet<IVec3>,
+    /// Cached regions whose file wasn't streamed in since it was written, so whatever the GPU
+    /// holds of them only came from edits made in the meantime
+    unloaded: HashSet<IVec3>,
+    /// Regions allocated on the GPU, as of the last time the camera moved to another region
+    resident: HashSet<IVec3>,
+    pending_loads: VecDeque<IVec3>,
+
+    camera_region: Option<IVec3>,
+}
+
+impl WorldStreamer {
+    /// Creates a streamer, picking up region files already in the cache directory
+    pub fn new(config: StreamingConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.cache_dir)?;
+        let mut cached = HashSet::new();
+        for entry in std::fs::read_dir(&config.cache_dir)? {
+            let path = entry?.path();
+            if let Some(region) = region_from_path(&path) {
+                cached.insert(region);
+            }
+        }
+        debug!("Found {} cached regions in {:?}", cached.len(), config.cache_dir);
+
+        Ok(Self {
+            config,
+
+            unloaded: cached.clone(),
+            cached,
+            resident: HashSet::new(),
+            pending_loads: VecDeque::new(),
+
+            camera_region: None,
+        })
+    }
+
+    pub fn config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    /// Evicts and streams in regions based on where the camera is. Call this once per frame,
+    /// before `World::process`. The resident regions only get checked when the camera moves
+    /// to another region, as that requires reading back the brick map. When the regions around
+    /// the camera don't fit in the brick map anymore, the world gets recentered on the camera,
+    /// see `World::recenter`, so the camera can go anywhere.
+    pub fn update(&mut self, ctx: &Context, world: &mut World, camera_pos: Vec3) -> anyhow::Result<StreamReport> {
+        puffin::profile_function!();
+        let mut report = StreamReport::default();
+        let size = world.config().brick_map_size as i32;
+        if size <= 2 * self.config.evict_radius as i32 {
+            anyhow::bail!("A brick map of size {} can't hold regions up to {} away from the camera!", size, self.config.evict_radius);
+        }
+
+        let camera_pos = camera_pos.floor().as_ivec3();
+        let camera_layer0 = world.config().layer0_pos(camera_pos);
+        let radius = self.config.evict_radius as i32;
+        if camera_layer0.cmplt(IVec3::splat(radius)).any() || camera_layer0.cmpge(IVec3::splat(size - radius)).any() {
+            report.regions_evicted += self.recenter(ctx, world, camera_layer0)?;
+        }
+
+        let world_config = *world.config();
+        let camera_region = world_config.layer0_world_pos(world_config.brick_map_idx(camera_pos).expect("Camera should be in the world after recentering!"));
+
+        if self.camera_region != Some(camera_region) {
+            self.camera_region = Some(camera_region);
+            self.resident = world.resident_layer0s(ctx).into_iter().map(|idx| world_config.layer0_world_pos(idx)).collect();
+
+            let far: Vec<IVec3> = self.resident.iter().copied().filter(|region| region_distance(*region, camera_region) > self.config.evict_radius).collect();
+            for region in far {
+                self.evict(ctx, world, region)?;
+                report.regions_evicted += 1;
+            }
+
+            // Regions that got edited while they were evicted are resident, but still need their file
+            let mut near: Vec<IVec3> = self.cached.iter().copied()
+                .filter(|region| !self.resident.contains(region) || self.unloaded.contains(region))
+                .filter(|region| region_distance(*region, camera_region) <= self.config.resident_radius)
+                .collect();
+            near.sort_by_key(|region| region_distance(*region, camera_region));
+            self.pending_loads = near.into();
+        }
+
+        while (report.regions_loaded as usize) < self.config.max_loads_per_update {
+            let region = match self.pending_loads.pop_front() {
+                Some(region) => region,
+                None => break,
+            };
+            self.load(ctx, world, region)?;
+            report.regions_loaded += 1;
+        }
+
+        report.regions_pending = self.pending_loads.len() as u32;
+        Ok(report)
+    }
+
+    /// Moves the world origin so the camera ends up in the middle of the brick map, evicting
+    /// the regions that don't fit in it anymore. Returns the amount of regions evicted.
+    fn recenter(&mut self, ctx: &Context, world: &mut World, camera_layer0: IVec3) -> anyhow::Result<u32> {
+        let world_config = *world.config();
+        let size = world_config.brick_map_size as i32;
+        let origin = world_config.origin + (IVec3::splat(size / 2) - camera_layer0) * 256;
+        let moved = WorldConfig { origin, ..world_config };
+
+        let mut evicted = 0;
+        for idx in world.resident_layer0s(ctx) {
+            let region = world_config.layer0_world_pos(idx);
+            if moved.brick_map_idx(region).is_none() {
+                self.evict(ctx, world, region)?;
+                evicted += 1;
+            }
+        }
+        world.recenter(ctx, origin)?;
+        // Forces the resident regions to be looked at again
+        self.camera_region = None;
+        Ok(evicted)
+    }
+
+    fn evict(&mut self, ctx: &Context, world: &mut World, region: IVec3) -> anyhow::Result<()> {
+        let brick_map_idx = match world.config().brick_map_idx(region) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        let bricks = world.evict_layer0(ctx, brick_map_idx);
+        debug!("Evicted region {} ({} bricks)", region, bricks.len());
+        self.store(region, bricks)?;
+        self.resident.remove(&region);
+        Ok(())
+    }
+
+    /// Writes the bricks of an evicted region to the cache. If the file in the cache wasn't
+    /// streamed in, the bricks only hold the edits made since, so they get merged into it.
+    fn store(&mut self, region: IVec3, bricks: Vec<(usize, Brick)>) -> anyhow::Result<()> {
+        let path = self.region_path(region);
+        let bricks = if self.unloaded.contains(&region) {
+            if bricks.is_empty() { return Ok(()); }
+            merge_bricks(region_from_bytes(&std::fs::read(&path)?)?, bricks)
+        } else {
+            bricks
+        };
+
+        if bricks.is_empty() {
+            // Everything in it got removed, so an older version in the cache is outdated
+            if self.cached.remove(&region) {
+                std::fs::remove_file(path)?;
+            }
+            self.unloaded.remove(&region);
+        } else {
+            std::fs::write(path, region_to_bytes(&bricks)?)?;
+            self.cached.insert(region);
+            self.unloaded.insert(region);
+        }
+        Ok(())
+    }
+
+    /// Streams a cached region in. Anything edited since it was evicted gets merged into its
+    /// file first, which means reading the region back from the GPU, so this stalls!
+    fn load(&mut self, ctx: &Context, world: &mut World, region: IVec3) -> anyhow::Result<()> {
+        if let Some(brick_map_idx) = world.config().brick_map_idx(region) {
+            let edited = world.evict_layer0(ctx, brick_map_idx);
+            self.store(region, edited)?;
+        }
+        let batch = self.read_region(region)?;
+        debug!("Streamed in region {} ({} voxels)", region, batch.len());
+        // Queued ahead of everything else, so edits that are queued already win over the file
+        world.stream_in(batch);
+        // The cached file stays around, it gets overwritten when the region is evicted again
+        self.unloaded.remove(&region);
+        self.resident.insert(region);
+        Ok(())
+    }
+
+    /// The voxels in the cache file of a region
+    fn read_region(&self, region: IVec3) -> anyhow::Result<VoxelBatch> {
+        let bytes = std::fs::read(self.region_path(region))?;
+        let mut batch = VoxelBatch::new();
+        for (layer0_idx, voxels) in region_from_bytes(&bytes)? {
+            let brick_world_pos = region + brick_pos(layer0_idx) * 16;
+            batch.extend(voxels.iter().enumerate().filter(|(_, voxel)| voxel.0 != 0).map(|(i, voxel)| {
+                (*voxel, brick_world_pos + voxel_pos(i))
+            }));
+        }
+        Ok(batch)
+    }
+
+    fn region_path(&self, region: IVec3) -> PathBuf {
+        self.config.cache_dir.join(format!("region_{}_{}_{}.{}", region.x, region.y, region.z, REGION_FILE_EXTENSION))
+    }
+}
+
+/// Position of a brick within its layer0 node, in bricks
+fn brick_pos(layer0_idx: usize) -> IVec3 {
+    ivec3((layer0_idx % 16) as i32, ((layer0_idx / 16) % 16) as i32, (layer0_idx / 256) as i32)
+}
+
+/// Position of a voxel within its brick
+fn voxel_pos(i: usize) -> IVec3 {
+    ivec3((i % 16) as i32, ((i / 16) % 16) as i32, (i / 256) as i32)
+}
+
+/// Puts the voxels of `edits` on top of the bricks of a region file. Empty voxels in the
+/// edits can't be told apart from voxels that were never written, so they don't remove anything.
+fn merge_bricks(cached: Vec<(usize, Vec<Voxel>)>, edits: Vec<(usize, Brick)>) -> Vec<(usize, Brick)> {
+    let mut merged: Vec<(usize, Brick)> = cached.into_iter().map(|(layer0_idx, voxels)| {
+        let mut brick = Brick::empty();
+        for (i, voxel) in voxels.into_iter().enumerate() {
+            if voxel.0 != 0 {
+                brick.set_voxel(voxel, voxel_pos(i).as_uvec3());
+            }
+        }
+        (layer0_idx, brick)
+    }).collect();
+    for (layer0_idx, edit) in edits {
+        match merged.iter_mut().find(|(idx, _)| *idx == layer0_idx) {
+            Some((_, brick)) => {
+                for i in 0..16 * 16 * 16 {
+                    let pos = voxel_pos(i).as_uvec3();
+                    let voxel = *edit.get_voxel(pos);
+                    if voxel.0 != 0 {
+                        brick.set_voxel(voxel, pos);
+                    }
+                }
+            },
+            None => merged.push((layer0_idx, edit)),
+        }
+    }
+    merged
+}
+
+/// Distance between two regions in layer0 nodes, along the axis where they're furthest apart
+fn region_distance(a: IVec3, b: IVec3) -> u32 {
+    let d = (a - b).abs() / 256;
+    d.max_element() as u32
+}
+
+fn region_from_path(path: &Path) -> Option<IVec3> {
+    if path.extension()? != REGION_FILE_EXTENSION { return None; }
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.strip_prefix("region_")?.split('_').map(|p| p.parse::<i32>());
+    let pos = ivec3(parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+    if parts.next().is_some() { return None; }
+    Some(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(i: u8) -> Voxel {
+        Voxel::new([i, 2, 3], 255, 0, false, 255)
+    }
+
+    fn brick(voxels: &[(Voxel, UVec3)]) -> Brick {
+        let mut brick = Brick::empty();
+        for (voxel, pos) in voxels {
+            brick.set_voxel(*voxel, *pos);
+        }
+        brick
+    }
+
+    /// Streamer with an empty cache directory of its own
+    fn streamer(name: &str) -> WorldStreamer {
+        let dir = std::env::temp_dir().join(format!("stardust_stream_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        WorldStreamer::new(StreamingConfig::new(dir)).unwrap()
+    }
+
+    fn voxels(batch: &VoxelBatch) -> Vec<(u32, IVec3)> {
+        let mut voxels: Vec<(u32, IVec3)> = batch.voxels().iter().map(|(voxel, pos)| (voxel.0, *pos)).collect();
+        voxels.sort_by_key(|(_, pos)| (pos.x, pos.y, pos.z));
+        voxels
+    }
+
+    #[test]
+    fn region_distances() {
+        assert_eq!(region_distance(IVec3::ZERO, IVec3::ZERO), 0);
+        assert_eq!(region_distance(ivec3(256, 0, 0), IVec3::ZERO), 1);
+        assert_eq!(region_distance(ivec3(-512, 256, 768), IVec3::ZERO), 3);
+        assert_eq!(region_distance(ivec3(-512, 0, 0), ivec3(512, 0, -256)), 4);
+    }
+
+    #[test]
+    fn region_paths() {
+        let streamer = streamer("paths");
+        for region in [IVec3::ZERO, ivec3(-256, 512, -7680)] {
+            let path = streamer.region_path(region);
+            assert!(path.starts_with(&streamer.config.cache_dir));
+            assert_eq!(region_from_path(&path), Some(region));
+        }
+        assert_eq!(region_from_path(Path::new("region_1_2_3.sdw")), None);
+        assert_eq!(region_from_path(Path::new("region_1_2.sdwr")), None);
+        assert_eq!(region_from_path(Path::new("region_1_2_3_4.sdwr")), None);
+        assert_eq!(region_from_path(Path::new("region_1_x_3.sdwr")), None);
+        assert_eq!(region_from_path(Path::new("world_1_2_3.sdwr")), None);
+        std::fs::remove_dir_all(&streamer.config.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn evict_load_round_trip() {
+        let mut streamer = streamer("round_trip");
+        let region = ivec3(-256, 0, 512);
+        streamer.store(region, vec![
+            (0, brick(&[(voxel(1), uvec3(1, 2, 3))])),
+            (4095, brick(&[(voxel(2), uvec3(15, 15, 15))])),
+        ]).unwrap();
+        assert!(streamer.cached.contains(&region));
+        assert!(streamer.unloaded.contains(&region));
+        let expected = vec![(voxel(1).0, region + ivec3(1, 2, 3)), (voxel(2).0, region + IVec3::splat(255))];
+        assert_eq!(voxels(&streamer.read_region(region).unwrap()), expected);
+
+        // Another streamer picks the file up
+        let reopened = WorldStreamer::new(streamer.config.clone()).unwrap();
+        assert!(reopened.cached.contains(&region) && reopened.unloaded.contains(&region));
+        assert_eq!(voxels(&reopened.read_region(region).unwrap()), expected);
+
+        // Once streamed in, evicting it again replaces the file
+        streamer.unloaded.remove(&region);
+        streamer.store(region, vec![(0, brick(&[(voxel(3), uvec3(0, 0, 0))]))]).unwrap();
+        assert_eq!(voxels(&streamer.read_region(region).unwrap()), vec![(voxel(3).0, region)]);
+
+        // Or removes it if everything in it is gone
+        streamer.unloaded.remove(&region);
+        streamer.store(region, Vec::new()).unwrap();
+        assert!(!streamer.cached.contains(&region));
+        assert!(!streamer.region_path(region).exists());
+        std::fs::remove_dir_all(&streamer.config.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn edits_while_evicted_are_merged() {
+        let mut streamer = streamer("edited");
+        let region = ivec3(0, -256, 0);
+        streamer.store(region, vec![(0, brick(&[(voxel(1), uvec3(1, 2, 3)), (voxel(2), uvec3(5, 5, 5))]))]).unwrap();
+
+        // Edits to the evicted region allocate it again, evicting it only gives back the edits
+        streamer.store(region, vec![
+            (0, brick(&[(voxel(4), uvec3(1, 2, 3))])),
+            (7, brick(&[(voxel(5), uvec3(0, 1, 0))])),
+        ]).unwrap();
+        assert!(streamer.unloaded.contains(&region));
+        assert_eq!(voxels(&streamer.read_region(region).unwrap()), vec![
+            (voxel(4).0, region + ivec3(1, 2, 3)),
+            (voxel(2).0, region + ivec3(5, 5, 5)),
+            (voxel(5).0, region + ivec3(7 * 16, 1, 0)),
+        ]);
+
+        // Nothing on the GPU leaves the file alone
+        streamer.store(region, Vec::new()).unwrap();
+        assert!(streamer.cached.contains(&region));
+        assert_eq!(streamer.read_region(region).unwrap().len(), 3);
+        std::fs::remove_dir_all(&streamer.config.cache_dir).unwrap();
+    }
+}
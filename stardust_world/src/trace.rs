@@ -0,0 +1,366 @@
+use stardust_common::math::*;
+use stardust_common::voxel::Voxel;
+
+use crate::CpuWorld;
+use crate::brick::*;
+
+// Same limit as the loop in `traceVoxels`
+const MAX_STEPS: u32 = 1024;
+
+/// Result of `CpuWorld::raycast` and `World::raycast`
+#[derive(Debug, Copy, Clone)]
+pub struct RayHit {
+    /// World position of the voxel that was hit
+    pub pos: IVec3,
+    /// Normal of the face the ray entered through, zero if the ray started inside the voxel
+    pub normal: IVec3,
+    pub voxel: Voxel,
+    /// Distance from the ray origin to where it entered the voxel
+    pub distance: f32,
+}
+
+/// Result of `CpuWorld::trace`
+#[derive(Debug, Copy, Clone)]
+pub struct TraceHit {
+    /// Distance from the ray origin to where it entered the voxel
+    pub distance: f32,
+    /// Normal of the face the ray entered through, zero if the ray started inside the voxel
+    pub normal: Vec3,
+    pub voxel: Voxel,
+    /// Loop iterations the traversal needed, every skip counts as a single step like in the shader
+    pub steps: u32,
+}
+
+impl CpuWorld {
+    /// Finds the first voxel along a ray, for things like picking and line of sight checks.
+    /// Walks the same layer0 -> brick -> voxel hierarchy as the renderer, skipping empty space.
+    /// `origin` is in world space, `dir` doesn't need to be normalized. Voxels further away
+    /// than `max_dist` are ignored, and so is anything queued but not processed yet.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        let (hit, grid_pos) = self.trace_internal(origin, dir, max_dist, u32::MAX, true)?;
+        Some(RayHit {
+            pos: grid_pos.as_ivec3() - self.config().origin,
+            normal: hit.normal.as_ivec3(),
+            voxel: hit.voxel,
+            distance: hit.distance,
+        })
+    }
+
+    /// CPU reference of `trace` in fs.glsl. Walks the layer0 -> brick -> voxel hierarchy,
+    /// skipping empty layer0 nodes, bricks and 4x4x4 cells using the brick occupancy masks.
+    /// `origin` is in world space, `dir` doesn't need to be normalized.
+    pub fn trace(&self, origin: Vec3, dir: Vec3) -> Option<TraceHit> {
+        self.trace_internal(origin, dir, f32::INFINITY, MAX_STEPS, true).map(|(hit, _)| hit)
+    }
+
+    /// Same as `trace`, but steps through every voxel of allocated bricks like the renderer did
+    /// before it had occupancy masks. Both should always hit the same voxel.
+    pub fn trace_dense(&self, origin: Vec3, dir: Vec3) -> Option<TraceHit> {
+        self.trace_internal(origin, dir, f32::INFINITY, MAX_STEPS, false).map(|(hit, _)| hit)
+    }
+
+    /// Returns the hit and the position of the voxel that was hit, in the brick map
+    fn trace_internal(&self, origin: Vec3, dir: Vec3, max_dist: f32, max_steps: u32, skip_cells: bool) -> Option<(TraceHit, Vec3)> {
+        let rd = dir.normalize();
+        if !rd.is_finite() { return None; }
+        // Axis aligned rays would divide by zero, and the infinities turn into NaNs further on
+        let rd = Vec3::select(rd.abs().cmplt(Vec3::splat(1e-8)), Vec3::splat(1e-8), rd);
+        let ro = origin + self.config().origin.as_vec3();
+
+        let half_size = Vec3::splat(self.config().world_size() as f32 / 2.0);
+        let (t_near, t_far, entry_normal) = box_intersection(ro - half_size, rd, half_size)?;
+        // Start where the ray enters the world, unless it starts inside already
+        let start = t_near.max(0.0);
+        if start > max_dist { return None; }
+        let normal = match t_near > 0.0 {
+            true => entry_normal,
+            false => Vec3::ZERO,
+        };
+        let (mut hit, grid_pos) = self.trace_voxels(ro + rd * start, rd, normal, t_far.min(max_dist) - start, max_steps, skip_cells)?;
+        hit.distance += start;
+        if hit.distance > max_dist { return None; }
+        Some((hit, grid_pos))
+    }
+
+    /// Mirrors `traceVoxels` in fs.glsl. `normal` is the one reported if the first voxel is hit,
+    /// the face of the world the ray entered through.
+    fn trace_voxels(&self, ro: Vec3, rd: Vec3, mut normal: Vec3, tmax: f32, max_steps: u32, skip_cells: bool) -> Option<(TraceHit, Vec3)> {
+        // Entering through the far side of an axis lands exactly on the world's edge, which
+        // would round to the voxel just outside of it
+        let mut grid_pos = ro.floor().clamp(Vec3::ZERO, Vec3::splat(self.config().world_size() as f32 - 1.0));
+        let mut to_side = ((rd.signum() * 0.5 + 0.5) + grid_pos - ro) / rd;
+
+        let mut dist = 0.0;
+        let mut mask;
+
+        for steps in 0..max_steps {
+            let pos = grid_pos.as_ivec3();
+            let layer0_pos = (grid_pos / 256.0).floor().as_ivec3();
+            let brick_pos = (grid_pos / 16.0).floor().as_ivec3();
+            let voxel_pos = (pos - brick_pos * 16).as_uvec3();
+
+            match self.lookup_layer0(layer0_pos) {
+                Some(layer0_pool_idx) => match self.lookup_brick(brick_pos - layer0_pos * 16, layer0_pool_idx) {
+                    Some(brick) => {
+                        if !skip_cells || brick.cell_occupied(voxel_pos) {
+                            let voxel = *brick.get_voxel(voxel_pos);
+                            if voxel.0 != 0 {
+                                return Some((TraceHit { distance: dist, normal, voxel, steps }, grid_pos));
+                            }
+
+                            mask = step_mask(to_side);
+                            dist = (to_side * mask).dot(Vec3::ONE);
+                            normal = mask * -rd.signum();
+                        } else {
+                            let cell_pos = (grid_pos / CELL_SIZE as f32).floor().as_ivec3();
+                            (normal, dist, mask) = skip_region(ro, rd, grid_pos, cell_pos, CELL_SIZE as f32);
+                        }
+                    },
+                    None => {
+                        (normal, dist, mask) = skip_region(ro, rd, grid_pos, brick_pos, 16.0);
+                    },
+                },
+                None => {
+                    (normal, dist, mask) = skip_region(ro, rd, grid_pos, layer0_pos, 256.0);
+                },
+            }
+
+            grid_pos += mask * rd.signum();
+            // Recomputed rather than accumulated, so rounding errors don't depend on how the ray got here
+            to_side = ((rd.signum() * 0.5 + 0.5) + grid_pos - ro) / rd;
+
+            // `dist` is where the ray enters the next voxel, so anything after it is out of reach too
+            if dist > tmax { return None; }
+        }
+
+        None
+    }
+
+    pub(crate) fn lookup_layer0(&self, layer0_pos: IVec3) -> Option<u32> {
+        let size = self.config().brick_map_size as i32;
+        if layer0_pos.cmplt(IVec3::ZERO).any() || layer0_pos.cmpge(IVec3::splat(size)).any() { return None; }
+        let brick_map_idx = layer0_pos.x + layer0_pos.y * size + layer0_pos.z * size * size;
+        match self.layer0_map()[brick_map_idx as usize] {
+            0 => None,
+            idx => Some(idx),
+        }
+    }
+
+    pub(crate) fn lookup_brick(&self, brick_pos: IVec3, layer0_pool_idx: u32) -> Option<&Brick> {
+        let layer0_idx = brick_pos.x + brick_pos.y * 16 + brick_pos.z * 16 * 16;
+        let brick_pool_idx = self.get_layer0(layer0_pool_idx)?.brick_indices[layer0_idx as usize];
+        self.get_brick(brick_pool_idx)
+    }
+}
+
+/// Mirrors `stepMask` in fs.glsl, picks the axis the ray crosses first. Ties pick a single
+/// axis too, x before y before z, as moving along two axes at once would skip a voxel.
+fn step_mask(v: Vec3) -> Vec3 {
+    if v.x <= v.y && v.x <= v.z {
+        Vec3::X
+    } else if v.y <= v.z {
+        Vec3::Y
+    } else {
+        Vec3::Z
+    }
+}
+
+/// Jumps to the first voxel past the region (layer0 node, brick or cell) at `region_pos`.
+/// Returns the normal, distance and how far to move along each axis, like the shader does.
+fn skip_region(ro: Vec3, rd: Vec3, grid_pos: Vec3, region_pos: IVec3, region_size: f32) -> (Vec3, f32, Vec3) {
+    let to_exit = ((rd.signum() * 0.5 + 0.5 + region_pos.as_vec3()) * region_size - ro) / rd;
+    let normal = -rd.signum() * step_mask(to_exit);
+    let dist = normal.abs().dot(to_exit);
+    // The exit point can land right on the edge of a voxel along another axis, where rounding
+    // puts it a voxel back. Never moving backwards keeps `grid_pos` and `to_side` in step.
+    let mask = (((ro + rd * dist - normal * 0.1).floor() - grid_pos) * rd.signum()).max(Vec3::ZERO);
+    (normal, dist, mask)
+}
+
+/// Mirrors `boxIntersection` in fs.glsl, returns the entry and exit distances along with the
+/// normal of the face the ray enters through
+fn box_intersection(ro: Vec3, rd: Vec3, rad: Vec3) -> Option<(f32, f32, Vec3)> {
+    let m = rd.recip();
+    let n = m * ro;
+    let k = m.abs() * rad;
+    let t1 = -n - k;
+    let t2 = -n + k;
+
+    let t_near = t1.max_element();
+    let t_far = t2.min_element();
+
+    if t_near > t_far || t_far < 0.0 { return None; }
+    Some((t_near, t_far, -rd.signum() * step_mask(-t1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::WorldConfig;
+
+    use super::*;
+
+    /// xorshift, good enough to scatter voxels and rays around
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn range(&mut self, min: i32, max: i32) -> i32 {
+            min + (self.next() % (max - min) as u32) as i32
+        }
+
+        fn vec3(&mut self, min: f32, max: f32) -> Vec3 {
+            Vec3::new(self.next() as f32, self.next() as f32, self.next() as f32) / u32::MAX as f32 * (max - min) + min
+        }
+    }
+
+    /// World spanning -256..256, with voxels scattered around a few spots. A third of them get
+    /// cleared again, and the deallocation passes only get to some of the bricks, so part of
+    /// the occupancy masks is out of date.
+    /// Returns the world and the positions that were written to.
+    fn random_world(rng: &mut Rng) -> (CpuWorld, Vec<IVec3>) {
+        let mut world = CpuWorld::new(WorldConfig {
+            brick_pool_size: 512,
+            layer0_pool_size: 8,
+            brick_map_size: 2,
+            origin: IVec3::splat(256),
+            voxel_queue_size: 4096,
+            dealloc_queue_size: 64,
+            ..WorldConfig::default()
+        });
+        let mut written = Vec::new();
+        for _ in 0..12 {
+            let center = ivec3(rng.range(-240, 240), rng.range(-240, 240), rng.range(-240, 240));
+            for _ in 0..800 {
+                let pos = center + ivec3(rng.range(-10, 10), rng.range(-10, 10), rng.range(-10, 10));
+                world.set_voxel(Voxel(rng.next() | 1), pos);
+                written.push(pos);
+            }
+        }
+        world.process();
+        for pos in written.iter().step_by(3) {
+            world.set_voxel(Voxel::empty(), *pos);
+        }
+        world.process();
+        (world, written)
+    }
+
+    /// World spanning -256..256 with the given voxels
+    fn world_with(voxels: &[IVec3]) -> CpuWorld {
+        let mut world = CpuWorld::new(WorldConfig {
+            brick_pool_size: 64,
+            layer0_pool_size: 8,
+            brick_map_size: 2,
+            origin: IVec3::splat(256),
+            ..WorldConfig::default()
+        });
+        for (i, pos) in voxels.iter().enumerate() {
+            world.set_voxel(Voxel(i as u32 + 1), *pos);
+        }
+        world.process();
+        world
+    }
+
+    #[test]
+    fn raycast_along_axes() {
+        let world = world_with(&[ivec3(5, 0, 0), ivec3(-5, 0, 0), ivec3(0, 5, 0), ivec3(0, 0, -5)]);
+        let origin = Vec3::splat(0.5);
+        let cases = [
+            (Vec3::X, ivec3(5, 0, 0), ivec3(-1, 0, 0), 1),
+            (-Vec3::X, ivec3(-5, 0, 0), ivec3(1, 0, 0), 2),
+            (Vec3::Y, ivec3(0, 5, 0), ivec3(0, -1, 0), 3),
+            (-Vec3::Z, ivec3(0, 0, -5), ivec3(0, 0, 1), 4),
+        ];
+        for (dir, pos, normal, voxel) in cases {
+            let hit = world.raycast(origin, dir, 100.0).unwrap();
+            assert_eq!(hit.pos, pos, "along {}", dir);
+            assert_eq!(hit.normal, normal, "along {}", dir);
+            assert_eq!(hit.voxel.0, voxel);
+            assert!((hit.distance - 4.5).abs() < 1e-4, "along {}: {}", dir, hit.distance);
+        }
+        assert!(world.raycast(origin, -Vec3::Y, 1000.0).is_none());
+        // Not normalized
+        assert_eq!(world.raycast(origin, Vec3::X * 20.0, 100.0).unwrap().pos, ivec3(5, 0, 0));
+        assert!(world.raycast(origin, Vec3::ZERO, 100.0).is_none());
+    }
+
+    #[test]
+    fn raycast_starting_inside_a_voxel() {
+        let world = world_with(&[ivec3(5, 0, 0), ivec3(8, 0, 0)]);
+        let hit = world.raycast(vec3(5.25, 0.5, 0.75), vec3(1.0, 0.3, -0.2), 100.0).unwrap();
+        assert_eq!(hit.pos, ivec3(5, 0, 0));
+        assert_eq!(hit.normal, IVec3::ZERO);
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn raycast_hits_the_world_border() {
+        let world = world_with(&[ivec3(-256, 0, 0), ivec3(255, 0, 0), ivec3(3, -256, 7)]);
+        let hit = world.raycast(vec3(-300.5, 0.5, 0.5), Vec3::X, 100.0).unwrap();
+        assert_eq!(hit.pos, ivec3(-256, 0, 0));
+        assert_eq!(hit.normal, ivec3(-1, 0, 0));
+        assert!((hit.distance - 44.5).abs() < 1e-4);
+
+        // Entering through the far side of the world
+        let hit = world.raycast(vec3(300.5, 0.5, 0.5), -Vec3::X, 100.0).unwrap();
+        assert_eq!(hit.pos, ivec3(255, 0, 0));
+        assert_eq!(hit.normal, ivec3(1, 0, 0));
+        assert!((hit.distance - 44.5).abs() < 1e-4);
+
+        let hit = world.raycast(vec3(3.5, -260.0, 7.5), vec3(0.0, 1.0, 0.01), 100.0).unwrap();
+        assert_eq!(hit.pos, ivec3(3, -256, 7));
+        assert_eq!(hit.normal, ivec3(0, -1, 0));
+
+        // Running along the border without entering
+        assert!(world.raycast(vec3(-300.5, 0.5, 0.5), -Vec3::X, 1000.0).is_none());
+    }
+
+    #[test]
+    fn raycast_stops_at_max_dist() {
+        let world = world_with(&[ivec3(5, 0, 0), ivec3(-256, 0, 0)]);
+        let origin = Vec3::splat(0.5);
+        assert!(world.raycast(origin, Vec3::X, 4.0).is_none());
+        assert!(world.raycast(origin, Vec3::X, 4.5).is_some());
+        assert!(world.raycast(origin, Vec3::X, 5.0).is_some());
+
+        // Cut off before the ray even enters the world
+        let origin = vec3(-300.5, 0.5, 0.5);
+        assert!(world.raycast(origin, Vec3::X, 40.0).is_none());
+        assert!(world.raycast(origin, Vec3::X, 45.0).is_some());
+    }
+
+    #[test]
+    fn trace_matches_trace_dense() {
+        let mut rng = Rng(0x2545F491);
+        let mut hits = 0;
+        for _ in 0..4 {
+            let (world, written) = random_world(&mut rng);
+            for _ in 0..500 {
+                // Aim near voxels that were written, so most rays hit something or only just miss
+                let origin = rng.vec3(-300.0, 300.0);
+                let target = written[rng.range(0, written.len() as i32) as usize].as_vec3() + rng.vec3(-4.0, 4.0);
+                let dir = target - origin;
+                let skipped = world.trace_internal(origin, dir, f32::INFINITY, u32::MAX, true);
+                let dense = world.trace_internal(origin, dir, f32::INFINITY, u32::MAX, false);
+                match (skipped, dense) {
+                    (Some((a, a_pos)), Some((b, b_pos))) => {
+                        assert_eq!(a_pos, b_pos, "ray from {} along {}", origin, dir);
+                        assert_eq!(a.voxel.0, b.voxel.0);
+                        assert_eq!(a.normal, b.normal);
+                        assert!((a.distance - b.distance).abs() < 1e-2);
+                        hits += 1;
+                    },
+                    (None, None) => {},
+                    (a, b) => panic!("ray from {} along {} hit {:?} and {:?}", origin, dir, a.map(|a| a.1), b.map(|b| b.1)),
+                }
+            }
+        }
+        // Make sure the test actually hits things
+        assert!(hits > 1000, "Only {} rays hit", hits);
+    }
+}